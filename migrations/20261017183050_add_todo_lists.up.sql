@@ -0,0 +1,14 @@
+CREATE TABLE IF NOT EXISTS todo_lists
+(
+    id         BIGSERIAL PRIMARY KEY,
+    owner_id   BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    name       TEXT NOT NULL,
+    archived   BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS todo_lists_owner_id_idx ON todo_lists (owner_id);
+
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS list_id BIGINT REFERENCES todo_lists (id) ON DELETE CASCADE;
+
+CREATE INDEX IF NOT EXISTS todos_list_id_idx ON todos (list_id);
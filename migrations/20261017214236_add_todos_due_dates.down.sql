@@ -0,0 +1,7 @@
+DROP INDEX IF EXISTS todos_due_at_idx;
+
+ALTER TABLE todos DROP COLUMN IF EXISTS completed_at;
+
+ALTER TABLE todos DROP COLUMN IF EXISTS priority;
+
+ALTER TABLE todos DROP COLUMN IF EXISTS due_at;
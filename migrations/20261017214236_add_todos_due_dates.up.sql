@@ -0,0 +1,11 @@
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS due_at TIMESTAMPTZ;
+
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS priority TEXT NOT NULL DEFAULT 'normal'
+    CONSTRAINT todos_priority_check CHECK (priority IN ('low', 'normal', 'high', 'urgent'));
+
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS completed_at TIMESTAMP;
+
+-- The best we know about todos that were already done.
+UPDATE todos SET completed_at = created_at WHERE done AND completed_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS todos_due_at_idx ON todos (due_at) WHERE NOT done;
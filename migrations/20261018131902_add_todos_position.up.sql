@@ -0,0 +1,9 @@
+-- Only moves set a todo's rank; until then it keeps its place in order of
+-- creation. Ranks are compared bytewise, and unique per owner so that two
+-- todos dropped into the same gap at once cannot end up tied.
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS moved_position TEXT COLLATE "C";
+
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS position TEXT COLLATE "C" NOT NULL
+    GENERATED ALWAYS AS (COALESCE(moved_position, 'a' || lpad(id::text, 19, '0') || 'V')) STORED;
+
+CREATE UNIQUE INDEX IF NOT EXISTS todos_owner_id_position_idx ON todos (owner_id, position);
@@ -0,0 +1,17 @@
+-- Every write to a todo, kept after the todo itself is gone, so neither
+-- todo_id nor the user ids reference anything.
+CREATE TABLE IF NOT EXISTS todo_events
+(
+    id          BIGSERIAL PRIMARY KEY,
+    todo_id     BIGINT    NOT NULL,
+    kind        TEXT      NOT NULL CONSTRAINT todo_events_kind_check CHECK (kind IN ('created', 'updated', 'deleted')),
+    -- Who the todo belonged to at the time, and who made the change, if they said.
+    owner_id    BIGINT,
+    actor_id    BIGINT,
+    -- The fields that changed, each as {"before": ..., "after": ...}.
+    changes     JSONB     NOT NULL,
+    occurred_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS todo_events_todo_id_idx ON todo_events (todo_id, id);
+CREATE INDEX IF NOT EXISTS todo_events_owner_id_idx ON todo_events (owner_id, occurred_at);
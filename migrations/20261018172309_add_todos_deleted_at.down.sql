@@ -0,0 +1,10 @@
+-- Without a trash, whatever is in it is gone.
+DELETE FROM todo_events WHERE kind IN ('restored', 'purged');
+ALTER TABLE todo_events DROP CONSTRAINT IF EXISTS todo_events_kind_check;
+ALTER TABLE todo_events ADD CONSTRAINT todo_events_kind_check CHECK (kind IN ('created', 'updated', 'deleted'));
+
+DELETE FROM todos WHERE deleted_at IS NOT NULL;
+
+DROP INDEX IF EXISTS todos_deleted_at_idx;
+
+ALTER TABLE todos DROP COLUMN IF EXISTS deleted_at;
@@ -0,0 +1,10 @@
+-- Deleting a todo only moves it to the trash, from where it can be restored
+-- until it is purged for good. Everything but the trash leaves these out.
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS deleted_at TIMESTAMP;
+
+CREATE INDEX IF NOT EXISTS todos_deleted_at_idx ON todos (owner_id, deleted_at) WHERE deleted_at IS NOT NULL;
+
+-- Going into the trash is a deletion; coming back out, or leaving it for
+-- good, are kinds of event of their own.
+ALTER TABLE todo_events DROP CONSTRAINT IF EXISTS todo_events_kind_check;
+ALTER TABLE todo_events ADD CONSTRAINT todo_events_kind_check CHECK (kind IN ('created', 'updated', 'deleted', 'restored', 'purged'));
@@ -0,0 +1,57 @@
+-- Every change to a todo that clients can see, however it was made, so that
+-- GET /todos/events can stream them and pick up again from any of them. A
+-- trigger records them, and NOTIFYs the todo_changes channel with each one
+-- as JSON, so that every instance of the app hears about it.
+CREATE TABLE IF NOT EXISTS todo_changes
+(
+    id          BIGSERIAL PRIMARY KEY,
+    todo_id     BIGINT    NOT NULL,
+    kind        TEXT      NOT NULL CONSTRAINT todo_changes_kind_check CHECK (kind IN ('created', 'updated', 'deleted')),
+    -- Who the todo belonged to at the time.
+    owner_id    BIGINT,
+    occurred_at TIMESTAMP NOT NULL DEFAULT now(),
+    -- The transaction that made the change. Ids are handed out as changes
+    -- are made, not as they are committed, so a change can show up after
+    -- others with higher ids. Changes are read in the order of their
+    -- transactions, and only once every transaction older than them is
+    -- over, so that none ever shows up behind a reader that has moved on
+    -- from where it belongs.
+    xid         XID8      NOT NULL DEFAULT pg_current_xact_id()
+);
+
+CREATE INDEX IF NOT EXISTS todo_changes_owner_id_xid_idx ON todo_changes (owner_id, xid, id);
+
+CREATE OR REPLACE FUNCTION record_todo_change() RETURNS TRIGGER AS $$
+DECLARE
+    todo   todos;
+    change todo_changes;
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        todo := NEW;
+        change.kind := CASE WHEN NEW.deleted_at IS NULL THEN 'created' END;
+    ELSIF TG_OP = 'DELETE' THEN
+        -- Purging a todo from the trash changes nothing anyone can see.
+        todo := OLD;
+        change.kind := CASE WHEN OLD.deleted_at IS NULL THEN 'deleted' END;
+    ELSE
+        -- A todo coming back out of the trash shows up as if it were new.
+        todo := NEW;
+        change.kind := CASE
+            WHEN OLD.deleted_at IS NULL AND NEW.deleted_at IS NOT NULL THEN 'deleted'
+            WHEN OLD.deleted_at IS NOT NULL AND NEW.deleted_at IS NULL THEN 'created'
+            WHEN NEW.deleted_at IS NULL AND NEW IS DISTINCT FROM OLD THEN 'updated'
+        END;
+    END IF;
+
+    IF change.kind IS NOT NULL THEN
+        INSERT INTO todo_changes (todo_id, kind, owner_id) VALUES (todo.id, change.kind, todo.owner_id) RETURNING * INTO change;
+        PERFORM pg_notify('todo_changes', json_build_object('id', change.id, 'todo_id', change.todo_id, 'kind', change.kind, 'owner_id', change.owner_id)::text);
+    END IF;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS todos_record_change ON todos;
+CREATE TRIGGER todos_record_change AFTER INSERT OR UPDATE OR DELETE ON todos
+    FOR EACH ROW EXECUTE FUNCTION record_todo_change();
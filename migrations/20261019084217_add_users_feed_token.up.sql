@@ -0,0 +1,3 @@
+-- The secret in the URL of a user's calendar feed, which calendar apps fetch
+-- without signing in. Like API tokens, it is stored hashed.
+ALTER TABLE users ADD COLUMN IF NOT EXISTS feed_token_hash BYTEA CONSTRAINT users_feed_token_hash_key UNIQUE;
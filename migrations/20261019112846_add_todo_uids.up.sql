@@ -0,0 +1,9 @@
+-- The UIDs calendar apps gave the todos they created over CalDAV, which they
+-- expect to find them by from then on. Other todos go by UIDs made from their ids.
+CREATE TABLE IF NOT EXISTS todo_uids
+(
+    todo_id  BIGINT NOT NULL PRIMARY KEY REFERENCES todos (id) ON DELETE CASCADE,
+    owner_id BIGINT NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    uid      TEXT   NOT NULL,
+    CONSTRAINT todo_uids_owner_id_uid_key UNIQUE (owner_id, uid)
+);
@@ -0,0 +1,4 @@
+-- How a repeating todo repeats, as the DTSTART and RRULE lines of an
+-- iCalendar component, with the time zone it keeps to. Only todos with a
+-- due date repeat.
+ALTER TABLE todos ADD COLUMN IF NOT EXISTS recurrence TEXT;
@@ -0,0 +1,26 @@
+CREATE VIRTUAL TABLE IF NOT EXISTS todos_search USING fts5
+(
+    title,
+    description,
+    content = 'todos',
+    content_rowid = 'id',
+    tokenize = 'porter unicode61'
+);
+
+INSERT INTO todos_search (rowid, title, description) SELECT id, title, description FROM todos;
+
+CREATE TRIGGER IF NOT EXISTS todos_search_insert AFTER INSERT ON todos
+BEGIN
+    INSERT INTO todos_search (rowid, title, description) VALUES (new.id, new.title, new.description);
+END;
+
+CREATE TRIGGER IF NOT EXISTS todos_search_delete AFTER DELETE ON todos
+BEGIN
+    INSERT INTO todos_search (todos_search, rowid, title, description) VALUES ('delete', old.id, old.title, old.description);
+END;
+
+CREATE TRIGGER IF NOT EXISTS todos_search_update AFTER UPDATE OF title, description ON todos
+BEGIN
+    INSERT INTO todos_search (todos_search, rowid, title, description) VALUES ('delete', old.id, old.title, old.description);
+    INSERT INTO todos_search (rowid, title, description) VALUES (new.id, new.title, new.description);
+END;
@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS todos_owner_id_idx;
+
+ALTER TABLE todos DROP COLUMN owner_id;
+
+DROP TABLE IF EXISTS users;
@@ -0,0 +1,12 @@
+-- Due dates are stored in UTC, in the same format as created_at, so that they compare as text.
+ALTER TABLE todos ADD COLUMN due_at TIMESTAMP;
+
+ALTER TABLE todos ADD COLUMN priority TEXT NOT NULL DEFAULT 'normal'
+    CONSTRAINT todos_priority_check CHECK (priority IN ('low', 'normal', 'high', 'urgent'));
+
+ALTER TABLE todos ADD COLUMN completed_at TIMESTAMP;
+
+-- The best we know about todos that were already done.
+UPDATE todos SET completed_at = created_at WHERE done AND completed_at IS NULL;
+
+CREATE INDEX IF NOT EXISTS todos_due_at_idx ON todos (due_at) WHERE NOT done;
@@ -0,0 +1,5 @@
+DROP INDEX IF EXISTS todos_owner_id_position_idx;
+
+ALTER TABLE todos DROP COLUMN position;
+
+ALTER TABLE todos DROP COLUMN moved_position;
@@ -0,0 +1,27 @@
+-- Without a trash, whatever is in it is gone.
+CREATE TABLE todo_events_old
+(
+    id          INTEGER   PRIMARY KEY AUTOINCREMENT,
+    todo_id     INTEGER   NOT NULL,
+    kind        TEXT      NOT NULL CONSTRAINT todo_events_kind_check CHECK (kind IN ('created', 'updated', 'deleted')),
+    owner_id    INTEGER,
+    actor_id    INTEGER,
+    changes     TEXT      NOT NULL,
+    occurred_at TIMESTAMP NOT NULL
+);
+
+INSERT INTO todo_events_old (id, todo_id, kind, owner_id, actor_id, changes, occurred_at)
+    SELECT id, todo_id, kind, owner_id, actor_id, changes, occurred_at FROM todo_events WHERE kind IN ('created', 'updated', 'deleted');
+
+DROP TABLE todo_events;
+
+ALTER TABLE todo_events_old RENAME TO todo_events;
+
+CREATE INDEX IF NOT EXISTS todo_events_todo_id_idx ON todo_events (todo_id, id);
+CREATE INDEX IF NOT EXISTS todo_events_owner_id_idx ON todo_events (owner_id, occurred_at);
+
+DELETE FROM todos WHERE deleted_at IS NOT NULL;
+
+DROP INDEX IF EXISTS todos_deleted_at_idx;
+
+ALTER TABLE todos DROP COLUMN deleted_at;
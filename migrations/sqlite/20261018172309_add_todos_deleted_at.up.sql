@@ -0,0 +1,29 @@
+-- Deleting a todo only moves it to the trash, from where it can be restored
+-- until it is purged for good. Everything but the trash leaves these out.
+ALTER TABLE todos ADD COLUMN deleted_at TIMESTAMP;
+
+CREATE INDEX IF NOT EXISTS todos_deleted_at_idx ON todos (owner_id, deleted_at) WHERE deleted_at IS NOT NULL;
+
+-- Going into the trash is a deletion; coming back out, or leaving it for
+-- good, are kinds of event of their own. SQLite cannot change a CHECK
+-- constraint in place, so the table is copied into one with the new kinds.
+CREATE TABLE todo_events_new
+(
+    id          INTEGER   PRIMARY KEY AUTOINCREMENT,
+    todo_id     INTEGER   NOT NULL,
+    kind        TEXT      NOT NULL CONSTRAINT todo_events_kind_check CHECK (kind IN ('created', 'updated', 'deleted', 'restored', 'purged')),
+    owner_id    INTEGER,
+    actor_id    INTEGER,
+    changes     TEXT      NOT NULL,
+    occurred_at TIMESTAMP NOT NULL
+);
+
+INSERT INTO todo_events_new (id, todo_id, kind, owner_id, actor_id, changes, occurred_at)
+    SELECT id, todo_id, kind, owner_id, actor_id, changes, occurred_at FROM todo_events;
+
+DROP TABLE todo_events;
+
+ALTER TABLE todo_events_new RENAME TO todo_events;
+
+CREATE INDEX IF NOT EXISTS todo_events_todo_id_idx ON todo_events (todo_id, id);
+CREATE INDEX IF NOT EXISTS todo_events_owner_id_idx ON todo_events (owner_id, occurred_at);
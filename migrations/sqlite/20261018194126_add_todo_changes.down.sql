@@ -0,0 +1,4 @@
+DROP TRIGGER IF EXISTS todos_change_delete;
+DROP TRIGGER IF EXISTS todos_change_update;
+DROP TRIGGER IF EXISTS todos_change_insert;
+DROP TABLE IF EXISTS todo_changes;
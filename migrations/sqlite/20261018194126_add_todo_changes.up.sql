@@ -0,0 +1,35 @@
+-- Every change to a todo that clients can see, however it was made, so that
+-- GET /todos/events can stream them and pick up again from any of them.
+-- SQLite has nothing like NOTIFY, so the app polls this for new ones.
+CREATE TABLE IF NOT EXISTS todo_changes
+(
+    id          INTEGER   PRIMARY KEY AUTOINCREMENT,
+    todo_id     INTEGER   NOT NULL,
+    kind        TEXT      NOT NULL CONSTRAINT todo_changes_kind_check CHECK (kind IN ('created', 'updated', 'deleted')),
+    -- Who the todo belonged to at the time.
+    owner_id    INTEGER,
+    occurred_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS todo_changes_owner_id_idx ON todo_changes (owner_id, id);
+
+CREATE TRIGGER IF NOT EXISTS todos_change_insert AFTER INSERT ON todos WHEN new.deleted_at IS NULL
+BEGIN
+    INSERT INTO todo_changes (todo_id, kind, owner_id) VALUES (new.id, 'created', new.owner_id);
+END;
+
+-- A todo coming back out of the trash shows up as if it were new.
+CREATE TRIGGER IF NOT EXISTS todos_change_update AFTER UPDATE ON todos WHEN old.deleted_at IS NULL OR new.deleted_at IS NULL
+BEGIN
+    INSERT INTO todo_changes (todo_id, kind, owner_id) VALUES (
+        new.id,
+        CASE WHEN new.deleted_at IS NOT NULL THEN 'deleted' WHEN old.deleted_at IS NOT NULL THEN 'created' ELSE 'updated' END,
+        new.owner_id
+    );
+END;
+
+-- Purging a todo from the trash changes nothing anyone can see.
+CREATE TRIGGER IF NOT EXISTS todos_change_delete AFTER DELETE ON todos WHEN old.deleted_at IS NULL
+BEGIN
+    INSERT INTO todo_changes (todo_id, kind, owner_id) VALUES (old.id, 'deleted', old.owner_id);
+END;
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS users_feed_token_hash_key;
+
+ALTER TABLE users DROP COLUMN feed_token_hash;
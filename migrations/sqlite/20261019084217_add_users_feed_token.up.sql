@@ -0,0 +1,6 @@
+-- The secret in the URL of a user's calendar feed, which calendar apps fetch
+-- without signing in. Like API tokens, it is stored hashed. SQLite cannot
+-- add a column with a UNIQUE constraint, so an index stands in for one.
+ALTER TABLE users ADD COLUMN feed_token_hash BLOB;
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_feed_token_hash_key ON users (feed_token_hash);
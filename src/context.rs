@@ -427,6 +427,10 @@ async fn extension_gbp_to_usd_handler() -> String {
 ///
 /// Place it into a web server and test to ensure it meets your requirements.
 ///
+/// Everything here is lost when the server stops. The todo app in the
+/// persistence section serves the same API from the database, where each
+/// user also owns their todos.
+///
 async fn run_users_server() {
     let app = Router::<Arc<Mutex<UsersState>>>::new()
         .route("/users", get(get_users))
@@ -10,6 +10,13 @@ mod welcome;
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some((subcommand, args)) = args.split_first() {
+        if subcommand == "migrate" {
+            return persistence::run_migrate_command(args).await;
+        }
+    }
+
     // playground::example_postgres().await.unwrap();
     basics::hello_world().await;
 
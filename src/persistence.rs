@@ -33,9 +33,29 @@
 //! 4. Run `sqlx migrate run` to run the migrations in the `migrations` folder.
 //!
 
-use axum::{async_trait, body::Body, extract::{Path, State}, response::{IntoResponse, Response}, routing::{delete, get, post, put}, Json, Router};
+use axum::{async_trait, body::{Body, Bytes}, extract::{ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade}, FromRequestParts, Path, Query, State}, http::{header, request::Parts, HeaderMap}, response::{IntoResponse, Response}, routing::{any, delete, get, patch, post, put}, Json, Router};
+use base64::Engine as _;
+use futures_util::{stream::BoxStream, StreamExt, TryStreamExt};
 use hyper::StatusCode;
-use sqlx::{postgres::PgPoolOptions, types::time::PrimitiveDateTime, Pool, Postgres};
+use self::{migrations::{MigrationStatus, POSTGRES_MIGRATIONS, SQLITE_MIGRATIONS}, recurrence::{Recurrence, RecurrenceSpec}, sqlite::TodoRepoSqlite};
+use sqlx::{postgres::{PgListener, PgPoolOptions}, PgConnection, types::time::{OffsetDateTime, PrimitiveDateTime}, Pool, Postgres, QueryBuilder};
+use std::{collections::{BTreeMap, BTreeSet}, convert::Infallible, pin::Pin, sync::{Arc, RwLock}, task::{Context, Poll}, time::Duration};
+use tokio::sync::{broadcast, mpsc};
+use time::{format_description::well_known::Rfc3339, UtcOffset};
+
+mod caldav;
+mod ical;
+mod memory;
+mod migrations;
+mod recurrence;
+mod sqlite;
+
+const CURSOR_BASE64: base64::engine::GeneralPurpose = base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+const DEFAULT_PAGE_LIMIT: i64 = 50;
+const MAX_PAGE_LIMIT: i64 = 100;
+
+const MAX_BATCH_OPERATIONS: usize = 1000;
 
 ///
 /// EXERCISE 1
@@ -92,6 +112,9 @@ async fn select_one_plus_one() {
 ///
 /// What do you notice about the type of the row?
 ///
+/// Note that the `search` column is a `tsvector`, which SQLx does not know how
+/// to decode, so you will have to name the columns you want instead of using `*`.
+///
 #[tokio::test]
 async fn select_star() {
     let pool = PgPoolOptions::new()
@@ -100,7 +123,7 @@ async fn select_star() {
         .await
         .unwrap();
 
-    let todos = sqlx::query!("SELECT * FROM todos")
+    let todos = sqlx::query!("SELECT id, title, description, done, created_at FROM todos")
         .fetch_all(&pool).await.unwrap();
 
     for todo in todos {
@@ -216,185 +239,5439 @@ async fn select_star_as() {
         .await
         .unwrap();
 
-    sqlx::query_as!(TodoRecord, "SELECT * FROM todos")
+    sqlx::query_as!(TodoRecord, "SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos")
         .fetch_all(&pool).await.unwrap();
 
     assert!(true);
 }
+#[derive(sqlx::FromRow, Debug, Clone, PartialEq, Eq)]
 struct TodoRecord {
     id: i64,
     title: String,
     description: String,
     done: bool,
     created_at: PrimitiveDateTime,
+    version: i64,
+    owner_id: Option<i64>,
+    list_id: Option<i64>,
+    due_at: Option<OffsetDateTime>,
+    priority: String,
+    completed_at: Option<PrimitiveDateTime>,
+    parent_id: Option<i64>,
+    position: String,
+    deleted_at: Option<PrimitiveDateTime>,
+    recurrence: Option<String>,
 }
 
+///
+/// Todos that have been deleted wait in the trash until they are restored
+/// or purged, and every method but those for the trash leaves them out.
+///
+/// Writes take the id of the user making them, where there is one, and
+/// record what they changed in the todo's history, in the same transaction.
+///
 #[async_trait]
 trait TodoRepo: Send + Sync {
-    async fn get_all(&self) -> Vec<Todo>;
-    async fn create(&self, title: String, description: String) -> Todo;
-    async fn get(&self, id: i64) -> Option<Todo>;
-    async fn update(&self, id: i64, title: Option<String>, description: Option<String>, done: Option<bool>) -> Option<Todo>;
-    async fn delete(&self, id: i64) -> Option<Todo>;
+    async fn get_all(&self, query: &TodoListQuery) -> Result<TodoPage, TodoRepoError>;
+    async fn search(&self, query: &TodoSearchQuery) -> Result<Vec<TodoSearchHit>, TodoRepoError>;
+    async fn create(&self, owner_id: Option<i64>, todo: CreateTodo) -> Result<Todo, TodoRepoError>;
+    async fn get(&self, id: i64) -> Result<Todo, TodoRepoError>;
+    /// The todo and its subtasks, and theirs, in id order.
+    async fn get_subtree(&self, id: i64) -> Result<Vec<Todo>, TodoRepoError>;
+    async fn update(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, changes: &UpdateTodo) -> Result<Todo, TodoRepoError>;
+    /// Refuses to mark a blocked todo done, unless `force` is set.
+    async fn patch(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, patch: &TodoPatch, force: bool) -> Result<Todo, TodoRepoError>;
+    /// Moves the todo, and with it all of its subtasks, to the trash.
+    async fn delete(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError>;
+    /// Moves the todo into the list `list_id`, or out of any list if it is `None`.
+    async fn move_todo(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, list_id: Option<i64>) -> Result<Todo, TodoRepoError>;
+    /// Moves the todo just before or after another of its owner's todos. Only the moved todo is written.
+    async fn reorder(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, placement: TodoPlacement) -> Result<Todo, TodoRepoError>;
+    /// Tags the todo, creating the tag for its owner if need be. Tagging a todo twice changes nothing.
+    async fn tag(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, tag: &str) -> Result<Todo, TodoRepoError>;
+    /// Takes the tag off the todo, if it has it.
+    async fn untag(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, tag: &str) -> Result<Todo, TodoRepoError>;
+    /// Records that the todo is blocked by `blocker_id`, unless that would leave a todo blocked by itself.
+    async fn block(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, blocker_id: i64) -> Result<Todo, TodoRepoError>;
+    /// Removes the link between the todo and `blocker_id`, if there is one.
+    async fn unblock(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, blocker_id: i64) -> Result<Todo, TodoRepoError>;
+    /// Runs all of `operations`, in order, or none of them. Any todos created belong to `owner_id`, and only theirs can be updated or deleted.
+    async fn batch(&self, owner_id: Option<i64>, operations: &[TodoOperation]) -> Result<Vec<TodoOperationResult>, TodoBatchError>;
+    ///
+    /// Creates a todo for `owner_id` as an import or a calendar app has it:
+    /// done, tagged and going by `uid` if need be, all at once or not at all.
+    ///
+    async fn import(&self, owner_id: Option<i64>, todo: &CreateTodo, done: bool, tags: &[&str], uid: Option<&str>) -> Result<Todo, TodoRepoError>;
+    /// Todos that are not done and fall due in the window of `query`, soonest first.
+    async fn get_due(&self, query: &TodoDueQuery) -> Result<Vec<Todo>, TodoRepoError>;
+    /// Everything recorded about the todo `id`, oldest first, whether or not it is still there.
+    async fn get_history(&self, id: i64) -> Result<Vec<TodoEvent>, TodoRepoError>;
+    /// When each of `owner_id`'s todos outside the trash was last written, going by its history, or by when it was created if that is older.
+    async fn last_modified(&self, owner_id: i64) -> Result<BTreeMap<i64, OffsetDateTime>, TodoRepoError>;
+    /// Events across todos, oldest first.
+    async fn get_audit(&self, query: &TodoAuditQuery) -> Result<Vec<TodoEvent>, TodoRepoError>;
+    /// Todos in the trash, most recently deleted first.
+    async fn get_trash(&self, query: &TodoTrashQuery) -> Result<Vec<Todo>, TodoRepoError>;
+    /// A todo that is in the trash.
+    async fn get_trashed(&self, id: i64) -> Result<Todo, TodoRepoError>;
+    /// Takes the todo out of the trash, along with the subtasks that went in with it, as long as its parent is not still there.
+    async fn restore(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError>;
+    /// Deletes the todos that went into the trash before `deleted_before` for good, returning how many there were.
+    async fn purge_trash(&self, deleted_before: PrimitiveDateTime) -> Result<u64, TodoRepoError>;
+    ///
+    /// Changes to todos, oldest first, however they were made. Those made
+    /// while older ones are still being made wait for them, so that reading
+    /// on after the last change read never misses one.
+    ///
+    async fn get_changes(&self, query: &TodoChangeQuery) -> Result<Vec<TodoChange>, TodoRepoError>;
+    /// The last of `owner_id`'s changes that `get_changes` gives now, if there are any, to read on after.
+    async fn last_change(&self, owner_id: i64) -> Result<Option<i64>, TodoRepoError>;
+    ///
+    /// Hears about every change to todos from now on, as it is made. The
+    /// channel closes if changes might have been missed, as when the
+    /// database connection they are heard on is lost.
+    ///
+    fn watch_changes(&self) -> broadcast::Receiver<TodoChange>;
+    /// Every one of `owner_id`'s todos outside the trash, in id order, read from the database as the stream is.
+    fn export(&self, owner_id: i64) -> BoxStream<'_, Result<Todo, TodoRepoError>>;
+    /// The UIDs calendar apps gave `owner_id`'s todos outside the trash, by todo id.
+    async fn get_uids(&self, owner_id: i64) -> Result<BTreeMap<i64, String>, TodoRepoError>;
+    /// Gives the todo `id` the UID a calendar app knows it by, taking it from any other of its owner's todos that had it.
+    async fn set_uid(&self, id: i64, uid: &str) -> Result<(), TodoRepoError>;
+}
+
+///
+/// Everything that can go wrong when talking to a `TodoRepo`. Backends
+/// translate their own errors into one of these, so that handlers can
+/// respond without knowing which backend they are talking to.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TodoRepoError {
+    /// There is no todo with the given id.
+    NotFound(i64),
+    /// There is no user with the given id.
+    UserNotFound(i64),
+    /// There is no todo list with the given id.
+    ListNotFound(i64),
+    /// The todo with the given id is no longer at the version the caller expected.
+    VersionMismatch(i64),
+    /// The change clashes with the current state of the data.
+    Conflict(String),
+    /// The change would leave the todo in a state it is not allowed to be in.
+    Invalid(String),
+    /// The backend could not be reached in time; retrying may help.
+    Unavailable(String),
+    /// Anything else, which is most likely a bug.
+    Internal(String),
+}
+
+impl std::fmt::Display for TodoRepoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TodoRepoError::NotFound(id) => write!(f, "todo {} not found", id),
+            TodoRepoError::UserNotFound(id) => write!(f, "user {} not found", id),
+            TodoRepoError::ListNotFound(id) => write!(f, "list {} not found", id),
+            TodoRepoError::VersionMismatch(id) => write!(f, "todo {} has been modified", id),
+            TodoRepoError::Conflict(message) => write!(f, "conflict: {}", message),
+            TodoRepoError::Invalid(message) => write!(f, "invalid: {}", message),
+            TodoRepoError::Unavailable(message) => write!(f, "unavailable: {}", message),
+            TodoRepoError::Internal(message) => write!(f, "internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for TodoRepoError {}
+
+impl From<sqlx::Error> for TodoRepoError {
+    fn from(error: sqlx::Error) -> Self {
+        match &error {
+            sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed | sqlx::Error::Io(_) | sqlx::Error::Tls(_) => {
+                TodoRepoError::Unavailable(error.to_string())
+            }
+            sqlx::Error::Database(db) if db.is_unique_violation() || db.is_foreign_key_violation() => {
+                TodoRepoError::Conflict(db.message().to_string())
+            }
+            // serialization_failure and deadlock_detected
+            sqlx::Error::Database(db) if matches!(db.code().as_deref(), Some("40001") | Some("40P01")) => {
+                TodoRepoError::Conflict(db.message().to_string())
+            }
+            _ => TodoRepoError::Internal(error.to_string()),
+        }
+    }
+}
+
+impl TodoRepoError {
+    /// The status and message to respond with. Details of backend failures
+    /// are logged rather than handed to the client.
+    fn status_and_message(self) -> (StatusCode, String) {
+        match self {
+            TodoRepoError::NotFound(id) => (StatusCode::NOT_FOUND, format!("todo {} not found", id)),
+            TodoRepoError::UserNotFound(id) => (StatusCode::NOT_FOUND, format!("user {} not found", id)),
+            TodoRepoError::ListNotFound(id) => (StatusCode::NOT_FOUND, format!("list {} not found", id)),
+            TodoRepoError::VersionMismatch(id) => {
+                (StatusCode::PRECONDITION_FAILED, format!("todo {} has been modified since it was read", id))
+            }
+            TodoRepoError::Conflict(message) => (StatusCode::CONFLICT, message),
+            TodoRepoError::Invalid(message) => (StatusCode::UNPROCESSABLE_ENTITY, message),
+            TodoRepoError::Unavailable(message) => {
+                eprintln!("Todo repository unavailable: {}", message);
+                (StatusCode::SERVICE_UNAVAILABLE, "the database is temporarily unavailable".to_string())
+            }
+            TodoRepoError::Internal(message) => {
+                eprintln!("Todo repository error: {}", message);
+                (StatusCode::INTERNAL_SERVER_ERROR, "internal error".to_string())
+            }
+        }
+    }
+}
+
+impl IntoResponse for TodoRepoError {
+    fn into_response(self) -> Response {
+        match self {
+            TodoRepoError::NotFound(id) => MissingTodoError(format!("todo {} not found", id)).into_response(),
+            error => {
+                let (status, message) = error.status_and_message();
+                json_error(status, &message)
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 struct TodoRepoPostgres {
     pool: Pool<Postgres>,
+    /// Every change to todos, as the database announces them, on a new channel each time the announcements could have been missed.
+    changes: Arc<RwLock<broadcast::Sender<TodoChange>>>,
 }
 
 impl TodoRepoPostgres {
-    async fn new() -> Self {
+    async fn new() -> Result<Self, TodoRepoError> {
+        Self::connect(&database_url()?).await
+    }
+
+    /// Connects to the database at `url`, and brings its schema up to date.
+    async fn connect(url: &str) -> Result<Self, TodoRepoError> {
         let pool = PgPoolOptions::new()
             .max_connections(16)
-            .connect(&std::env::var("DATABASE_URL").unwrap())
-            .await
-            .unwrap();
+            .acquire_timeout(Duration::from_secs(5))
+            .connect(url)
+            .await?;
 
-        Self { pool }
+        migrations::migrate_up(&mut *pool.acquire().await?, &POSTGRES_MIGRATIONS).await?;
+        let changes = Self::listen(&pool).await?;
+
+        Ok(Self { pool, changes })
     }
-}
 
-#[async_trait]
-impl TodoRepo for TodoRepoPostgres {
-    async fn get_all(&self) -> Vec<Todo> {
-        sqlx::query_as!(TodoRecord, "SELECT * FROM todos")
-            .fetch_all(&self.pool).await.unwrap()
-            .into_iter()
-            .map(|r| Todo::from_record(r))
-            .collect()
+    ///
+    /// Starts passing on the changes the database announces, whichever
+    /// instance of the app (or whoever else) made them, to whoever is
+    /// watching. This is listening by the time it returns.
+    ///
+    async fn listen(pool: &Pool<Postgres>) -> Result<Arc<RwLock<broadcast::Sender<TodoChange>>>, TodoRepoError> {
+        let mut listener = PgListener::connect_with(pool).await?;
+        listener.listen("todo_changes").await?;
+
+        let changes = Arc::new(RwLock::new(broadcast::channel(CHANGE_BUFFER).0));
+        let sender = changes.clone();
+        tokio::spawn(async move {
+            // The listener reconnects by itself after losing the connection,
+            // but misses whatever is announced meanwhile. Replacing the channel
+            // then closes it for whoever was watching, who catch up from there.
+            let start_over = || *sender.write().expect("nothing panics holding the lock") = broadcast::channel(CHANGE_BUFFER).0;
+            loop {
+                match listener.try_recv().await {
+                    Ok(Some(notification)) => match serde_json::from_str::<TodoChange>(notification.payload()) {
+                        Ok(change) => {
+                            // Nobody watching is fine too.
+                            let _ = sender.read().expect("nothing panics holding the lock").send(change);
+                        }
+                        Err(error) => eprintln!("Ignoring a todo change that could not be read: {}", error),
+                    },
+                    Ok(None) => start_over(),
+                    Err(error) => {
+                        eprintln!("Could not listen for todo changes: {}", error);
+                        start_over();
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    }
+                }
+            }
+        });
 
+        Ok(changes)
     }
 
-    async fn create(&self, title: String, description: String) -> Todo {
-        Todo::from_record(
-            sqlx::query_as!(
-                TodoRecord,
-                "INSERT INTO todos (title, description, done) VALUES ($1, $2, $3) RETURNING *",
-                title,
-                description,
-                false,
+    /// The tags of the todos with the given ids, as `(todo_id, tag)` pairs in tag order.
+    async fn tags_of(conn: &mut PgConnection, ids: &[i64]) -> Result<Vec<(i64, String)>, TodoRepoError> {
+        let rows = sqlx::query!(
+            r#"SELECT todo_tags.todo_id, tags.name FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id WHERE todo_tags.todo_id = ANY($1) ORDER BY tags.name COLLATE "C""#,
+            ids,
+        )
+            .fetch_all(conn).await?;
+
+        Ok(rows.into_iter().map(|row| (row.todo_id, row.name)).collect())
+    }
+
+    /// What blocks the todos with the given ids, as `(todo_id, blocker_id)` pairs in blocker order. Trashed blockers no longer count.
+    async fn blockers_of(conn: &mut PgConnection, ids: &[i64]) -> Result<Vec<(i64, i64)>, TodoRepoError> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT todo_dependencies.todo_id, todo_dependencies.blocker_id
+            FROM todo_dependencies
+            JOIN todos AS blockers ON blockers.id = todo_dependencies.blocker_id
+            WHERE todo_dependencies.todo_id = ANY($1) AND blockers.deleted_at IS NULL
+            ORDER BY todo_dependencies.blocker_id
+            "#,
+            ids,
+        )
+            .fetch_all(conn).await?;
+
+        Ok(rows.into_iter().map(|row| (row.todo_id, row.blocker_id)).collect())
+    }
+
+    /// Fills in the tags and blockers of `todos`, with a query each however many there are.
+    async fn load_relations(conn: &mut PgConnection, mut todos: Vec<&mut Todo>) -> Result<(), TodoRepoError> {
+        let ids: Vec<i64> = todos.iter().map(|todo| todo.id).collect();
+        let tags = Self::tags_of(conn, &ids).await?;
+        attach_tags(&mut todos, tags);
+        let blockers = Self::blockers_of(conn, &ids).await?;
+        attach_blockers(&mut todos, blockers);
+
+        Ok(())
+    }
+
+    /// The blockers of the todo `id` that are not done yet, if the todo itself is not done yet.
+    async fn open_blockers_on(conn: &mut PgConnection, id: i64) -> Result<Vec<i64>, TodoRepoError> {
+        Ok(sqlx::query_scalar!(
+            r#"
+            SELECT todo_dependencies.blocker_id
+            FROM todo_dependencies
+            JOIN todos AS blocked ON blocked.id = todo_dependencies.todo_id
+            JOIN todos AS blockers ON blockers.id = todo_dependencies.blocker_id
+            WHERE todo_dependencies.todo_id = $1 AND NOT blocked.done AND NOT blockers.done AND blockers.deleted_at IS NULL
+            ORDER BY todo_dependencies.blocker_id
+            "#,
+            id,
+        )
+            .fetch_all(conn).await?)
+    }
+
+    async fn with_relations(conn: &mut PgConnection, record: TodoRecord) -> Result<Todo, TodoRepoError> {
+        let mut todo = Todo::from_record(record);
+        Self::load_relations(conn, vec![&mut todo]).await?;
+
+        Ok(todo)
+    }
+
+    /// Reads a todo that is not in the trash, and locks its row until the end
+    /// of the transaction, so nobody can change it between reading and writing it back.
+    async fn lock_on(conn: &mut PgConnection, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        let record = sqlx::query_as!(
+            TodoRecord,
+            "SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos WHERE id = $1 AND deleted_at IS NULL FOR UPDATE",
+            id,
+        )
+            .fetch_optional(&mut *conn).await?
+            .ok_or(TodoRepoError::NotFound(id))?;
+
+        if expected_version.is_some_and(|version| version != record.version) {
+            return Err(TodoRepoError::VersionMismatch(id));
+        }
+
+        Self::with_relations(conn, record).await
+    }
+
+    /// Locks the todo `id` as `lock_on` does, acting as if there were no such todo unless it is `owner_id`'s.
+    async fn check_owner_on(conn: &mut PgConnection, owner_id: Option<i64>, id: i64) -> Result<(), TodoRepoError> {
+        match Self::lock_on(conn, id, None).await? {
+            todo if todo.owner_id == owner_id => Ok(()),
+            _ => Err(TodoRepoError::NotFound(id)),
+        }
+    }
+
+    ///
+    /// The todo `id` and its subtasks, and theirs, in id order; empty if there
+    /// is no such todo. Only todos that went into the trash at `deleted_at`
+    /// count, or those that are not in the trash if that is `None`.
+    ///
+    async fn subtree_on(conn: &mut PgConnection, id: i64, deleted_at: Option<PrimitiveDateTime>) -> Result<Vec<Todo>, TodoRepoError> {
+        let records = sqlx::query_as!(
+            TodoRecord,
+            r#"
+            WITH RECURSIVE subtree (id) AS (
+                SELECT id FROM todos WHERE id = $1 AND deleted_at IS NOT DISTINCT FROM $2
+                UNION SELECT todos.id FROM todos JOIN subtree ON todos.parent_id = subtree.id WHERE todos.deleted_at IS NOT DISTINCT FROM $2
             )
-                .fetch_one(&self.pool).await.unwrap()
+            SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence
+            FROM todos
+            WHERE id IN (SELECT id FROM subtree)
+            ORDER BY id
+            "#,
+            id,
+            deleted_at,
         )
+            .fetch_all(&mut *conn).await?;
+
+        let mut todos: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+        Self::load_relations(conn, todos.iter_mut().collect()).await?;
+
+        Ok(todos)
     }
 
-    async fn get(&self, id: i64) -> Option<Todo> {
-        sqlx::query_as!(TodoRecord, "SELECT * FROM todos WHERE id = $1", &id)
-            .fetch_optional(&self.pool).await.unwrap()
-            .map(|r| Todo::from_record(r))
+    ///
+    /// Moves `todos`, which must be in id order, into the trash at
+    /// `deleted_at`, or out of it if that is `None`, and records the move in
+    /// each of their histories. Returns them as they end up, in id order.
+    ///
+    async fn trash_on(conn: &mut PgConnection, actor_id: Option<i64>, todos: &[Todo], deleted_at: Option<PrimitiveDateTime>) -> Result<Vec<Todo>, TodoRepoError> {
+        let ids: Vec<i64> = todos.iter().map(|todo| todo.id).collect();
+        let records = sqlx::query_as!(
+            TodoRecord,
+            "UPDATE todos SET deleted_at = $1, version = version + 1 WHERE id = ANY($2) RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence",
+            deleted_at,
+            &ids,
+        )
+            .fetch_all(&mut *conn).await?;
+
+        let mut moved: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+        moved.sort_by_key(|todo| todo.id);
+        Self::load_relations(conn, moved.iter_mut().collect()).await?;
+        for (before, after) in todos.iter().zip(&moved) {
+            Self::record_on(conn, actor_id, Some(before), Some(after)).await?;
+        }
+
+        Ok(moved)
     }
 
-    async fn update(&self, id: i64, title: Option<String>, description: Option<String>, done: Option<bool>) -> Option<Todo> {
-        sqlx::query_as!(
+    /// Adds the change from `before` to `after` to the todo's history, unless nothing changed.
+    async fn record_on(conn: &mut PgConnection, actor_id: Option<i64>, before: Option<&Todo>, after: Option<&Todo>) -> Result<(), TodoRepoError> {
+        let Some(event) = TodoEvent::between(actor_id, before, after) else {
+            return Ok(());
+        };
+
+        sqlx::query!(
+            "INSERT INTO todo_events (todo_id, kind, owner_id, actor_id, changes, occurred_at) VALUES ($1, $2, $3, $4, $5, $6)",
+            event.todo_id,
+            event.kind.as_str(),
+            event.owner_id,
+            event.actor_id,
+            sqlx::types::Json(&event.changes) as _,
+            utc_primitive(event.occurred_at),
+        )
+            .execute(conn).await?;
+
+        Ok(())
+    }
+
+    /// Bumps the version of a todo whose tags or blockers have changed.
+    async fn touch_on(conn: &mut PgConnection, id: i64) -> Result<Todo, TodoRepoError> {
+        let record = sqlx::query_as!(
             TodoRecord,
-            "UPDATE todos SET title = COALESCE($1, title), description = COALESCE($2, description), done = COALESCE($3, done) WHERE id = $4 RETURNING *",
-            title,
-            description,
-            done,
+            "UPDATE todos SET version = version + 1 WHERE id = $1 RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence",
             id,
         )
-            .fetch_optional(&self.pool).await.unwrap()
-            .map(|r| Todo::from_record(r))
+            .fetch_one(&mut *conn).await?;
+
+        Self::with_relations(conn, record).await
     }
 
-    async fn delete(&self, id: i64) -> Option<Todo> {
-        sqlx::query_as!(
+    ///
+    /// Waits for any other write linking the todo `id`'s owner's todos, as
+    /// subtasks or blockers, to finish. Links only ever join one owner's todos,
+    /// so taking turns per owner stops two links made at once from closing a
+    /// loop, each before the other is seen. The turn has to come before the
+    /// todo is locked, as the two writes would lock each other's todos.
+    ///
+    async fn take_turn_on(conn: &mut PgConnection, id: i64) -> Result<(), TodoRepoError> {
+        sqlx::query!("SELECT pg_advisory_xact_lock(COALESCE((SELECT owner_id FROM todos WHERE id = $1), 0))", id)
+            .execute(conn).await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Checks that `parent_id` is another of `owner_id`'s todos, and that it
+    /// is not the todo `id` (if the todo exists yet) or one of its subtasks.
+    /// Moving a todo takes `take_turn_on` first.
+    ///
+    async fn check_parent_on(conn: &mut PgConnection, id: Option<i64>, owner_id: Option<i64>, parent_id: i64) -> Result<(), TodoRepoError> {
+        let parent_owner_id = sqlx::query_scalar!("SELECT owner_id FROM todos WHERE id = $1 AND deleted_at IS NULL", parent_id)
+            .fetch_optional(&mut *conn).await?;
+        if parent_owner_id != Some(owner_id) {
+            return Err(missing_parent(parent_id));
+        }
+        let Some(id) = id else {
+            return Ok(());
+        };
+
+        // Walking up from the parent only ever follows one row per level.
+        let cyclic = sqlx::query_scalar!(
+            r#"
+            WITH RECURSIVE ancestors (id) AS (
+                SELECT $1::bigint
+                UNION SELECT todos.parent_id FROM todos JOIN ancestors ON todos.id = ancestors.id WHERE todos.parent_id IS NOT NULL
+            )
+            SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = $2) AS "cyclic!"
+            "#,
+            parent_id,
+            id,
+        )
+            .fetch_one(conn).await?;
+
+        if cyclic { Err(cyclic_parent(id)) } else { Ok(()) }
+    }
+
+    // The writes below take a connection rather than the pool, so that a
+    // batch can run them inside its transaction.
+
+    async fn create_on(conn: &mut PgConnection, owner_id: Option<i64>, todo: &CreateTodo) -> Result<Todo, TodoRepoError> {
+        if let Some(parent_id) = todo.parent_id {
+            Self::check_parent_on(conn, None, owner_id, parent_id).await?;
+        }
+        let recurrence = recurrence_of(todo.recurrence.as_ref(), todo.due_at)?;
+
+        let record = sqlx::query_as!(
+            TodoRecord,
+            "INSERT INTO todos (title, description, done, owner_id, list_id, due_at, priority, parent_id, recurrence) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence",
+            todo.title,
+            todo.description,
+            false,
+            owner_id,
+            todo.list_id,
+            todo.due_at,
+            todo.priority.as_str(),
+            todo.parent_id,
+            recurrence.as_ref().map(Recurrence::to_string),
+        )
+            .fetch_one(&mut *conn).await?;
+
+        // A new todo has no tags or blockers yet.
+        let todo = Todo::from_record(record);
+        Self::record_on(conn, owner_id, None, Some(&todo)).await?;
+
+        Ok(todo)
+    }
+
+    /// Callers should pass a transaction, as the todo is locked and read before it is written.
+    async fn update_on(conn: &mut PgConnection, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, changes: &UpdateTodo) -> Result<Todo, TodoRepoError> {
+        if let Some(Some(_)) = changes.parent_id {
+            Self::take_turn_on(conn, id).await?;
+        }
+        let current = Self::lock_on(conn, id, expected_version).await?;
+        if changes.done == Some(true) && !current.done && !changes.force {
+            let blockers = Self::open_blockers_on(conn, id).await?;
+            if !blockers.is_empty() {
+                return Err(blocked(id, &blockers));
+            }
+        }
+        if changes.done == Some(true) && changes.complete_subtasks && !changes.force {
+            // Nor may anything besides the todo and its subtasks still hold up one of those.
+            let subtree = Self::subtree_on(conn, id, None).await?;
+            for subtask in subtree.iter().filter(|subtask| subtask.id != id && !subtask.done) {
+                let mut blockers = Self::open_blockers_on(conn, subtask.id).await?;
+                blockers.retain(|blocker_id| !subtree.iter().any(|todo| todo.id == *blocker_id));
+                if !blockers.is_empty() {
+                    return Err(blocked(subtask.id, &blockers));
+                }
+            }
+        }
+        if let Some(Some(parent_id)) = changes.parent_id {
+            Self::check_parent_on(conn, Some(id), current.owner_id, parent_id).await?;
+        }
+        let (recurrence, handed_on) = recurrence_after(&current, changes)?;
+
+        // The right-hand sides all see the row as it was, so `done` there is whether it was already done.
+        let record = sqlx::query_as!(
             TodoRecord,
-            "DELETE FROM todos WHERE id = $1 RETURNING *",
+            r#"
+            UPDATE todos SET
+                title = COALESCE($1, title),
+                description = COALESCE($2, description),
+                done = COALESCE($3, done),
+                completed_at = CASE WHEN $3 IS NULL OR ($3 AND done) THEN completed_at WHEN $3 THEN $4 END,
+                due_at = CASE WHEN $5 THEN $6 ELSE due_at END,
+                priority = COALESCE($7, priority),
+                parent_id = CASE WHEN $8 THEN $9 ELSE parent_id END,
+                recurrence = $10,
+                version = version + 1
+            WHERE id = $11
+            RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence
+            "#,
+            changes.title,
+            changes.description,
+            changes.done,
+            now(),
+            changes.due_at.is_some(),
+            changes.due_at.flatten(),
+            changes.priority.map(TodoPriority::as_str),
+            changes.parent_id.is_some(),
+            changes.parent_id.flatten(),
+            recurrence.as_ref().map(Recurrence::to_string),
             id,
         )
-            .fetch_optional(&self.pool).await.unwrap()
-            .map(|r| Todo::from_record(r))
+            .fetch_one(&mut *conn).await?;
+        let todo = Self::with_relations(conn, record).await?;
+        Self::record_on(conn, actor_id, Some(&current), Some(&todo)).await?;
+        if let Some(recurrence) = handed_on {
+            Self::recur_on(conn, actor_id, &todo, &recurrence).await?;
+        }
+
+        if changes.complete_subtasks && changes.done == Some(true) {
+            // Repeating subtasks hand their recurrence on, just as they would if marked done one by one.
+            let repeating: BTreeMap<i64, Recurrence> = Self::subtree_on(conn, id, None).await?.into_iter()
+                .filter_map(|subtask| Some((subtask.id, subtask.recurrence?)))
+                .collect();
+            let records = sqlx::query_as!(
+                TodoRecord,
+                r#"
+                WITH RECURSIVE subtasks (id) AS (
+                    SELECT id FROM todos WHERE parent_id = $1 AND deleted_at IS NULL
+                    UNION SELECT todos.id FROM todos JOIN subtasks ON todos.parent_id = subtasks.id WHERE todos.deleted_at IS NULL
+                )
+                UPDATE todos SET done = TRUE, completed_at = $2, recurrence = NULL, version = version + 1 WHERE id IN (SELECT id FROM subtasks) AND NOT done
+                RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence
+                "#,
+                id,
+                now(),
+            )
+                .fetch_all(&mut *conn).await?;
+
+            let mut subtasks: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+            Self::load_relations(conn, subtasks.iter_mut().collect()).await?;
+            for subtask in &subtasks {
+                // Only subtasks that were not done yet were written, and nothing else about them changed.
+                let recurrence = repeating.get(&subtask.id).cloned();
+                let before = Todo { done: false, completed_at: None, recurrence: recurrence.clone(), version: subtask.version - 1, ..subtask.clone() };
+                Self::record_on(conn, actor_id, Some(&before), Some(subtask)).await?;
+                if let Some(recurrence) = recurrence {
+                    Self::recur_on(conn, actor_id, subtask, &recurrence).await?;
+                }
+            }
+        }
+
+        Ok(todo)
     }
-}
 
-///
-/// GRADUATION PROJECT
-///
-/// In this project, you will build a simple CRUD API for a todo list,
-/// which uses sqlx for persistence.
-///
-pub async fn run_todo_app() {
-    let app = Router::<TodoRepoPostgres>::new()
-        .route("/todos", get(get_todos::<TodoRepoPostgres>))
-        .route("/todos/:id", get(get_todo::<TodoRepoPostgres>))
-        .route("/todos", post(create_todo::<TodoRepoPostgres>))
-        .route("/todos/:id", put(update_todo::<TodoRepoPostgres>))
-        .route("/todos/:id", delete(delete_todo::<TodoRepoPostgres>))
-        .with_state(TodoRepoPostgres::new().await);
+    /// Creates the todo that comes after `done`, which was just marked done and repeated by `recurrence`, with the same tags.
+    async fn recur_on(conn: &mut PgConnection, actor_id: Option<i64>, done: &Todo, recurrence: &Recurrence) -> Result<(), TodoRepoError> {
+        let Some(next) = done.next_instance(recurrence) else {
+            return Ok(());
+        };
+        let created = Self::create_on(conn, done.owner_id, &next).await?;
+        if done.tags.is_empty() {
+            return Ok(());
+        }
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
-        .await
-        .unwrap();
+        sqlx::query!("INSERT INTO todo_tags (todo_id, tag_id) SELECT $1, tag_id FROM todo_tags WHERE todo_id = $2", created.id, done.id)
+            .execute(&mut *conn).await?;
+        let tagged = Self::touch_on(conn, created.id).await?;
+        Self::record_on(conn, actor_id, Some(&created), Some(&tagged)).await
+    }
 
-    println!("Listening on {}", listener.local_addr().unwrap());
+    /// Callers should pass a transaction, as the todo is locked and read before it is trashed.
+    async fn delete_on(conn: &mut PgConnection, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        Self::lock_on(conn, id, expected_version).await?;
 
-    axum::serve(listener, app).await.unwrap();
-}
+        // The subtasks all go into the trash at the same time as the todo,
+        // which is how restoring it knows to bring back just those.
+        let subtree = Self::subtree_on(conn, id, None).await?;
+        let deleted = Self::trash_on(conn, actor_id, &subtree, Some(now())).await?;
 
-async fn get_todos<R: TodoRepo>(state: State<R>) -> Json<Vec<Todo>> {
-    Json((*state).get_all().await)
-}
+        deleted.into_iter().find(|todo| todo.id == id).ok_or(TodoRepoError::NotFound(id))
+    }
 
-async fn get_todo<R: TodoRepo>(Path(id): Path<i64>, state: State<R>) -> Result<Json<Todo>, MissingTodoError> {
-    (*state).get(id).await.map(Json).ok_or_else(|| MissingTodoError("".to_string()))
-}
+    /// Callers should pass a transaction, as the todo is locked and read before it is tagged.
+    async fn tag_on(conn: &mut PgConnection, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, tag: &str) -> Result<Todo, TodoRepoError> {
+        let current = Self::lock_on(conn, id, expected_version).await?;
+        let owner_id = current.owner_id.ok_or_else(untaggable)?;
 
-async fn create_todo<R: TodoRepo>(state: State<R>, Json(spec): Json<CreateTodo>) -> Json<Todo> {
-    Json((*state).create(spec.title, spec.description).await)
-}
+        // Upserting, rather than doing nothing on conflict, so that the id comes back either way.
+        let tag_id = sqlx::query_scalar!(
+            "INSERT INTO tags (owner_id, name) VALUES ($1, $2) ON CONFLICT (owner_id, name) DO UPDATE SET name = EXCLUDED.name RETURNING id",
+            owner_id,
+            tag,
+        )
+            .fetch_one(&mut *conn).await?;
+        let added = sqlx::query!("INSERT INTO todo_tags (todo_id, tag_id) VALUES ($1, $2) ON CONFLICT DO NOTHING", id, tag_id)
+            .execute(&mut *conn).await?
+            .rows_affected();
 
-async fn update_todo<R: TodoRepo>(Path(id): Path<i64>, state: State<R>, Json(update): Json<UpdateTodo>) -> Result<Json<Todo>, MissingTodoError> {
-    (*state).update(id, update.title, update.description, update.done).await
-        .map(Json).ok_or_else(|| MissingTodoError("".to_string()))
-}
+        let todo = if added > 0 { Self::touch_on(conn, id).await? } else { current.clone() };
+        Self::record_on(conn, actor_id, Some(&current), Some(&todo)).await?;
 
-async fn delete_todo<R: TodoRepo>(Path(id): Path<i64>, state: State<R>) -> Result<Json<Todo>, MissingTodoError> {
-    (*state).delete(id).await.map(Json).ok_or_else(|| MissingTodoError("".to_string()))
-}
+        Ok(todo)
+    }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
-struct Todo {
-    id: i64,
-    title: String,
-    description: String,
-    done: bool,
+    /// Callers should pass a transaction, as the UID is taken from any other todo before it is given to this one.
+    async fn set_uid_on(conn: &mut PgConnection, id: i64, uid: &str) -> Result<(), TodoRepoError> {
+        let owner_id = sqlx::query_scalar!("SELECT owner_id FROM todos WHERE id = $1 AND deleted_at IS NULL", id)
+            .fetch_optional(&mut *conn).await?
+            .ok_or(TodoRepoError::NotFound(id))?
+            .ok_or_else(|| no_owner(id))?;
+
+        sqlx::query!("DELETE FROM todo_uids WHERE owner_id = $1 AND uid = $2", owner_id, uid).execute(&mut *conn).await?;
+        sqlx::query!(
+            "INSERT INTO todo_uids (todo_id, owner_id, uid) VALUES ($1, $2, $3) ON CONFLICT (todo_id) DO UPDATE SET uid = excluded.uid",
+            id,
+            owner_id,
+            uid,
+        )
+            .execute(&mut *conn).await?;
+
+        Ok(())
+    }
 }
 
-impl Todo {
-    fn from_record(record: TodoRecord) -> Self {
-        Todo {
-            id: record.id,
-            title: record.title,
-            description: record.description,
-            done: record.done,
+#[async_trait]
+impl TodoRepo for TodoRepoPostgres {
+    async fn get_all(&self, query: &TodoListQuery) -> Result<TodoPage, TodoRepoError> {
+        let mut sql = QueryBuilder::<Postgres>::new("SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos WHERE deleted_at IS NULL");
+
+        if let Some(owner_id) = query.filter.owner_id {
+            sql.push(" AND owner_id = ").push_bind(owner_id);
+        }
+        match query.filter.list_id {
+            Some(list_id) => sql.push(" AND list_id = ").push_bind(list_id),
+            None => sql.push(" AND NOT EXISTS (SELECT 1 FROM todo_lists WHERE todo_lists.id = todos.list_id AND todo_lists.archived)"),
+        };
+        if let Some(done) = query.filter.done {
+            sql.push(" AND done = ").push_bind(done);
+        }
+        if let Some(actionable) = query.filter.actionable {
+            sql.push(if actionable { " AND NOT EXISTS" } else { " AND EXISTS" });
+            sql.push(" (SELECT 1 FROM todo_dependencies JOIN todos AS blockers ON blockers.id = todo_dependencies.blocker_id WHERE todo_dependencies.todo_id = todos.id AND NOT blockers.done AND blockers.deleted_at IS NULL)");
         }
+        if let Some(created_after) = query.filter.created_after {
+            sql.push(" AND created_at > ").push_bind(created_after);
+        }
+        if let Some(created_before) = query.filter.created_before {
+            sql.push(" AND created_at < ").push_bind(created_before);
+        }
+        if !query.filter.tags.is_empty() {
+            sql.push(" AND (SELECT COUNT(*) FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id WHERE todo_tags.todo_id = todos.id AND tags.name IN (");
+            let mut names = sql.separated(", ");
+            for tag in &query.filter.tags {
+                names.push_bind(tag.clone());
+            }
+            sql.push("))");
+            match query.filter.tag_match {
+                TagMatch::Any => sql.push(" > 0"),
+                TagMatch::All => sql.push(" = ").push_bind(query.filter.tags.len() as i64),
+            };
+        }
+
+        // Titles are compared bytewise, so the order does not depend on the
+        // collation the database happens to be configured with.
+        let column = match query.sort.field {
+            TodoSortField::Position => "position",
+            TodoSortField::CreatedAt => "created_at",
+            TodoSortField::Title => "title COLLATE \"C\"",
+        };
+        let (direction, comparison) = if query.sort.descending { (" DESC", " < ") } else { (" ASC", " > ") };
+
+        if let Some(cursor) = &query.cursor {
+            sql.push(" AND (").push(column).push(", id)").push(comparison).push("(");
+            match &cursor.key {
+                TodoSortKey::Position(position) => sql.push_bind(position.clone()),
+                TodoSortKey::CreatedAt(created_at) => sql.push_bind(*created_at),
+                TodoSortKey::Title(title) => sql.push_bind(title.clone()),
+            };
+            sql.push(", ").push_bind(cursor.id).push(")");
+        }
+
+        // Fetch one row more than requested, so we know whether there is a next page.
+        sql.push(" ORDER BY ").push(column).push(direction).push(", id").push(direction);
+        sql.push(" LIMIT ").push_bind(query.limit + 1);
+
+        let mut conn = self.pool.acquire().await?;
+        let records = sql.build_query_as::<TodoRecord>()
+            .fetch_all(&mut *conn).await?;
+
+        let mut page = TodoPage::from_records(records, query);
+        Self::load_relations(&mut conn, page.items.iter_mut().collect()).await?;
+
+        Ok(page)
     }
-}
 
-#[derive(serde::Deserialize)]
-struct CreateTodo {
-    title: String,
-    description: String,
-}
+    async fn search(&self, query: &TodoSearchQuery) -> Result<Vec<TodoSearchHit>, TodoRepoError> {
+        let mut conn = self.pool.acquire().await?;
+        let records = sqlx::query_as!(
+            TodoSearchRecord,
+            r#"
+            SELECT
+                id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence,
+                ts_rank(search, q) AS "rank!",
+                ts_headline('english', title, q, 'StartSel=<mark>, StopSel=</mark>, HighlightAll=TRUE') AS "title_highlight!",
+                ts_headline('english', description, q, 'StartSel=<mark>, StopSel=</mark>, MaxFragments=2') AS "description_highlight!"
+            FROM todos, websearch_to_tsquery('english', $1) q
+            WHERE search @@ q AND ($3::bigint IS NULL OR owner_id = $3) AND deleted_at IS NULL
+                AND NOT EXISTS (SELECT 1 FROM todo_lists WHERE todo_lists.id = todos.list_id AND todo_lists.archived)
+            ORDER BY ts_rank(search, q) DESC, id
+            LIMIT $2
+            "#,
+            query.q,
+            query.limit,
+            query.owner_id,
+        )
+            .fetch_all(&mut *conn).await?;
 
-#[derive(serde::Deserialize)]
-struct UpdateTodo {
-    title: Option<String>,
-    description: Option<String>,
-    done: Option<bool>,
-}
+        let mut hits: Vec<TodoSearchHit> = records.into_iter().map(TodoSearchHit::from_record).collect();
+        Self::load_relations(&mut conn, hits.iter_mut().map(|hit| &mut hit.todo).collect()).await?;
 
-#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
-struct MissingTodoError(String);
+        Ok(hits)
+    }
 
-impl IntoResponse for MissingTodoError {
-    fn into_response(self) -> Response {
-        Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .header("Content-Type", "application/json")
-            .body(Body::from(format!("{{message:{}}}", serde_json::json!(&self.0))))
-            .unwrap()
+    async fn create(&self, owner_id: Option<i64>, todo: CreateTodo) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let todo = Self::create_on(&mut tx, owner_id, &todo).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn get(&self, id: i64) -> Result<Todo, TodoRepoError> {
+        let mut conn = self.pool.acquire().await?;
+        let record = sqlx::query_as!(TodoRecord, "SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos WHERE id = $1 AND deleted_at IS NULL", &id)
+            .fetch_optional(&mut *conn).await?
+            .ok_or(TodoRepoError::NotFound(id))?;
+
+        Self::with_relations(&mut conn, record).await
+    }
+
+    async fn get_subtree(&self, id: i64) -> Result<Vec<Todo>, TodoRepoError> {
+        let todos = Self::subtree_on(&mut *self.pool.acquire().await?, id, None).await?;
+        if todos.is_empty() {
+            return Err(TodoRepoError::NotFound(id));
+        }
+
+        Ok(todos)
+    }
+
+    async fn update(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, changes: &UpdateTodo) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let todo = Self::update_on(&mut tx, actor_id, id, expected_version, changes).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn patch(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, patch: &TodoPatch, force: bool) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let current = Self::lock_on(&mut tx, id, expected_version).await?;
+
+        let patched = patch.apply(&current)?;
+        if patched.done && !current.done && !force {
+            let blockers = Self::open_blockers_on(&mut tx, id).await?;
+            if !blockers.is_empty() {
+                return Err(blocked(id, &blockers));
+            }
+        }
+        let (recurrence, handed_on) = recurrence_after(&current, &patched.as_update())?;
+
+        let record = sqlx::query_as!(
+            TodoRecord,
+            "UPDATE todos SET title = $1, description = $2, done = $3, completed_at = $4, due_at = $5, priority = $6, recurrence = $7, version = version + 1 WHERE id = $8 RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence",
+            patched.title,
+            patched.description,
+            patched.done,
+            current.completed_at_if_done(patched.done, now()),
+            patched.due_at,
+            patched.priority.as_str(),
+            recurrence.as_ref().map(Recurrence::to_string),
+            id,
+        )
+            .fetch_one(&mut *tx).await?;
+        let todo = Self::with_relations(&mut tx, record).await?;
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        if let Some(recurrence) = handed_on {
+            Self::recur_on(&mut tx, actor_id, &todo, &recurrence).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn delete(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let todo = Self::delete_on(&mut tx, actor_id, id, expected_version).await?;
+        tx.commit().await?;
+
+        Ok(todo)
     }
-}
\ No newline at end of file
+
+    async fn move_todo(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, list_id: Option<i64>) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let current = Self::lock_on(&mut tx, id, expected_version).await?;
+
+        let record = sqlx::query_as!(
+            TodoRecord,
+            "UPDATE todos SET list_id = $1, version = version + 1 WHERE id = $2 RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence",
+            list_id,
+            id,
+        )
+            .fetch_one(&mut *tx).await?;
+        let todo = Self::with_relations(&mut tx, record).await?;
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn reorder(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, placement: TodoPlacement) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let current = Self::lock_on(&mut tx, id, expected_version).await?;
+        let anchor_id = placement.anchor_id();
+        if anchor_id == id {
+            return Err(unmovable(id));
+        }
+        let anchor = sqlx::query!("SELECT owner_id, position FROM todos WHERE id = $1 AND deleted_at IS NULL", anchor_id)
+            .fetch_optional(&mut *tx).await?
+            .filter(|anchor| anchor.owner_id == current.owner_id)
+            .ok_or_else(|| missing_anchor(anchor_id))?;
+
+        // The new position goes between the anchor and whichever todo is on
+        // its other side, not counting the one being moved. Trashed todos
+        // keep their positions, and so count, so that restoring one cannot
+        // leave it tied with another.
+        let (lower, upper) = match placement {
+            TodoPlacement::Before(_) => {
+                let lower = sqlx::query_scalar!(
+                    "SELECT position FROM todos WHERE owner_id IS NOT DISTINCT FROM $1 AND id <> $2 AND position < $3 ORDER BY position DESC LIMIT 1",
+                    current.owner_id,
+                    id,
+                    anchor.position,
+                )
+                    .fetch_optional(&mut *tx).await?;
+                (lower, Some(anchor.position))
+            }
+            TodoPlacement::After(_) => {
+                let upper = sqlx::query_scalar!(
+                    "SELECT position FROM todos WHERE owner_id IS NOT DISTINCT FROM $1 AND id <> $2 AND position > $3 ORDER BY position LIMIT 1",
+                    current.owner_id,
+                    id,
+                    anchor.position,
+                )
+                    .fetch_optional(&mut *tx).await?;
+                (Some(anchor.position), upper)
+            }
+        };
+        let position = rank_between(lower.as_deref(), upper.as_deref()).ok_or_else(|| unmovable(id))?;
+
+        let record = sqlx::query_as!(
+            TodoRecord,
+            "UPDATE todos SET moved_position = $1, version = version + 1 WHERE id = $2 RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence",
+            position,
+            id,
+        )
+            .fetch_one(&mut *tx).await?;
+        let todo = Self::with_relations(&mut tx, record).await?;
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn tag(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, tag: &str) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let todo = Self::tag_on(&mut tx, actor_id, id, expected_version, tag).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn untag(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, tag: &str) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let current = Self::lock_on(&mut tx, id, expected_version).await?;
+
+        let removed = sqlx::query!(
+            "DELETE FROM todo_tags USING tags WHERE tags.id = todo_tags.tag_id AND todo_tags.todo_id = $1 AND tags.name = $2",
+            id,
+            tag,
+        )
+            .execute(&mut *tx).await?
+            .rows_affected();
+
+        let todo = if removed > 0 { Self::touch_on(&mut tx, id).await? } else { current.clone() };
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn block(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, blocker_id: i64) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        Self::take_turn_on(&mut tx, id).await?;
+        let current = Self::lock_on(&mut tx, id, expected_version).await?;
+
+        let blocker_exists = sqlx::query_scalar!(r#"SELECT EXISTS (SELECT 1 FROM todos WHERE id = $1 AND deleted_at IS NULL) AS "exists!""#, blocker_id)
+            .fetch_one(&mut *tx).await?;
+        if !blocker_exists {
+            return Err(missing_blocker(blocker_id));
+        }
+
+        // Following what blocks the blocker, and so on, must not lead back here.
+        let cyclic = sqlx::query_scalar!(
+            r#"
+            WITH RECURSIVE blockers (id) AS (
+                SELECT $1::bigint
+                UNION SELECT todo_dependencies.blocker_id FROM todo_dependencies JOIN blockers ON todo_dependencies.todo_id = blockers.id
+            )
+            SELECT EXISTS (SELECT 1 FROM blockers WHERE id = $2) AS "cyclic!"
+            "#,
+            blocker_id,
+            id,
+        )
+            .fetch_one(&mut *tx).await?;
+        if cyclic {
+            return Err(cyclic_dependency(id, blocker_id));
+        }
+
+        let added = sqlx::query!("INSERT INTO todo_dependencies (todo_id, blocker_id) VALUES ($1, $2) ON CONFLICT DO NOTHING", id, blocker_id)
+            .execute(&mut *tx).await?
+            .rows_affected();
+
+        let todo = if added > 0 { Self::touch_on(&mut tx, id).await? } else { current.clone() };
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn unblock(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, blocker_id: i64) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let current = Self::lock_on(&mut tx, id, expected_version).await?;
+
+        let removed = sqlx::query!("DELETE FROM todo_dependencies WHERE todo_id = $1 AND blocker_id = $2", id, blocker_id)
+            .execute(&mut *tx).await?
+            .rows_affected();
+
+        let todo = if removed > 0 { Self::touch_on(&mut tx, id).await? } else { current.clone() };
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn batch(&self, owner_id: Option<i64>, operations: &[TodoOperation]) -> Result<Vec<TodoOperationResult>, TodoBatchError> {
+        // Dropping the transaction without committing it rolls everything back.
+        let mut tx = self.pool.begin().await?;
+
+        let mut results = Vec::with_capacity(operations.len());
+        for (index, operation) in operations.iter().enumerate() {
+            let result = match operation {
+                TodoOperation::Create(todo) => {
+                    Self::create_on(&mut tx, owner_id, todo).await.map(TodoOperationResult::Created)
+                }
+                TodoOperation::Update { id, version, changes } => {
+                    Self::check_owner_on(&mut tx, owner_id, *id).await.map_err(TodoBatchError::at(index))?;
+                    Self::update_on(&mut tx, owner_id, *id, *version, changes).await.map(TodoOperationResult::Updated)
+                }
+                TodoOperation::Delete { id, version } => {
+                    Self::check_owner_on(&mut tx, owner_id, *id).await.map_err(TodoBatchError::at(index))?;
+                    Self::delete_on(&mut tx, owner_id, *id, *version).await.map(TodoOperationResult::Deleted)
+                }
+            };
+            results.push(result.map_err(TodoBatchError::at(index))?);
+        }
+
+        tx.commit().await?;
+
+        Ok(results)
+    }
+
+    async fn import(&self, owner_id: Option<i64>, todo: &CreateTodo, done: bool, tags: &[&str], uid: Option<&str>) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let mut imported = Self::create_on(&mut tx, owner_id, todo).await?;
+        if let Some(uid) = uid {
+            Self::set_uid_on(&mut tx, imported.id, uid).await?;
+        }
+        if done {
+            let done = UpdateTodo { done: Some(true), force: true, ..Default::default() };
+            imported = Self::update_on(&mut tx, owner_id, imported.id, None, &done).await?;
+        }
+        for tag in tags {
+            imported = Self::tag_on(&mut tx, owner_id, imported.id, None, tag).await?;
+        }
+        tx.commit().await?;
+
+        Ok(imported)
+    }
+
+    async fn get_due(&self, query: &TodoDueQuery) -> Result<Vec<Todo>, TodoRepoError> {
+        let mut conn = self.pool.acquire().await?;
+        let records = sqlx::query_as!(
+            TodoRecord,
+            r#"
+            SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence
+            FROM todos
+            WHERE NOT done AND deleted_at IS NULL AND due_at < $1 AND ($2::timestamptz IS NULL OR due_at >= $2) AND ($3::bigint IS NULL OR owner_id = $3)
+                AND NOT EXISTS (SELECT 1 FROM todo_lists WHERE todo_lists.id = todos.list_id AND todo_lists.archived)
+            ORDER BY due_at, id
+            LIMIT $4
+            "#,
+            query.due_before,
+            query.due_from,
+            query.owner_id,
+            query.limit,
+        )
+            .fetch_all(&mut *conn).await?;
+
+        let mut todos: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+        Self::load_relations(&mut conn, todos.iter_mut().collect()).await?;
+
+        Ok(todos)
+    }
+
+    async fn get_history(&self, id: i64) -> Result<Vec<TodoEvent>, TodoRepoError> {
+        let records = sqlx::query_as!(
+            TodoEventRecord,
+            r#"SELECT id, todo_id, kind, owner_id, actor_id, changes AS "changes: sqlx::types::Json<TodoChanges>", occurred_at FROM todo_events WHERE todo_id = $1 ORDER BY id"#,
+            id,
+        )
+            .fetch_all(&self.pool).await?;
+
+        Ok(records.into_iter().map(TodoEvent::from_record).collect())
+    }
+
+    async fn last_modified(&self, owner_id: i64) -> Result<BTreeMap<i64, OffsetDateTime>, TodoRepoError> {
+        let records = sqlx::query!(
+            r#"
+            SELECT todos.id, COALESCE(MAX(todo_events.occurred_at), todos.created_at) AS "last_modified!"
+            FROM todos
+            LEFT JOIN todo_events ON todo_events.todo_id = todos.id
+            WHERE todos.owner_id = $1 AND todos.deleted_at IS NULL
+            GROUP BY todos.id
+            "#,
+            owner_id,
+        )
+            .fetch_all(&self.pool).await?;
+
+        Ok(records.into_iter().map(|record| (record.id, record.last_modified.assume_utc())).collect())
+    }
+
+    async fn get_audit(&self, query: &TodoAuditQuery) -> Result<Vec<TodoEvent>, TodoRepoError> {
+        let records = sqlx::query_as!(
+            TodoEventRecord,
+            r#"
+            SELECT id, todo_id, kind, owner_id, actor_id, changes AS "changes: sqlx::types::Json<TodoChanges>", occurred_at
+            FROM todo_events
+            WHERE ($1::bigint IS NULL OR owner_id = $1) AND ($2::timestamp IS NULL OR occurred_at >= $2) AND ($3::bigint IS NULL OR id > $3)
+            ORDER BY id
+            LIMIT $4
+            "#,
+            query.owner_id,
+            query.since,
+            query.after,
+            query.limit,
+        )
+            .fetch_all(&self.pool).await?;
+
+        Ok(records.into_iter().map(TodoEvent::from_record).collect())
+    }
+
+    async fn get_changes(&self, query: &TodoChangeQuery) -> Result<Vec<TodoChange>, TodoRepoError> {
+        let records = sqlx::query_as!(
+            TodoChangeRecord,
+            r#"
+            WITH after AS (SELECT xid, id FROM todo_changes WHERE id = $2)
+            SELECT id, todo_id, kind, owner_id
+            FROM todo_changes
+            WHERE ($1::bigint IS NULL OR owner_id = $1)
+                -- Changes from transactions any older than one still going on wait until it is over, so nothing turns up behind them.
+                AND xid < pg_snapshot_xmin(pg_current_snapshot())
+                AND ($2::bigint IS NULL OR (xid, id) > (SELECT xid, id FROM after) OR (NOT EXISTS (SELECT FROM after) AND id > $2))
+            ORDER BY xid, id
+            LIMIT $3
+            "#,
+            query.owner_id,
+            query.after,
+            query.limit,
+        )
+            .fetch_all(&self.pool).await?;
+
+        Ok(records.into_iter().map(TodoChange::from_record).collect())
+    }
+
+    async fn last_change(&self, owner_id: i64) -> Result<Option<i64>, TodoRepoError> {
+        let id = sqlx::query_scalar!(
+            "SELECT id FROM todo_changes WHERE owner_id = $1 AND xid < pg_snapshot_xmin(pg_current_snapshot()) ORDER BY xid DESC, id DESC LIMIT 1",
+            owner_id,
+        )
+            .fetch_optional(&self.pool).await?;
+
+        Ok(id)
+    }
+
+    fn watch_changes(&self) -> broadcast::Receiver<TodoChange> {
+        self.changes.read().expect("nothing panics holding the lock").subscribe()
+    }
+
+    fn export(&self, owner_id: i64) -> BoxStream<'_, Result<Todo, TodoRepoError>> {
+        sqlx::query!(
+            r#"
+            SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence,
+                ARRAY(
+                    SELECT tags.name FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id
+                    WHERE todo_tags.todo_id = todos.id
+                    ORDER BY tags.name COLLATE "C"
+                ) AS "tags!",
+                ARRAY(
+                    SELECT todo_dependencies.blocker_id FROM todo_dependencies JOIN todos AS blockers ON blockers.id = todo_dependencies.blocker_id
+                    WHERE todo_dependencies.todo_id = todos.id AND blockers.deleted_at IS NULL
+                    ORDER BY todo_dependencies.blocker_id
+                ) AS "blocked_by!"
+            FROM todos
+            WHERE owner_id = $1 AND deleted_at IS NULL
+            ORDER BY id
+            "#,
+            owner_id,
+        )
+            .fetch(&self.pool)
+            .map(|row| {
+                let row = row?;
+                let record = TodoRecord {
+                    id: row.id,
+                    title: row.title,
+                    description: row.description,
+                    done: row.done,
+                    created_at: row.created_at,
+                    version: row.version,
+                    owner_id: row.owner_id,
+                    list_id: row.list_id,
+                    due_at: row.due_at,
+                    priority: row.priority,
+                    completed_at: row.completed_at,
+                    parent_id: row.parent_id,
+                    position: row.position,
+                    deleted_at: row.deleted_at,
+                    recurrence: row.recurrence,
+                };
+
+                Ok(Todo { tags: row.tags, blocked_by: row.blocked_by, ..Todo::from_record(record) })
+            })
+            .boxed()
+    }
+
+    async fn get_uids(&self, owner_id: i64) -> Result<BTreeMap<i64, String>, TodoRepoError> {
+        let records = sqlx::query!(
+            "SELECT todo_uids.todo_id, todo_uids.uid FROM todo_uids JOIN todos ON todos.id = todo_uids.todo_id WHERE todo_uids.owner_id = $1 AND todos.deleted_at IS NULL",
+            owner_id,
+        )
+            .fetch_all(&self.pool).await?;
+
+        Ok(records.into_iter().map(|record| (record.todo_id, record.uid)).collect())
+    }
+
+    async fn set_uid(&self, id: i64, uid: &str) -> Result<(), TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        Self::set_uid_on(&mut tx, id, uid).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_trash(&self, query: &TodoTrashQuery) -> Result<Vec<Todo>, TodoRepoError> {
+        let mut conn = self.pool.acquire().await?;
+        let records = sqlx::query_as!(
+            TodoRecord,
+            r#"
+            SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence
+            FROM todos
+            WHERE deleted_at IS NOT NULL AND ($1::bigint IS NULL OR owner_id = $1)
+            ORDER BY deleted_at DESC, id
+            LIMIT $2
+            "#,
+            query.owner_id,
+            query.limit,
+        )
+            .fetch_all(&mut *conn).await?;
+
+        let mut todos: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+        Self::load_relations(&mut conn, todos.iter_mut().collect()).await?;
+
+        Ok(todos)
+    }
+
+    async fn get_trashed(&self, id: i64) -> Result<Todo, TodoRepoError> {
+        let mut conn = self.pool.acquire().await?;
+        let record = sqlx::query_as!(TodoRecord, "SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos WHERE id = $1 AND deleted_at IS NOT NULL", id)
+            .fetch_optional(&mut *conn).await?
+            .ok_or(TodoRepoError::NotFound(id))?;
+
+        Self::with_relations(&mut conn, record).await
+    }
+
+    async fn restore(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let record = sqlx::query_as!(
+            TodoRecord,
+            "SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos WHERE id = $1 AND deleted_at IS NOT NULL FOR UPDATE",
+            id,
+        )
+            .fetch_optional(&mut *tx).await?
+            .ok_or(TodoRepoError::NotFound(id))?;
+        if expected_version.is_some_and(|version| version != record.version) {
+            return Err(TodoRepoError::VersionMismatch(id));
+        }
+        if let Some(parent_id) = record.parent_id {
+            let parent_trashed = sqlx::query_scalar!(r#"SELECT EXISTS (SELECT 1 FROM todos WHERE id = $1 AND deleted_at IS NOT NULL) AS "trashed!""#, parent_id)
+                .fetch_one(&mut *tx).await?;
+            if parent_trashed {
+                return Err(trashed_parent(id, parent_id));
+            }
+        }
+
+        let subtree = Self::subtree_on(&mut tx, id, record.deleted_at).await?;
+        let restored = Self::trash_on(&mut tx, actor_id, &subtree, None).await?;
+        tx.commit().await?;
+
+        restored.into_iter().find(|todo| todo.id == id).ok_or(TodoRepoError::NotFound(id))
+    }
+
+    async fn purge_trash(&self, deleted_before: PrimitiveDateTime) -> Result<u64, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+
+        // A subtask never goes into the trash after its parent, so this never
+        // leaves one behind whose parent is purged.
+        let records = sqlx::query_as!(
+            TodoRecord,
+            "SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos WHERE deleted_at < $1 ORDER BY id FOR UPDATE",
+            deleted_before,
+        )
+            .fetch_all(&mut *tx).await?;
+        let mut purged: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+        Self::load_relations(&mut tx, purged.iter_mut().collect()).await?;
+
+        let ids: Vec<i64> = purged.iter().map(|todo| todo.id).collect();
+        sqlx::query!("DELETE FROM todos WHERE id = ANY($1)", &ids)
+            .execute(&mut *tx).await?;
+        for todo in &purged {
+            Self::record_on(&mut tx, None, Some(todo), None).await?;
+        }
+        tx.commit().await?;
+
+        Ok(purged.len() as u64)
+    }
+}
+
+///
+/// The people todos belong to. This is the users API of the context
+/// section's graduation project, kept in the database rather than in memory,
+/// so that accounts survive a restart.
+///
+/// Methods are named after the ones on `UsersState`, so that they do not
+/// clash with those of `TodoRepo` on backends that implement both.
+///
+#[async_trait]
+trait UserRepo: Send + Sync {
+    async fn get_users(&self) -> Result<Vec<User>, TodoRepoError>;
+    async fn get_user(&self, id: i64) -> Result<User, TodoRepoError>;
+    async fn create_user(&self, name: String, email: String, token_hash: Vec<u8>) -> Result<User, TodoRepoError>;
+    async fn update_user(&self, id: i64, name: Option<String>, email: Option<String>) -> Result<User, TodoRepoError>;
+    /// Deletes the user, along with every todo they own.
+    async fn delete_user(&self, id: i64) -> Result<User, TodoRepoError>;
+    /// Finds the user whose API token hashes to `token_hash`.
+    async fn authenticate(&self, token_hash: &[u8]) -> Result<Option<User>, TodoRepoError>;
+    /// Gives the user a new calendar feed token, which replaces any they had.
+    async fn set_feed_token(&self, id: i64, feed_token_hash: Vec<u8>) -> Result<User, TodoRepoError>;
+    /// Finds the user whose calendar feed token hashes to `feed_token_hash`.
+    async fn feed_owner(&self, feed_token_hash: &[u8]) -> Result<Option<User>, TodoRepoError>;
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+struct User {
+    id: i64,
+    name: String,
+    email: String,
+}
+
+/// Turns the unique violation a clashing email causes into a clearer conflict.
+fn email_taken(email: &str) -> impl FnOnce(sqlx::Error) -> TodoRepoError + '_ {
+    move |error| match TodoRepoError::from(error) {
+        TodoRepoError::Conflict(_) => TodoRepoError::Conflict(format!("a user with email {} already exists", email)),
+        error => error,
+    }
+}
+
+#[async_trait]
+impl UserRepo for TodoRepoPostgres {
+    async fn get_users(&self) -> Result<Vec<User>, TodoRepoError> {
+        Ok(sqlx::query_as!(User, "SELECT id, name, email FROM users ORDER BY id").fetch_all(&self.pool).await?)
+    }
+
+    async fn get_user(&self, id: i64) -> Result<User, TodoRepoError> {
+        sqlx::query_as!(User, "SELECT id, name, email FROM users WHERE id = $1", id)
+            .fetch_optional(&self.pool).await?
+            .ok_or(TodoRepoError::UserNotFound(id))
+    }
+
+    async fn create_user(&self, name: String, email: String, token_hash: Vec<u8>) -> Result<User, TodoRepoError> {
+        sqlx::query_as!(
+            User,
+            "INSERT INTO users (name, email, token_hash) VALUES ($1, $2, $3) RETURNING id, name, email",
+            name,
+            email,
+            token_hash,
+        )
+            .fetch_one(&self.pool).await
+            .map_err(email_taken(&email))
+    }
+
+    async fn update_user(&self, id: i64, name: Option<String>, email: Option<String>) -> Result<User, TodoRepoError> {
+        sqlx::query_as!(
+            User,
+            "UPDATE users SET name = COALESCE($1, name), email = COALESCE($2, email) WHERE id = $3 RETURNING id, name, email",
+            name,
+            email,
+            id,
+        )
+            .fetch_optional(&self.pool).await
+            .map_err(email_taken(email.as_deref().unwrap_or_default()))?
+            .ok_or(TodoRepoError::UserNotFound(id))
+    }
+
+    async fn delete_user(&self, id: i64) -> Result<User, TodoRepoError> {
+        sqlx::query_as!(User, "DELETE FROM users WHERE id = $1 RETURNING id, name, email", id)
+            .fetch_optional(&self.pool).await?
+            .ok_or(TodoRepoError::UserNotFound(id))
+    }
+
+    async fn authenticate(&self, token_hash: &[u8]) -> Result<Option<User>, TodoRepoError> {
+        Ok(sqlx::query_as!(User, "SELECT id, name, email FROM users WHERE token_hash = $1", token_hash).fetch_optional(&self.pool).await?)
+    }
+
+    async fn set_feed_token(&self, id: i64, feed_token_hash: Vec<u8>) -> Result<User, TodoRepoError> {
+        sqlx::query_as!(User, "UPDATE users SET feed_token_hash = $1 WHERE id = $2 RETURNING id, name, email", feed_token_hash, id)
+            .fetch_optional(&self.pool).await?
+            .ok_or(TodoRepoError::UserNotFound(id))
+    }
+
+    async fn feed_owner(&self, feed_token_hash: &[u8]) -> Result<Option<User>, TodoRepoError> {
+        Ok(sqlx::query_as!(User, "SELECT id, name, email FROM users WHERE feed_token_hash = $1", feed_token_hash).fetch_optional(&self.pool).await?)
+    }
+}
+
+///
+/// The lists (or projects) a user sorts their todos into. A todo belongs to
+/// at most one list, and goes into the trash when its list goes. Archiving a
+/// list keeps it and its todos, but takes them out of the everyday listings.
+///
+#[async_trait]
+trait TodoListRepo: Send + Sync {
+    async fn get_lists(&self, owner_id: i64, include_archived: bool) -> Result<Vec<TodoList>, TodoRepoError>;
+    async fn get_list(&self, id: i64) -> Result<TodoList, TodoRepoError>;
+    async fn create_list(&self, owner_id: i64, name: String) -> Result<TodoList, TodoRepoError>;
+    async fn update_list(&self, id: i64, name: Option<String>, archived: Option<bool>) -> Result<TodoList, TodoRepoError>;
+    ///
+    /// Deletes the list, moving every todo in it into the trash as deleting
+    /// the todo would, all at the same time. Todos in the trash outlast the
+    /// list, but are no longer in any list.
+    ///
+    async fn delete_list(&self, actor_id: Option<i64>, id: i64) -> Result<TodoList, TodoRepoError>;
+}
+
+#[derive(sqlx::FromRow, serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+struct TodoList {
+    id: i64,
+    owner_id: i64,
+    name: String,
+    archived: bool,
+}
+
+#[async_trait]
+impl TodoListRepo for TodoRepoPostgres {
+    async fn get_lists(&self, owner_id: i64, include_archived: bool) -> Result<Vec<TodoList>, TodoRepoError> {
+        Ok(sqlx::query_as!(
+            TodoList,
+            "SELECT id, owner_id, name, archived FROM todo_lists WHERE owner_id = $1 AND ($2 OR NOT archived) ORDER BY id",
+            owner_id,
+            include_archived,
+        )
+            .fetch_all(&self.pool).await?)
+    }
+
+    async fn get_list(&self, id: i64) -> Result<TodoList, TodoRepoError> {
+        sqlx::query_as!(TodoList, "SELECT id, owner_id, name, archived FROM todo_lists WHERE id = $1", id)
+            .fetch_optional(&self.pool).await?
+            .ok_or(TodoRepoError::ListNotFound(id))
+    }
+
+    async fn create_list(&self, owner_id: i64, name: String) -> Result<TodoList, TodoRepoError> {
+        Ok(sqlx::query_as!(
+            TodoList,
+            "INSERT INTO todo_lists (owner_id, name) VALUES ($1, $2) RETURNING id, owner_id, name, archived",
+            owner_id,
+            name,
+        )
+            .fetch_one(&self.pool).await?)
+    }
+
+    async fn update_list(&self, id: i64, name: Option<String>, archived: Option<bool>) -> Result<TodoList, TodoRepoError> {
+        sqlx::query_as!(
+            TodoList,
+            "UPDATE todo_lists SET name = COALESCE($1, name), archived = COALESCE($2, archived) WHERE id = $3 RETURNING id, owner_id, name, archived",
+            name,
+            archived,
+            id,
+        )
+            .fetch_optional(&self.pool).await?
+            .ok_or(TodoRepoError::ListNotFound(id))
+    }
+
+    async fn delete_list(&self, actor_id: Option<i64>, id: i64) -> Result<TodoList, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let listed = sqlx::query_scalar!("SELECT id FROM todos WHERE list_id = $1 AND deleted_at IS NULL ORDER BY id FOR UPDATE", id)
+            .fetch_all(&mut *tx).await?;
+        let mut doomed = BTreeMap::new();
+        for todo_id in listed {
+            doomed.extend(Self::subtree_on(&mut tx, todo_id, None).await?.into_iter().map(|todo| (todo.id, todo)));
+        }
+        let doomed: Vec<Todo> = doomed.into_values().collect();
+        Self::trash_on(&mut tx, actor_id, &doomed, Some(now())).await?;
+
+        // Otherwise the list would take the todos in the trash with it.
+        sqlx::query!("UPDATE todos SET list_id = NULL WHERE list_id = $1", id)
+            .execute(&mut *tx).await?;
+        let list = sqlx::query_as!(TodoList, "DELETE FROM todo_lists WHERE id = $1 RETURNING id, owner_id, name, archived", id)
+            .fetch_optional(&mut *tx).await?
+            .ok_or(TodoRepoError::ListNotFound(id))?;
+        tx.commit().await?;
+
+        Ok(list)
+    }
+}
+
+///
+/// GRADUATION PROJECT
+///
+/// In this project, you will build a simple CRUD API for a todo list,
+/// which uses sqlx for persistence.
+///
+/// The backend is chosen by the scheme of `DATABASE_URL`, which may point at
+/// either Postgres (`postgres://...`) or SQLite (`sqlite://...`).
+///
+pub async fn run_todo_app() {
+    let app = match connect_todo_router().await {
+        Ok(app) => app,
+        Err(error) => {
+            eprintln!("Could not start the todo app: {}", error);
+            return;
+        }
+    };
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000")
+        .await
+        .unwrap();
+
+    println!("Listening on {}", listener.local_addr().unwrap());
+
+    axum::serve(listener, app).await.unwrap();
+}
+
+fn database_url() -> Result<String, TodoRepoError> {
+    std::env::var("DATABASE_URL").map_err(|_| TodoRepoError::Unavailable("DATABASE_URL is not set".to_string()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DatabaseKind {
+    Postgres,
+    Sqlite,
+}
+
+impl DatabaseKind {
+    fn of(url: &str) -> Result<Self, TodoRepoError> {
+        match url.split_once(':').map(|(scheme, _)| scheme) {
+            Some("postgres") | Some("postgresql") => Ok(DatabaseKind::Postgres),
+            Some("sqlite") => Ok(DatabaseKind::Sqlite),
+            _ => Err(TodoRepoError::Unavailable("DATABASE_URL must be a postgres:// or sqlite:// URL".to_string())),
+        }
+    }
+}
+
+async fn connect_todo_router() -> Result<Router, TodoRepoError> {
+    let url = database_url()?;
+    let retention = trash_retention()?;
+
+    match DatabaseKind::of(&url)? {
+        DatabaseKind::Postgres => Ok(todo_app(TodoRepoPostgres::connect(&url).await?, retention)),
+        DatabaseKind::Sqlite => Ok(todo_app(TodoRepoSqlite::connect(&url).await?, retention)),
+    }
+}
+
+/// How long deleted todos stay in the trash, unless `TRASH_RETENTION` says otherwise.
+const DEFAULT_TRASH_RETENTION: time::Duration = time::Duration::days(30);
+
+/// How often the trash is checked for todos that have been there too long.
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long deleted todos stay in the trash, from `TRASH_RETENTION`, such as `30d`.
+fn trash_retention() -> Result<time::Duration, TodoRepoError> {
+    match std::env::var("TRASH_RETENTION") {
+        Ok(value) => parse_window("TRASH_RETENTION", &value)
+            .map_err(|error| TodoRepoError::Unavailable(format!("TRASH_RETENTION {}", error.message))),
+        Err(_) => Ok(DEFAULT_TRASH_RETENTION),
+    }
+}
+
+/// The router for `repo`, which has its trash purged of todos older than `retention` in the background.
+fn todo_app<R: TodoRepo + UserRepo + TodoListRepo + Clone + 'static>(repo: R, retention: time::Duration) -> Router {
+    tokio::spawn(purge_trash_periodically(repo.clone(), retention));
+
+    todo_router(repo)
+}
+
+async fn purge_trash_periodically<R: TodoRepo>(repo: R, retention: time::Duration) {
+    let mut interval = tokio::time::interval(TRASH_PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        match repo.purge_trash(now() - retention).await {
+            Ok(0) => {}
+            Ok(purged) => println!("Purged {} todos from the trash", purged),
+            Err(error) => eprintln!("Could not purge the trash: {}", error),
+        }
+    }
+}
+
+///
+/// The `migrate` subcommand, which manages the schema of the database at
+/// `DATABASE_URL` using the migrations built into the binary:
+///
+///  - `migrate up` applies every pending migration;
+///  - `migrate status` lists the migrations, and which have been applied;
+///  - `migrate down` reverts the most recently applied migration.
+///
+/// Exits the process with a non-zero status if anything goes wrong.
+///
+pub async fn run_migrate_command(args: &[String]) {
+    let command = match args {
+        [command] if ["up", "status", "down"].contains(&command.as_str()) => command.as_str(),
+        _ => {
+            eprintln!("usage: migrate <up|status|down>");
+            std::process::exit(2);
+        }
+    };
+
+    if let Err(error) = migrate_command(command).await {
+        eprintln!("Could not migrate: {}", error);
+        std::process::exit(1);
+    }
+}
+
+async fn migrate_command(command: &str) -> Result<(), TodoRepoError> {
+    use sqlx::{migrate::Migrate, sqlite::SqliteConnectOptions, Connection, PgConnection, SqliteConnection};
+    use std::str::FromStr;
+
+    let url = database_url()?;
+    let (mut conn, migrator): (Box<dyn Migrate + Send>, _) = match DatabaseKind::of(&url)? {
+        DatabaseKind::Postgres => (Box::new(PgConnection::connect(&url).await?), &POSTGRES_MIGRATIONS),
+        DatabaseKind::Sqlite => {
+            let options = SqliteConnectOptions::from_str(&url)?.create_if_missing(true);
+            (Box::new(SqliteConnection::connect_with(&options).await?), &SQLITE_MIGRATIONS)
+        }
+    };
+
+    match command {
+        "up" => {
+            let applied = migrations::migrate_up(&mut *conn, migrator).await?;
+            for migration in &applied {
+                println!("Applied {} {}", migration.version, migration.description);
+            }
+            if applied.is_empty() {
+                println!("Already up to date");
+            }
+        }
+        "down" => match migrations::migrate_down(&mut *conn, migrator).await? {
+            Some(migration) => println!("Reverted {} {}", migration.version, migration.description),
+            None => println!("Nothing to revert"),
+        },
+        _ => {
+            let status = MigrationStatus::read(&mut *conn, migrator).await?;
+            for (migration, applied) in &status.migrations {
+                let state = if status.dirty == Some(migration.version) {
+                    "failed"
+                } else if status.modified.contains(&migration.version) {
+                    "modified"
+                } else if *applied {
+                    "applied"
+                } else {
+                    "pending"
+                };
+                println!("{} {:<8} {}", migration.version, state, migration.description);
+            }
+            for version in &status.unknown {
+                println!("{} {:<8} (not part of this build)", version, "unknown");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn todo_router<R: TodoRepo + UserRepo + TodoListRepo + Clone + 'static>(repo: R) -> Router {
+    Router::<R>::new()
+        .route("/users", get(get_users::<R>))
+        .route("/users/:id", get(get_user::<R>))
+        .route("/users", post(create_user::<R>))
+        .route("/users/:id", put(update_user::<R>))
+        .route("/users/:id", delete(delete_user::<R>))
+        .route("/users/:id/feed", post(create_feed::<R>))
+        .route("/feeds/:token/todos.ics", get(get_feed::<R>))
+        .route("/lists", get(get_lists::<R>))
+        .route("/lists/:id", get(get_list::<R>))
+        .route("/lists", post(create_list::<R>))
+        .route("/lists/:id", put(update_list::<R>))
+        .route("/lists/:id", delete(delete_list::<R>))
+        .route("/lists/:id/todos", get(get_list_todos::<R>))
+        .route("/lists/:id/todos", post(create_list_todo::<R>))
+        .route("/todos", get(get_todos::<R>))
+        .route("/todos/search", get(search_todos::<R>))
+        .route("/todos/overdue", get(get_overdue_todos::<R>))
+        .route("/todos/upcoming", get(get_upcoming_todos::<R>))
+        .route("/todos/events", get(get_todo_events::<R>))
+        .route("/todos/export", get(export_todos::<R>))
+        .route("/todos/import", post(import_todos::<R>))
+        .route("/todos/:id", get(get_todo::<R>))
+        .route("/todos", post(create_todo::<R>))
+        // The router treats `:` as the start of a path parameter, so this
+        // matches `/todos` followed by anything; the handler checks the rest.
+        .route("/todos:action", post(batch_todos::<R>))
+        .route("/todos/:id", put(update_todo::<R>))
+        .route("/todos/:id", patch(patch_todo::<R>))
+        .route("/todos/:id", delete(delete_todo::<R>))
+        .route("/todos/:id/list", put(move_todo::<R>))
+        .route("/todos/:id/move", post(reorder_todo::<R>))
+        .route("/todos/:id/tags/:tag", put(tag_todo::<R>))
+        .route("/todos/:id/tags/:tag", delete(untag_todo::<R>))
+        .route("/todos/:id/blockers/:blocker_id", put(block_todo::<R>))
+        .route("/todos/:id/blockers/:blocker_id", delete(unblock_todo::<R>))
+        .route("/todos/:id/history", get(get_todo_history::<R>))
+        .route("/todos/:id/restore", post(restore_todo::<R>))
+        .route("/trash", get(get_trash::<R>))
+        .route("/audit", get(get_audit::<R>))
+        .route("/ws/todos", get(todo_socket::<R>))
+        .route("/.well-known/caldav", any(caldav::well_known))
+        .route("/dav", any(caldav::principal::<R>))
+        .route("/dav/", any(caldav::principal::<R>))
+        .route("/dav/lists", any(caldav::home::<R>))
+        .route("/dav/lists/", any(caldav::home::<R>))
+        .route("/dav/lists/:list_id", any(caldav::calendar::<R>))
+        .route("/dav/lists/:list_id/", any(caldav::calendar::<R>))
+        .route("/dav/lists/:list_id/:name", any(caldav::resource::<R>))
+        .with_state(repo)
+}
+
+async fn get_users<R: UserRepo>(_: AuthenticatedUser, state: State<R>) -> Result<Json<Vec<User>>, TodoRepoError> {
+    (*state).get_users().await.map(Json)
+}
+
+async fn get_user<R: UserRepo>(Path(id): Path<i64>, _: AuthenticatedUser, state: State<R>) -> Result<Json<User>, TodoRepoError> {
+    (*state).get_user(id).await.map(Json)
+}
+
+async fn create_user<R: UserRepo>(state: State<R>, Json(spec): Json<CreateUser>) -> Result<Json<NewUser>, TodoApiError> {
+    let email = valid_email(spec.email)?;
+    let token = generate_token();
+    let user = (*state).create_user(spec.name, email, hash_token(&token)).await?;
+
+    Ok(Json(NewUser { user, token }))
+}
+
+async fn update_user<R: UserRepo>(Path(id): Path<i64>, AuthenticatedUser(me): AuthenticatedUser, state: State<R>, Json(update): Json<UpdateUser>) -> Result<Json<User>, TodoApiError> {
+    if me.id != id {
+        return Err(TodoApiError::Forbidden);
+    }
+    let email = update.email.map(valid_email).transpose()?;
+
+    Ok(Json((*state).update_user(id, update.name, email).await?))
+}
+
+async fn delete_user<R: UserRepo>(Path(id): Path<i64>, AuthenticatedUser(me): AuthenticatedUser, state: State<R>) -> Result<Json<User>, TodoApiError> {
+    if me.id != id {
+        return Err(TodoApiError::Forbidden);
+    }
+
+    Ok(Json((*state).delete_user(id).await?))
+}
+
+///
+/// Gives the caller a calendar feed of their todos, at a secret URL that
+/// calendar apps can subscribe to without signing in. Asking again makes a
+/// new URL, and the old one stops working, which is how a URL that got out
+/// is taken back.
+///
+async fn create_feed<R: UserRepo>(Path(id): Path<i64>, AuthenticatedUser(me): AuthenticatedUser, state: State<R>) -> Result<Json<TodoFeed>, TodoApiError> {
+    if me.id != id {
+        return Err(TodoApiError::Forbidden);
+    }
+    let token = generate_token();
+    (*state).set_feed_token(id, hash_token(&token)).await?;
+
+    Ok(Json(TodoFeed { url: format!("/feeds/{}/todos.ics", token) }))
+}
+
+///
+/// A user's todos that have due dates, done or not, as an iCalendar feed.
+/// The token in the URL is all there is to authenticate with, so one that
+/// is no longer anybody's is simply not found.
+///
+async fn get_feed<R: TodoRepo + UserRepo>(Path(token): Path<String>, State(repo): State<R>) -> Result<Response, TodoApiError> {
+    let owner = repo.feed_owner(&hash_token(&token)).await?.ok_or(TodoApiError::UnknownFeed)?;
+    let last_modified = repo.last_modified(owner.id).await?;
+    let uids = repo.get_uids(owner.id).await?;
+    let todos: Vec<Todo> = repo.export(owner.id).try_collect().await?;
+
+    let mut calendar = ical::CALENDAR_START.to_string();
+    for todo in todos.into_iter().filter(|todo| todo.due_at.is_some()) {
+        let modified = last_modified.get(&todo.id).copied();
+        calendar.push_str(&ical::vtodo(&PortableTodo::of(todo), &uids, modified));
+    }
+    calendar.push_str(ical::CALENDAR_END);
+
+    Ok(([(header::CONTENT_TYPE, TodoFileFormat::Ics.content_type())], calendar).into_response())
+}
+
+async fn get_lists<R: TodoListRepo>(Query(params): Query<Vec<(String, String)>>, AuthenticatedUser(me): AuthenticatedUser, state: State<R>) -> Result<Json<Vec<TodoList>>, TodoApiError> {
+    let include_archived = include_archived(params)?;
+
+    Ok(Json((*state).get_lists(me.id, include_archived).await?))
+}
+
+async fn get_list<R: TodoListRepo>(Path(id): Path<i64>, AuthenticatedUser(me): AuthenticatedUser, state: State<R>) -> Result<Json<TodoList>, TodoApiError> {
+    Ok(Json(owned_list(&*state, id, &me).await?))
+}
+
+async fn create_list<R: TodoListRepo>(AuthenticatedUser(me): AuthenticatedUser, state: State<R>, Json(spec): Json<CreateList>) -> Result<Json<TodoList>, TodoApiError> {
+    let name = valid_list_name(spec.name)?;
+
+    Ok(Json((*state).create_list(me.id, name).await?))
+}
+
+async fn update_list<R: TodoListRepo>(Path(id): Path<i64>, AuthenticatedUser(me): AuthenticatedUser, state: State<R>, Json(update): Json<UpdateList>) -> Result<Json<TodoList>, TodoApiError> {
+    owned_list(&*state, id, &me).await?;
+    let name = update.name.map(valid_list_name).transpose()?;
+
+    Ok(Json((*state).update_list(id, name, update.archived).await?))
+}
+
+async fn delete_list<R: TodoListRepo>(Path(id): Path<i64>, AuthenticatedUser(me): AuthenticatedUser, state: State<R>) -> Result<Json<TodoList>, TodoApiError> {
+    owned_list(&*state, id, &me).await?;
+
+    Ok(Json((*state).delete_list(Some(me.id), id).await?))
+}
+
+async fn get_list_todos<R: TodoRepo + TodoListRepo>(Path(id): Path<i64>, Query(params): Query<Vec<(String, String)>>, AuthenticatedUser(me): AuthenticatedUser, state: State<R>) -> Result<Json<TodoPage>, TodoApiError> {
+    owned_list(&*state, id, &me).await?;
+    let mut query = TodoListQuery::from_params(params)?;
+    query.filter.owner_id = Some(me.id);
+    query.filter.list_id = Some(id);
+
+    Ok(Json((*state).get_all(&query).await?))
+}
+
+async fn create_list_todo<R: TodoRepo + TodoListRepo>(Path(id): Path<i64>, AuthenticatedUser(me): AuthenticatedUser, state: State<R>, Json(mut spec): Json<CreateTodo>) -> Result<VersionedTodo, TodoApiError> {
+    open_list(&*state, id, &me).await?;
+    spec.list_id = Some(id);
+
+    Ok(VersionedTodo((*state).create(Some(me.id), spec).await?))
+}
+
+///
+/// The list `id`, as long as it belongs to `owner`. Other people's lists are
+/// reported as missing rather than forbidden, so that their ids give nothing
+/// away.
+///
+async fn owned_list<R: TodoListRepo>(repo: &R, id: i64, owner: &User) -> Result<TodoList, TodoRepoError> {
+    match repo.get_list(id).await? {
+        list if list.owner_id == owner.id => Ok(list),
+        _ => Err(TodoRepoError::ListNotFound(id)),
+    }
+}
+
+/// As `owned_list`, but also refusing archived lists, which take no new todos.
+async fn open_list<R: TodoListRepo>(repo: &R, id: i64, owner: &User) -> Result<TodoList, TodoRepoError> {
+    let list = owned_list(repo, id, owner).await?;
+    if list.archived {
+        return Err(TodoRepoError::Conflict(format!("list {} is archived", id)));
+    }
+
+    Ok(list)
+}
+
+fn valid_list_name(name: String) -> Result<String, TodoRepoError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(TodoRepoError::Invalid("a list needs a name".to_string()));
+    }
+
+    Ok(name.to_string())
+}
+
+/// Reads the only query parameter `GET /lists` takes.
+fn include_archived(params: Vec<(String, String)>) -> Result<bool, InvalidQueryError> {
+    let mut include_archived = false;
+    for (name, value) in params {
+        match name.as_str() {
+            "include_archived" => {
+                include_archived = value.parse::<bool>().map_err(|_| InvalidQueryError::new(&name, "must be `true` or `false`"))?;
+            }
+            _ => return Err(InvalidQueryError::new(&name, "unknown query parameter")),
+        }
+    }
+
+    Ok(include_archived)
+}
+
+async fn get_todos<R: TodoRepo>(Query(params): Query<Vec<(String, String)>>, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<Json<TodoPage>, TodoApiError> {
+    let mut query = TodoListQuery::from_params(params)?;
+    query.filter.owner_id = Some(owner.id);
+
+    Ok(Json((*state).get_all(&query).await?))
+}
+
+async fn search_todos<R: TodoRepo>(Query(params): Query<Vec<(String, String)>>, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<Json<Vec<TodoSearchHit>>, TodoApiError> {
+    let mut query = TodoSearchQuery::from_params(params)?;
+    query.owner_id = Some(owner.id);
+
+    Ok(Json((*state).search(&query).await?))
+}
+
+async fn get_overdue_todos<R: TodoRepo>(Query(params): Query<Vec<(String, String)>>, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<Json<Vec<Todo>>, TodoApiError> {
+    let mut query = TodoDueQuery::overdue(params, now().assume_utc())?;
+    query.owner_id = Some(owner.id);
+
+    Ok(Json((*state).get_due(&query).await?))
+}
+
+async fn get_upcoming_todos<R: TodoRepo>(Query(params): Query<Vec<(String, String)>>, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<Json<Vec<Todo>>, TodoApiError> {
+    let mut query = TodoDueQuery::upcoming(params, now().assume_utc())?;
+    query.owner_id = Some(owner.id);
+
+    Ok(Json((*state).get_due(&query).await?))
+}
+
+///
+/// One of the caller's todos or, with `expand=subtree`, the todo with its
+/// subtasks nested inside it. Either way, the `ETag` is the version of the
+/// todo asked for.
+///
+async fn get_todo<R: TodoRepo>(Path(id): Path<i64>, Query(params): Query<Vec<(String, String)>>, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<Response, TodoApiError> {
+    let expand_subtree = expand_subtree(params)?;
+    let todo = owned_todo(&*state, id, &owner).await?;
+    if !expand_subtree {
+        return Ok(VersionedTodo(todo).into_response());
+    }
+
+    let tree = TodoTree::build(id, (*state).get_subtree(id).await?).ok_or(TodoRepoError::NotFound(id))?;
+
+    Ok(([(header::ETAG, format!("\"{}\"", tree.todo.version))], Json(tree)).into_response())
+}
+
+fn expand_subtree(params: Vec<(String, String)>) -> Result<bool, InvalidQueryError> {
+    let mut expand_subtree = false;
+    for (name, value) in params {
+        match name.as_str() {
+            "expand" if value == "subtree" => expand_subtree = true,
+            "expand" => return Err(InvalidQueryError::new(&name, "must be `subtree`")),
+            _ => return Err(InvalidQueryError::new(&name, "unknown query parameter")),
+        }
+    }
+
+    Ok(expand_subtree)
+}
+
+async fn create_todo<R: TodoRepo + TodoListRepo>(AuthenticatedUser(owner): AuthenticatedUser, state: State<R>, Json(spec): Json<CreateTodo>) -> Result<VersionedTodo, TodoRepoError> {
+    if let Some(list_id) = spec.list_id {
+        open_list(&*state, list_id, &owner).await?;
+    }
+
+    (*state).create(Some(owner.id), spec).await.map(VersionedTodo)
+}
+
+async fn batch_todos<R: TodoRepo + TodoListRepo>(Path(action): Path<String>, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>, Json(batch): Json<TodoBatch>) -> Result<Json<TodoBatchResponse>, TodoApiError> {
+    if action != ":batch" {
+        return Err(TodoApiError::UnknownAction(action));
+    }
+    if batch.operations.len() > MAX_BATCH_OPERATIONS {
+        let message = format!("a batch may have at most {} operations", MAX_BATCH_OPERATIONS);
+        return Err(TodoRepoError::Invalid(message).into());
+    }
+    for (index, operation) in batch.operations.iter().enumerate() {
+        if let TodoOperation::Create(CreateTodo { list_id: Some(list_id), .. }) = operation {
+            open_list(&*state, *list_id, &owner).await.map_err(TodoBatchError::at(index))?;
+        }
+    }
+
+    let results = (*state).batch(Some(owner.id), &batch.operations).await?;
+
+    Ok(Json(TodoBatchResponse { results }))
+}
+
+async fn update_todo<R: TodoRepo>(Path(id): Path<i64>, if_match: IfMatch, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>, Json(update): Json<UpdateTodo>) -> Result<VersionedTodo, TodoApiError> {
+    let expected_version = if_match.expected_version(&owned_todo(&*state, id, &owner).await?)?;
+
+    Ok(VersionedTodo((*state).update(Some(owner.id), id, expected_version, &update).await?))
+}
+
+///
+/// Applies a merge or JSON patch to a todo. Patches have no room for flags,
+/// so marking a blocked todo done takes `?force=true`.
+///
+async fn patch_todo<R: TodoRepo>(Path(id): Path<i64>, Query(params): Query<Vec<(String, String)>>, if_match: IfMatch, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>, headers: HeaderMap, body: Bytes) -> Result<VersionedTodo, TodoApiError> {
+    let force = force(params)?;
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    let patch = TodoPatch::parse(content_type, &body)?;
+    let expected_version = if_match.expected_version(&owned_todo(&*state, id, &owner).await?)?;
+
+    Ok(VersionedTodo((*state).patch(Some(owner.id), id, expected_version, &patch, force).await?))
+}
+
+fn force(params: Vec<(String, String)>) -> Result<bool, InvalidQueryError> {
+    let mut force = false;
+    for (name, value) in params {
+        match name.as_str() {
+            "force" => force = value.parse::<bool>().map_err(|_| InvalidQueryError::new(&name, "must be `true` or `false`"))?,
+            _ => return Err(InvalidQueryError::new(&name, "unknown query parameter")),
+        }
+    }
+
+    Ok(force)
+}
+
+async fn delete_todo<R: TodoRepo>(Path(id): Path<i64>, if_match: IfMatch, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<VersionedTodo, TodoApiError> {
+    let expected_version = if_match.expected_version(&owned_todo(&*state, id, &owner).await?)?;
+
+    Ok(VersionedTodo((*state).delete(Some(owner.id), id, expected_version).await?))
+}
+
+///
+/// Moves one of the caller's todos into another of their lists, or out of
+/// any list when `list_id` is `null`.
+///
+async fn move_todo<R: TodoRepo + TodoListRepo>(Path(id): Path<i64>, if_match: IfMatch, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>, Json(target): Json<MoveTodo>) -> Result<VersionedTodo, TodoRepoError> {
+    let expected_version = if_match.expected_version(&owned_todo(&*state, id, &owner).await?)?;
+    if let Some(list_id) = target.list_id {
+        open_list(&*state, list_id, &owner).await?;
+    }
+
+    (*state).move_todo(Some(owner.id), id, expected_version, target.list_id).await.map(VersionedTodo)
+}
+
+///
+/// Moves one of the caller's todos just before or after another of theirs,
+/// as when it is dragged there in a listing.
+///
+async fn reorder_todo<R: TodoRepo>(Path(id): Path<i64>, if_match: IfMatch, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>, Json(placement): Json<TodoPlacement>) -> Result<VersionedTodo, TodoRepoError> {
+    let expected_version = if_match.expected_version(&owned_todo(&*state, id, &owner).await?)?;
+    owned_todo(&*state, placement.anchor_id(), &owner).await?;
+
+    (*state).reorder(Some(owner.id), id, expected_version, placement).await.map(VersionedTodo)
+}
+
+async fn tag_todo<R: TodoRepo>(Path((id, tag)): Path<(i64, String)>, if_match: IfMatch, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<VersionedTodo, TodoRepoError> {
+    let expected_version = if_match.expected_version(&owned_todo(&*state, id, &owner).await?)?;
+    let tag = valid_tag(&tag)?;
+
+    (*state).tag(Some(owner.id), id, expected_version, tag).await.map(VersionedTodo)
+}
+
+async fn untag_todo<R: TodoRepo>(Path((id, tag)): Path<(i64, String)>, if_match: IfMatch, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<VersionedTodo, TodoRepoError> {
+    let expected_version = if_match.expected_version(&owned_todo(&*state, id, &owner).await?)?;
+
+    (*state).untag(Some(owner.id), id, expected_version, tag.trim()).await.map(VersionedTodo)
+}
+
+/// Records that one of the caller's todos has to wait for another of theirs.
+async fn block_todo<R: TodoRepo>(Path((id, blocker_id)): Path<(i64, i64)>, if_match: IfMatch, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<VersionedTodo, TodoRepoError> {
+    let expected_version = if_match.expected_version(&owned_todo(&*state, id, &owner).await?)?;
+    owned_todo(&*state, blocker_id, &owner).await?;
+
+    (*state).block(Some(owner.id), id, expected_version, blocker_id).await.map(VersionedTodo)
+}
+
+async fn unblock_todo<R: TodoRepo>(Path((id, blocker_id)): Path<(i64, i64)>, if_match: IfMatch, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<VersionedTodo, TodoRepoError> {
+    let expected_version = if_match.expected_version(&owned_todo(&*state, id, &owner).await?)?;
+
+    (*state).unblock(Some(owner.id), id, expected_version, blocker_id).await.map(VersionedTodo)
+}
+
+///
+/// What has happened to one of the caller's todos, oldest first. It is still
+/// there once the todo is deleted, but only ever for the todo's owner.
+///
+async fn get_todo_history<R: TodoRepo>(Path(id): Path<i64>, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<Json<Vec<TodoEvent>>, TodoRepoError> {
+    let events: Vec<TodoEvent> = (*state).get_history(id).await?.into_iter().filter(|event| event.owner_id == Some(owner.id)).collect();
+    if events.is_empty() {
+        // Todos written before there was a history have none, but are still the caller's, in the trash or out of it.
+        let todo = match (*state).get(id).await {
+            Err(TodoRepoError::NotFound(_)) => (*state).get_trashed(id).await?,
+            todo => todo?,
+        };
+        if todo.owner_id != Some(owner.id) {
+            return Err(TodoRepoError::NotFound(id));
+        }
+    }
+
+    Ok(Json(events))
+}
+
+/// What has happened to any of the caller's todos, oldest first.
+async fn get_audit<R: TodoRepo>(Query(params): Query<Vec<(String, String)>>, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<Json<Vec<TodoEvent>>, TodoApiError> {
+    let mut query = TodoAuditQuery::from_params(params)?;
+    query.owner_id = Some(owner.id);
+
+    Ok(Json((*state).get_audit(&query).await?))
+}
+
+/// The caller's todos in the trash, most recently deleted first.
+async fn get_trash<R: TodoRepo>(Query(params): Query<Vec<(String, String)>>, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<Json<Vec<Todo>>, TodoApiError> {
+    let mut query = TodoTrashQuery::from_params(params)?;
+    query.owner_id = Some(owner.id);
+
+    Ok(Json((*state).get_trash(&query).await?))
+}
+
+/// Takes one of the caller's todos out of the trash, with the subtasks that were deleted along with it.
+async fn restore_todo<R: TodoRepo>(Path(id): Path<i64>, if_match: IfMatch, AuthenticatedUser(owner): AuthenticatedUser, state: State<R>) -> Result<VersionedTodo, TodoRepoError> {
+    let expected_version = match (*state).get_trashed(id).await? {
+        todo if todo.owner_id == Some(owner.id) => if_match.expected_version(&todo)?,
+        _ => return Err(TodoRepoError::NotFound(id)),
+    };
+
+    (*state).restore(Some(owner.id), id, expected_version).await.map(VersionedTodo)
+}
+
+/// How often an idle event stream sends a comment, so that proxies keep it open, and we notice when the client has gone.
+const EVENT_STREAM_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+///
+/// Streams changes to the caller's todos as server-sent events, each named
+/// for its kind and carrying the todo as it is now (`null` once it is
+/// gone), with the id of the change as the event id. A client reconnecting
+/// with `Last-Event-ID` first gets whatever it missed. The stream ends if
+/// the server could have missed changes itself, for the client to do that.
+///
+async fn get_todo_events<R: TodoRepo + UserRepo + Clone + 'static>(LastEventId(last_event_id): LastEventId, AuthenticatedUser(owner): AuthenticatedUser, State(repo): State<R>) -> Result<Response, TodoRepoError> {
+    // Watching starts before catching up, so nothing can fall in between.
+    let changes = repo.watch_changes();
+    let after = match last_event_id {
+        Some(last_event_id) => Some(last_event_id),
+        None => repo.last_change(owner.id).await?,
+    };
+    let (events, receiver) = mpsc::channel(16);
+    tokio::spawn(stream_changes(repo, owner.id, after, changes, events));
+
+    Ok(([(header::CONTENT_TYPE, "text/event-stream"), (header::CACHE_CONTROL, "no-cache")], Body::new(EventStream(receiver))).into_response())
+}
+
+///
+/// Sends `owner_id`'s changes after the change `after`, if any, until the
+/// client goes away. Changes are read from the repo, after the last one
+/// sent, whenever one is heard of, rather than sent as they are heard of,
+/// since they can be heard of before older ones have been made.
+///
+async fn stream_changes<R: TodoRepo>(repo: R, owner_id: i64, mut after: Option<i64>, mut changes: broadcast::Receiver<TodoChange>, events: mpsc::Sender<String>) {
+    // Changes heard of that may not have been sent yet, for want of older ones being made first.
+    let mut unsent = BTreeSet::new();
+
+    let mut keep_alive = tokio::time::interval(EVENT_STREAM_KEEP_ALIVE);
+    loop {
+        loop {
+            let query = TodoChangeQuery { after, limit: MAX_PAGE_LIMIT, owner_id: Some(owner_id) };
+            let page = match repo.get_changes(&query).await {
+                Ok(page) => page,
+                Err(error) => {
+                    eprintln!("Could not read todo changes: {}", error);
+                    return;
+                }
+            };
+            for change in &page {
+                if !send_change(&repo, change, &events).await {
+                    return;
+                }
+                unsent.remove(&change.id);
+                after = Some(change.id);
+            }
+            if (page.len() as i64) < MAX_PAGE_LIMIT {
+                break;
+            }
+        }
+
+        // Reading again on hearing of a change, or of any change at all while
+        // some may be held back, and otherwise every so often regardless.
+        let sent = loop {
+            tokio::select! {
+                change = changes.recv() => match change {
+                    Ok(change) if change.owner_id == Some(owner_id) => {
+                        unsent.insert(change.id);
+                        break true;
+                    }
+                    Ok(_) if unsent.is_empty() => {}
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => break true,
+                    // Changes may have been missed, so the stream ends, and
+                    // the client reconnects from the last event it got.
+                    Err(broadcast::error::RecvError::Closed) => break false,
+                },
+                _ = keep_alive.tick() => {
+                    unsent.clear();
+                    break events.send(": keep-alive\n\n".to_string()).await.is_ok();
+                }
+            }
+        };
+        if !sent {
+            return;
+        }
+    }
+}
+
+/// Sends `change` down an event stream, returning whether the client is still there.
+async fn send_change<R: TodoRepo>(repo: &R, change: &TodoChange, events: &mpsc::Sender<String>) -> bool {
+    // The todo may have changed again since, or be gone by now.
+    let todo = match change.kind {
+        TodoChangeKind::Deleted => None,
+        _ => repo.get(change.todo_id).await.ok(),
+    };
+    let data = serde_json::json!({ "todo_id": change.todo_id, "todo": todo });
+
+    events.send(format!("id: {}\nevent: {}\ndata: {}\n\n", change.id, change.kind.as_str(), data)).await.is_ok()
+}
+
+/// A `text/event-stream` body, which sends events as they come down the channel, and ends when it does.
+struct EventStream(mpsc::Receiver<String>);
+
+impl hyper::body::Body for EventStream {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<hyper::body::Frame<Bytes>, Infallible>>> {
+        self.0.poll_recv(cx).map(|event| event.map(|event| Ok(hyper::body::Frame::data(Bytes::from(event)))))
+    }
+}
+
+/// How often a todo socket pings its client, which is dropped once two of these go by without a word from it.
+const SOCKET_HEARTBEAT: Duration = Duration::from_secs(30);
+
+/// How long a todo socket waits for its client to take each frame before giving up on it.
+const SOCKET_SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The close code for clients that fell too far behind the changes: "try again later".
+const SOCKET_FELL_BEHIND: u16 = 1013;
+
+///
+/// A WebSocket for editing todos live. Clients `subscribe` to their lists,
+/// and send `create`, `update` and `delete` commands shaped like batch
+/// operations, each with a `ref` of their choosing that comes back on its
+/// `ack` or `error` frame. Changes anyone else makes to todos in the
+/// subscribed lists arrive as frames named for their kind.
+///
+async fn todo_socket<R: TodoRepo + UserRepo + TodoListRepo + Clone + 'static>(upgrade: WebSocketUpgrade, AuthenticatedUser(owner): AuthenticatedUser, State(repo): State<R>) -> Response {
+    let changes = repo.watch_changes();
+
+    upgrade.on_upgrade(move |socket| TodoSocket { repo, owner, socket, lists: BTreeSet::new(), written: BTreeSet::new() }.run(changes))
+}
+
+/// One client's end of `/ws/todos`.
+struct TodoSocket<R> {
+    repo: R,
+    owner: User,
+    socket: WebSocket,
+    /// The lists the client has subscribed to.
+    lists: BTreeSet<i64>,
+    /// The todos, at the versions the client's own commands left them, that it has had acks for rather than broadcasts.
+    written: BTreeSet<(i64, i64)>,
+}
+
+impl<R: TodoRepo + TodoListRepo> TodoSocket<R> {
+    ///
+    /// Answers commands and passes on changes until the client goes away.
+    /// Clients that stop reading are dropped once a frame has waited
+    /// `SOCKET_SEND_TIMEOUT` for them, and those that fall further behind
+    /// than the change feed buffers are closed with `SOCKET_FELL_BEHIND`,
+    /// to reconnect and reload their lists.
+    ///
+    async fn run(mut self, mut changes: broadcast::Receiver<TodoChange>) {
+        let mut heartbeat = tokio::time::interval(SOCKET_HEARTBEAT);
+        let mut last_heard = tokio::time::Instant::now();
+        loop {
+            let carry_on = tokio::select! {
+                message = self.socket.recv() => match message {
+                    Some(Ok(message)) => {
+                        last_heard = tokio::time::Instant::now();
+                        self.receive(message).await
+                    }
+                    _ => false,
+                },
+                change = changes.recv() => match change {
+                    Ok(change) => match self.change_frame(&change).await {
+                        Some(frame) => self.send(Message::Text(frame.to_string())).await,
+                        None => true,
+                    },
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        let reason = "fell behind; reconnect and reload".into();
+                        self.send(Message::Close(Some(CloseFrame { code: SOCKET_FELL_BEHIND, reason }))).await;
+                        false
+                    }
+                    Err(broadcast::error::RecvError::Closed) => false,
+                },
+                _ = heartbeat.tick() => last_heard.elapsed() < 2 * SOCKET_HEARTBEAT && self.send(Message::Ping(Vec::new())).await,
+            };
+            if !carry_on {
+                return;
+            }
+        }
+    }
+
+    /// Sends a frame, returning whether the client took it in time.
+    async fn send(&mut self, message: Message) -> bool {
+        matches!(tokio::time::timeout(SOCKET_SEND_TIMEOUT, self.socket.send(message)).await, Ok(Ok(())))
+    }
+
+    /// Answers a frame from the client, returning whether it is still there.
+    async fn receive(&mut self, message: Message) -> bool {
+        let (reference, command) = match message {
+            Message::Text(text) => TodoSocketCommand::parse(&text),
+            Message::Binary(_) => (serde_json::Value::Null, Err(TodoRepoError::Invalid("commands must be sent as text".to_string()))),
+            // Pings are answered by the socket itself.
+            Message::Ping(_) | Message::Pong(_) => return true,
+            Message::Close(_) => return false,
+        };
+        let frame = match command {
+            Ok(command) => self.execute(command).await,
+            Err(error) => Err(error),
+        };
+        let frame = match frame {
+            Ok(mut ack) => {
+                ack["type"] = "ack".into();
+                ack["ref"] = reference;
+                ack
+            }
+            Err(error) => {
+                let (status, message) = error.status_and_message();
+                serde_json::json!({ "type": "error", "ref": reference, "status": status.as_u16(), "message": message })
+            }
+        };
+
+        self.send(Message::Text(frame.to_string())).await
+    }
+
+    /// Carries out a command with the same checks as the REST endpoints, returning what goes in its ack.
+    async fn execute(&mut self, command: TodoSocketCommand) -> Result<serde_json::Value, TodoRepoError> {
+        let owner_id = Some(self.owner.id);
+        let result = match command {
+            TodoSocketCommand::Subscribe(list_id) => {
+                owned_list(&self.repo, list_id, &self.owner).await?;
+                self.lists.insert(list_id);
+                return Ok(serde_json::json!({ "list_id": list_id }));
+            }
+            TodoSocketCommand::Unsubscribe(list_id) => {
+                self.lists.remove(&list_id);
+                return Ok(serde_json::json!({ "list_id": list_id }));
+            }
+            TodoSocketCommand::Todo(TodoOperation::Create(spec)) => {
+                if let Some(list_id) = spec.list_id {
+                    open_list(&self.repo, list_id, &self.owner).await?;
+                }
+                TodoOperationResult::Created(self.repo.create(owner_id, spec).await?)
+            }
+            TodoSocketCommand::Todo(TodoOperation::Update { id, version, changes }) => {
+                owned_todo(&self.repo, id, &self.owner).await?;
+                TodoOperationResult::Updated(self.repo.update(owner_id, id, version, &changes).await?)
+            }
+            TodoSocketCommand::Todo(TodoOperation::Delete { id, version }) => {
+                owned_todo(&self.repo, id, &self.owner).await?;
+                TodoOperationResult::Deleted(self.repo.delete(owner_id, id, version).await?)
+            }
+        };
+        let (TodoOperationResult::Created(todo) | TodoOperationResult::Updated(todo) | TodoOperationResult::Deleted(todo)) = &result;
+        self.written.insert((todo.id, todo.version));
+
+        Ok(serde_json::to_value(&result).expect("todos serialize"))
+    }
+
+    ///
+    /// The frame telling the client of `change`, unless it is to a todo
+    /// outside the subscribed lists, or the client's own command made it.
+    /// Changes go to the list a todo is in now, so one moved out of a
+    /// subscribed list is not heard of there again.
+    ///
+    async fn change_frame(&mut self, change: &TodoChange) -> Option<serde_json::Value> {
+        if change.owner_id != Some(self.owner.id) || self.lists.is_empty() {
+            return None;
+        }
+        // The todo may have changed again since, or be gone for good.
+        let todo = match change.kind {
+            TodoChangeKind::Deleted => self.repo.get_trashed(change.todo_id).await,
+            _ => self.repo.get(change.todo_id).await,
+        };
+        let todo = todo.ok()?;
+        if self.written.remove(&(todo.id, todo.version)) || !todo.list_id.is_some_and(|list_id| self.lists.contains(&list_id)) {
+            return None;
+        }
+        let current = (change.kind != TodoChangeKind::Deleted).then_some(&todo);
+
+        Some(serde_json::json!({ "type": change.kind.as_str(), "list_id": todo.list_id, "todo_id": todo.id, "todo": current }))
+    }
+}
+
+///
+/// Exports the caller's todos, other than those in the trash, as CSV, JSON
+/// Lines or iCalendar that `POST /todos/import` takes back. Todos are
+/// written out as they are read, so the export is never held in memory as
+/// a whole.
+///
+async fn export_todos<R: TodoRepo + Clone + 'static>(Query(params): Query<Vec<(String, String)>>, AuthenticatedUser(owner): AuthenticatedUser, State(repo): State<R>) -> Result<Response, TodoApiError> {
+    let (format, _) = file_params(params, false)?;
+    // Todos go by the UIDs calendar apps know them by, wherever they are written out as iCalendar.
+    let uids = if format == TodoFileFormat::Ics { repo.get_uids(owner.id).await? } else { BTreeMap::new() };
+
+    let (lines, receiver) = mpsc::channel(16);
+    tokio::spawn(async move {
+        if let Some(header) = format.header() {
+            if lines.send(Ok(header)).await.is_err() {
+                return;
+            }
+        }
+        let mut todos = repo.export(owner.id);
+        while let Some(todo) = todos.next().await {
+            // Failing partway through breaks off the response, so that it cannot be taken for the whole export.
+            let line = todo.map(|todo| format.line(&PortableTodo::of(todo), &uids));
+            if let Err(error) = &line {
+                eprintln!("Could not finish exporting todos: {}", error);
+            }
+            let failed = line.is_err();
+            if lines.send(line).await.is_err() || failed {
+                return;
+            }
+        }
+        if let Some(footer) = format.footer() {
+            let _ = lines.send(Ok(footer)).await;
+        }
+    });
+    let body = Body::from_stream(futures_util::stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|line| (line, receiver))
+    }));
+    let disposition = format!("attachment; filename=\"todos.{}\"", format.extension());
+
+    Ok(([(header::CONTENT_TYPE, format.content_type().to_string()), (header::CONTENT_DISPOSITION, disposition)], body).into_response())
+}
+
+///
+/// Imports todos from CSV, JSON Lines or iCalendar, one row at a time, so that a bad
+/// row costs only itself, and reports why each row that failed did.
+/// `?dry_run=true` checks every row as importing it would, without writing
+/// anything. A row's `id` is only there for later rows to name as their
+/// `parent_id`; the todos it makes get ids of their own.
+///
+async fn import_todos<R: TodoRepo + TodoListRepo>(Query(params): Query<Vec<(String, String)>>, AuthenticatedUser(owner): AuthenticatedUser, State(repo): State<R>, body: Bytes) -> Result<Json<TodoImportReport>, TodoApiError> {
+    let (format, dry_run) = file_params(params, true)?;
+    let rows = format.read(&body)?;
+    let row_ids: BTreeSet<i64> = rows.iter().filter_map(|(_, row)| row.as_ref().ok()?.id).collect();
+
+    let mut imported = BTreeMap::new();
+    let mut report = TodoImportReport { dry_run, imported: 0, errors: Vec::new() };
+    for (line, row) in rows {
+        let result = match row {
+            Ok(row) => import_todo(&repo, &owner, row, &row_ids, &mut imported, dry_run).await,
+            Err(message) => Err(TodoRepoError::Invalid(message)),
+        };
+        match result {
+            Ok(()) => report.imported += 1,
+            Err(error) => report.errors.push(TodoImportError { line, message: error.status_and_message().1 }),
+        }
+    }
+
+    Ok(Json(report))
+}
+
+///
+/// Checks a row as the endpoints for single todos would, and imports it
+/// unless this is a dry run. `imported` maps the ids of the rows imported so
+/// far to those of the todos they made, which a dry run has none of.
+///
+async fn import_todo<R: TodoRepo + TodoListRepo>(repo: &R, owner: &User, row: PortableTodo, row_ids: &BTreeSet<i64>, imported: &mut BTreeMap<i64, Option<i64>>, dry_run: bool) -> Result<(), TodoRepoError> {
+    let parent_id = match row.parent_id {
+        Some(parent_id) if row_ids.contains(&parent_id) => match imported.get(&parent_id) {
+            Some(id) => *id,
+            None => return Err(TodoRepoError::Invalid(format!("todo {} of this import comes later, or could not be imported", parent_id))),
+        },
+        Some(parent_id) => Some(owned_todo(repo, parent_id, owner).await?.id),
+        None => None,
+    };
+    if let Some(list_id) = row.list_id {
+        open_list(repo, list_id, owner).await?;
+    }
+    let tags = row.tags.iter().map(|tag| valid_tag(tag)).collect::<Result<Vec<_>, _>>()?;
+    recurrence_of(row.recurrence.as_ref(), row.due_at)?;
+
+    let id = if dry_run {
+        None
+    } else {
+        let spec = CreateTodo { title: row.title, description: row.description, list_id: row.list_id, due_at: row.due_at, priority: row.priority, parent_id, recurrence: row.recurrence };
+        Some(repo.import(Some(owner.id), &spec, row.done, &tags, None).await?.id)
+    };
+    if let Some(row_id) = row.id {
+        imported.insert(row_id, id);
+    }
+
+    Ok(())
+}
+
+/// The `format` of an export or import, and whether an import is a `dry_run`, which exports have no use for.
+fn file_params(params: Vec<(String, String)>, importing: bool) -> Result<(TodoFileFormat, bool), InvalidQueryError> {
+    let (mut format, mut dry_run) = (None, false);
+    for (name, value) in params {
+        match name.as_str() {
+            "format" => format = Some(TodoFileFormat::parse(&value).ok_or_else(|| InvalidQueryError::new(&name, "must be `csv`, `ndjson` or `ics`"))?),
+            "dry_run" if importing => dry_run = value.parse::<bool>().map_err(|_| InvalidQueryError::new(&name, "must be `true` or `false`"))?,
+            _ => return Err(InvalidQueryError::new(&name, "unknown query parameter")),
+        }
+    }
+    let format = format.ok_or_else(|| InvalidQueryError::new("format", "is required"))?;
+
+    Ok((format, dry_run))
+}
+
+/// The todo `id`, as long as it belongs to `owner`; like lists, other people's todos are reported missing.
+async fn owned_todo<R: TodoRepo>(repo: &R, id: i64, owner: &User) -> Result<Todo, TodoRepoError> {
+    match repo.get(id).await? {
+        todo if todo.owner_id == Some(owner.id) => Ok(todo),
+        _ => Err(TodoRepoError::NotFound(id)),
+    }
+}
+
+fn valid_tag(tag: &str) -> Result<&str, TodoRepoError> {
+    let tag = tag.trim();
+    if tag.is_empty() {
+        return Err(TodoRepoError::Invalid("a tag must not be blank".to_string()));
+    }
+
+    Ok(tag)
+}
+
+fn untaggable() -> TodoRepoError {
+    TodoRepoError::Invalid("only todos with an owner can be tagged".to_string())
+}
+
+/// Like lists, other people's todos are reported missing rather than forbidden.
+fn missing_parent(parent_id: i64) -> TodoRepoError {
+    TodoRepoError::Conflict(format!("parent todo {} does not exist", parent_id))
+}
+
+fn cyclic_parent(id: i64) -> TodoRepoError {
+    TodoRepoError::Invalid(format!("todo {} cannot be a subtask of itself or of its own subtasks", id))
+}
+
+/// Like parents, other people's todos are reported missing.
+fn missing_anchor(anchor_id: i64) -> TodoRepoError {
+    TodoRepoError::Conflict(format!("todo {} does not exist", anchor_id))
+}
+
+fn unmovable(id: i64) -> TodoRepoError {
+    TodoRepoError::Invalid(format!("todo {} cannot be moved next to itself", id))
+}
+
+fn missing_blocker(blocker_id: i64) -> TodoRepoError {
+    TodoRepoError::Conflict(format!("blocking todo {} does not exist", blocker_id))
+}
+
+fn trashed_parent(id: i64, parent_id: i64) -> TodoRepoError {
+    TodoRepoError::Conflict(format!("todo {} cannot be restored while its parent, todo {}, is in the trash", id, parent_id))
+}
+
+fn cyclic_dependency(id: i64, blocker_id: i64) -> TodoRepoError {
+    TodoRepoError::Invalid(format!("todo {} cannot be blocked by todo {}, which it already blocks", id, blocker_id))
+}
+
+fn no_owner(id: i64) -> TodoRepoError {
+    TodoRepoError::Invalid(format!("todo {} belongs to nobody, so no calendar app can know it", id))
+}
+
+/// The recurrence a todo due at `due_at` is given, if any. Only todos that are due can repeat.
+fn recurrence_of(spec: Option<&RecurrenceSpec>, due_at: Option<OffsetDateTime>) -> Result<Option<Recurrence>, TodoRepoError> {
+    let Some(spec) = spec else {
+        return Ok(None);
+    };
+    if due_at.is_none() {
+        return Err(TodoRepoError::Invalid("a todo needs a due date to repeat".to_string()));
+    }
+
+    spec.anchored(due_at).map(Some).map_err(TodoRepoError::Invalid)
+}
+
+///
+/// The recurrence `current` is left with once `changes` are made, and the
+/// one a new todo takes over if they mark it done. Handing the recurrence on
+/// means a todo that is reopened and marked done again does not repeat twice.
+///
+fn recurrence_after(current: &Todo, changes: &UpdateTodo) -> Result<(Option<Recurrence>, Option<Recurrence>), TodoRepoError> {
+    let due_at = changes.due_at.unwrap_or(current.due_at);
+    let recurrence = match &changes.recurrence {
+        Some(spec) => recurrence_of(spec.as_ref(), due_at)?,
+        None if current.recurrence.is_some() && due_at.is_none() => {
+            return Err(TodoRepoError::Invalid("a repeating todo needs a due date; stop it repeating first".to_string()));
+        }
+        None => current.recurrence.clone(),
+    };
+
+    match changes.done {
+        Some(true) if !current.done => Ok((None, recurrence)),
+        _ => Ok((recurrence, None)),
+    }
+}
+
+fn blocked(id: i64, blocker_ids: &[i64]) -> TodoRepoError {
+    let blocker_ids: Vec<String> = blocker_ids.iter().map(|blocker_id| blocker_id.to_string()).collect();
+    TodoRepoError::Conflict(format!("todo {} is blocked by todos {} that are not done; force it to mark it done anyway", id, blocker_ids.join(", ")))
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct Todo {
+    id: i64,
+    title: String,
+    description: String,
+    done: bool,
+    /// In UTC, whatever offset it was given with.
+    #[serde(with = "optional_rfc3339")]
+    due_at: Option<OffsetDateTime>,
+    priority: TodoPriority,
+    /// How the todo repeats, if it does. Marking it done hands this on to a new todo, due at the next occurrence.
+    recurrence: Option<Recurrence>,
+    /// When `done` last went from false to true; cleared when it goes back.
+    #[serde(with = "optional_rfc3339")]
+    completed_at: Option<OffsetDateTime>,
+    version: i64,
+    owner_id: Option<i64>,
+    list_id: Option<i64>,
+    /// The todo this is a subtask of.
+    parent_id: Option<i64>,
+    /// Where the todo falls among its owner's, comparing bytewise. Set by moving it.
+    position: String,
+    /// When the todo went into the trash, if it is there.
+    #[serde(with = "optional_rfc3339")]
+    deleted_at: Option<OffsetDateTime>,
+    /// In alphabetical (bytewise) order.
+    tags: Vec<String>,
+    /// The todos this one is waiting on, in id order.
+    blocked_by: Vec<i64>,
+}
+
+impl Todo {
+    /// The tags and blockers are not part of the record, so are left for the backend to fill in.
+    fn from_record(record: TodoRecord) -> Self {
+        Todo {
+            id: record.id,
+            title: record.title,
+            description: record.description,
+            done: record.done,
+            due_at: record.due_at,
+            // The column is checked, so it always holds one of the priorities.
+            priority: TodoPriority::parse(&record.priority).unwrap_or_default(),
+            // The column is only ever written from a recurrence, so it always reads back as one.
+            recurrence: record.recurrence.and_then(|recurrence| recurrence.parse().ok()),
+            completed_at: record.completed_at.map(PrimitiveDateTime::assume_utc),
+            version: record.version,
+            owner_id: record.owner_id,
+            list_id: record.list_id,
+            parent_id: record.parent_id,
+            position: record.position,
+            deleted_at: record.deleted_at.map(PrimitiveDateTime::assume_utc),
+            tags: Vec::new(),
+            blocked_by: Vec::new(),
+        }
+    }
+
+    ///
+    /// The todo that comes after this one once it is done, if it repeats by
+    /// `recurrence` and that has not run out: the same todo, due at the next
+    /// occurrence after this one is, and repeating in its place.
+    ///
+    fn next_instance(&self, recurrence: &Recurrence) -> Option<CreateTodo> {
+        let due_at = recurrence.next_after(self.due_at?)?;
+
+        Some(CreateTodo {
+            title: self.title.clone(),
+            description: self.description.clone(),
+            list_id: self.list_id,
+            due_at: Some(due_at),
+            priority: self.priority,
+            parent_id: self.parent_id,
+            recurrence: Some(recurrence.clone().into()),
+        })
+    }
+
+    /// When the todo counts as completed if `done` is set to `done`.
+    fn completed_at_if_done(&self, done: bool, now: PrimitiveDateTime) -> Option<PrimitiveDateTime> {
+        match (self.done, done) {
+            (false, true) => Some(now),
+            (true, true) => self.completed_at.map(utc_primitive),
+            (_, false) => None,
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+enum TodoPriority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Urgent,
+}
+
+impl TodoPriority {
+    /// How the priority is stored, which is also how it is spelled in JSON.
+    fn as_str(self) -> &'static str {
+        match self {
+            TodoPriority::Low => "low",
+            TodoPriority::Normal => "normal",
+            TodoPriority::High => "high",
+            TodoPriority::Urgent => "urgent",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [TodoPriority::Low, TodoPriority::Normal, TodoPriority::High, TodoPriority::Urgent]
+            .into_iter()
+            .find(|priority| priority.as_str() == value)
+    }
+}
+
+///
+/// Optional timestamps as RFC 3339 strings. They are read into UTC, at the
+/// microsecond precision Postgres stores, so that a todo reads back exactly
+/// as it was written.
+///
+mod optional_rfc3339 {
+    use super::*;
+
+    pub(super) fn serialize<S: serde::Serializer>(value: &Option<OffsetDateTime>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => serializer.serialize_some(&value.format(&Rfc3339).map_err(serde::ser::Error::custom)?),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(super) fn deserialize<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<OffsetDateTime>, D::Error> {
+        let Some(value) = <Option<String> as serde::Deserialize>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        let value = OffsetDateTime::parse(&value, &Rfc3339).map_err(serde::de::Error::custom)?;
+
+        Ok(Some(utc_micros(value)))
+    }
+
+    /// For updates, where a missing field leaves the timestamp alone but `null` clears it.
+    pub(super) fn deserialize_change<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Option<OffsetDateTime>>, D::Error> {
+        deserialize(deserializer).map(Some)
+    }
+}
+
+/// Timestamps that are always there, as RFC 3339 strings.
+mod rfc3339 {
+    use super::*;
+
+    pub(super) fn serialize<S: serde::Serializer>(value: &OffsetDateTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.format(&Rfc3339).map_err(serde::ser::Error::custom)?)
+    }
+}
+
+/// `value` in UTC, truncated to microseconds.
+fn utc_micros(value: OffsetDateTime) -> OffsetDateTime {
+    let value = value.to_offset(UtcOffset::UTC);
+
+    value.replace_nanosecond(value.nanosecond() / 1_000 * 1_000).unwrap()
+}
+
+/// A todo with its subtasks, each with theirs.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct TodoTree {
+    #[serde(flatten)]
+    todo: Todo,
+    /// In id order.
+    subtasks: Vec<TodoTree>,
+}
+
+impl TodoTree {
+    /// Nests the subtree of `id`, as `get_subtree` returns it, under the todo `id`.
+    fn build(id: i64, todos: Vec<Todo>) -> Option<Self> {
+        let mut root = None;
+        let mut subtasks: BTreeMap<i64, Vec<Todo>> = BTreeMap::new();
+        for todo in todos {
+            if todo.id == id {
+                root = Some(todo);
+            } else if let Some(parent_id) = todo.parent_id {
+                subtasks.entry(parent_id).or_default().push(todo);
+            }
+        }
+
+        root.map(|root| Self::nest(root, &mut subtasks))
+    }
+
+    fn nest(todo: Todo, subtasks: &mut BTreeMap<i64, Vec<Todo>>) -> Self {
+        let mut children = subtasks.remove(&todo.id).unwrap_or_default();
+        children.sort_by_key(|child| child.id);
+
+        TodoTree {
+            subtasks: children.into_iter().map(|child| Self::nest(child, subtasks)).collect(),
+            todo,
+        }
+    }
+}
+
+/// Hands out `(todo_id, tag)` pairs, in order, to the todos they belong to.
+fn attach_tags(todos: &mut [&mut Todo], tags: impl IntoIterator<Item = (i64, String)>) {
+    for (todo_id, tag) in tags {
+        if let Some(todo) = todos.iter_mut().find(|todo| todo.id == todo_id) {
+            todo.tags.push(tag);
+        }
+    }
+}
+
+/// Hands out `(todo_id, blocker_id)` pairs, in order, to the todos they belong to.
+fn attach_blockers(todos: &mut [&mut Todo], blockers: impl IntoIterator<Item = (i64, i64)>) {
+    for (todo_id, blocker_id) in blockers {
+        if let Some(todo) = todos.iter_mut().find(|todo| todo.id == todo_id) {
+            todo.blocked_by.push(blocker_id);
+        }
+    }
+}
+
+///
+/// One write to a todo, as its history keeps it: which fields changed, from
+/// what to what, and who changed them. Events are kept after the todo, and
+/// the users involved, are gone.
+///
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+struct TodoEvent {
+    id: i64,
+    todo_id: i64,
+    kind: TodoEventKind,
+    /// Who the todo belonged to at the time.
+    owner_id: Option<i64>,
+    /// Who made the change, or `null` when nobody did, as when the trash is emptied.
+    actor_id: Option<i64>,
+    changes: TodoChanges,
+    #[serde(serialize_with = "rfc3339::serialize")]
+    occurred_at: OffsetDateTime,
+}
+
+/// The fields a write changed, by their names in the todo's JSON.
+type TodoChanges = BTreeMap<String, TodoFieldChange>;
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct TodoFieldChange {
+    /// `null` when the todo is being created.
+    before: serde_json::Value,
+    /// `null` when the todo is being purged.
+    after: serde_json::Value,
+}
+
+#[derive(serde::Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum TodoEventKind {
+    Created,
+    Updated,
+    /// The todo went into the trash.
+    Deleted,
+    /// The todo came back out of the trash.
+    Restored,
+    /// The todo was deleted for good.
+    Purged,
+}
+
+impl TodoEventKind {
+    /// How the kind is stored, which is also how it is spelled in JSON.
+    fn as_str(self) -> &'static str {
+        match self {
+            TodoEventKind::Created => "created",
+            TodoEventKind::Updated => "updated",
+            TodoEventKind::Deleted => "deleted",
+            TodoEventKind::Restored => "restored",
+            TodoEventKind::Purged => "purged",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [TodoEventKind::Created, TodoEventKind::Updated, TodoEventKind::Deleted, TodoEventKind::Restored, TodoEventKind::Purged]
+            .into_iter()
+            .find(|kind| kind.as_str() == value)
+    }
+}
+
+impl TodoEvent {
+    ///
+    /// The event for a write that took a todo from `before` to `after`, one
+    /// of which is missing when it was created or purged, or `None` if the
+    /// write changed nothing. The id is left for the backend to fill in.
+    ///
+    fn between(actor_id: Option<i64>, before: Option<&Todo>, after: Option<&Todo>) -> Option<Self> {
+        let todo = after.or(before)?;
+        let kind = match (before, after) {
+            (None, _) => TodoEventKind::Created,
+            (_, None) => TodoEventKind::Purged,
+            (Some(before), Some(after)) => match (before.deleted_at, after.deleted_at) {
+                (None, Some(_)) => TodoEventKind::Deleted,
+                (Some(_), None) => TodoEventKind::Restored,
+                _ => TodoEventKind::Updated,
+            },
+        };
+
+        // Comparing the todos as JSON means every field, tags and blockers
+        // included, shows up in the history as clients see it.
+        let fields = |todo: Option<&Todo>| match todo.map(serde_json::to_value) {
+            Some(Ok(serde_json::Value::Object(fields))) => fields,
+            _ => serde_json::Map::new(),
+        };
+        let (before, after) = (fields(before), fields(after));
+        let changes: TodoChanges = before.keys().chain(after.keys())
+            .filter_map(|field| {
+                let change = TodoFieldChange {
+                    before: before.get(field).cloned().unwrap_or_default(),
+                    after: after.get(field).cloned().unwrap_or_default(),
+                };
+                (change.before != change.after).then(|| (field.clone(), change))
+            })
+            .collect();
+        if changes.is_empty() {
+            return None;
+        }
+
+        Some(TodoEvent { id: 0, todo_id: todo.id, kind, owner_id: todo.owner_id, actor_id, changes, occurred_at: now().assume_utc() })
+    }
+
+    fn from_record(record: TodoEventRecord) -> Self {
+        TodoEvent {
+            id: record.id,
+            todo_id: record.todo_id,
+            // The column is checked, so it always holds one of the kinds.
+            kind: TodoEventKind::parse(&record.kind).unwrap_or(TodoEventKind::Updated),
+            owner_id: record.owner_id,
+            actor_id: record.actor_id,
+            changes: record.changes.0,
+            occurred_at: record.occurred_at.assume_utc(),
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct TodoEventRecord {
+    id: i64,
+    todo_id: i64,
+    kind: String,
+    owner_id: Option<i64>,
+    actor_id: Option<i64>,
+    changes: sqlx::types::Json<TodoChanges>,
+    occurred_at: PrimitiveDateTime,
+}
+
+/// How many changes to todos a watcher can fall behind by before it misses some.
+const CHANGE_BUFFER: usize = 1024;
+
+///
+/// A change to a todo that clients can see, as `GET /todos/events` streams
+/// it. The database records these itself, so they include changes made by
+/// other instances of the app, or by hand.
+///
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq, Eq)]
+struct TodoChange {
+    id: i64,
+    todo_id: i64,
+    kind: TodoChangeKind,
+    /// Who the todo belonged to at the time.
+    owner_id: Option<i64>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum TodoChangeKind {
+    /// The todo was created, or came back out of the trash.
+    Created,
+    Updated,
+    /// The todo went into the trash, or was deleted outright.
+    Deleted,
+}
+
+impl TodoChangeKind {
+    /// How the kind is stored, which is also how it is spelled in JSON.
+    fn as_str(self) -> &'static str {
+        match self {
+            TodoChangeKind::Created => "created",
+            TodoChangeKind::Updated => "updated",
+            TodoChangeKind::Deleted => "deleted",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        [TodoChangeKind::Created, TodoChangeKind::Updated, TodoChangeKind::Deleted]
+            .into_iter()
+            .find(|kind| kind.as_str() == value)
+    }
+}
+
+impl TodoChange {
+    fn from_record(record: TodoChangeRecord) -> Self {
+        TodoChange {
+            id: record.id,
+            todo_id: record.todo_id,
+            // The column is checked, so it always holds one of the kinds.
+            kind: TodoChangeKind::parse(&record.kind).unwrap_or(TodoChangeKind::Updated),
+            owner_id: record.owner_id,
+        }
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct TodoChangeRecord {
+    id: i64,
+    todo_id: i64,
+    kind: String,
+    owner_id: Option<i64>,
+}
+
+///
+/// A todo sent back as JSON, with its version as the `ETag`, so that clients
+/// can make their next write conditional on it with `If-Match`.
+///
+struct VersionedTodo(Todo);
+
+impl IntoResponse for VersionedTodo {
+    fn into_response(self) -> Response {
+        ([(header::ETAG, format!("\"{}\"", self.0.version))], Json(self.0)).into_response()
+    }
+}
+
+///
+/// The versions of a todo that a write expects to replace, taken from the
+/// entity tags listed in the `If-Match` header. No header, or `*`, means any
+/// version will do. If-Match compares tags strongly, so weak ones never
+/// match; when nothing listed could ever match one of our own entity tags,
+/// the precondition fails straight away.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct IfMatch(Option<BTreeSet<i64>>);
+
+impl IfMatch {
+    /// The version for a write to expect of `todo`: the one it is at now, if that is listed, so that the write still fails should it change first.
+    fn expected_version(&self, todo: &Todo) -> Result<Option<i64>, TodoRepoError> {
+        match &self.0 {
+            None => Ok(None),
+            Some(versions) if versions.contains(&todo.version) => Ok(Some(todo.version)),
+            Some(_) => Err(TodoRepoError::VersionMismatch(todo.id)),
+        }
+    }
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for IfMatch {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let Some(value) = parts.headers.get(header::IF_MATCH) else {
+            return Ok(IfMatch(None));
+        };
+
+        let value = value.to_str().unwrap_or_default().trim();
+        if value == "*" {
+            return Ok(IfMatch(None));
+        }
+
+        let tags: Vec<&str> = value.split(',').map(str::trim).filter(|tag| !tag.is_empty()).collect();
+        let versions: BTreeSet<i64> = tags.iter()
+            .filter_map(|tag| tag.strip_prefix('"')?.strip_suffix('"')?.parse::<i64>().ok())
+            .collect();
+        if !versions.is_empty() {
+            return Ok(IfMatch(Some(versions)));
+        }
+
+        let message = match tags.iter().find(|tag| tag.starts_with("W/")) {
+            Some(weak) => format!("If-Match compares entity tags strongly, so a weak one such as {} never matches; send {} instead", weak, &weak[2..]),
+            None => "If-Match does not match any version of this todo".to_string(),
+        };
+        Err(json_error(StatusCode::PRECONDITION_FAILED, &message))
+    }
+}
+
+///
+/// Where a client reconnecting to an event stream had got to, from the
+/// `Last-Event-ID` header that `EventSource` sends. We only ever hand out
+/// change ids as event ids, so anything else is refused.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LastEventId(Option<i64>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for LastEventId {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let value = parts.headers.get("Last-Event-ID").map(|value| value.to_str().unwrap_or_default().trim());
+        let Some(value) = value.filter(|value| !value.is_empty()) else {
+            return Ok(LastEventId(None));
+        };
+
+        value.parse::<i64>()
+            .map(|id| LastEventId(Some(id)))
+            .map_err(|_| json_error(StatusCode::BAD_REQUEST, "Last-Event-ID must be the id of an event"))
+    }
+}
+
+///
+/// The user a request was made by, identified by the API token they were
+/// given when they signed up, sent as `Authorization: Bearer <token>`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AuthenticatedUser(User);
+
+#[async_trait]
+impl<R: UserRepo> FromRequestParts<R> for AuthenticatedUser {
+    type Rejection = TodoApiError;
+
+    async fn from_request_parts(parts: &mut Parts, repo: &R) -> Result<Self, Self::Rejection> {
+        let token = parts.headers.get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(TodoApiError::Unauthenticated)?;
+
+        repo.authenticate(&hash_token(token.trim())).await?
+            .map(AuthenticatedUser)
+            .ok_or(TodoApiError::Unauthenticated)
+    }
+}
+
+/// A new API token: 256 random bits, which is far too many to guess.
+fn generate_token() -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(rand::random::<[u8; 32]>())
+}
+
+/// Tokens are stored hashed, so that reading the database is not enough to impersonate anyone.
+fn hash_token(token: &str) -> Vec<u8> {
+    use sha2::Digest;
+
+    sha2::Sha256::digest(token.as_bytes()).to_vec()
+}
+
+fn valid_email(email: String) -> Result<String, TodoRepoError> {
+    let email = email.trim();
+    if email.is_empty() || !email.contains('@') {
+        return Err(TodoRepoError::Invalid(format!("{:?} is not an email address", email)));
+    }
+
+    Ok(email.to_string())
+}
+
+#[derive(serde::Deserialize)]
+struct CreateUser {
+    name: String,
+    email: String,
+}
+
+#[derive(serde::Deserialize)]
+struct UpdateUser {
+    name: Option<String>,
+    email: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct CreateList {
+    name: String,
+}
+
+#[derive(serde::Deserialize)]
+struct UpdateList {
+    name: Option<String>,
+    archived: Option<bool>,
+}
+
+/// A user who has just signed up, with the only copy of their API token.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct NewUser {
+    #[serde(flatten)]
+    user: User,
+    token: String,
+}
+
+/// Where a user's calendar feed is, relative to this server, which cannot be sure what it is called from outside.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct TodoFeed {
+    url: String,
+}
+
+///
+/// Everything `GET /todos` can be asked for: which todos to include, in which
+/// order, and which page of the result.
+///
+#[derive(Debug, Clone, PartialEq)]
+struct TodoListQuery {
+    filter: TodoFilter,
+    sort: TodoSort,
+    limit: i64,
+    cursor: Option<TodoCursor>,
+}
+
+impl Default for TodoListQuery {
+    fn default() -> Self {
+        TodoListQuery {
+            filter: TodoFilter::default(),
+            sort: TodoSort::default(),
+            limit: DEFAULT_PAGE_LIMIT,
+            cursor: None,
+        }
+    }
+}
+
+impl TodoListQuery {
+    fn from_params(params: Vec<(String, String)>) -> Result<Self, InvalidQueryError> {
+        let mut query = TodoListQuery::default();
+        let mut cursor = None;
+
+        for (name, value) in params {
+            match name.as_str() {
+                "limit" => {
+                    query.limit = value.parse::<i64>().ok()
+                        .filter(|limit| (1..=MAX_PAGE_LIMIT).contains(limit))
+                        .ok_or_else(|| InvalidQueryError::new(&name, format!("must be an integer between 1 and {}", MAX_PAGE_LIMIT)))?;
+                }
+                "cursor" => cursor = Some(value),
+                "done" => {
+                    query.filter.done = Some(
+                        value.parse::<bool>().map_err(|_| InvalidQueryError::new(&name, "must be `true` or `false`"))?
+                    );
+                }
+                "actionable" => {
+                    query.filter.actionable = Some(
+                        value.parse::<bool>().map_err(|_| InvalidQueryError::new(&name, "must be `true` or `false`"))?
+                    );
+                }
+                "created_after" => query.filter.created_after = Some(parse_timestamp(&name, &value)?),
+                "created_before" => query.filter.created_before = Some(parse_timestamp(&name, &value)?),
+                "tag" => {
+                    let tag = valid_tag(&value).map_err(|_| InvalidQueryError::new(&name, "must not be blank"))?.to_string();
+                    if !query.filter.tags.contains(&tag) {
+                        query.filter.tags.push(tag);
+                    }
+                }
+                "tag_match" => {
+                    query.filter.tag_match = match value.as_str() {
+                        "any" => TagMatch::Any,
+                        "all" => TagMatch::All,
+                        _ => return Err(InvalidQueryError::new(&name, "must be `any` or `all`")),
+                    };
+                }
+                "sort" => query.sort = TodoSort::parse(&value).ok_or_else(|| InvalidQueryError::new(&name, format!("unknown sort field `{}`", value)))?,
+                _ => return Err(InvalidQueryError::new(&name, "unknown query parameter")),
+            }
+        }
+
+        // A cursor only makes sense for the sort order it was issued under.
+        query.cursor = cursor.map(|cursor| TodoCursor::decode(&cursor, query.sort)).transpose()?;
+
+        Ok(query)
+    }
+}
+
+fn parse_timestamp(name: &str, value: &str) -> Result<PrimitiveDateTime, InvalidQueryError> {
+    let timestamp = OffsetDateTime::parse(value, &Rfc3339)
+        .map_err(|_| InvalidQueryError::new(name, "must be an RFC 3339 timestamp"))?;
+
+    Ok(utc_primitive(timestamp))
+}
+
+/// `timestamp` as a UTC time without an offset, which is how `created_at` is stored.
+fn utc_primitive(timestamp: OffsetDateTime) -> PrimitiveDateTime {
+    let timestamp = timestamp.to_offset(UtcOffset::UTC);
+
+    PrimitiveDateTime::new(timestamp.date(), timestamp.time())
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct TodoFilter {
+    /// Set from the authenticated user, never from the query string.
+    owner_id: Option<i64>,
+    /// Set from the path of `/lists/:id/todos`. Without it, todos in
+    /// archived lists are left out.
+    list_id: Option<i64>,
+    done: Option<bool>,
+    /// Whether every todo blocking the todo is done (or nothing blocks it).
+    actionable: Option<bool>,
+    created_after: Option<PrimitiveDateTime>,
+    created_before: Option<PrimitiveDateTime>,
+    /// Distinct tags, of which a todo must have any or all, as `tag_match` says.
+    tags: Vec<String>,
+    tag_match: TagMatch,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum TagMatch {
+    Any,
+    #[default]
+    All,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum TodoSortField {
+    #[default]
+    Position,
+    CreatedAt,
+    Title,
+}
+
+///
+/// The order of a todo listing. Ties are always broken by id, in the same
+/// direction, so that every todo has a unique position for paging.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct TodoSort {
+    field: TodoSortField,
+    descending: bool,
+}
+
+impl TodoSort {
+    fn parse(value: &str) -> Option<Self> {
+        let (descending, field) = match value.strip_prefix('-') {
+            Some(field) => (true, field),
+            None => (false, value),
+        };
+        let field = match field {
+            "position" => TodoSortField::Position,
+            "created_at" => TodoSortField::CreatedAt,
+            "title" => TodoSortField::Title,
+            _ => return None,
+        };
+
+        Some(TodoSort { field, descending })
+    }
+}
+
+///
+/// A page of todos, in the order requested. If there are more todos after
+/// this page, `next_cursor` can be passed back to `GET /todos` to fetch them.
+///
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+struct TodoPage {
+    items: Vec<Todo>,
+    next_cursor: Option<TodoCursor>,
+}
+
+impl TodoPage {
+    fn from_records(mut records: Vec<TodoRecord>, query: &TodoListQuery) -> Self {
+        let limit = query.limit as usize;
+        let next_cursor = if records.len() > limit {
+            records.truncate(limit);
+            records.last().map(|r| TodoCursor::after(r, query.sort))
+        } else {
+            None
+        };
+
+        TodoPage {
+            items: records.into_iter().map(Todo::from_record).collect(),
+            next_cursor,
+        }
+    }
+}
+
+///
+/// A keyset cursor pointing just past the last todo of a page. Clients only
+/// ever see it in its encoded, opaque form.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TodoCursor {
+    key: TodoSortKey,
+    id: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TodoSortKey {
+    Position(String),
+    CreatedAt(PrimitiveDateTime),
+    Title(String),
+}
+
+impl TodoCursor {
+    fn after(record: &TodoRecord, sort: TodoSort) -> Self {
+        let key = match sort.field {
+            TodoSortField::Position => TodoSortKey::Position(record.position.clone()),
+            TodoSortField::CreatedAt => TodoSortKey::CreatedAt(record.created_at),
+            TodoSortField::Title => TodoSortKey::Title(record.title.clone()),
+        };
+
+        TodoCursor { key, id: record.id }
+    }
+
+    fn encode(&self) -> String {
+        let text = match &self.key {
+            TodoSortKey::Position(position) => format!("p:{}:{}", self.id, position),
+            TodoSortKey::CreatedAt(created_at) => format!("c:{}:{}", self.id, created_at.assume_utc().unix_timestamp_nanos()),
+            TodoSortKey::Title(title) => format!("t:{}:{}", self.id, title),
+        };
+
+        CURSOR_BASE64.encode(text)
+    }
+
+    fn decode(encoded: &str, sort: TodoSort) -> Result<Self, InvalidQueryError> {
+        let invalid = || InvalidQueryError::new("cursor", "invalid cursor");
+
+        let bytes = CURSOR_BASE64.decode(encoded).map_err(|_| invalid())?;
+        let text = String::from_utf8(bytes).map_err(|_| invalid())?;
+        let (kind, rest) = text.split_once(':').ok_or_else(invalid)?;
+        let (id, key) = rest.split_once(':').ok_or_else(invalid)?;
+        let id = id.parse::<i64>().map_err(|_| invalid())?;
+        if id <= 0 {
+            return Err(invalid());
+        }
+
+        let key = match (kind, sort.field) {
+            ("p", TodoSortField::Position) => TodoSortKey::Position(key.to_string()),
+            ("c", TodoSortField::CreatedAt) => {
+                let nanos = key.parse::<i128>().map_err(|_| invalid())?;
+                let created_at = OffsetDateTime::from_unix_timestamp_nanos(nanos).map_err(|_| invalid())?;
+                TodoSortKey::CreatedAt(PrimitiveDateTime::new(created_at.date(), created_at.time()))
+            }
+            ("t", TodoSortField::Title) => TodoSortKey::Title(key.to_string()),
+            _ => return Err(invalid()),
+        };
+
+        Ok(TodoCursor { key, id })
+    }
+}
+
+impl serde::Serialize for TodoCursor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.encode())
+    }
+}
+
+///
+/// A full-text search over todo titles and descriptions. The query `q` uses
+/// web search syntax: quoted phrases, `or`, and `-` to exclude a word.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TodoSearchQuery {
+    q: String,
+    limit: i64,
+    /// Set from the authenticated user, never from the query string.
+    owner_id: Option<i64>,
+}
+
+impl TodoSearchQuery {
+    fn from_params(params: Vec<(String, String)>) -> Result<Self, InvalidQueryError> {
+        let mut q = None;
+        let mut limit = DEFAULT_PAGE_LIMIT;
+
+        for (name, value) in params {
+            match name.as_str() {
+                "q" => q = Some(value),
+                "limit" => {
+                    limit = value.parse::<i64>().ok()
+                        .filter(|limit| (1..=MAX_PAGE_LIMIT).contains(limit))
+                        .ok_or_else(|| InvalidQueryError::new(&name, format!("must be an integer between 1 and {}", MAX_PAGE_LIMIT)))?;
+                }
+                _ => return Err(InvalidQueryError::new(&name, "unknown query parameter")),
+            }
+        }
+
+        let q = q.filter(|q| !q.trim().is_empty())
+            .ok_or_else(|| InvalidQueryError::new("q", "must not be empty"))?;
+
+        Ok(TodoSearchQuery { q, limit, owner_id: None })
+    }
+
+    /// The distinct words of `q`, lowercased, for backends without a query language of their own.
+    fn terms(&self) -> Vec<String> {
+        let mut terms: Vec<String> = Vec::new();
+        for word in self.q.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()) {
+            let word = word.to_lowercase();
+            if !terms.contains(&word) {
+                terms.push(word);
+            }
+        }
+
+        terms
+    }
+}
+
+///
+/// The todos `GET /todos/overdue` and `GET /todos/upcoming` list: those not
+/// yet done that are due from `due_from` (if set) up to `due_before`.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TodoDueQuery {
+    due_from: Option<OffsetDateTime>,
+    due_before: OffsetDateTime,
+    limit: i64,
+    /// Set from the authenticated user, never from the query string.
+    owner_id: Option<i64>,
+}
+
+impl TodoDueQuery {
+    /// Todos that were due before `now`.
+    fn overdue(params: Vec<(String, String)>, now: OffsetDateTime) -> Result<Self, InvalidQueryError> {
+        let mut query = TodoDueQuery { due_from: None, due_before: now, limit: DEFAULT_PAGE_LIMIT, owner_id: None };
+
+        for (name, value) in params {
+            match name.as_str() {
+                "limit" => {
+                    query.limit = value.parse::<i64>().ok()
+                        .filter(|limit| (1..=MAX_PAGE_LIMIT).contains(limit))
+                        .ok_or_else(|| InvalidQueryError::new(&name, format!("must be an integer between 1 and {}", MAX_PAGE_LIMIT)))?;
+                }
+                _ => return Err(InvalidQueryError::new(&name, "unknown query parameter")),
+            }
+        }
+
+        Ok(query)
+    }
+
+    /// Todos due from `now` until the end of the `within` window, a week unless asked otherwise.
+    fn upcoming(params: Vec<(String, String)>, now: OffsetDateTime) -> Result<Self, InvalidQueryError> {
+        let mut within = time::Duration::weeks(1);
+        let mut limit = DEFAULT_PAGE_LIMIT;
+
+        for (name, value) in params {
+            match name.as_str() {
+                "within" => within = parse_window(&name, &value)?,
+                "limit" => {
+                    limit = value.parse::<i64>().ok()
+                        .filter(|limit| (1..=MAX_PAGE_LIMIT).contains(limit))
+                        .ok_or_else(|| InvalidQueryError::new(&name, format!("must be an integer between 1 and {}", MAX_PAGE_LIMIT)))?;
+                }
+                _ => return Err(InvalidQueryError::new(&name, "unknown query parameter")),
+            }
+        }
+
+        Ok(TodoDueQuery { due_from: Some(now), due_before: now + within, limit, owner_id: None })
+    }
+}
+
+/// A length of time such as `36h`, `7d` or `2w`, of up to a year.
+fn parse_window(name: &str, value: &str) -> Result<time::Duration, InvalidQueryError> {
+    let invalid = || InvalidQueryError::new(name, "must be a number of hours, days or weeks, such as `7d`, of up to a year");
+
+    let (count, unit) = value.char_indices().last()
+        .map(|(index, unit)| (&value[..index], unit))
+        .ok_or_else(invalid)?;
+    let count: u32 = count.parse().map_err(|_| invalid())?;
+    let window = match unit {
+        'h' => time::Duration::hours(count.into()),
+        'd' => time::Duration::days(count.into()),
+        'w' => time::Duration::weeks(count.into()),
+        _ => return Err(invalid()),
+    };
+    if window.is_zero() || window > time::Duration::days(366) {
+        return Err(invalid());
+    }
+
+    Ok(window)
+}
+
+///
+/// Which events `GET /audit` returns: those from `since` on, oldest first.
+/// To page through them, pass the id of the last event seen as `after`.
+///
+#[derive(Debug, Clone, PartialEq)]
+struct TodoAuditQuery {
+    since: Option<PrimitiveDateTime>,
+    after: Option<i64>,
+    limit: i64,
+    /// Set from the authenticated user, never from the query string.
+    owner_id: Option<i64>,
+}
+
+impl TodoAuditQuery {
+    fn from_params(params: Vec<(String, String)>) -> Result<Self, InvalidQueryError> {
+        let mut query = TodoAuditQuery { since: None, after: None, limit: DEFAULT_PAGE_LIMIT, owner_id: None };
+
+        for (name, value) in params {
+            match name.as_str() {
+                "since" => query.since = Some(parse_timestamp(&name, &value)?),
+                "after" => {
+                    query.after = Some(value.parse::<i64>().map_err(|_| InvalidQueryError::new(&name, "must be the id of an event"))?);
+                }
+                "limit" => {
+                    query.limit = value.parse::<i64>().ok()
+                        .filter(|limit| (1..=MAX_PAGE_LIMIT).contains(limit))
+                        .ok_or_else(|| InvalidQueryError::new(&name, format!("must be an integer between 1 and {}", MAX_PAGE_LIMIT)))?;
+                }
+                _ => return Err(InvalidQueryError::new(&name, "unknown query parameter")),
+            }
+        }
+
+        Ok(query)
+    }
+}
+
+/// Which changes `get_changes` returns: those after the change `after`, if given, up to `limit` of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TodoChangeQuery {
+    after: Option<i64>,
+    limit: i64,
+    owner_id: Option<i64>,
+}
+
+/// Which todos `GET /trash` returns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TodoTrashQuery {
+    limit: i64,
+    /// Set from the authenticated user, never from the query string.
+    owner_id: Option<i64>,
+}
+
+impl TodoTrashQuery {
+    fn from_params(params: Vec<(String, String)>) -> Result<Self, InvalidQueryError> {
+        let mut query = TodoTrashQuery { limit: DEFAULT_PAGE_LIMIT, owner_id: None };
+
+        for (name, value) in params {
+            match name.as_str() {
+                "limit" => {
+                    query.limit = value.parse::<i64>().ok()
+                        .filter(|limit| (1..=MAX_PAGE_LIMIT).contains(limit))
+                        .ok_or_else(|| InvalidQueryError::new(&name, format!("must be an integer between 1 and {}", MAX_PAGE_LIMIT)))?;
+                }
+                _ => return Err(InvalidQueryError::new(&name, "unknown query parameter")),
+            }
+        }
+
+        Ok(query)
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct TodoSearchRecord {
+    id: i64,
+    title: String,
+    description: String,
+    done: bool,
+    created_at: PrimitiveDateTime,
+    version: i64,
+    owner_id: Option<i64>,
+    list_id: Option<i64>,
+    due_at: Option<OffsetDateTime>,
+    priority: String,
+    completed_at: Option<PrimitiveDateTime>,
+    parent_id: Option<i64>,
+    position: String,
+    deleted_at: Option<PrimitiveDateTime>,
+    recurrence: Option<String>,
+    rank: f32,
+    title_highlight: String,
+    description_highlight: String,
+}
+
+///
+/// A todo matching a search, along with its relevance and the title and
+/// description with matching words wrapped in `<mark>` tags. The highlights
+/// contain the todo text verbatim, so clients must escape them before
+/// rendering anything other than the marks as HTML.
+///
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct TodoSearchHit {
+    todo: Todo,
+    rank: f32,
+    title_highlight: String,
+    description_highlight: String,
+}
+
+impl TodoSearchHit {
+    fn from_record(record: TodoSearchRecord) -> Self {
+        TodoSearchHit {
+            todo: Todo::from_record(TodoRecord {
+                id: record.id,
+                title: record.title,
+                description: record.description,
+                done: record.done,
+                created_at: record.created_at,
+                version: record.version,
+                owner_id: record.owner_id,
+                list_id: record.list_id,
+                due_at: record.due_at,
+                priority: record.priority,
+                completed_at: record.completed_at,
+                parent_id: record.parent_id,
+                position: record.position,
+                deleted_at: record.deleted_at,
+                recurrence: record.recurrence,
+            }),
+            rank: record.rank,
+            title_highlight: record.title_highlight,
+            description_highlight: record.description_highlight,
+        }
+    }
+}
+
+/// The current time, at the microsecond precision Postgres stores.
+fn now() -> PrimitiveDateTime {
+    let now = OffsetDateTime::now_utc();
+    let now = now.replace_nanosecond(now.nanosecond() / 1_000 * 1_000).unwrap();
+
+    PrimitiveDateTime::new(now.date(), now.time())
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+struct CreateTodo {
+    title: String,
+    description: String,
+    #[serde(default)]
+    list_id: Option<i64>,
+    #[serde(default, deserialize_with = "optional_rfc3339::deserialize")]
+    due_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    priority: TodoPriority,
+    #[serde(default)]
+    parent_id: Option<i64>,
+    #[serde(default)]
+    recurrence: Option<RecurrenceSpec>,
+}
+
+impl CreateTodo {
+    fn new(title: impl Into<String>, description: impl Into<String>) -> Self {
+        CreateTodo {
+            title: title.into(),
+            description: description.into(),
+            list_id: None,
+            due_at: None,
+            priority: TodoPriority::default(),
+            parent_id: None,
+            recurrence: None,
+        }
+    }
+}
+
+///
+/// The fields of a todo to change; those left out stay as they are. Setting
+/// `due_at`, `parent_id` or `recurrence` to `null` clears it.
+///
+#[derive(serde::Deserialize, Debug, Clone, Default, PartialEq)]
+struct UpdateTodo {
+    title: Option<String>,
+    description: Option<String>,
+    done: Option<bool>,
+    #[serde(default, deserialize_with = "optional_rfc3339::deserialize_change")]
+    due_at: Option<Option<OffsetDateTime>>,
+    priority: Option<TodoPriority>,
+    /// Makes the todo a subtask of another of its owner's todos, or a top-level todo again.
+    #[serde(default, deserialize_with = "deserialize_change")]
+    parent_id: Option<Option<i64>>,
+    /// Makes the todo repeat, starting from when it is due unless the recurrence says otherwise.
+    #[serde(default, deserialize_with = "deserialize_change")]
+    recurrence: Option<Option<RecurrenceSpec>>,
+    /// When `done` is set to true, marks every subtask done as well, unless something besides the todo and its subtasks blocks one of them.
+    #[serde(default)]
+    complete_subtasks: bool,
+    /// Marks the todo done even if it is blocked by todos that are not.
+    #[serde(default)]
+    force: bool,
+}
+
+/// For updates, where a missing field is left alone but `null` clears it.
+fn deserialize_change<'de, D: serde::Deserializer<'de>, T: serde::Deserialize<'de>>(deserializer: D) -> Result<Option<T>, D::Error> {
+    T::deserialize(deserializer).map(Some)
+}
+
+#[derive(serde::Deserialize)]
+struct MoveTodo {
+    list_id: Option<i64>,
+}
+
+/// Where to move a todo to, next to another one: `{"before": id}` or `{"after": id}`.
+#[derive(serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+enum TodoPlacement {
+    Before(i64),
+    After(i64),
+}
+
+impl TodoPlacement {
+    fn anchor_id(self) -> i64 {
+        match self {
+            TodoPlacement::Before(anchor_id) | TodoPlacement::After(anchor_id) => anchor_id,
+        }
+    }
+}
+
+/// The digits of a position, in bytewise order.
+const POSITION_DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+///
+/// A position that sorts strictly between `lower` and `upper`, where `None`
+/// stands for the start or the end. Positions are fractions in base 62,
+/// with digits after the point only, so there is always room for another
+/// between two of them as long as none ends in a zero; this never makes
+/// one that does. `None` if `lower` is not below `upper`.
+///
+fn rank_between(lower: Option<&str>, upper: Option<&str>) -> Option<String> {
+    let rank = match (lower, upper) {
+        (Some(lower), Some(upper)) if lower >= upper => return None,
+        // Rather than jumping halfway to the end, which would put the todo
+        // after any created later, stay just past the last one.
+        (Some(lower), None) => [lower.as_bytes(), b"V"].concat(),
+        (lower, upper) => rank_midpoint(lower.unwrap_or_default().as_bytes(), upper.map(str::as_bytes)),
+    };
+
+    String::from_utf8(rank).ok()
+}
+
+fn rank_midpoint(lower: &[u8], upper: Option<&[u8]>) -> Vec<u8> {
+    let digit = |byte: u8| POSITION_DIGITS.iter().position(|&digit| digit == byte).unwrap_or(0);
+
+    // Digits both share stay as they are; `lower` is padded with zeros.
+    if let Some(upper) = upper {
+        let shared = upper.iter().enumerate()
+            .take_while(|&(index, &byte)| lower.get(index).copied().unwrap_or(b'0') == byte)
+            .count();
+        if shared > 0 {
+            let rest = rank_midpoint(lower.get(shared..).unwrap_or_default(), Some(&upper[shared..]));
+            return [&upper[..shared], &rest].concat();
+        }
+    }
+
+    let low = lower.first().map_or(0, |&byte| digit(byte));
+    let high = upper.and_then(|upper| upper.first()).map_or(POSITION_DIGITS.len(), |&byte| digit(byte));
+    if high - low > 1 {
+        vec![POSITION_DIGITS[(low + high).div_ceil(2)]]
+    } else if let Some(upper) = upper.filter(|upper| upper.len() > 1) {
+        vec![upper[0]]
+    } else {
+        [&[POSITION_DIGITS[low]][..], &rank_midpoint(lower.get(1..).unwrap_or_default(), None)].concat()
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct TodoBatch {
+    operations: Vec<TodoOperation>,
+}
+
+///
+/// One step of a batch, tagged by `op`. Each takes the same fields as the
+/// corresponding single-todo endpoint, with `version` standing in for the
+/// `If-Match` header.
+///
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase", deny_unknown_fields)]
+enum TodoOperation {
+    Create(CreateTodo),
+    Update {
+        id: i64,
+        version: Option<i64>,
+        #[serde(flatten)]
+        changes: UpdateTodo,
+    },
+    Delete {
+        id: i64,
+        version: Option<i64>,
+    },
+}
+
+/// What became of the todo an operation touched.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "result", content = "todo", rename_all = "lowercase")]
+enum TodoOperationResult {
+    Created(Todo),
+    Updated(Todo),
+    Deleted(Todo),
+}
+
+#[derive(serde::Serialize)]
+struct TodoBatchResponse {
+    results: Vec<TodoOperationResult>,
+}
+
+///
+/// Why a batch was rolled back, and which operation (if any single one)
+/// was to blame.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TodoBatchError {
+    index: Option<usize>,
+    error: TodoRepoError,
+}
+
+impl TodoBatchError {
+    fn at(index: usize) -> impl FnOnce(TodoRepoError) -> Self {
+        move |error| TodoBatchError { index: Some(index), error }
+    }
+}
+
+impl<E: Into<TodoRepoError>> From<E> for TodoBatchError {
+    fn from(error: E) -> Self {
+        TodoBatchError { index: None, error: error.into() }
+    }
+}
+
+impl IntoResponse for TodoBatchError {
+    fn into_response(self) -> Response {
+        let (status, message) = self.error.status_and_message();
+
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::json!({ "message": message, "operation": self.index }).to_string()))
+            .unwrap()
+    }
+}
+
+/// What a client asks of `/ws/todos`: a subscription, or a batch operation on one todo.
+#[derive(Debug, Clone, PartialEq)]
+enum TodoSocketCommand {
+    Subscribe(i64),
+    Unsubscribe(i64),
+    Todo(TodoOperation),
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TodoSubscription {
+    list_id: i64,
+}
+
+impl TodoSocketCommand {
+    /// Parses a frame, returning its `ref`, or `null` if it has none, along with the command it holds.
+    fn parse(text: &str) -> (serde_json::Value, Result<Self, TodoRepoError>) {
+        let mut fields = match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(text) {
+            Ok(fields) => fields,
+            Err(error) => return (serde_json::Value::Null, Err(TodoRepoError::Invalid(error.to_string()))),
+        };
+        let reference = fields.remove("ref").unwrap_or_default();
+        let command = match fields.get("op").and_then(|op| op.as_str()) {
+            Some(op @ ("subscribe" | "unsubscribe")) => {
+                let subscribe = op == "subscribe";
+                fields.remove("op");
+                serde_json::from_value::<TodoSubscription>(fields.into())
+                    .map(|TodoSubscription { list_id }| if subscribe { TodoSocketCommand::Subscribe(list_id) } else { TodoSocketCommand::Unsubscribe(list_id) })
+            }
+            _ => serde_json::from_value(fields.into()).map(TodoSocketCommand::Todo),
+        };
+
+        (reference, command.map_err(|error| TodoRepoError::Invalid(error.to_string())))
+    }
+}
+
+/// The formats todos are exported and imported in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TodoFileFormat {
+    Csv,
+    /// JSON Lines: an object on each line.
+    Ndjson,
+    /// iCalendar, with a `VTODO` for each todo.
+    Ics,
+}
+
+/// The columns of a CSV export, and those an import may have, in any order.
+const PORTABLE_COLUMNS: [&str; 10] = ["id", "title", "description", "done", "due_at", "priority", "list_id", "parent_id", "tags", "recurrence"];
+
+impl TodoFileFormat {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "csv" => Some(TodoFileFormat::Csv),
+            "ndjson" => Some(TodoFileFormat::Ndjson),
+            "ics" => Some(TodoFileFormat::Ics),
+            _ => None,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            TodoFileFormat::Csv => "text/csv; charset=utf-8",
+            TodoFileFormat::Ndjson => "application/x-ndjson",
+            TodoFileFormat::Ics => "text/calendar; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            TodoFileFormat::Csv => "csv",
+            TodoFileFormat::Ndjson => "ndjson",
+            TodoFileFormat::Ics => "ics",
+        }
+    }
+
+    /// The line a file starts with, if the format has one.
+    fn header(self) -> Option<String> {
+        match self {
+            TodoFileFormat::Csv => Some(csv_line(PORTABLE_COLUMNS)),
+            TodoFileFormat::Ndjson => None,
+            TodoFileFormat::Ics => Some(ical::CALENDAR_START.to_string()),
+        }
+    }
+
+    /// The line a file ends with, if the format has one.
+    fn footer(self) -> Option<String> {
+        match self {
+            TodoFileFormat::Csv | TodoFileFormat::Ndjson => None,
+            TodoFileFormat::Ics => Some(ical::CALENDAR_END.to_string()),
+        }
+    }
+
+    /// A todo as a line of a file, line ending and all; in iCalendar, it takes several, and `uids` are the UIDs it goes by.
+    fn line(self, todo: &PortableTodo, uids: &BTreeMap<i64, String>) -> String {
+        match self {
+            TodoFileFormat::Csv => {
+                let recurrence = todo.anchored_recurrence();
+                let todo = serde_json::to_value(todo).expect("todos serialize");
+                csv_line(PORTABLE_COLUMNS.map(|column| match &todo[column] {
+                    serde_json::Value::Null => String::new(),
+                    serde_json::Value::String(value) => value.clone(),
+                    serde_json::Value::Array(tags) => tags.iter().filter_map(|tag| tag.as_str()).collect::<Vec<_>>().join(";"),
+                    serde_json::Value::Object(_) => recurrence.as_ref().map(Recurrence::to_string).unwrap_or_default(),
+                    value => value.to_string(),
+                }))
+            }
+            TodoFileFormat::Ndjson => format!("{}\n", serde_json::to_value(todo).expect("todos serialize")),
+            TodoFileFormat::Ics => ical::vtodo(todo, uids, None),
+        }
+    }
+
+    /// The rows of a file, failing as a whole only if there is no making sense of it at all.
+    fn read(self, body: &[u8]) -> Result<ImportRows, TodoRepoError> {
+        match self {
+            TodoFileFormat::Csv => read_csv(body),
+            TodoFileFormat::Ndjson => {
+                let text = std::str::from_utf8(body).map_err(|_| TodoRepoError::Invalid("an import must be UTF-8 text".to_string()))?;
+
+                Ok(text.lines().zip(1..)
+                    .filter(|(line, _)| !line.trim().is_empty())
+                    .map(|(line, number)| (number, serde_json::from_str(line).map_err(|error| error.to_string())))
+                    .collect())
+            }
+            TodoFileFormat::Ics => ical::read_ics(body),
+        }
+    }
+}
+
+/// The rows of an import, each with the line it starts on, and the todo on it or why there is none.
+type ImportRows = Vec<(u64, Result<PortableTodo, String>)>;
+
+/// A CSV record, quoted as need be, line ending and all.
+fn csv_line<T: AsRef<[u8]>>(fields: impl IntoIterator<Item = T>) -> String {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(fields).expect("writing to memory cannot fail");
+
+    String::from_utf8(writer.into_inner().expect("writing to memory cannot fail")).expect("the fields are UTF-8")
+}
+
+/// The rows of a CSV file, read by the names in its header, with empty cells taken as missing.
+fn read_csv(body: &[u8]) -> Result<ImportRows, TodoRepoError> {
+    let mut reader = csv::Reader::from_reader(body);
+    let columns: Vec<String> = reader.headers()
+        .map_err(|error| TodoRepoError::Invalid(format!("the header could not be read: {}", error)))?
+        .iter()
+        .map(|column| column.trim_start_matches('\u{feff}').trim().to_string())
+        .collect();
+    if let Some(column) = columns.iter().find(|column| !PORTABLE_COLUMNS.contains(&column.as_str())) {
+        return Err(TodoRepoError::Invalid(format!("unknown column {:?}; the columns are {}", column, PORTABLE_COLUMNS.join(", "))));
+    }
+
+    // With CRLF line endings, the reader takes each record to start at the
+    // LF before it, and numbers its lines one short, so lines are counted here.
+    let (mut counted_to, mut line) = (0, 1);
+    let mut line_at = |position: Option<&csv::Position>| {
+        let byte = position.map_or(counted_to, |position| (position.byte() as usize + 1).min(body.len()));
+        line += body[counted_to..byte].iter().filter(|&&byte| byte == b'\n').count() as u64;
+        counted_to = byte;
+        line
+    };
+
+    let mut rows = Vec::new();
+    for record in reader.records() {
+        let row = match record {
+            Ok(record) => {
+                let line = line_at(record.position());
+                let cells = columns.iter().zip(record.iter())
+                    .filter(|(_, cell)| !cell.is_empty())
+                    .map(|(column, cell)| csv_cell(column, cell).map(|value| (column.clone(), value)))
+                    .collect::<Result<serde_json::Map<_, _>, _>>();
+                let todo = cells.and_then(|cells| serde_json::from_value(cells.into()).map_err(|error| error.to_string()));
+                (line, todo)
+            }
+            Err(error) => (line_at(error.position()), Err(error.to_string())),
+        };
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+/// A CSV cell as the JSON value it stands for in the column it is in.
+fn csv_cell(column: &str, cell: &str) -> Result<serde_json::Value, String> {
+    match column {
+        "id" | "list_id" | "parent_id" => cell.trim().parse::<i64>().map(Into::into).map_err(|_| format!("{} must be a whole number", column)),
+        "done" => cell.trim().parse::<bool>().map(Into::into).map_err(|_| "done must be `true` or `false`".to_string()),
+        "tags" => Ok(cell.split(';').map(str::trim).filter(|tag| !tag.is_empty()).collect::<Vec<_>>().into()),
+        "recurrence" => cell.parse::<Recurrence>().map(|recurrence| serde_json::to_value(RecurrenceSpec::from(recurrence)).expect("recurrences serialize")),
+        _ => Ok(cell.into()),
+    }
+}
+
+/// A todo as it is exported and imported, leaving out what only means anything to this app, such as its version.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct PortableTodo {
+    #[serde(default)]
+    id: Option<i64>,
+    title: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    done: bool,
+    #[serde(default, with = "optional_rfc3339")]
+    due_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    priority: TodoPriority,
+    #[serde(default)]
+    list_id: Option<i64>,
+    #[serde(default)]
+    parent_id: Option<i64>,
+    /// Separated by semicolons in CSV.
+    #[serde(default)]
+    tags: Vec<String>,
+    /// In CSV, as it is in iCalendar: a `DTSTART` with the time zone, then an `RRULE` on a line of its own.
+    #[serde(default)]
+    recurrence: Option<RecurrenceSpec>,
+}
+
+impl PortableTodo {
+    fn of(todo: Todo) -> Self {
+        PortableTodo {
+            id: Some(todo.id),
+            title: todo.title,
+            description: todo.description,
+            done: todo.done,
+            due_at: todo.due_at,
+            priority: todo.priority,
+            list_id: todo.list_id,
+            parent_id: todo.parent_id,
+            tags: todo.tags,
+            recurrence: todo.recurrence.map(RecurrenceSpec::from),
+        }
+    }
+
+    /// The recurrence, as long as it says when it starts, as those that were exported do.
+    fn anchored_recurrence(&self) -> Option<Recurrence> {
+        self.recurrence.clone().and_then(|spec| Recurrence::try_from(spec).ok())
+    }
+}
+
+/// What an import did, or would have done: how many rows it imported, and why each of the others could not be.
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+struct TodoImportReport {
+    dry_run: bool,
+    imported: usize,
+    errors: Vec<TodoImportError>,
+}
+
+#[derive(serde::Serialize, Debug, Clone, PartialEq)]
+struct TodoImportError {
+    line: u64,
+    message: String,
+}
+
+///
+/// A partial update of a todo, expressed against its JSON representation:
+/// either an RFC 7396 merge patch, where `null` clears a field, or an
+/// RFC 6902 JSON patch.
+///
+#[derive(Debug, Clone, PartialEq)]
+enum TodoPatch {
+    Merge(serde_json::Value),
+    Json(json_patch::Patch),
+}
+
+const MERGE_PATCH_CONTENT_TYPE: &str = "application/merge-patch+json";
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+
+impl TodoPatch {
+    fn parse(content_type: Option<&str>, body: &[u8]) -> Result<Self, TodoApiError> {
+        let media_type = content_type.unwrap_or_default().split(';').next().unwrap_or_default().trim();
+
+        match media_type {
+            MERGE_PATCH_CONTENT_TYPE => serde_json::from_slice(body)
+                .map(TodoPatch::Merge)
+                .map_err(|error| TodoApiError::MalformedBody(error.to_string())),
+            JSON_PATCH_CONTENT_TYPE => serde_json::from_slice(body)
+                .map(TodoPatch::Json)
+                .map_err(|error| TodoApiError::MalformedBody(error.to_string())),
+            _ => Err(TodoApiError::UnsupportedPatchType),
+        }
+    }
+
+    ///
+    /// Applies the patch to `todo`, and checks that the result is still a
+    /// todo. Clearing the description leaves it empty, clearing the priority
+    /// makes it normal, and clearing the due date removes it, but the title
+    /// and done flag are required. Only those fields can be patched at all;
+    /// `completed_at` follows `done`.
+    ///
+    fn apply(&self, todo: &Todo) -> Result<PatchedTodo, TodoRepoError> {
+        let mut document = serde_json::to_value(todo).unwrap();
+
+        match self {
+            TodoPatch::Merge(patch) => json_patch::merge(&mut document, patch),
+            TodoPatch::Json(patch) => json_patch::patch(&mut document, patch)
+                .map_err(|error| TodoRepoError::Invalid(error.to_string()))?,
+        }
+
+        let patched: PatchedTodo = serde_json::from_value(document)
+            .map_err(|error| TodoRepoError::Invalid(error.to_string()))?;
+
+        let read_only = (patched.id, patched.version, patched.owner_id, patched.list_id, patched.parent_id, &patched.position, &patched.tags, &patched.blocked_by, patched.completed_at, patched.deleted_at, &patched.recurrence);
+        if read_only != (todo.id, todo.version, todo.owner_id, todo.list_id, todo.parent_id, &todo.position, &todo.tags, &todo.blocked_by, todo.completed_at, todo.deleted_at, &todo.recurrence) {
+            return Err(TodoRepoError::Invalid("only title, description, done, due_at and priority can be patched".to_string()));
+        }
+
+        Ok(patched)
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct PatchedTodo {
+    id: i64,
+    title: String,
+    #[serde(default)]
+    description: String,
+    done: bool,
+    #[serde(default, deserialize_with = "optional_rfc3339::deserialize")]
+    due_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    priority: TodoPriority,
+    #[serde(default)]
+    recurrence: Option<Recurrence>,
+    #[serde(default, deserialize_with = "optional_rfc3339::deserialize")]
+    completed_at: Option<OffsetDateTime>,
+    version: i64,
+    #[serde(default)]
+    owner_id: Option<i64>,
+    #[serde(default)]
+    list_id: Option<i64>,
+    #[serde(default)]
+    parent_id: Option<i64>,
+    #[serde(default)]
+    position: String,
+    #[serde(default, deserialize_with = "optional_rfc3339::deserialize")]
+    deleted_at: Option<OffsetDateTime>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    blocked_by: Vec<i64>,
+}
+
+impl PatchedTodo {
+    /// The patch as an update, as far as what becomes of the todo's recurrence goes.
+    fn as_update(&self) -> UpdateTodo {
+        UpdateTodo { done: Some(self.done), due_at: Some(self.due_at), ..Default::default() }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
+struct MissingTodoError(String);
+
+impl IntoResponse for MissingTodoError {
+    fn into_response(self) -> Response {
+        json_error(StatusCode::NOT_FOUND, &self.0)
+    }
+}
+
+fn json_error(status: StatusCode, message: &str) -> Response {
+    Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::json!({ "message": message }).to_string()))
+        .unwrap()
+}
+
+///
+/// A query parameter that could not be understood, reported back to the
+/// client together with the name of the offending parameter.
+///
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, PartialEq, Eq)]
+struct InvalidQueryError {
+    parameter: String,
+    message: String,
+}
+
+impl InvalidQueryError {
+    fn new(parameter: &str, message: impl Into<String>) -> Self {
+        InvalidQueryError { parameter: parameter.to_string(), message: message.into() }
+    }
+}
+
+impl IntoResponse for InvalidQueryError {
+    fn into_response(self) -> Response {
+        Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .header("Content-Type", "application/json")
+            .body(Body::from(serde_json::to_string(&self).unwrap()))
+            .unwrap()
+    }
+}
+
+///
+/// The errors a todo handler can fail with: either the request itself was
+/// malformed, or the repository could not carry it out.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TodoApiError {
+    InvalidQuery(InvalidQueryError),
+    MalformedBody(String),
+    UnsupportedPatchType,
+    UnknownAction(String),
+    Unauthenticated,
+    Forbidden,
+    UnknownFeed,
+    Repo(TodoRepoError),
+    Batch(TodoBatchError),
+}
+
+impl From<InvalidQueryError> for TodoApiError {
+    fn from(error: InvalidQueryError) -> Self {
+        TodoApiError::InvalidQuery(error)
+    }
+}
+
+impl From<TodoRepoError> for TodoApiError {
+    fn from(error: TodoRepoError) -> Self {
+        TodoApiError::Repo(error)
+    }
+}
+
+impl From<TodoBatchError> for TodoApiError {
+    fn from(error: TodoBatchError) -> Self {
+        TodoApiError::Batch(error)
+    }
+}
+
+impl IntoResponse for TodoApiError {
+    fn into_response(self) -> Response {
+        match self {
+            TodoApiError::InvalidQuery(error) => error.into_response(),
+            TodoApiError::MalformedBody(message) => json_error(StatusCode::BAD_REQUEST, &message),
+            TodoApiError::UnsupportedPatchType => {
+                let mut response = json_error(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    &format!("patches must be {} or {}", MERGE_PATCH_CONTENT_TYPE, JSON_PATCH_CONTENT_TYPE),
+                );
+                response.headers_mut().insert(
+                    "Accept-Patch",
+                    header::HeaderValue::from_static("application/merge-patch+json, application/json-patch+json"),
+                );
+                response
+            }
+            TodoApiError::UnknownAction(action) => json_error(StatusCode::NOT_FOUND, &format!("no such action: {}", action)),
+            TodoApiError::Unauthenticated => {
+                let mut response = json_error(StatusCode::UNAUTHORIZED, "a valid bearer token is required");
+                response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+                response
+            }
+            TodoApiError::Forbidden => json_error(StatusCode::FORBIDDEN, "users may only change their own account"),
+            TodoApiError::UnknownFeed => json_error(StatusCode::NOT_FOUND, "no such feed"),
+            TodoApiError::Repo(error) => error.into_response(),
+            TodoApiError::Batch(error) => error.into_response(),
+        }
+    }
+}
+
+#[tokio::test]
+async fn todo_cursor_round_trip() {
+    let by_position = TodoSort::default();
+    let cursor = TodoCursor { key: TodoSortKey::Position("a0000000000000000042V".to_string()), id: 42 };
+    assert_eq!(TodoCursor::decode(&cursor.encode(), by_position), Ok(cursor));
+
+    let by_created_at = TodoSort { field: TodoSortField::CreatedAt, descending: false };
+    let cursor = TodoCursor {
+        key: TodoSortKey::CreatedAt(time::macros::datetime!(2023-12-13 09:18:44.123456)),
+        id: 42,
+    };
+    assert_eq!(TodoCursor::decode(&cursor.encode(), by_created_at), Ok(cursor));
+
+    let by_title = TodoSort { field: TodoSortField::Title, descending: true };
+    let cursor = TodoCursor { key: TodoSortKey::Title("Learn: SQLx".to_string()), id: 7 };
+    assert_eq!(TodoCursor::decode(&cursor.encode(), by_title), Ok(cursor));
+}
+
+#[tokio::test]
+async fn todo_positions_fall_between_their_neighbours() {
+    assert_eq!(rank_between(None, Some("a0000000000000000001V")).as_deref(), Some("I"));
+    assert_eq!(rank_between(Some("a0000000000000000001V"), Some("a0000000000000000002V")).as_deref(), Some("a0000000000000000002"));
+    assert_eq!(rank_between(Some("a0000000000000000002V"), None).as_deref(), Some("a0000000000000000002VV"));
+    assert_eq!(rank_between(Some("b"), Some("a")), None);
+
+    // Squeezing todos into the same gap over and over keeps finding room.
+    let (lower, mut upper) = ("a0000000000000000001V".to_string(), "a0000000000000000002V".to_string());
+    for _ in 0..200 {
+        let rank = rank_between(Some(&lower), Some(&upper)).unwrap();
+        assert!(lower < rank && rank < upper && !rank.ends_with('0'), "{} < {} < {}", lower, rank, upper);
+        upper = rank;
+    }
+    assert!(upper.len() < 80);
+}
+
+#[tokio::test]
+async fn tampered_todo_cursor_rejected() {
+    let sort = TodoSort { field: TodoSortField::CreatedAt, descending: false };
+
+    assert!(TodoCursor::decode("not a cursor", sort).is_err());
+    assert!(TodoCursor::decode(&CURSOR_BASE64.encode("c:1:abc"), sort).is_err());
+    assert!(TodoCursor::decode(&CURSOR_BASE64.encode("c:-1:0"), sort).is_err());
+    assert!(TodoCursor::decode(&CURSOR_BASE64.encode(format!("c:1:{}", i128::MAX)), sort).is_err());
+    assert!(TodoCursor::decode(&CURSOR_BASE64.encode("t:1:Learn SQLx"), sort).is_err());
+}
+
+#[tokio::test]
+async fn todo_list_query_from_params() {
+    let params = vec![
+        ("done".to_string(), "false".to_string()),
+        ("actionable".to_string(), "true".to_string()),
+        ("created_after".to_string(), "2023-12-13T10:00:00+01:00".to_string()),
+        ("sort".to_string(), "-title".to_string()),
+        ("limit".to_string(), "10".to_string()),
+        ("tag".to_string(), "home".to_string()),
+        ("tag".to_string(), "urgent".to_string()),
+        ("tag".to_string(), "home".to_string()),
+        ("tag_match".to_string(), "any".to_string()),
+    ];
+
+    assert_eq!(
+        TodoListQuery::from_params(params),
+        Ok(TodoListQuery {
+            filter: TodoFilter {
+                owner_id: None,
+                list_id: None,
+                done: Some(false),
+                actionable: Some(true),
+                created_after: Some(time::macros::datetime!(2023-12-13 09:00:00)),
+                created_before: None,
+                tags: vec!["home".to_string(), "urgent".to_string()],
+                tag_match: TagMatch::Any,
+            },
+            sort: TodoSort { field: TodoSortField::Title, descending: true },
+            limit: 10,
+            cursor: None,
+        })
+    );
+}
+
+#[tokio::test]
+async fn todo_list_query_rejects_bad_params() {
+    let rejected = |name: &str, value: &str| {
+        TodoListQuery::from_params(vec![(name.to_string(), value.to_string())]).unwrap_err().parameter
+    };
+
+    assert_eq!(rejected("sort", "-priority"), "sort");
+    assert_eq!(rejected("done", "maybe"), "done");
+    assert_eq!(rejected("created_after", "yesterday"), "created_after");
+    assert_eq!(rejected("limit", "0"), "limit");
+    assert_eq!(rejected("tag", " "), "tag");
+    assert_eq!(rejected("tag_match", "most"), "tag_match");
+    assert_eq!(rejected("colour", "blue"), "colour");
+}
+
+#[tokio::test]
+async fn todo_due_query_windows() {
+    let now = time::macros::datetime!(2026-10-17 12:00 UTC);
+    let within = |value: &str| {
+        TodoDueQuery::upcoming(vec![("within".to_string(), value.to_string())], now).map(|query| query.due_before - now)
+    };
+
+    assert_eq!(TodoDueQuery::upcoming(Vec::new(), now).map(|query| (query.due_from, query.due_before)), Ok((Some(now), now + time::Duration::weeks(1))));
+    assert_eq!(within("36h"), Ok(time::Duration::hours(36)));
+    assert_eq!(within("3d"), Ok(time::Duration::days(3)));
+    assert_eq!(within("2w"), Ok(time::Duration::weeks(2)));
+    for value in ["", "d", "0d", "-1d", "7", "7x", "1.5d", "53w"] {
+        assert_eq!(within(value).unwrap_err().parameter, "within", "{:?}", value);
+    }
+
+    assert_eq!(TodoDueQuery::overdue(Vec::new(), now).map(|query| (query.due_from, query.due_before)), Ok((None, now)));
+    assert!(TodoDueQuery::overdue(vec![("within".to_string(), "7d".to_string())], now).is_err());
+}
+
+#[tokio::test]
+async fn get_todos_pages_through_all_todos() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoPostgres::new().await.unwrap();
+    let (owner, token) = sign_up(&repo).await;
+    let mut created = Vec::new();
+    for i in 0..3 {
+        created.push(repo.create(Some(owner.id), CreateTodo::new(format!("Paged todo {}", i), "")).await.unwrap().id);
+    }
+    repo.create(None, CreateTodo::new("Someone else's todo", "")).await.unwrap();
+
+    let app = Router::<TodoRepoPostgres>::new()
+        .route("/todos", get(get_todos::<TodoRepoPostgres>))
+        .with_state(repo);
+
+    let mut seen = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let uri = match &cursor {
+            Some(cursor) => format!("/todos?limit=2&cursor={}", cursor),
+            None => "/todos?limit=2".to_string(),
+        };
+        let response = app.clone()
+            .oneshot(hyper::Request::builder().uri(uri).header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(page["items"].as_array().unwrap().len() <= 2);
+        seen.extend(page["items"].as_array().unwrap().iter().map(|t| t["id"].as_i64().unwrap()));
+
+        match page["next_cursor"].as_str() {
+            Some(next) => cursor = Some(next.to_string()),
+            None => break,
+        }
+    }
+
+    // Only the owner's own todos are listed.
+    assert_eq!(seen, created);
+
+    let response = app.clone()
+        .oneshot(hyper::Request::builder().uri("/todos?cursor=garbage").header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    for authorization in [None, Some("Bearer not-a-token"), Some("Basic Zm9vOmJhcg==")] {
+        let mut request = hyper::Request::builder().uri("/todos");
+        if let Some(authorization) = authorization {
+            request = request.header("Authorization", authorization);
+        }
+        let response = app.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["WWW-Authenticate"], "Bearer");
+    }
+}
+
+#[tokio::test]
+async fn todo_search_query_requires_q() {
+    assert_eq!(TodoSearchQuery::from_params(vec![]).unwrap_err().parameter, "q");
+    assert_eq!(TodoSearchQuery::from_params(vec![("q".to_string(), "  ".to_string())]).unwrap_err().parameter, "q");
+}
+
+#[tokio::test]
+async fn search_todos_ranks_and_highlights() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let word = format!("quokka{}", OffsetDateTime::now_utc().unix_timestamp_nanos());
+    let repo = TodoRepoPostgres::new().await.unwrap();
+    let (owner, token) = sign_up(&repo).await;
+    let in_description = repo.create(Some(owner.id), CreateTodo::new("Visit Rottnest", format!("Take a selfie with a {}", word))).await.unwrap();
+    let in_title = repo.create(Some(owner.id), CreateTodo::new(format!("Feed the {}", word), "Leaves only")).await.unwrap();
+    repo.create(None, CreateTodo::new(format!("Someone else's {}", word), "")).await.unwrap();
+
+    let app = Router::<TodoRepoPostgres>::new()
+        .route("/todos/search", get(search_todos::<TodoRepoPostgres>))
+        .with_state(repo);
+
+    let response = app
+        .oneshot(hyper::Request::builder().uri(format!("/todos/search?q={}", word)).header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let hits: Vec<TodoSearchHit> = serde_json::from_slice(&body).unwrap();
+
+    // Title matches are weighted above description matches.
+    assert_eq!(hits.iter().map(|h| h.todo.id).collect::<Vec<_>>(), vec![in_title.id, in_description.id]);
+    assert_eq!(hits[0].title_highlight, format!("Feed the <mark>{}</mark>", word));
+    assert!(hits[1].description_highlight.contains(&format!("<mark>{}</mark>", word)));
+}
+
+#[tokio::test]
+async fn get_todos_filters_and_sorts() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoPostgres::new().await.unwrap();
+    let (owner, token) = sign_up(&repo).await;
+    let first = repo.create(Some(owner.id), CreateTodo::new("Filtered todo", "")).await.unwrap();
+    let second = repo.create(Some(owner.id), CreateTodo::new("Filtered todo", "")).await.unwrap();
+    repo.create(Some(owner.id), CreateTodo::new("Unfinished todo", "")).await.unwrap();
+    repo.update(None, first.id, None, &UpdateTodo { done: Some(true), ..Default::default() }).await.unwrap();
+    repo.update(None, second.id, None, &UpdateTodo { done: Some(true), ..Default::default() }).await.unwrap();
+
+    let app = Router::<TodoRepoPostgres>::new()
+        .route("/todos", get(get_todos::<TodoRepoPostgres>))
+        .with_state(repo);
+
+    let response = app.clone()
+        .oneshot(hyper::Request::builder().uri("/todos?done=true&sort=-created_at&limit=100").header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let items = page["items"].as_array().unwrap();
+    assert!(items.iter().all(|t| t["done"] == serde_json::json!(true)));
+
+    let ids: Vec<i64> = items.iter().map(|t| t["id"].as_i64().unwrap()).collect();
+    assert_eq!(ids, vec![second.id, first.id]);
+
+    let response = app
+        .oneshot(hyper::Request::builder().uri("/todos?sort=priority").header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let error: InvalidQueryError = serde_json::from_slice(&body).unwrap();
+    assert_eq!(error.parameter, "sort");
+}
+
+#[tokio::test]
+async fn todo_repo_errors_map_to_statuses() {
+    assert_eq!(TodoRepoError::NotFound(1).into_response().status(), StatusCode::NOT_FOUND);
+    assert_eq!(TodoRepoError::Conflict("".to_string()).into_response().status(), StatusCode::CONFLICT);
+    assert_eq!(TodoRepoError::Unavailable("".to_string()).into_response().status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(TodoRepoError::Internal("".to_string()).into_response().status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    assert!(matches!(TodoRepoError::from(sqlx::Error::PoolTimedOut), TodoRepoError::Unavailable(_)));
+}
+
+#[tokio::test]
+async fn missing_todo_is_not_found() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoPostgres::new().await.unwrap();
+    let (_, token) = sign_up(&repo).await;
+    let app = Router::<TodoRepoPostgres>::new()
+        .route("/todos/:id", get(get_todo::<TodoRepoPostgres>).delete(delete_todo::<TodoRepoPostgres>))
+        .with_state(repo);
+
+    for method in [hyper::Method::GET, hyper::Method::DELETE] {
+        let response = app.clone()
+            .oneshot(hyper::Request::builder().method(method).uri("/todos/0").header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["message"], "todo 0 not found");
+    }
+}
+
+#[tokio::test]
+async fn if_match_parses_entity_tags() {
+    let if_match = |value: Option<&str>| {
+        let mut request = hyper::Request::builder();
+        if let Some(value) = value {
+            request = request.header(header::IF_MATCH, value);
+        }
+        let (mut parts, _) = request.body(()).unwrap().into_parts();
+        async move { IfMatch::from_request_parts(&mut parts, &()).await.map_err(|r| r.status()) }
+    };
+
+    let versions = |versions: &[i64]| Ok(IfMatch(Some(versions.iter().copied().collect())));
+
+    assert_eq!(if_match(None).await, Ok(IfMatch(None)));
+    assert_eq!(if_match(Some("*")).await, Ok(IfMatch(None)));
+    assert_eq!(if_match(Some("\"3\"")).await, versions(&[3]));
+    assert_eq!(if_match(Some("\"3\", \"5\",\"xyzzy\"")).await, versions(&[3, 5]));
+    // Weak tags never match, but other tags in the list still can.
+    assert_eq!(if_match(Some("W/\"3\", \"4\"")).await, versions(&[4]));
+    assert_eq!(if_match(Some("W/\"3\"")).await, Err(StatusCode::PRECONDITION_FAILED));
+    assert_eq!(if_match(Some("\"xyzzy\"")).await, Err(StatusCode::PRECONDITION_FAILED));
+}
+
+#[tokio::test]
+async fn stale_writes_fail_precondition() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoPostgres::new().await.unwrap();
+    let (owner, token) = sign_up(&repo).await;
+    let todo = repo.create(Some(owner.id), CreateTodo::new("Edit me", "")).await.unwrap();
+    let uri = format!("/todos/{}", todo.id);
+
+    let app = Router::<TodoRepoPostgres>::new()
+        .route("/todos/:id", get(get_todo::<TodoRepoPostgres>).put(update_todo::<TodoRepoPostgres>).delete(delete_todo::<TodoRepoPostgres>))
+        .with_state(repo);
+
+    let request = |method: hyper::Method, if_match: &str, body: &str| {
+        hyper::Request::builder()
+            .method(method)
+            .uri(&uri)
+            .header(header::IF_MATCH, if_match)
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(hyper::Request::builder().uri(&uri).header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(response.headers()[header::ETAG], "\"1\"");
+
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "\"1\"", r#"{"done": true}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::ETAG], "\"2\"");
+
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "\"1\"", r#"{"title": "Lost update"}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+
+    let response = app.clone().oneshot(request(hyper::Method::DELETE, "\"1\"", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let response = app.clone().oneshot(request(hyper::Method::DELETE, "W/\"2\"", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    assert!(String::from_utf8_lossy(&body).contains("a weak one such as W/\\\"2\\\" never matches; send \\\"2\\\" instead"));
+
+    // Any of the versions listed will do.
+    let response = app.clone().oneshot(request(hyper::Method::DELETE, "\"1\", \"2\"", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    // The todo is in the trash now, at the version restoring it expects.
+    assert_eq!(response.headers()[header::ETAG], "\"3\"");
+}
+
+#[tokio::test]
+async fn todo_patch_apply() {
+    let todo = Todo {
+        id: 1,
+        title: "Learn SQLx".to_string(),
+        description: "Soon".to_string(),
+        done: false,
+        due_at: None,
+        priority: TodoPriority::Normal,
+        recurrence: None,
+        completed_at: None,
+        version: 3,
+        owner_id: None,
+        list_id: None,
+        parent_id: None,
+        position: "a0000000000000000001V".to_string(),
+        deleted_at: None,
+        tags: Vec::new(),
+        blocked_by: Vec::new(),
+    };
+    let merge = |patch: serde_json::Value| TodoPatch::Merge(patch).apply(&todo);
+    let json = |patch: serde_json::Value| TodoPatch::Json(serde_json::from_value(patch).unwrap()).apply(&todo);
+
+    let patched = merge(serde_json::json!({ "description": null, "done": true })).unwrap();
+    assert_eq!((patched.title.as_str(), patched.description.as_str(), patched.done), ("Learn SQLx", "", true));
+
+    let patched = json(serde_json::json!([
+        { "op": "test", "path": "/done", "value": false },
+        { "op": "replace", "path": "/title", "value": "Learn Axum" },
+    ])).unwrap();
+    assert_eq!(patched.title, "Learn Axum");
+
+    let patched = merge(serde_json::json!({ "due_at": "2026-10-20T09:30:00+02:00", "priority": "high" })).unwrap();
+    assert_eq!((patched.due_at, patched.priority), (Some(time::macros::datetime!(2026-10-20 07:30 UTC)), TodoPriority::High));
+
+    assert!(matches!(merge(serde_json::json!({ "title": null })), Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(merge(serde_json::json!({ "priority": "someday" })), Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(merge(serde_json::json!({ "completed_at": "2026-10-17T12:00:00Z" })), Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(merge(serde_json::json!({ "parent_id": 2 })), Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(merge(serde_json::json!({ "blocked_by": [2] })), Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(merge(serde_json::json!({ "position": "0" })), Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(merge(serde_json::json!({ "done": "yes" })), Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(merge(serde_json::json!({ "colour": "blue" })), Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(merge(serde_json::json!({ "version": 4 })), Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(json(serde_json::json!([{ "op": "test", "path": "/done", "value": true }])), Err(TodoRepoError::Invalid(_))));
+}
+
+#[tokio::test]
+async fn patch_todo_by_content_type() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoPostgres::new().await.unwrap();
+    let (owner, token) = sign_up(&repo).await;
+    let todo = repo.create(Some(owner.id), CreateTodo::new("Patch me", "Not for long")).await.unwrap();
+    let uri = format!("/todos/{}", todo.id);
+
+    let app = Router::<TodoRepoPostgres>::new()
+        .route("/todos/:id", patch(patch_todo::<TodoRepoPostgres>))
+        .with_state(repo);
+
+    let request = |content_type: &str, body: &str| {
+        hyper::Request::builder()
+            .method(hyper::Method::PATCH)
+            .uri(&uri)
+            .header("Content-Type", content_type)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(request(MERGE_PATCH_CONTENT_TYPE, r#"{"description": null}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let patched: Todo = serde_json::from_slice(&body).unwrap();
+    assert_eq!((patched.description.as_str(), patched.version), ("", 2));
+
+    let response = app.clone().oneshot(request(JSON_PATCH_CONTENT_TYPE, r#"[{"op": "remove", "path": "/title"}]"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let response = app.clone().oneshot(request(MERGE_PATCH_CONTENT_TYPE, "{")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app.clone().oneshot(request("application/json", "{}")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[tokio::test]
+async fn batch_todos_all_or_nothing() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = memory::TodoRepoInMemory::default();
+    let (owner, token) = sign_up(&repo).await;
+    let app = todo_router(repo);
+
+    let request = |uri: &str, body: serde_json::Value| {
+        hyper::Request::builder()
+            .method(hyper::Method::POST)
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let response = app.clone().oneshot(request("/todos:batch", serde_json::json!({ "operations": [
+        { "op": "create", "title": "Pack", "description": "" },
+        { "op": "create", "title": "Leave", "description": "" },
+        { "op": "update", "id": 1, "version": 1, "done": true },
+    ] }))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let results = body["results"].as_array().unwrap();
+    assert_eq!(results.iter().map(|r| r["result"].as_str().unwrap()).collect::<Vec<_>>(), ["created", "created", "updated"]);
+    let completed_at = results[2]["todo"]["completed_at"].clone();
+    assert!(completed_at.is_string());
+    assert_eq!(results[2]["todo"], serde_json::json!({
+        "id": 1, "title": "Pack", "description": "", "done": true, "due_at": null, "priority": "normal", "recurrence": null, "completed_at": completed_at,
+        "version": 2, "owner_id": owner.id, "list_id": null, "parent_id": null, "position": "a0000000000000000001V", "deleted_at": null, "tags": [], "blocked_by": [],
+    }));
+
+    let response = app.clone().oneshot(request("/todos:batch", serde_json::json!({ "operations": [
+        { "op": "delete", "id": 2 },
+        { "op": "delete", "id": 1, "version": 1 },
+    ] }))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(body["operation"], 1);
+    let response = app.clone().oneshot(request("/todos:batch", serde_json::json!({ "operations": [
+        { "op": "delete", "id": 2, "version": 1 },
+    ] }))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let too_many = vec![serde_json::json!({ "op": "delete", "id": 1 }); MAX_BATCH_OPERATIONS + 1];
+    let response = app.clone().oneshot(request("/todos:batch", serde_json::json!({ "operations": too_many }))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let response = app.clone().oneshot(request("/todos:purge", serde_json::json!({ "operations": [] }))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = app.oneshot(request("/todos", serde_json::json!({ "title": "Still here", "description": "" }))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+/// Signs up a user with an email no other test uses, returning them and their API token.
+async fn sign_up<R: UserRepo>(repo: &R) -> (User, String) {
+    let token = generate_token();
+    let email = format!("{}@example.com", OffsetDateTime::now_utc().unix_timestamp_nanos());
+    let user = repo.create_user("Tester".to_string(), email, hash_token(&token)).await.unwrap();
+
+    (user, token)
+}
+
+///
+/// The behaviour every `TodoRepo` must have, whatever it is backed by. It
+/// only looks at the users and todos it creates itself, so it can run
+/// against a database that other tests are using at the same time.
+///
+async fn check_todo_repo_behaviour<R: TodoRepo + UserRepo + TodoListRepo>(repo: &R) {
+    let nonce = OffsetDateTime::now_utc().unix_timestamp_nanos();
+    let (owner, token) = sign_up(repo).await;
+    assert_eq!(repo.get_user(owner.id).await, Ok(owner.clone()));
+    assert_eq!(repo.authenticate(&hash_token(&token)).await, Ok(Some(owner.clone())));
+    assert_eq!(repo.authenticate(&hash_token("not a token")).await, Ok(None));
+    assert_eq!(repo.feed_owner(&hash_token(&token)).await, Ok(None));
+    let feed_token = format!("feed {}", token);
+    assert_eq!(repo.set_feed_token(owner.id, hash_token(&feed_token)).await, Ok(owner.clone()));
+    assert_eq!(repo.feed_owner(&hash_token(&feed_token)).await, Ok(Some(owner.clone())));
+
+    let first = repo.create(Some(owner.id), CreateTodo::new(format!("Behaviour {} b", nonce), "Write it once")).await.unwrap();
+    assert_eq!((first.done, first.version, first.owner_id), (false, 1, Some(owner.id)));
+    assert_eq!(repo.get(first.id).await, Ok(first.clone()));
+
+    let second = repo.create(Some(owner.id), CreateTodo::new(format!("Behaviour {} a", nonce), "")).await.unwrap();
+    let third = repo.create(Some(owner.id), CreateTodo::new(format!("Behaviour {} c", nonce), "")).await.unwrap();
+    let unowned = repo.create(None, CreateTodo::new(format!("Behaviour {} unowned", nonce), "")).await.unwrap();
+    assert!(first.id < second.id && second.id < third.id);
+
+    // Updates only touch the fields they are given, and bump the version.
+    let updated = repo.update(None, first.id, None, &UpdateTodo { done: Some(true), ..Default::default() }).await.unwrap();
+    assert!(updated.completed_at.is_some());
+    assert_eq!(updated, Todo { done: true, completed_at: updated.completed_at, version: 2, ..first.clone() });
+    assert_eq!(repo.update(None, first.id, Some(1), &UpdateTodo { title: Some("Lost".to_string()), ..Default::default() }).await, Err(TodoRepoError::VersionMismatch(first.id)));
+    assert_eq!(repo.update(None, 0, None, &UpdateTodo { done: Some(true), ..Default::default() }).await, Err(TodoRepoError::NotFound(0)));
+
+    let patched = repo.patch(None, first.id, Some(2), &TodoPatch::Merge(serde_json::json!({ "description": null })), false).await.unwrap();
+    assert_eq!(patched, Todo { description: "".to_string(), version: 3, ..updated });
+    let invalid = TodoPatch::Merge(serde_json::json!({ "title": null }));
+    assert!(matches!(repo.patch(None, first.id, None, &invalid, false).await, Err(TodoRepoError::Invalid(_))));
+    assert_eq!(repo.get(first.id).await, Ok(patched.clone()));
+
+    // Listings only include the owner's todos, in the requested order across pages.
+    let owned = TodoFilter { owner_id: Some(owner.id), ..Default::default() };
+    let ids = |todos: Vec<Todo>| todos.into_iter().map(|t| t.id).collect::<Vec<_>>();
+    let by_title = TodoListQuery { filter: owned.clone(), sort: TodoSort { field: TodoSortField::Title, descending: false }, limit: 2, ..Default::default() };
+    assert_eq!(ids(list_all(repo, by_title).await), vec![second.id, first.id, third.id]);
+    let newest_first = TodoListQuery { filter: owned.clone(), sort: TodoSort { field: TodoSortField::CreatedAt, descending: true }, limit: 2, ..Default::default() };
+    assert_eq!(ids(list_all(repo, newest_first).await), vec![third.id, second.id, first.id]);
+    let done = TodoListQuery { filter: TodoFilter { done: Some(true), ..owned.clone() }, ..Default::default() };
+    assert_eq!(ids(list_all(repo, done).await), vec![first.id]);
+
+    // Batches run in order, and roll back entirely if any operation fails.
+    let fourth_title = format!("Behaviour {} d", nonce);
+    let results = repo.batch(Some(owner.id), &[
+        TodoOperation::Create(CreateTodo::new(fourth_title.clone(), "")),
+        TodoOperation::Update { id: second.id, version: Some(1), changes: UpdateTodo { priority: Some(TodoPriority::High), ..Default::default() } },
+        TodoOperation::Delete { id: third.id, version: None },
+    ]).await.unwrap();
+    let fourth = match &results[0] {
+        TodoOperationResult::Created(todo) => todo.clone(),
+        result => panic!("expected a created todo, got {:?}", result),
+    };
+    assert_eq!((fourth.title.as_str(), fourth.owner_id), (fourth_title.as_str(), Some(owner.id)));
+    assert_eq!(results[1], TodoOperationResult::Updated(Todo { priority: TodoPriority::High, version: 2, ..second.clone() }));
+    assert!(matches!(&results[2], TodoOperationResult::Deleted(todo) if todo.deleted_at.is_some() && *todo == Todo { version: 2, deleted_at: todo.deleted_at, ..third.clone() }));
+    assert_eq!(repo.get(third.id).await, Err(TodoRepoError::NotFound(third.id)));
+
+    let failed = repo.batch(Some(owner.id), &[
+        TodoOperation::Create(CreateTodo::new(format!("Behaviour {} e", nonce), "")),
+        TodoOperation::Delete { id: fourth.id, version: None },
+        TodoOperation::Update { id: second.id, version: Some(1), changes: UpdateTodo { done: Some(false), ..Default::default() } },
+    ]).await;
+    assert_eq!(failed, Err(TodoBatchError { index: Some(2), error: TodoRepoError::VersionMismatch(second.id) }));
+    assert_eq!(repo.get(fourth.id).await, Ok(fourth.clone()));
+    // Nor can a batch reach anyone else's todos.
+    for operation in [TodoOperation::Update { id: unowned.id, version: None, changes: UpdateTodo::default() }, TodoOperation::Delete { id: unowned.id, version: None }] {
+        let stolen = repo.batch(Some(owner.id), &[TodoOperation::Delete { id: fourth.id, version: None }, operation]).await;
+        assert_eq!(stolen, Err(TodoBatchError { index: Some(1), error: TodoRepoError::NotFound(unowned.id) }));
+    }
+    assert_eq!(repo.get(fourth.id).await, Ok(fourth.clone()));
+    let all_owned = TodoListQuery { filter: owned.clone(), ..Default::default() };
+    assert_eq!(ids(list_all(repo, all_owned.clone()).await), vec![first.id, second.id, fourth.id]);
+
+    // Deleting moves the todo to the trash, and hands it back as it is there.
+    assert_eq!(repo.delete(None, first.id, Some(2)).await, Err(TodoRepoError::VersionMismatch(first.id)));
+    let trashed = repo.delete(None, first.id, None).await.unwrap();
+    assert!(trashed.deleted_at.is_some());
+    assert_eq!(trashed, Todo { version: 4, deleted_at: trashed.deleted_at, ..patched });
+    assert_eq!(repo.get(first.id).await, Err(TodoRepoError::NotFound(first.id)));
+    assert_eq!(repo.delete(None, first.id, None).await, Err(TodoRepoError::NotFound(first.id)));
+
+    // Todos can be filed in a list, and moved between lists.
+    let inbox = repo.create_list(owner.id, "Inbox".to_string()).await.unwrap();
+    let errands = repo.create_list(owner.id, "Errands".to_string()).await.unwrap();
+    assert_eq!((inbox.owner_id, inbox.archived), (owner.id, false));
+    assert_eq!(repo.get_list(inbox.id).await, Ok(inbox.clone()));
+    let filed = repo.create(Some(owner.id), CreateTodo { list_id: Some(inbox.id), ..CreateTodo::new(format!("Behaviour {} f", nonce), "") }).await.unwrap();
+    assert_eq!(filed.list_id, Some(inbox.id));
+    let moved = repo.move_todo(None, filed.id, Some(1), Some(errands.id)).await.unwrap();
+    assert_eq!(moved, Todo { list_id: Some(errands.id), version: 2, ..filed.clone() });
+    assert_eq!(repo.move_todo(None, filed.id, Some(1), None).await, Err(TodoRepoError::VersionMismatch(filed.id)));
+    assert!(matches!(repo.move_todo(None, filed.id, None, Some(0)).await, Err(TodoRepoError::Conflict(_))));
+    let in_errands = TodoListQuery { filter: TodoFilter { list_id: Some(errands.id), ..owned.clone() }, ..Default::default() };
+    assert_eq!(ids(list_all(repo, in_errands.clone()).await), vec![filed.id]);
+
+    // Archiving a list takes it, and its todos, out of the everyday listings.
+    let archived = repo.update_list(errands.id, None, Some(true)).await.unwrap();
+    assert_eq!(archived, TodoList { archived: true, ..errands.clone() });
+    assert_eq!(repo.get_lists(owner.id, false).await, Ok(vec![inbox.clone()]));
+    assert_eq!(repo.get_lists(owner.id, true).await, Ok(vec![inbox.clone(), archived.clone()]));
+    assert_eq!(ids(list_all(repo, all_owned.clone()).await), vec![second.id, fourth.id]);
+    assert_eq!(ids(list_all(repo, in_errands).await), vec![filed.id]);
+    let exported: Vec<Todo> = repo.export(owner.id).map(Result::unwrap).collect().await;
+    assert_eq!(ids(exported), vec![second.id, fourth.id, filed.id]);
+    let last_modified = repo.last_modified(owner.id).await.unwrap();
+    assert_eq!(last_modified.keys().copied().collect::<Vec<_>>(), vec![second.id, fourth.id, filed.id]);
+    assert_eq!(Some(last_modified[&filed.id]), repo.get_history(filed.id).await.unwrap().last().map(|event| event.occurred_at));
+    // A UID a calendar app gave one todo moves to another it is given to.
+    let uid = format!("behaviour-{}@example.com", nonce);
+    assert_eq!(repo.set_uid(filed.id, &uid).await, Ok(()));
+    assert_eq!(repo.get_uids(owner.id).await, Ok(BTreeMap::from([(filed.id, uid.clone())])));
+    assert_eq!(repo.set_uid(second.id, &uid).await, Ok(()));
+    assert_eq!(repo.get_uids(owner.id).await, Ok(BTreeMap::from([(second.id, uid.clone())])));
+    assert_eq!(repo.set_uid(i64::MAX, &uid).await, Err(TodoRepoError::NotFound(i64::MAX)));
+
+    // Deleting a list puts the todos in it into the trash, where they are in no list.
+    assert_eq!(repo.delete_list(Some(owner.id), errands.id).await, Ok(archived));
+    assert_eq!(repo.get_list(errands.id).await, Err(TodoRepoError::ListNotFound(errands.id)));
+    assert_eq!(repo.get(filed.id).await, Err(TodoRepoError::NotFound(filed.id)));
+    let trashed = repo.get_trashed(filed.id).await.unwrap();
+    assert_eq!((trashed.list_id, trashed.deleted_at.is_some()), (None, true));
+    let deleted = repo.get_history(filed.id).await.unwrap().pop().unwrap();
+    assert_eq!((deleted.kind, deleted.actor_id), (TodoEventKind::Deleted, Some(owner.id)));
+
+    // Tagging bumps the version, unless the todo already had the tag.
+    let tagged = repo.tag(None, second.id, Some(2), "urgent").await.unwrap();
+    assert_eq!((tagged.tags.as_slice(), tagged.version), (["urgent".to_string()].as_slice(), 3));
+    assert_eq!(repo.tag(None, second.id, None, "urgent").await, Ok(tagged.clone()));
+    assert_eq!(repo.tag(None, second.id, Some(2), "late").await, Err(TodoRepoError::VersionMismatch(second.id)));
+    let tagged = repo.tag(None, second.id, None, "Home").await.unwrap();
+    assert_eq!(tagged.tags, ["Home", "urgent"]);
+    let fourth = repo.tag(None, fourth.id, None, "Home").await.unwrap();
+    assert_eq!(repo.get(second.id).await, Ok(tagged.clone()));
+
+    // Listings carry the tags, and can ask for todos with any or all of several.
+    let tagged_with = |tags: &[&str], tag_match| TodoListQuery {
+        filter: TodoFilter { tags: tags.iter().map(|tag| tag.to_string()).collect(), tag_match, ..owned.clone() },
+        ..Default::default()
+    };
+    assert_eq!(list_all(repo, tagged_with(&["Home", "urgent"], TagMatch::Any)).await, vec![tagged.clone(), fourth.clone()]);
+    assert_eq!(list_all(repo, tagged_with(&["Home", "urgent"], TagMatch::All)).await, vec![tagged.clone()]);
+    assert!(list_all(repo, tagged_with(&["home"], TagMatch::Any)).await.is_empty());
+
+    let untagged = repo.untag(None, second.id, None, "urgent").await.unwrap();
+    assert_eq!((untagged.tags.as_slice(), untagged.version), (["Home".to_string()].as_slice(), 5));
+    assert_eq!(repo.untag(None, second.id, None, "urgent").await, Ok(untagged));
+    assert!(matches!(repo.tag(None, unowned.id, None, "urgent").await, Err(TodoRepoError::Invalid(_))));
+    assert_eq!(repo.delete(None, fourth.id, None).await.map(|todo| todo.id), Ok(fourth.id));
+
+    // Todos can have a due date and a priority, and show up as overdue or upcoming until they are done.
+    let start = now().assume_utc();
+    let due = |title: &str, due_at| CreateTodo { due_at: Some(due_at), ..CreateTodo::new(format!("Behaviour {} {}", nonce, title), "") };
+    let overdue = repo.create(Some(owner.id), CreateTodo { priority: TodoPriority::Urgent, ..due("g", start - time::Duration::days(1)) }).await.unwrap();
+    assert_eq!((overdue.due_at, overdue.priority, overdue.completed_at), (Some(start - time::Duration::days(1)), TodoPriority::Urgent, None));
+    let soon = repo.create(Some(owner.id), due("h", start + time::Duration::hours(1))).await.unwrap();
+    let later = repo.create(Some(owner.id), due("i", start + time::Duration::days(30))).await.unwrap();
+    assert_eq!(repo.get(soon.id).await, Ok(soon.clone()));
+
+    let overdue_query = TodoDueQuery { due_from: None, due_before: start, limit: 10, owner_id: Some(owner.id) };
+    let upcoming_query = TodoDueQuery { due_from: Some(start), due_before: start + time::Duration::weeks(1), ..overdue_query.clone() };
+    assert_eq!(repo.get_due(&overdue_query).await, Ok(vec![overdue.clone()]));
+    assert_eq!(repo.get_due(&upcoming_query).await, Ok(vec![soon.clone()]));
+    let all_due = TodoDueQuery { due_before: start + time::Duration::days(31), ..overdue_query.clone() };
+    assert_eq!(ids(repo.get_due(&all_due).await.unwrap()), vec![overdue.id, soon.id, later.id]);
+
+    let rescheduled = repo.update(None, later.id, None, &UpdateTodo {
+        due_at: Some(Some(start - time::Duration::hours(2))),
+        priority: Some(TodoPriority::Low),
+        ..Default::default()
+    }).await.unwrap();
+    assert_eq!(rescheduled, Todo { due_at: Some(start - time::Duration::hours(2)), priority: TodoPriority::Low, version: 2, ..later.clone() });
+    assert_eq!(ids(repo.get_due(&overdue_query).await.unwrap()), vec![overdue.id, later.id]);
+
+    // Completing a todo records when, doing so again keeps the first time, and reopening it clears it.
+    let completed = repo.update(None, overdue.id, None, &UpdateTodo { done: Some(true), ..Default::default() }).await.unwrap();
+    let completed_at = completed.completed_at.unwrap();
+    assert!(completed_at >= start);
+    assert_eq!(repo.get_due(&overdue_query).await, Ok(vec![rescheduled.clone()]));
+    let recompleted = repo.patch(None, overdue.id, None, &TodoPatch::Merge(serde_json::json!({ "title": "Done twice", "done": true })), false).await.unwrap();
+    assert_eq!(recompleted.completed_at, Some(completed_at));
+    let reopened = repo.update(None, overdue.id, None, &UpdateTodo { done: Some(false), due_at: Some(None), ..Default::default() }).await.unwrap();
+    assert_eq!((reopened.completed_at, reopened.due_at), (None, None));
+    assert_eq!(repo.get_due(&overdue_query).await, Ok(vec![rescheduled]));
+
+    // Marking a repeating todo done hands its rule on to the next instance, tags and all, at the same local time across DST.
+    let weekly = || Some(RecurrenceSpec { rule: "FREQ=WEEKLY;BYDAY=MO,TH;COUNT=3".to_string(), time_zone: "Europe/Berlin".to_string(), starts_at: None });
+    let repeating = repo.create(Some(owner.id), CreateTodo { recurrence: weekly(), ..due("j", time::macros::datetime!(2026-10-19 07:00 UTC)) }).await.unwrap();
+    assert!(repeating.recurrence.is_some());
+    let repeating = repo.tag(None, repeating.id, None, "weekly").await.unwrap();
+    let next_of = |done: Todo| {
+        let query = all_owned.clone();
+        async move {
+            let open: Vec<Todo> = list_all(repo, query).await.into_iter().filter(|todo| todo.title == done.title && !todo.done).collect();
+            (done, open)
+        }
+    };
+    let done = UpdateTodo { done: Some(true), ..Default::default() };
+    let (repeated, open) = next_of(repo.update(None, repeating.id, None, &done).await.unwrap()).await;
+    assert_eq!((repeated.done, repeated.recurrence), (true, None));
+    assert_eq!(open.len(), 1);
+    let next = &open[0];
+    assert_eq!((next.due_at, next.tags.as_slice(), next.recurrence.clone()), (Some(time::macros::datetime!(2026-10-22 07:00 UTC)), ["weekly".to_string()].as_slice(), repeating.recurrence.clone()));
+    assert_eq!(repo.update(None, next.id, None, &UpdateTodo { due_at: Some(None), ..Default::default() }).await.map(|_| ()).map_err(|error| matches!(error, TodoRepoError::Invalid(_))), Err(true));
+    let (_, open) = next_of(repo.patch(None, next.id, None, &TodoPatch::Merge(serde_json::json!({ "done": true })), false).await.unwrap()).await;
+    assert_eq!(open.iter().map(|todo| todo.due_at).collect::<Vec<_>>(), vec![Some(time::macros::datetime!(2026-10-26 08:00 UTC))]);
+    let (_, open) = next_of(repo.update(None, open[0].id, None, &done).await.unwrap()).await;
+    assert!(open.is_empty());
+    // So does a repeating subtask completed along with its parent.
+    let chores = repo.create(Some(owner.id), CreateTodo::new(format!("Behaviour {} chores", nonce), "")).await.unwrap();
+    let bins = CreateTodo { parent_id: Some(chores.id), recurrence: weekly(), ..due("bins", time::macros::datetime!(2026-10-22 07:00 UTC)) };
+    let bins = repo.create(Some(owner.id), bins).await.unwrap();
+    repo.update(None, chores.id, None, &UpdateTodo { done: Some(true), complete_subtasks: true, ..Default::default() }).await.unwrap();
+    let (bins, open) = next_of(repo.get(bins.id).await.unwrap()).await;
+    assert_eq!((bins.done, bins.recurrence), (true, None));
+    assert_eq!(open.iter().map(|todo| (todo.parent_id, todo.due_at)).collect::<Vec<_>>(), vec![(Some(chores.id), Some(time::macros::datetime!(2026-10-26 08:00 UTC)))]);
+    assert!(matches!(repo.create(Some(owner.id), CreateTodo { recurrence: weekly(), ..CreateTodo::new("Undated", "") }).await, Err(TodoRepoError::Invalid(_))));
+    let hourly = Some(RecurrenceSpec { rule: "FREQ=HOURLY".to_string(), ..weekly().unwrap() });
+    assert!(matches!(repo.create(Some(owner.id), CreateTodo { recurrence: hourly, ..due("k", start) }).await, Err(TodoRepoError::Invalid(_))));
+
+    // Todos can have subtasks, nested as deeply as need be, but never inside themselves.
+    let subtask = |title: &str, parent: &Todo| CreateTodo { parent_id: Some(parent.id), ..CreateTodo::new(format!("Behaviour {} {}", nonce, title), "") };
+    let project = repo.create(Some(owner.id), CreateTodo::new(format!("Behaviour {} project", nonce), "")).await.unwrap();
+    let step = repo.create(Some(owner.id), subtask("step", &project)).await.unwrap();
+    let detail = repo.create(Some(owner.id), subtask("detail", &step)).await.unwrap();
+    let aside = repo.create(Some(owner.id), subtask("aside", &project)).await.unwrap();
+    assert_eq!(detail.parent_id, Some(step.id));
+    assert_eq!(repo.get_subtree(project.id).await, Ok(vec![project.clone(), step.clone(), detail.clone(), aside.clone()]));
+    assert_eq!(repo.get_subtree(step.id).await, Ok(vec![step.clone(), detail.clone()]));
+    assert_eq!(repo.get_subtree(0).await, Err(TodoRepoError::NotFound(0)));
+
+    let reparent = |parent: &Todo| UpdateTodo { parent_id: Some(Some(parent.id)), ..Default::default() };
+    assert!(matches!(repo.update(None, project.id, None, &reparent(&detail)).await, Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(repo.update(None, project.id, None, &reparent(&project)).await, Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(repo.create(None, subtask("stranger", &project)).await, Err(TodoRepoError::Conflict(_))));
+    let aside = repo.update(None, aside.id, None, &reparent(&step)).await.unwrap();
+    assert_eq!((aside.parent_id, aside.version), (Some(step.id), 2));
+
+    // Completing a todo can complete its subtasks too; reopening it leaves them alone.
+    let complete = |complete_subtasks| UpdateTodo { done: Some(true), complete_subtasks, ..Default::default() };
+    repo.update(None, detail.id, None, &complete(false)).await.unwrap();
+    let step = repo.update(None, step.id, None, &complete(false)).await.unwrap();
+    assert!(!repo.get(aside.id).await.unwrap().done);
+    let project = repo.update(None, project.id, None, &complete(true)).await.unwrap();
+    let subtree = repo.get_subtree(project.id).await.unwrap();
+    assert!(subtree.iter().all(|todo| todo.done && todo.completed_at.is_some()));
+    assert_eq!(subtree.iter().map(|todo| todo.version).collect::<Vec<_>>(), vec![project.version, step.version, 2, aside.version + 1]);
+    let completed = repo.get_history(aside.id).await.unwrap().pop().unwrap();
+    assert_eq!(completed.changes.keys().collect::<Vec<_>>(), vec!["completed_at", "done", "version"]);
+    let project = repo.update(None, project.id, None, &UpdateTodo { done: Some(false), ..Default::default() }).await.unwrap();
+    assert!(repo.get(step.id).await.unwrap().done);
+
+    // Deleting a todo deletes its subtasks, and theirs.
+    assert_eq!(repo.delete(None, project.id, None).await.map(|todo| todo.id), Ok(project.id));
+    for todo in [step, detail, aside] {
+        assert_eq!(repo.get(todo.id).await, Err(TodoRepoError::NotFound(todo.id)));
+    }
+
+    // Any todo can wait on others, as long as nothing ends up waiting on itself.
+    let create = |title: &str| CreateTodo::new(format!("Behaviour {} {}", nonce, title), "");
+    let design = repo.create(Some(owner.id), create("design")).await.unwrap();
+    let build = repo.create(Some(owner.id), create("build")).await.unwrap();
+    let ship = repo.create(Some(owner.id), create("ship")).await.unwrap();
+    let build = repo.block(None, build.id, Some(1), design.id).await.unwrap();
+    assert_eq!((build.blocked_by.clone(), build.version), (vec![design.id], 2));
+    assert_eq!(repo.block(None, build.id, None, design.id).await, Ok(build.clone()));
+    repo.block(None, ship.id, None, build.id).await.unwrap();
+    let ship = repo.block(None, ship.id, None, design.id).await.unwrap();
+    assert_eq!(ship.blocked_by, vec![design.id, build.id]);
+    assert!(matches!(repo.block(None, design.id, None, ship.id).await, Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(repo.block(None, design.id, None, design.id).await, Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(repo.block(None, design.id, None, 0).await, Err(TodoRepoError::Conflict(_))));
+    assert_eq!(repo.get(design.id).await, Ok(design.clone()));
+
+    // Only todos whose blockers are all done are actionable, and only those can be marked done without forcing it.
+    let actionable = |actionable| TodoListQuery { filter: TodoFilter { actionable: Some(actionable), ..owned.clone() }, ..Default::default() };
+    let titles = |todos: Vec<Todo>| todos.into_iter().map(|todo| todo.title).filter(|title| title.starts_with(&format!("Behaviour {} ", nonce))).collect::<Vec<_>>();
+    assert!(titles(list_all(repo, actionable(true)).await).contains(&design.title));
+    assert!(!titles(list_all(repo, actionable(true)).await).contains(&build.title));
+    let complete = |force| UpdateTodo { done: Some(true), force, ..Default::default() };
+    assert!(matches!(repo.update(None, build.id, None, &complete(false)).await, Err(TodoRepoError::Conflict(_))));
+    assert!(matches!(repo.patch(None, build.id, None, &TodoPatch::Merge(serde_json::json!({ "done": true })), false).await, Err(TodoRepoError::Conflict(_))));
+    // So can a todo's subtasks along with it, though they may wait on each other.
+    let release = repo.create(Some(owner.id), create("release")).await.unwrap();
+    let notes = repo.create(Some(owner.id), CreateTodo { parent_id: Some(release.id), ..create("notes") }).await.unwrap();
+    let announce = repo.create(Some(owner.id), CreateTodo { parent_id: Some(release.id), ..create("announce") }).await.unwrap();
+    repo.block(None, announce.id, None, notes.id).await.unwrap();
+    repo.block(None, notes.id, None, design.id).await.unwrap();
+    let complete_all = |force| UpdateTodo { done: Some(true), complete_subtasks: true, force, ..Default::default() };
+    assert_eq!(repo.update(None, release.id, None, &complete_all(false)).await, Err(blocked(notes.id, &[design.id])));
+    assert!(!repo.get(release.id).await.unwrap().done);
+    repo.update(None, release.id, None, &complete_all(true)).await.unwrap();
+    assert!(repo.get(notes.id).await.unwrap().done && repo.get(announce.id).await.unwrap().done);
+    repo.update(None, design.id, None, &complete(false)).await.unwrap();
+    assert!(titles(list_all(repo, actionable(true)).await).contains(&build.title));
+    assert_eq!(titles(list_all(repo, actionable(false)).await), vec![ship.title.clone()]);
+    let ship = repo.patch(None, ship.id, None, &TodoPatch::Merge(serde_json::json!({ "done": true })), true).await.unwrap();
+    assert!(ship.done);
+    // Once it is done, it can be edited without forcing anything, though it is still blocked.
+    let renamed = UpdateTodo { title: Some(format!("{} today", ship.title)), ..Default::default() };
+    repo.update(None, ship.id, None, &renamed).await.unwrap();
+    let ship = repo.patch(None, ship.id, None, &TodoPatch::Merge(serde_json::json!({ "done": true, "description": "By Friday" })), false).await.unwrap();
+    assert_eq!((ship.done, ship.description.as_str()), (true, "By Friday"));
+    let build = repo.unblock(None, build.id, None, design.id).await.unwrap();
+    assert_eq!((build.blocked_by.clone(), build.version), (Vec::new(), 3));
+    assert_eq!(repo.unblock(None, build.id, None, design.id).await, Ok(build.clone()));
+
+    // Deleting a blocker unblocks whatever it was blocking, until it is restored.
+    let trashed = repo.delete(None, build.id, None).await.unwrap();
+    assert_eq!(repo.get(ship.id).await.unwrap().blocked_by, vec![design.id]);
+    repo.restore(None, build.id, Some(trashed.version)).await.unwrap();
+    assert_eq!(repo.get(ship.id).await.unwrap().blocked_by, vec![design.id, build.id]);
+    repo.delete(None, build.id, None).await.unwrap();
+
+    // Imports are all or nothing: a todo nobody owns cannot be tagged, so it is not created either.
+    let uid = format!("imported-{}@example.com", nonce);
+    let imported = repo.import(Some(owner.id), &create("imported"), true, &["imported"], Some(&uid)).await.unwrap();
+    assert_eq!((imported.done, imported.tags.as_slice()), (true, ["imported".to_string()].as_slice()));
+    assert_eq!(repo.get_uids(owner.id).await.unwrap().get(&imported.id), Some(&uid));
+    assert_eq!(repo.import(None, &create("half imported"), true, &["imported"], None).await, Err(untaggable()));
+    assert!(!titles(list_all(repo, TodoListQuery::default()).await).contains(&format!("Behaviour {} half imported", nonce)));
+
+    // Todos list in the order they were created until moved; a move rewrites only the moved todo.
+    let (one, two, three) = (repo.create(Some(owner.id), create("rank 1")).await.unwrap(), repo.create(Some(owner.id), create("rank 2")).await.unwrap(), repo.create(Some(owner.id), create("rank 3")).await.unwrap());
+    let ranked = || async {
+        let todos = list_all(repo, TodoListQuery { filter: owned.clone(), ..Default::default() }).await;
+        todos.into_iter().filter(|todo| todo.title.starts_with(&format!("Behaviour {} rank", nonce))).map(|todo| todo.id).collect::<Vec<_>>()
+    };
+    assert_eq!(ranked().await, vec![one.id, two.id, three.id]);
+    let moved = repo.reorder(None, three.id, Some(1), TodoPlacement::Before(one.id)).await.unwrap();
+    assert_eq!(moved, Todo { position: moved.position.clone(), version: 2, ..three.clone() });
+    assert_eq!(ranked().await, vec![three.id, one.id, two.id]);
+    repo.reorder(None, one.id, None, TodoPlacement::After(two.id)).await.unwrap();
+    repo.reorder(None, two.id, None, TodoPlacement::Before(one.id)).await.unwrap();
+    assert_eq!(ranked().await, vec![three.id, two.id, one.id]);
+    repo.reorder(None, three.id, None, TodoPlacement::After(two.id)).await.unwrap();
+    assert_eq!(ranked().await, vec![two.id, three.id, one.id]);
+    assert_eq!(repo.get(two.id).await.unwrap().version, 2);
+    assert_eq!(repo.reorder(None, one.id, Some(1), TodoPlacement::Before(two.id)).await, Err(TodoRepoError::VersionMismatch(one.id)));
+    assert!(matches!(repo.reorder(None, one.id, None, TodoPlacement::Before(one.id)).await, Err(TodoRepoError::Invalid(_))));
+    assert!(matches!(repo.reorder(None, one.id, None, TodoPlacement::After(unowned.id)).await, Err(TodoRepoError::Conflict(_))));
+    let later = repo.create(Some(owner.id), create("rank 4")).await.unwrap();
+    assert_eq!(ranked().await, vec![two.id, three.id, one.id, later.id]);
+
+    // Every write is kept in the todo's history, which outlives the todo, and writes that change nothing are left out.
+    let since = now();
+    let logged = repo.create(Some(owner.id), create("logged")).await.unwrap();
+    let audited = format!("Behaviour {} audited", nonce);
+    repo.update(Some(owner.id), logged.id, None, &UpdateTodo { title: Some(audited.clone()), ..Default::default() }).await.unwrap();
+    repo.tag(Some(owner.id), logged.id, None, "audited").await.unwrap();
+    repo.tag(Some(owner.id), logged.id, None, "audited").await.unwrap();
+    let child = repo.create(Some(owner.id), CreateTodo { parent_id: Some(logged.id), ..create("logged child") }).await.unwrap();
+    repo.delete(None, logged.id, None).await.unwrap();
+
+    let history = repo.get_history(logged.id).await.unwrap();
+    let kinds = |events: &[TodoEvent]| events.iter().map(|event| (event.todo_id, event.kind)).collect::<Vec<_>>();
+    assert_eq!(kinds(&history), [TodoEventKind::Created, TodoEventKind::Updated, TodoEventKind::Updated, TodoEventKind::Deleted].map(|kind| (logged.id, kind)));
+    assert_eq!(history.iter().map(|event| event.actor_id).collect::<Vec<_>>(), vec![Some(owner.id), Some(owner.id), Some(owner.id), None]);
+    assert_eq!(history[0].changes["title"], TodoFieldChange { before: serde_json::Value::Null, after: serde_json::json!(logged.title) });
+    assert_eq!(history[1].changes.keys().collect::<Vec<_>>(), vec!["title", "version"]);
+    assert_eq!(history[2].changes["tags"], TodoFieldChange { before: serde_json::json!([]), after: serde_json::json!(["audited"]) });
+    assert_eq!(history[3].changes.keys().collect::<Vec<_>>(), vec!["deleted_at", "version"]);
+    assert_eq!(kinds(&repo.get_history(child.id).await.unwrap()), vec![(child.id, TodoEventKind::Created), (child.id, TodoEventKind::Deleted)]);
+    assert_eq!(repo.get_history(0).await, Ok(Vec::new()));
+
+    // The audit trail has what happened to any of the owner's todos, from a given time on, a page at a time.
+    let audit = |after, limit| TodoAuditQuery { since: Some(since), after, limit, owner_id: Some(owner.id) };
+    let trail = repo.get_audit(&audit(None, MAX_PAGE_LIMIT)).await.unwrap();
+    assert_eq!(kinds(&trail), vec![
+        (logged.id, TodoEventKind::Created),
+        (logged.id, TodoEventKind::Updated),
+        (logged.id, TodoEventKind::Updated),
+        (child.id, TodoEventKind::Created),
+        (logged.id, TodoEventKind::Deleted),
+        (child.id, TodoEventKind::Deleted),
+    ]);
+    assert_eq!(repo.get_audit(&audit(Some(trail[2].id), 2)).await, Ok(trail[3..5].to_vec()));
+
+    // Deleted todos wait in the trash, most recently deleted first, where nothing else can reach them.
+    let in_trash = TodoTrashQuery { limit: MAX_PAGE_LIMIT, owner_id: Some(owner.id) };
+    let trash = repo.get_trash(&in_trash).await.unwrap();
+    assert_eq!(ids(trash[..2].to_vec()), vec![logged.id, child.id]);
+    assert!(ids(trash.clone()).contains(&first.id));
+    assert_eq!(repo.get_trashed(logged.id).await, Ok(trash[0].clone()));
+    assert_eq!(repo.get_trashed(second.id).await, Err(TodoRepoError::NotFound(second.id)));
+    assert!(matches!(repo.block(None, second.id, None, first.id).await, Err(TodoRepoError::Conflict(_))));
+    assert!(matches!(repo.create(Some(owner.id), CreateTodo { parent_id: Some(logged.id), ..create("orphan") }).await, Err(TodoRepoError::Conflict(_))));
+
+    // Restoring a todo brings back the subtasks that went with it, but not a subtask on its own while its parent is still trashed.
+    assert!(matches!(repo.restore(None, child.id, None).await, Err(TodoRepoError::Conflict(_))));
+    assert_eq!(repo.restore(None, logged.id, Some(1)).await, Err(TodoRepoError::VersionMismatch(logged.id)));
+    let restored = repo.restore(Some(owner.id), logged.id, None).await.unwrap();
+    assert_eq!((restored.title.as_str(), restored.deleted_at, restored.version), (audited.as_str(), None, trash[0].version + 1));
+    assert_eq!(repo.get(child.id).await.map(|todo| todo.deleted_at), Ok(None));
+    let event = repo.get_history(logged.id).await.unwrap().pop().unwrap();
+    assert_eq!(event.kind, TodoEventKind::Restored);
+    assert_eq!(event.changes.keys().collect::<Vec<_>>(), vec!["deleted_at", "version"]);
+    assert_eq!(repo.restore(None, logged.id, None).await, Err(TodoRepoError::NotFound(logged.id)));
+
+    repo.delete(None, child.id, None).await.unwrap();
+    repo.delete(None, logged.id, None).await.unwrap();
+    repo.restore(None, logged.id, None).await.unwrap();
+    assert_eq!(repo.get(child.id).await, Err(TodoRepoError::NotFound(child.id)));
+
+    // Purging deletes whatever went into the trash before the cutoff for good, leaving its history behind.
+    let cutoff = now();
+    repo.delete(None, logged.id, None).await.unwrap();
+    assert!(repo.purge_trash(cutoff).await.unwrap() >= 2);
+    assert_eq!(ids(repo.get_trash(&in_trash).await.unwrap()), vec![logged.id]);
+    assert_eq!(repo.get_trashed(child.id).await, Err(TodoRepoError::NotFound(child.id)));
+    let event = repo.get_history(child.id).await.unwrap().pop().unwrap();
+    assert_eq!((event.kind, event.actor_id, event.changes["title"].after.clone()), (TodoEventKind::Purged, None, serde_json::Value::Null));
+
+    // Every change anyone could see goes into a feed, which can be watched as it happens, or read from any point.
+    let mark = all_changes(repo, owner.id, None).await.last().map(|change| change.id);
+    let mut watching = repo.watch_changes();
+    let watched = repo.create(Some(owner.id), create("watched")).await.unwrap();
+    repo.update(None, watched.id, None, &UpdateTodo { priority: Some(TodoPriority::Low), ..Default::default() }).await.unwrap();
+    repo.tag(None, watched.id, None, "watched").await.unwrap();
+    repo.tag(None, watched.id, None, "watched").await.unwrap();
+    repo.delete(None, watched.id, None).await.unwrap();
+    repo.restore(None, watched.id, None).await.unwrap();
+    let changes: Vec<_> = settled_changes(repo, owner.id, mark, |changes| changes.iter().filter(|change| change.todo_id == watched.id).count() == 5).await
+        .into_iter()
+        .filter(|change| change.todo_id == watched.id)
+        .collect();
+    assert_eq!(changes.iter().map(|change| (change.todo_id, change.kind)).collect::<Vec<_>>(), [
+        TodoChangeKind::Created,
+        TodoChangeKind::Updated,
+        TodoChangeKind::Updated,
+        TodoChangeKind::Deleted,
+        TodoChangeKind::Created,
+    ].map(|kind| (watched.id, kind)));
+    let mut heard = Vec::new();
+    while heard.len() < changes.len() {
+        let change = tokio::time::timeout(Duration::from_secs(5), watching.recv()).await.unwrap().unwrap();
+        // Watchers may also hear of changes from just before they started watching.
+        if change.todo_id == watched.id {
+            heard.push(change);
+        }
+    }
+    assert_eq!(heard, changes);
+
+    // Exports hold every todo outside the trash, as it is now, tags and blockers and all.
+    repo.block(None, watched.id, None, second.id).await.unwrap();
+    let exported: Vec<Todo> = repo.export(owner.id).map(Result::unwrap).collect().await;
+    let mut listed = list_all(repo, all_owned.clone()).await;
+    listed.sort_by_key(|todo| todo.id);
+    assert_eq!(exported, listed);
+    assert!(exported.iter().any(|todo| todo.id == watched.id && todo.tags == ["watched"] && todo.blocked_by == [second.id]));
+
+    // Emails are unique, and deleting a user deletes their lists and todos.
+    let (other, _) = sign_up(repo).await;
+    let renamed = repo.update_user(owner.id, Some("Renamed".to_string()), None).await.unwrap();
+    assert_eq!(renamed, User { name: "Renamed".to_string(), ..owner.clone() });
+    assert!(matches!(repo.update_user(owner.id, None, Some(other.email.clone())).await, Err(TodoRepoError::Conflict(_))));
+    assert!(matches!(repo.create_user("Copycat".to_string(), other.email.clone(), hash_token("copycat")).await, Err(TodoRepoError::Conflict(_))));
+    assert_eq!(repo.delete_user(owner.id).await, Ok(renamed));
+    assert_eq!(repo.get_user(owner.id).await, Err(TodoRepoError::UserNotFound(owner.id)));
+    assert_eq!(repo.get(second.id).await, Err(TodoRepoError::NotFound(second.id)));
+    settled_changes(repo, owner.id, mark, |changes| changes.iter().any(|change| (change.todo_id, change.kind) == (second.id, TodoChangeKind::Deleted))).await;
+    assert_eq!(repo.get_list(inbox.id).await, Err(TodoRepoError::ListNotFound(inbox.id)));
+    assert_eq!(repo.get_history(logged.id).await.unwrap().len(), 8);
+    assert!(list_all(repo, all_owned).await.is_empty());
+    assert_eq!(repo.authenticate(&hash_token(&token)).await, Ok(None));
+    assert_eq!(repo.feed_owner(&hash_token(&feed_token)).await, Ok(None));
+}
+
+/// The changes to `owner_id`'s todos after the change `after`, however many pages they take.
+async fn all_changes<R: TodoRepo>(repo: &R, owner_id: i64, after: Option<i64>) -> Vec<TodoChange> {
+    let mut query = TodoChangeQuery { after, limit: MAX_PAGE_LIMIT, owner_id: Some(owner_id) };
+    let mut changes = Vec::new();
+    loop {
+        let page = repo.get_changes(&query).await.unwrap();
+        match page.last() {
+            Some(last) => query.after = Some(last.id),
+            None => return changes,
+        }
+        changes.extend(page);
+    }
+}
+
+/// The changes to `owner_id`'s todos after the change `after`, once `settled` holds for them, as it does once transactions older than theirs are over.
+async fn settled_changes<R: TodoRepo>(repo: &R, owner_id: i64, after: Option<i64>, settled: impl Fn(&[TodoChange]) -> bool) -> Vec<TodoChange> {
+    let wait = async {
+        loop {
+            let changes = all_changes(repo, owner_id, after).await;
+            if settled(&changes) {
+                return changes;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+
+    tokio::time::timeout(Duration::from_secs(5), wait).await.expect("the changes never settled")
+}
+
+async fn list_all<R: TodoRepo>(repo: &R, mut query: TodoListQuery) -> Vec<Todo> {
+    let mut todos = Vec::new();
+    loop {
+        let page = repo.get_all(&query).await.unwrap();
+        todos.extend(page.items);
+        match page.next_cursor {
+            Some(cursor) => query.cursor = Some(cursor),
+            None => return todos,
+        }
+    }
+}
+
+#[tokio::test]
+async fn todo_repo_postgres_behaves() {
+    check_todo_repo_behaviour(&TodoRepoPostgres::new().await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn concurrent_reparenting_never_closes_a_loop() {
+    let repo = TodoRepoPostgres::new().await.unwrap();
+    let (owner, _) = sign_up(&repo).await;
+    let under = |parent: &Todo| UpdateTodo { parent_id: Some(Some(parent.id)), ..Default::default() };
+
+    for _ in 0..20 {
+        let chicken = repo.create(Some(owner.id), CreateTodo::new("Chicken", "")).await.unwrap();
+        let egg = repo.create(Some(owner.id), CreateTodo::new("Egg", "")).await.unwrap();
+        let (chicken_under_egg, egg_under_chicken) = (under(&egg), under(&chicken));
+        let (chicken_moved, egg_moved) = tokio::join!(
+            repo.update(Some(owner.id), chicken.id, None, &chicken_under_egg),
+            repo.update(Some(owner.id), egg.id, None, &egg_under_chicken),
+        );
+        // One of them goes first, and the other is refused for the loop it would make, rather than deadlocking.
+        assert!(matches!((chicken_moved, egg_moved), (Ok(_), Err(TodoRepoError::Invalid(_))) | (Err(TodoRepoError::Invalid(_)), Ok(_))));
+    }
+}
+
+#[tokio::test]
+async fn changes_wait_for_older_transactions() {
+    let repo = TodoRepoPostgres::new().await.unwrap();
+    let (owner, _) = sign_up(&repo).await;
+
+    // A todo created first but committed last would otherwise turn up behind a reader that had read on past the other.
+    let mut slow = repo.pool.begin().await.unwrap();
+    let slow_id: i64 = sqlx::query_scalar("INSERT INTO todos (title, description, owner_id) VALUES ('Slow', '', $1) RETURNING id")
+        .bind(owner.id)
+        .fetch_one(&mut *slow).await.unwrap();
+    let quick = repo.create(Some(owner.id), CreateTodo::new("Quick", "")).await.unwrap();
+    assert_eq!(all_changes(&repo, owner.id, None).await, []);
+    assert_eq!(repo.last_change(owner.id).await, Ok(None));
+
+    slow.commit().await.unwrap();
+    let changes = settled_changes(&repo, owner.id, None, |changes| changes.len() == 2).await;
+    assert_eq!(changes.iter().map(|change| change.todo_id).collect::<Vec<_>>(), [slow_id, quick.id]);
+    assert_eq!(all_changes(&repo, owner.id, Some(changes[0].id)).await, changes[1..]);
+    assert_eq!(repo.last_change(owner.id).await, Ok(Some(changes[1].id)));
+}
+
+#[tokio::test]
+async fn sql_writes_reach_watchers() {
+    let repo = TodoRepoPostgres::new().await.unwrap();
+    let mut watching = repo.watch_changes();
+    let todo = repo.create(None, CreateTodo::new("Edit me by hand", "")).await.unwrap();
+
+    // Writes from outside the app, such as a migration or a psql session, are seen too.
+    sqlx::query("UPDATE todos SET done = true WHERE id = $1").bind(todo.id).execute(&repo.pool).await.unwrap();
+    let mut heard = Vec::new();
+    while heard.len() < 2 {
+        let change = tokio::time::timeout(Duration::from_secs(5), watching.recv()).await.unwrap().unwrap();
+        if change.todo_id == todo.id {
+            heard.push(change.kind);
+        }
+    }
+    assert_eq!(heard, [TodoChangeKind::Created, TodoChangeKind::Updated]);
+}
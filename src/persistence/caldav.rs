@@ -0,0 +1,487 @@
+//!
+//! A minimal CalDAV server (RFC 4791), for task apps to sync todos with.
+//! Each of a user's lists that is not archived is a calendar collection,
+//! holding a `VTODO` resource for each todo in it, named after the todo's
+//! UID and with the todo's version as its ETag, as in the rest of the API.
+//! Reads go through the same `TodoRepo` methods as everything else, and
+//! `PUT` and `DELETE` through create, update and delete, so that changes
+//! made either way show up in the other.
+//!
+//! `/dav/` is the user's principal, `/dav/lists/` their calendar home, and
+//! `/dav/lists/:id/` and `/dav/lists/:id/:uid.ics` a list and a todo in it.
+//! Task apps sign in with HTTP Basic authentication, giving the API token
+//! as the password; the user name is not looked at.
+//!
+
+use axum::{http::Method, response::Redirect};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+
+use super::*;
+
+const DAV: &str = "DAV:";
+const CALDAV: &str = "urn:ietf:params:xml:ns:caldav";
+
+const PRINCIPAL_HREF: &str = "/dav/";
+const HOME_HREF: &str = "/dav/lists/";
+
+/// The characters of a UID that go into a path as they are: those RFC 3986 leaves unreserved, and the `@` most UIDs have.
+const UID_SEGMENT: &AsciiSet = &NON_ALPHANUMERIC.remove(b'-').remove(b'.').remove(b'_').remove(b'~').remove(b'@');
+
+const ALLOWED_METHODS: &str = "OPTIONS, GET, HEAD, PUT, DELETE, PROPFIND, REPORT";
+
+///
+/// The user a CalDAV request was made by. Task apps ask for a user name and
+/// a password, so the API token goes in as the password of HTTP Basic
+/// authentication, though a bearer token will do as well.
+///
+pub(super) struct DavUser(User);
+
+#[async_trait]
+impl<R: UserRepo> FromRequestParts<R> for DavUser {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, repo: &R) -> Result<Self, Self::Rejection> {
+        let authorization = parts.headers.get(header::AUTHORIZATION).and_then(|value| value.to_str().ok()).unwrap_or_default();
+        let token = match authorization.strip_prefix("Basic ") {
+            Some(credentials) => base64::engine::general_purpose::STANDARD.decode(credentials.trim()).ok()
+                .and_then(|credentials| String::from_utf8(credentials).ok())
+                .and_then(|credentials| Some(credentials.split_once(':')?.1.to_string())),
+            None => authorization.strip_prefix("Bearer ").map(|token| token.trim().to_string()),
+        };
+        let user = match token {
+            Some(token) => repo.authenticate(&hash_token(&token)).await.map_err(IntoResponse::into_response)?,
+            None => None,
+        };
+
+        user.map(DavUser).ok_or_else(|| {
+            let mut response = json_error(StatusCode::UNAUTHORIZED, "sign in with your API token as the password");
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Basic realm=\"todos\", charset=\"UTF-8\""));
+            response
+        })
+    }
+}
+
+/// Where task apps that are only given the server's name look for CalDAV (RFC 6764).
+pub(super) async fn well_known() -> Redirect {
+    Redirect::permanent(PRINCIPAL_HREF)
+}
+
+pub(super) async fn principal<R: UserRepo>(DavUser(me): DavUser, method: Method, body: Bytes) -> Result<Response, TodoApiError> {
+    match method.as_str() {
+        "PROPFIND" => propfind(vec![DavResource::Principal(me)], &body),
+        method => Ok(other_method(method)),
+    }
+}
+
+pub(super) async fn home<R: UserRepo + TodoListRepo>(DavUser(me): DavUser, State(repo): State<R>, method: Method, headers: HeaderMap, body: Bytes) -> Result<Response, TodoApiError> {
+    match method.as_str() {
+        "PROPFIND" => {
+            let mut resources = vec![DavResource::Home];
+            if into_members(&headers) {
+                resources.extend(repo.get_lists(me.id, false).await?.into_iter().map(DavResource::Calendar));
+            }
+            propfind(resources, &body)
+        }
+        method => Ok(other_method(method)),
+    }
+}
+
+pub(super) async fn calendar<R: TodoRepo + UserRepo + TodoListRepo>(Path(list_id): Path<i64>, DavUser(me): DavUser, State(repo): State<R>, method: Method, headers: HeaderMap, body: Bytes) -> Result<Response, TodoApiError> {
+    if !matches!(method.as_str(), "PROPFIND" | "REPORT") {
+        return Ok(other_method(method.as_str()));
+    }
+    let list = open_list(&repo, list_id, &me).await?;
+
+    if method.as_str() == "REPORT" {
+        return report(&repo, &me, list_id, &body).await;
+    }
+    let mut resources = vec![DavResource::Calendar(list)];
+    if into_members(&headers) {
+        resources.extend(list_todos(&repo, &me, list_id).await?.into_iter().map(DavResource::Todo));
+    }
+
+    propfind(resources, &body)
+}
+
+///
+/// A todo as a calendar object. Writing one that is not there creates a
+/// todo in the list, which keeps the UID it was given, all in one go;
+/// writing one that is updates the todo, and tags or untags it to match its
+/// categories. Each of those is a write of its own, so a conflicting one
+/// partway through can leave the todo half updated, though never at the
+/// version that was read.
+///
+pub(super) async fn resource<R: TodoRepo + UserRepo + TodoListRepo>(
+    Path((list_id, name)): Path<(i64, String)>,
+    if_match: IfMatch,
+    DavUser(me): DavUser,
+    State(repo): State<R>,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Response, TodoApiError> {
+    if !matches!(method.as_str(), "GET" | "HEAD" | "PROPFIND" | "PUT" | "DELETE") {
+        return Ok(other_method(method.as_str()));
+    }
+    open_list(&repo, list_id, &me).await?;
+    let Some(uid) = name.strip_suffix(".ics") else {
+        return Ok(json_error(StatusCode::NOT_FOUND, "calendar objects are named after their UID, followed by .ics"));
+    };
+    let uids = repo.get_uids(me.id).await?;
+    let existing = find_todo(&repo, &me, list_id, uid, &uids).await?;
+
+    match (method.as_str(), existing) {
+        ("PUT", existing) => put_todo(&repo, &me, list_id, uid, existing, &if_match, &headers, &body, &uids).await,
+        (_, None) => Ok(json_error(StatusCode::NOT_FOUND, &format!("there is no todo with the UID {} in this list", uid))),
+        ("DELETE", Some(todo)) => {
+            repo.delete(Some(me.id), todo.id, if_match.expected_version(&todo)?).await?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+        ("PROPFIND", Some(todo)) => {
+            let todo = DavTodo::of(&repo, &me, todo, &uids).await?;
+            propfind(vec![DavResource::Todo(todo)], &body)
+        }
+        (_, Some(todo)) => {
+            let todo = DavTodo::of(&repo, &me, todo, &uids).await?;
+            Ok(([(header::CONTENT_TYPE, TodoFileFormat::Ics.content_type().to_string()), (header::ETAG, todo.etag)], todo.calendar).into_response())
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn put_todo<R: TodoRepo + TodoListRepo>(
+    repo: &R,
+    me: &User,
+    list_id: i64,
+    uid: &str,
+    existing: Option<Todo>,
+    if_match: &IfMatch,
+    headers: &HeaderMap,
+    body: &[u8],
+    uids: &BTreeMap<i64, String>,
+) -> Result<Response, TodoApiError> {
+    if existing.is_some() && headers.contains_key(header::IF_NONE_MATCH) {
+        return Ok(json_error(StatusCode::PRECONDITION_FAILED, &format!("there is already a todo with the UID {} in this list", uid)));
+    }
+    if existing.is_none() && matches!(if_match, IfMatch(Some(_))) {
+        return Ok(json_error(StatusCode::PRECONDITION_FAILED, &format!("there is no todo with the UID {} in this list", uid)));
+    }
+
+    let (body_uid, incoming) = ical::read_resource(body, |uid| todo_by_uid(uids, uid)).map_err(TodoRepoError::Invalid)?;
+    if body_uid != uid {
+        return Err(TodoRepoError::Invalid(format!("a calendar object must be named after its UID, {}", body_uid)).into());
+    }
+    if let Some(parent_id) = incoming.parent_id {
+        owned_todo(repo, parent_id, me).await?;
+    }
+    let tags = incoming.tags.iter().map(|tag| valid_tag(tag)).collect::<Result<Vec<_>, _>>()?;
+
+    let (status, todo) = match existing {
+        Some(todo) => {
+            let expected_version = if_match.expected_version(&todo)?;
+            let changes = UpdateTodo {
+                title: Some(incoming.title),
+                description: Some(incoming.description),
+                done: Some(incoming.done),
+                due_at: Some(incoming.due_at),
+                priority: Some(incoming.priority),
+                parent_id: Some(incoming.parent_id),
+                recurrence: Some(incoming.recurrence),
+                // Task apps know nothing of blockers, so they cannot be expected to mind them.
+                force: true,
+                ..Default::default()
+            };
+            (StatusCode::NO_CONTENT, repo.update(Some(me.id), todo.id, expected_version, &changes).await?)
+        }
+        None => {
+            if ical::todo_id(uid).is_some() {
+                return Err(TodoRepoError::Conflict(format!("UIDs such as {} are kept for the todos they are made from", uid)).into());
+            }
+            if let Some(id) = todo_by_uid(uids, uid) {
+                return Err(TodoRepoError::Conflict(format!("todo {}, in another list, already has the UID {}", id, uid)).into());
+            }
+            let spec = CreateTodo {
+                title: incoming.title,
+                description: incoming.description,
+                list_id: Some(list_id),
+                due_at: incoming.due_at,
+                priority: incoming.priority,
+                parent_id: incoming.parent_id,
+                recurrence: incoming.recurrence,
+            };
+            (StatusCode::CREATED, repo.import(Some(me.id), &spec, incoming.done, &tags, Some(uid)).await?)
+        }
+    };
+
+    let mut todo = todo;
+    for tag in todo.tags.clone() {
+        if !tags.contains(&tag.as_str()) {
+            todo = repo.untag(Some(me.id), todo.id, None, &tag).await?;
+        }
+    }
+    for tag in tags {
+        if !todo.tags.iter().any(|existing| existing == tag) {
+            todo = repo.tag(Some(me.id), todo.id, None, tag).await?;
+        }
+    }
+
+    Ok((status, [(header::ETAG, etag(&todo))]).into_response())
+}
+
+/// The id of the todo that goes by `uid`, whether a calendar app gave it that UID or it is the one made from its id.
+fn todo_by_uid(uids: &BTreeMap<i64, String>, uid: &str) -> Option<i64> {
+    uids.iter()
+        .find(|(_, known)| *known == uid)
+        .map(|(id, _)| *id)
+        .or_else(|| ical::todo_id(uid).filter(|id| !uids.contains_key(id)))
+}
+
+/// The todo in the list `list_id` that goes by `uid`, if there is one.
+async fn find_todo<R: TodoRepo>(repo: &R, me: &User, list_id: i64, uid: &str, uids: &BTreeMap<i64, String>) -> Result<Option<Todo>, TodoRepoError> {
+    let Some(id) = todo_by_uid(uids, uid) else {
+        return Ok(None);
+    };
+
+    match repo.get(id).await {
+        Ok(todo) if todo.owner_id == Some(me.id) && todo.list_id == Some(list_id) => Ok(Some(todo)),
+        Ok(_) | Err(TodoRepoError::NotFound(_)) => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// The todos in the list `list_id`, as calendar objects.
+async fn list_todos<R: TodoRepo>(repo: &R, me: &User, list_id: i64) -> Result<Vec<DavTodo>, TodoRepoError> {
+    let uids = repo.get_uids(me.id).await?;
+    let last_modified = repo.last_modified(me.id).await?;
+    let todos: Vec<Todo> = repo.export(me.id).try_collect().await?;
+
+    Ok(todos.into_iter()
+        .filter(|todo| todo.list_id == Some(list_id))
+        .map(|todo| DavTodo::new(todo, &uids, &last_modified))
+        .collect())
+}
+
+///
+/// Answers a `calendar-query`, with every todo in the list, or a
+/// `calendar-multiget`, with the todos it names. Of a query's filters, only
+/// which components it asks for is looked at; task apps filter what they
+/// are sent by the rest themselves.
+///
+async fn report<R: TodoRepo>(repo: &R, me: &User, list_id: i64, body: &[u8]) -> Result<Response, TodoApiError> {
+    let document = xml_body(body)?.ok_or_else(|| TodoApiError::MalformedBody("a REPORT needs a body".to_string()))?;
+    let root = document.root_element();
+    let requested = requested_properties(root);
+    let todos = list_todos(repo, me, list_id).await?;
+
+    let responses = if root.has_tag_name((CALDAV, "calendar-query")) {
+        let components: Vec<&str> = root.children()
+            .filter(|node| node.has_tag_name((CALDAV, "filter")))
+            .flat_map(|filter| filter.children().filter(|node| node.has_tag_name((CALDAV, "comp-filter"))))
+            .flat_map(|calendar| calendar.children().filter(|node| node.has_tag_name((CALDAV, "comp-filter"))))
+            .filter_map(|component| component.attribute("name"))
+            .collect();
+        if components.iter().all(|component| component.eq_ignore_ascii_case("VTODO")) {
+            todos.into_iter().map(|todo| response(&DavResource::Todo(todo), requested.as_deref())).collect()
+        } else {
+            Vec::new()
+        }
+    } else if root.has_tag_name((CALDAV, "calendar-multiget")) {
+        let mut todos: BTreeMap<String, DavTodo> = todos.into_iter().map(|todo| (href_path(&todo.href), todo)).collect();
+        root.children()
+            .filter(|node| node.has_tag_name((DAV, "href")))
+            .map(|href| href.text().unwrap_or_default().trim().to_string())
+            .map(|href| match todos.remove(&href_path(&href)) {
+                Some(todo) => response(&DavResource::Todo(todo), requested.as_deref()),
+                None => format!("<D:response><D:href>{}</D:href><D:status>HTTP/1.1 404 Not Found</D:status></D:response>", xml_text(&href)),
+            })
+            .collect()
+    } else {
+        let error = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:error xmlns:D=\"{}\"><D:supported-report/></D:error>\n", DAV);
+        return Ok((StatusCode::FORBIDDEN, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], error).into_response());
+    };
+
+    Ok(multistatus(responses))
+}
+
+/// The resources task apps see.
+enum DavResource {
+    Principal(User),
+    Home,
+    Calendar(TodoList),
+    Todo(DavTodo),
+}
+
+/// A todo as a calendar object: where it is, its ETag, and the calendar it makes.
+struct DavTodo {
+    href: String,
+    etag: String,
+    calendar: String,
+}
+
+impl DavTodo {
+    /// `last_modified` goes into the calendar's `DTSTAMP`, so that the same version of a todo always reads the same.
+    fn new(todo: Todo, uids: &BTreeMap<i64, String>, last_modified: &BTreeMap<i64, OffsetDateTime>) -> Self {
+        let uid = uids.get(&todo.id).cloned().unwrap_or_else(|| ical::uid(todo.id));
+        let href = format!("{}{}/{}.ics", HOME_HREF, todo.list_id.unwrap_or_default(), utf8_percent_encode(&uid, UID_SEGMENT));
+        let etag = etag(&todo);
+        let modified = last_modified.get(&todo.id).copied();
+        let calendar = format!("{}{}{}", ical::CALENDAR_START, ical::vtodo(&PortableTodo::of(todo), uids, modified), ical::CALENDAR_END);
+
+        DavTodo { href, etag, calendar }
+    }
+
+    async fn of<R: TodoRepo>(repo: &R, me: &User, todo: Todo, uids: &BTreeMap<i64, String>) -> Result<Self, TodoRepoError> {
+        let last_modified = repo.last_modified(me.id).await?;
+
+        Ok(DavTodo::new(todo, uids, &last_modified))
+    }
+}
+
+/// The same entity tag the rest of the API gives a todo.
+fn etag(todo: &Todo) -> String {
+    format!("\"{}\"", todo.version)
+}
+
+impl DavResource {
+    fn href(&self) -> String {
+        match self {
+            DavResource::Principal(_) => PRINCIPAL_HREF.to_string(),
+            DavResource::Home => HOME_HREF.to_string(),
+            DavResource::Calendar(list) => format!("{}{}/", HOME_HREF, list.id),
+            DavResource::Todo(todo) => todo.href.clone(),
+        }
+    }
+
+    /// Every property of the resource, by namespace and name, with the XML that goes inside it.
+    fn properties(&self) -> Vec<(&'static str, &'static str, String)> {
+        let href = |href: &str| format!("<D:href>{}</D:href>", xml_text(href));
+        let mut properties = vec![(DAV, "current-user-principal", href(PRINCIPAL_HREF))];
+        match self {
+            DavResource::Principal(user) => properties.extend([
+                (DAV, "resourcetype", "<D:principal/>".to_string()),
+                (DAV, "displayname", xml_text(&user.name)),
+                (DAV, "principal-URL", href(PRINCIPAL_HREF)),
+                (CALDAV, "calendar-home-set", href(HOME_HREF)),
+            ]),
+            DavResource::Home => properties.push((DAV, "resourcetype", "<D:collection/>".to_string())),
+            DavResource::Calendar(list) => properties.extend([
+                (DAV, "resourcetype", "<D:collection/><C:calendar/>".to_string()),
+                (DAV, "displayname", xml_text(&list.name)),
+                (CALDAV, "supported-calendar-component-set", "<C:comp name=\"VTODO\"/>".to_string()),
+                (DAV, "current-user-privilege-set", "<D:privilege><D:read/></D:privilege><D:privilege><D:write/></D:privilege>".to_string()),
+            ]),
+            DavResource::Todo(todo) => properties.extend([
+                (DAV, "resourcetype", String::new()),
+                (DAV, "getetag", xml_text(&todo.etag)),
+                (DAV, "getcontenttype", "text/calendar; charset=utf-8; component=VTODO".to_string()),
+                (CALDAV, "calendar-data", xml_text(&todo.calendar)),
+            ]),
+        }
+
+        properties
+    }
+}
+
+fn propfind(resources: Vec<DavResource>, body: &[u8]) -> Result<Response, TodoApiError> {
+    let document = xml_body(body)?;
+    let requested = document.as_ref().and_then(|document| requested_properties(document.root_element()));
+
+    Ok(multistatus(resources.iter().map(|resource| response(resource, requested.as_deref())).collect()))
+}
+
+/// Whether a PROPFIND goes into the members of a collection, as it does unless it has `Depth: 0`.
+fn into_members(headers: &HeaderMap) -> bool {
+    headers.get("Depth").and_then(|depth| depth.to_str().ok()).is_none_or(|depth| depth.trim() != "0")
+}
+
+/// An XML request body, or `None` if there is none, which PROPFIND takes as asking for everything.
+fn xml_body(body: &[u8]) -> Result<Option<roxmltree::Document<'_>>, TodoApiError> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    let text = std::str::from_utf8(body).map_err(|_| TodoApiError::MalformedBody("the body must be XML in UTF-8".to_string()))?;
+
+    roxmltree::Document::parse(text)
+        .map(Some)
+        .map_err(|error| TodoApiError::MalformedBody(format!("the body is not XML: {}", error)))
+}
+
+/// The properties a PROPFIND or REPORT asks for, by namespace and name, or `None` if it asks for all of them.
+fn requested_properties(root: roxmltree::Node) -> Option<Vec<(String, String)>> {
+    let prop = root.children().find(|node| node.has_tag_name((DAV, "prop")))?;
+
+    Some(prop.children()
+        .filter(|node| node.is_element())
+        .map(|node| (node.tag_name().namespace().unwrap_or_default().to_string(), node.tag_name().name().to_string()))
+        .collect())
+}
+
+/// A resource's part of a `207 Multi-Status`, with the properties asked for, or all but its calendar data, which has to be asked for by name.
+fn response(resource: &DavResource, requested: Option<&[(String, String)]>) -> String {
+    let element = |namespace: &str, name: &str, value: &str| {
+        let prefix = if namespace == CALDAV { "C" } else { "D" };
+        if value.is_empty() { format!("<{}:{}/>", prefix, name) } else { format!("<{0}:{1}>{2}</{0}:{1}>", prefix, name, value) }
+    };
+    let properties = resource.properties();
+
+    let (mut found, mut missing) = (String::new(), String::new());
+    match requested {
+        None => {
+            for (namespace, name, value) in properties.iter().filter(|(_, name, _)| *name != "calendar-data") {
+                found.push_str(&element(namespace, name, value));
+            }
+        }
+        Some(requested) => {
+            for (namespace, name) in requested {
+                match properties.iter().find(|(known_namespace, known_name, _)| known_namespace == namespace && known_name == name) {
+                    Some((namespace, name, value)) => found.push_str(&element(namespace, name, value)),
+                    None => missing.push_str(&format!("<{} xmlns=\"{}\"/>", name, xml_text(namespace))),
+                }
+            }
+        }
+    }
+
+    let mut response = format!("<D:response><D:href>{}</D:href>", xml_text(&resource.href()));
+    if !found.is_empty() || missing.is_empty() {
+        response.push_str(&format!("<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>", found));
+    }
+    if !missing.is_empty() {
+        response.push_str(&format!("<D:propstat><D:prop>{}</D:prop><D:status>HTTP/1.1 404 Not Found</D:status></D:propstat>", missing));
+    }
+    response.push_str("</D:response>");
+
+    response
+}
+
+fn multistatus(responses: Vec<String>) -> Response {
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<D:multistatus xmlns:D=\"{}\" xmlns:C=\"{}\">{}</D:multistatus>\n",
+        DAV,
+        CALDAV,
+        responses.concat(),
+    );
+
+    (StatusCode::MULTI_STATUS, [(header::CONTENT_TYPE, "application/xml; charset=utf-8")], body).into_response()
+}
+
+/// The path an href names, decoded, whether it is given as a path or as a whole URL.
+fn href_path(href: &str) -> String {
+    let path = match href.split_once("://") {
+        Some((_, rest)) => rest.find('/').map_or("/", |start| &rest[start..]),
+        None => href,
+    };
+
+    percent_decode_str(path).decode_utf8_lossy().into_owned()
+}
+
+/// Text as it goes into XML. Carriage returns are escaped too, since XML would otherwise read them as plain line feeds.
+fn xml_text(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\r', "&#13;")
+}
+
+/// The answer to `OPTIONS`, which asks what a resource can do, or to a method it cannot.
+fn other_method(method: &str) -> Response {
+    let status = if method == "OPTIONS" { StatusCode::OK } else { StatusCode::METHOD_NOT_ALLOWED };
+
+    (status, [("DAV", "1, 3, calendar-access"), ("Allow", ALLOWED_METHODS)]).into_response()
+}
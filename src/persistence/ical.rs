@@ -0,0 +1,490 @@
+//!
+//! iCalendar (RFC 5545), as far as todos need it: writing them as `VTODO`
+//! components, for calendar feeds and exports, and reading them back in
+//! imports. What is written keeps to the letter of the RFC, since calendar
+//! apps are picky about what they are given; what is read may come from any
+//! of them, so anything a todo has no use for is passed over.
+//!
+
+use time_tz::timezones;
+
+use super::*;
+
+/// The start of a calendar, up to its first component.
+pub(super) const CALENDAR_START: &str = "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//rust-web//todos//EN\r\nCALSCALE:GREGORIAN\r\nX-WR-CALNAME:Todos\r\n";
+
+pub(super) const CALENDAR_END: &str = "END:VCALENDAR\r\n";
+
+/// What follows the id in the UIDs of todos, which calendar apps expect to be unique far beyond this app.
+const UID_DOMAIN: &str = "@rust-web";
+
+/// Where a todo's list goes, for want of a property of the RFC's own.
+const LIST_ID_PROPERTY: &str = "X-TODO-LIST-ID";
+
+///
+/// The todo as a `VTODO` component, line endings and all. `uids` holds the
+/// UIDs calendar apps gave todos, which the todo and its parent go by if
+/// they have one. `last_modified` is when the todo was last written, if
+/// known; it doubles as the `DTSTAMP`, which is otherwise when the
+/// component was written out.
+///
+pub(super) fn vtodo(todo: &PortableTodo, uids: &BTreeMap<i64, String>, last_modified: Option<OffsetDateTime>) -> String {
+    let uid_of = |id: i64| uids.get(&id).map_or_else(|| uid(id), |uid| text(uid));
+    let mut lines = vec!["BEGIN:VTODO".to_string()];
+    if let Some(id) = todo.id {
+        lines.push(format!("UID:{}", uid_of(id)));
+    }
+    lines.push(format!("DTSTAMP:{}", date_time(last_modified.unwrap_or_else(OffsetDateTime::now_utc))));
+    if let Some(last_modified) = last_modified {
+        lines.push(format!("LAST-MODIFIED:{}", date_time(last_modified)));
+    }
+    lines.push(format!("SUMMARY:{}", text(&todo.title)));
+    if !todo.description.is_empty() {
+        lines.push(format!("DESCRIPTION:{}", text(&todo.description)));
+    }
+    lines.push(format!("STATUS:{}", if todo.done { "COMPLETED" } else { "NEEDS-ACTION" }));
+    if let Some(due_at) = todo.due_at {
+        lines.push(format!("DUE:{}", date_time(due_at)));
+    }
+    if let Some(recurrence) = todo.anchored_recurrence() {
+        lines.extend(recurrence.to_string().lines().map(str::to_string));
+    }
+    lines.push(format!("PRIORITY:{}", priority(todo.priority)));
+    if !todo.tags.is_empty() {
+        lines.push(format!("CATEGORIES:{}", todo.tags.iter().map(|tag| text(tag)).collect::<Vec<_>>().join(",")));
+    }
+    if let Some(parent_id) = todo.parent_id {
+        lines.push(format!("RELATED-TO:{}", uid_of(parent_id)));
+    }
+    if let Some(list_id) = todo.list_id {
+        lines.push(format!("{}:{}", LIST_ID_PROPERTY, list_id));
+    }
+    lines.push("END:VTODO".to_string());
+
+    lines.iter().map(|line| fold(line)).collect()
+}
+
+/// The UID of a todo no calendar app has given one.
+pub(super) fn uid(id: i64) -> String {
+    format!("todo-{}{}", id, UID_DOMAIN)
+}
+
+/// The id of the todo that `uid` would be made from, if it is one of those.
+pub(super) fn todo_id(uid: &str) -> Option<i64> {
+    uid.strip_prefix("todo-")?.strip_suffix(UID_DOMAIN)?.parse().ok()
+}
+
+/// A date and time in UTC, the one form of them every calendar app reads.
+fn date_time(at: OffsetDateTime) -> String {
+    let at = at.to_offset(UtcOffset::UTC);
+
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", at.year(), u8::from(at.month()), at.day(), at.hour(), at.minute(), at.second())
+}
+
+/// RFC 5545 priorities run from 1, the highest, to 9, with 0 for none.
+fn priority(priority: TodoPriority) -> u8 {
+    match priority {
+        TodoPriority::Urgent => 1,
+        TodoPriority::High => 3,
+        TodoPriority::Normal => 5,
+        TodoPriority::Low => 9,
+    }
+}
+
+/// Text with the characters that mean something in a property value escaped.
+fn text(value: &str) -> String {
+    value.replace('\\', "\\\\").replace(';', "\\;").replace(',', "\\,").replace("\r\n", "\\n").replace(['\r', '\n'], "\\n")
+}
+
+/// Splits a content line into lines of at most 75 octets, as RFC 5545 asks; each one after the first starts with a space.
+fn fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 2);
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+
+    folded
+}
+
+/// A content line once unfolded: its name, in upper case, its parameters, and its value, still escaped.
+#[derive(Debug, Clone, PartialEq)]
+struct ContentLine {
+    name: String,
+    params: Vec<(String, String)>,
+    value: String,
+}
+
+impl ContentLine {
+    fn parse(line: &str) -> Result<Self, String> {
+        let malformed = || format!("{:?} is not a content line", line);
+        let name_end = line.find([';', ':']).ok_or_else(malformed)?;
+        let name = line[..name_end].trim().to_ascii_uppercase();
+        if name.is_empty() {
+            return Err(malformed());
+        }
+
+        let mut params = Vec::new();
+        let mut rest = &line[name_end..];
+        while let Some(param) = rest.strip_prefix(';') {
+            let (param_name, after) = param.split_once('=').ok_or_else(malformed)?;
+            // Quoted values may hold the `;` and `:` that end unquoted ones.
+            let (param_value, after) = match after.strip_prefix('"') {
+                Some(quoted) => {
+                    let (value, after) = quoted.split_once('"').ok_or_else(malformed)?;
+                    (value, after)
+                }
+                None => after.split_at(after.find([';', ':']).ok_or_else(malformed)?),
+            };
+            params.push((param_name.trim().to_ascii_uppercase(), param_value.to_string()));
+            rest = after;
+        }
+        let value = rest.strip_prefix(':').ok_or_else(malformed)?;
+
+        Ok(ContentLine { name, params, value: value.to_string() })
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params.iter().find(|(param, _)| param == name).map(|(_, value)| value.as_str())
+    }
+
+    /// The value as text, or as a list of texts, split at the commas that are not escaped.
+    fn texts(&self) -> Vec<String> {
+        let (mut texts, mut current, mut chars) = (Vec::new(), String::new(), self.value.chars());
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some('n' | 'N') => current.push('\n'),
+                    Some(escaped) => current.push(escaped),
+                    None => {}
+                },
+                ',' => texts.push(std::mem::take(&mut current)),
+                c => current.push(c),
+            }
+        }
+        texts.push(current);
+
+        texts
+    }
+
+    fn text(&self) -> String {
+        self.texts().join(",")
+    }
+
+    ///
+    /// The value as a date and time. Those in UTC are read as they are; so
+    /// are floating ones, which are in no time zone in particular, and dates,
+    /// which are taken as their first moment. Those with a `TZID` are read on
+    /// the clock of that time zone, which has to be one the IANA database
+    /// knows by that name.
+    ///
+    fn date_time(&self) -> Result<OffsetDateTime, String> {
+        let local = self.local_date_time()?;
+        let Some(name) = self.param("TZID") else {
+            return Ok(local.assume_utc());
+        };
+        let zone = timezones::get_by_name(name)
+            .ok_or_else(|| format!("{} is in the time zone {}, which is not one we know; give it in UTC, or in a time zone such as Europe/Berlin", self.name, name))?;
+
+        Ok(recurrence::instant_in(zone, local))
+    }
+
+    /// The value as a date and time on the clock of whatever time zone it is in.
+    fn local_date_time(&self) -> Result<PrimitiveDateTime, String> {
+        let invalid = || format!("{} must be a date, or a date and time, such as 20261101T090000Z", self.name);
+
+        let value = self.value.trim();
+        let value = value.strip_suffix('Z').unwrap_or(value);
+        let (date, time) = value.split_once('T').unwrap_or((value, "000000"));
+        let digits = |text: &str, from: usize, to: usize| text.get(from..to).filter(|digits| digits.bytes().all(|b| b.is_ascii_digit())).and_then(|digits| digits.parse::<u16>().ok());
+        if date.len() != 8 || time.len() != 6 {
+            return Err(invalid());
+        }
+        let (year, month, day) = (digits(date, 0, 4).ok_or_else(invalid)?, digits(date, 4, 6).ok_or_else(invalid)?, digits(date, 6, 8).ok_or_else(invalid)?);
+        let (hour, minute, second) = (digits(time, 0, 2).ok_or_else(invalid)?, digits(time, 2, 4).ok_or_else(invalid)?, digits(time, 4, 6).ok_or_else(invalid)?);
+
+        let month = time::Month::try_from(month as u8).map_err(|_| invalid())?;
+        let date = time::Date::from_calendar_date(year as i32, month, day as u8).map_err(|_| invalid())?;
+        let time = time::Time::from_hms(hour as u8, minute as u8, second as u8).map_err(|_| invalid())?;
+
+        Ok(PrimitiveDateTime::new(date, time))
+    }
+}
+
+///
+/// The todos of an iCalendar file, one row for each `VTODO`, numbered by the
+/// line it begins on; other components, such as events, are passed over.
+/// Todos are linked to their parents by UID, which for todos from other apps
+/// stand in for ids the same way the ids of earlier rows of other imports do.
+///
+pub(super) fn read_ics(body: &[u8]) -> Result<ImportRows, TodoRepoError> {
+    let components = vtodo_properties(body).map_err(TodoRepoError::Invalid)?;
+
+    // Todos from other apps have UIDs of their own, which are given ids below zero, where no todo's id can be.
+    let mut foreign_ids = BTreeMap::new();
+    for properties in components.iter().filter_map(|(_, properties)| properties.as_ref().ok()) {
+        if let Some(uid) = properties.iter().find(|property| property.name == "UID").map(ContentLine::text) {
+            if todo_id(&uid).is_none() {
+                let next = -(foreign_ids.len() as i64) - 1;
+                foreign_ids.entry(uid).or_insert(next);
+            }
+        }
+    }
+    let id_of = |uid: &str| todo_id(uid).or_else(|| foreign_ids.get(uid).copied());
+
+    Ok(components.into_iter()
+        .map(|(number, properties)| (number, properties.and_then(|properties| portable_todo(&properties, &id_of))))
+        .collect())
+}
+
+///
+/// The one todo a CalDAV resource holds, along with its UID. `id_of` finds
+/// the todo a UID names, if there is one, for the todo's parent.
+///
+pub(super) fn read_resource(body: &[u8], id_of: impl Fn(&str) -> Option<i64>) -> Result<(String, PortableTodo), String> {
+    let mut components = vtodo_properties(body)?;
+    if components.len() != 1 {
+        return Err("a calendar object must hold exactly one VTODO".to_string());
+    }
+    let properties = components.remove(0).1?;
+    let uid = properties.iter().find(|property| property.name == "UID").map(ContentLine::text).ok_or("a VTODO needs a UID")?;
+
+    Ok((uid, portable_todo(&properties, &id_of)?))
+}
+
+/// The content lines of each `VTODO` in an iCalendar file, with the line it begins on, or why they cannot be read.
+type VtodoProperties = Vec<(u64, Result<Vec<ContentLine>, String>)>;
+
+fn vtodo_properties(body: &[u8]) -> Result<VtodoProperties, String> {
+    let text = std::str::from_utf8(body).map_err(|_| "iCalendar must be UTF-8 text".to_string())?;
+
+    // Lines that start with a space or a tab carry on the one before.
+    let mut lines: Vec<(u64, String)> = Vec::new();
+    for (line, number) in text.trim_start_matches('\u{feff}').split('\n').zip(1..) {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match (line.strip_prefix([' ', '\t']), lines.last_mut()) {
+            (Some(continued), Some((_, previous))) => previous.push_str(continued),
+            _ if line.trim().is_empty() => {}
+            _ => lines.push((number, line.to_string())),
+        }
+    }
+    if !lines.first().is_some_and(|(_, line)| line.trim().eq_ignore_ascii_case("BEGIN:VCALENDAR")) {
+        return Err("an iCalendar file must start with BEGIN:VCALENDAR".to_string());
+    }
+
+    // The content lines of each VTODO, leaving out those of any components inside it, such as alarms.
+    let mut components: Vec<(u64, Result<Vec<ContentLine>, String>)> = Vec::new();
+    let (mut current, mut depth) = (None, 0);
+    let never_ended = || Err("BEGIN:VTODO is never ended".to_string());
+    for (number, line) in lines {
+        let parsed = ContentLine::parse(&line);
+        let boundary = parsed.as_ref().ok()
+            .filter(|line| line.name == "BEGIN" || line.name == "END")
+            .map(|line| (line.name == "BEGIN", line.value.trim().to_ascii_uppercase()));
+        let Some((_, properties)) = &mut current else {
+            if boundary.is_some_and(|(begin, component)| begin && component == "VTODO") {
+                current = Some((number, Ok(Vec::new())));
+                depth = 0;
+            }
+            continue;
+        };
+        match boundary {
+            Some((true, _)) => depth += 1,
+            Some((false, _)) if depth > 0 => depth -= 1,
+            Some((false, component)) => {
+                let (begun_at, properties) = current.take().unwrap();
+                components.push((begun_at, if component == "VTODO" { properties } else { never_ended() }));
+            }
+            None if depth > 0 => {}
+            None => {
+                if let Ok(lines) = properties {
+                    match parsed {
+                        Ok(property) => lines.push(property),
+                        Err(message) => *properties = Err(format!("line {}: {}", number, message)),
+                    }
+                }
+            }
+        }
+    }
+    if let Some((begun_at, _)) = current {
+        components.push((begun_at, never_ended()));
+    }
+
+    Ok(components)
+}
+
+/// The todo a `VTODO` describes, with `id_of` finding the ids of the todos it names by UID.
+fn portable_todo(properties: &[ContentLine], id_of: &impl Fn(&str) -> Option<i64>) -> Result<PortableTodo, String> {
+    let mut todo = PortableTodo {
+        id: None,
+        title: String::new(),
+        description: String::new(),
+        done: false,
+        due_at: None,
+        priority: TodoPriority::Normal,
+        list_id: None,
+        parent_id: None,
+        tags: Vec::new(),
+        recurrence: None,
+    };
+    let (mut title, mut status, mut completed) = (None, None, false);
+    let (mut starts_at, mut rule) = (None, None);
+    for property in properties {
+        match property.name.as_str() {
+            "UID" => todo.id = id_of(&property.text()),
+            "SUMMARY" => title = Some(property.text()),
+            "DESCRIPTION" => todo.description = property.text(),
+            "STATUS" => status = Some(property.value.trim().to_ascii_uppercase()),
+            "COMPLETED" => completed = true,
+            "DUE" => todo.due_at = Some(property.date_time()?),
+            "DTSTART" => starts_at = Some(property),
+            "RRULE" => rule = Some(property.value.trim().to_string()),
+            "PRIORITY" => {
+                todo.priority = match property.value.trim().parse::<u8>() {
+                    Ok(1..=2) => TodoPriority::Urgent,
+                    Ok(3..=4) => TodoPriority::High,
+                    Ok(0 | 5) => TodoPriority::Normal,
+                    Ok(6..=9) => TodoPriority::Low,
+                    _ => return Err("PRIORITY must be a whole number from 0 to 9".to_string()),
+                }
+            }
+            "CATEGORIES" => todo.tags.extend(property.texts().into_iter().map(|tag| tag.trim().to_string()).filter(|tag| !tag.is_empty())),
+            "RELATED-TO" if property.param("RELTYPE").is_none_or(|reltype| reltype.eq_ignore_ascii_case("PARENT")) => {
+                let uid = property.text();
+                todo.parent_id = Some(id_of(&uid).ok_or_else(|| format!("RELATED-TO names {}, which is not one of these todos", uid))?);
+            }
+            name if name == LIST_ID_PROPERTY => {
+                todo.list_id = Some(property.value.trim().parse().map_err(|_| format!("{} must be a whole number", LIST_ID_PROPERTY))?);
+            }
+            _ => {}
+        }
+    }
+    todo.title = title.ok_or("a VTODO needs a SUMMARY")?;
+    // Without a STATUS, a completion time is the only sign of a todo being done.
+    todo.done = status.map_or(completed, |status| status == "COMPLETED");
+    if let Some(rule) = rule {
+        // A series starts when the todo is first due, unless it says otherwise; those that say in UTC, or in no zone, repeat in UTC.
+        let time_zone = starts_at.and_then(|starts_at| starts_at.param("TZID")).unwrap_or("UTC").to_string();
+        let spec = RecurrenceSpec { rule, time_zone, starts_at: None };
+        let recurrence = match starts_at {
+            Some(starts_at) => spec.starting_at(starts_at.local_date_time()?)?,
+            None => spec.anchored(todo.due_at)?,
+        };
+        todo.recurrence = Some(recurrence.into());
+    }
+
+    Ok(todo)
+}
+
+#[tokio::test]
+async fn vtodos_read_back_as_written() {
+    let todo = PortableTodo {
+        id: Some(42),
+        title: "Pack; then, leave".to_string(),
+        description: "A line\nand a backslash \\ and a very long line that goes on well past where lines are folded, with ünïcödé in it".to_string(),
+        done: true,
+        due_at: Some(time::macros::datetime!(2026-11-01 09:00 +01:00)),
+        priority: TodoPriority::High,
+        list_id: Some(7),
+        parent_id: Some(41),
+        tags: vec!["travel".to_string(), "a, b".to_string()],
+        recurrence: None,
+    };
+    let modified = time::macros::datetime!(2026-10-19 08:30:15 UTC);
+    let component = vtodo(&todo, &BTreeMap::new(), Some(modified));
+
+    assert!(component.contains("\r\nUID:todo-42@rust-web\r\n"));
+    assert!(component.contains("\r\nDTSTAMP:20261019T083015Z\r\nLAST-MODIFIED:20261019T083015Z\r\n"));
+    assert!(component.contains("\r\nSUMMARY:Pack\\; then\\, leave\r\n"));
+    assert!(component.contains("\r\nSTATUS:COMPLETED\r\nDUE:20261101T080000Z\r\nPRIORITY:3\r\nCATEGORIES:travel,a\\, b\r\n"));
+    assert!(component.split("\r\n").all(|line| line.len() <= 75));
+    assert!(component.split("\r\n").any(|line| line.starts_with(' ')));
+
+    let calendar = format!("{}{}{}", CALENDAR_START, component, CALENDAR_END);
+    let rows = read_ics(calendar.as_bytes()).unwrap();
+    assert_eq!(rows, vec![(6, Ok(PortableTodo { due_at: Some(time::macros::datetime!(2026-11-01 08:00 UTC)), ..todo.clone() }))]);
+
+    let weekly = RecurrenceSpec { rule: "FREQ=WEEKLY;BYDAY=MO,TH".to_string(), time_zone: "Europe/Berlin".to_string(), starts_at: Some("2026-10-19T09:00:00".to_string()) };
+    let repeating = PortableTodo { due_at: Some(time::macros::datetime!(2026-10-19 07:00 UTC)), recurrence: Some(weekly), ..todo };
+    let component = vtodo(&repeating, &BTreeMap::new(), Some(modified));
+    assert!(component.contains("\r\nDUE:20261019T070000Z\r\nDTSTART;TZID=Europe/Berlin:20261019T090000\r\nRRULE:FREQ=WEEKLY;BYDAY=MO,TH\r\n"));
+
+    let calendar = format!("{}{}{}", CALENDAR_START, component, CALENDAR_END);
+    assert_eq!(read_ics(calendar.as_bytes()).unwrap(), vec![(6, Ok(repeating.clone()))]);
+
+    // Without a DTSTART, the series starts when the todo is due.
+    let calendar = format!("{}BEGIN:VTODO\r\nSUMMARY:Water plants\r\nDUE:20261022T070000Z\r\nRRULE:FREQ=DAILY;INTERVAL=3\r\nEND:VTODO\r\n{}", CALENDAR_START, CALENDAR_END);
+    let rows = read_ics(calendar.as_bytes()).unwrap();
+    let every_three_days = RecurrenceSpec { rule: "FREQ=DAILY;INTERVAL=3".to_string(), time_zone: "Etc/UTC".to_string(), starts_at: Some("2026-10-22T07:00:00".to_string()) };
+    assert_eq!(rows[0].1.as_ref().unwrap().recurrence, Some(every_three_days));
+}
+
+#[tokio::test]
+async fn vtodos_from_other_apps() {
+    let calendar = "\u{feff}BEGIN:VCALENDAR\n\
+        VERSION:2.0\n\
+        BEGIN:VEVENT\n\
+        SUMMARY:Not a todo\n\
+        END:VEVENT\n\
+        BEGIN:VTODO\n\
+        UID:ABC-123\n\
+        SUMMARY:Write\n\
+        \x20 report\n\
+        DUE;VALUE=DATE:20261101\n\
+        PRIORITY:0\n\
+        COMPLETED:20261020T100000Z\n\
+        CATEGORIES:work\n\
+        CATEGORIES:writing,\n\
+        BEGIN:VALARM\n\
+        SUMMARY:Not the todo's\n\
+        END:VALARM\n\
+        END:VTODO\n\
+        BEGIN:VTODO\n\
+        SUMMARY:Proofread\n\
+        STATUS:IN-PROCESS\n\
+        RELATED-TO;RELTYPE=PARENT:ABC-123\n\
+        RELATED-TO;RELTYPE=SIBLING:elsewhere\n\
+        END:VTODO\n\
+        BEGIN:VTODO\n\
+        SUMMARY:Meet\n\
+        DUE;TZID=Europe/Berlin:20261101T090000\n\
+        END:VTODO\n\
+        BEGIN:VTODO\n\
+        SUMMARY:Land\n\
+        DUE;TZID=Mars/Olympus:20261101T090000\n\
+        END:VTODO\n\
+        BEGIN:VTODO\n\
+        DESCRIPTION:Untitled\n\
+        RELATED-TO:somewhere-else\n\
+        END:VTODO\n\
+        BEGIN:VTODO\n\
+        SUMMARY:Unfinished\n\
+        END:VCALENDAR\n";
+    let rows = read_ics(calendar.as_bytes()).unwrap();
+    let blank = PortableTodo { id: None, title: String::new(), description: String::new(), done: false, due_at: None, priority: TodoPriority::Normal, list_id: None, parent_id: None, tags: Vec::new(), recurrence: None };
+
+    assert_eq!(rows, vec![
+        (6, Ok(PortableTodo {
+            id: Some(-1),
+            title: "Write report".to_string(),
+            done: true,
+            due_at: Some(time::macros::datetime!(2026-11-01 00:00 UTC)),
+            tags: vec!["work".to_string(), "writing".to_string()],
+            ..blank.clone()
+        })),
+        (19, Ok(PortableTodo { title: "Proofread".to_string(), parent_id: Some(-1), ..blank.clone() })),
+        // Berlin is an hour ahead of UTC by then.
+        (25, Ok(PortableTodo { title: "Meet".to_string(), due_at: Some(time::macros::datetime!(2026-11-01 08:00 UTC)), ..blank.clone() })),
+        (29, Err("DUE is in the time zone Mars/Olympus, which is not one we know; give it in UTC, or in a time zone such as Europe/Berlin".to_string())),
+        (33, Err("RELATED-TO names somewhere-else, which is not one of these todos".to_string())),
+        (37, Err("BEGIN:VTODO is never ended".to_string())),
+    ]);
+
+    assert!(matches!(read_ics(b"SUMMARY:Nothing to see\n"), Err(TodoRepoError::Invalid(_))));
+}
@@ -0,0 +1,1912 @@
+//!
+//! An in-memory `TodoRepo`, with the same semantics as the Postgres one, so
+//! that the todo app can be exercised without a database.
+//!
+
+use std::{cmp::Ordering, collections::{BTreeMap, BTreeSet}, ops::{Deref, DerefMut}, sync::Arc};
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use super::*;
+
+#[derive(Debug, Clone)]
+pub(super) struct TodoRepoInMemory {
+    store: Arc<Mutex<TodoStore>>,
+    /// Every change to todos, as it is made.
+    changes: broadcast::Sender<TodoChange>,
+}
+
+impl Default for TodoRepoInMemory {
+    fn default() -> Self {
+        TodoRepoInMemory { store: Arc::default(), changes: broadcast::channel(CHANGE_BUFFER).0 }
+    }
+}
+
+impl TodoRepoInMemory {
+    async fn lock(&self) -> StoreGuard<'_> {
+        let store = self.store.lock().await;
+        let seen = store.changes.len();
+
+        StoreGuard { store, seen, watchers: &self.changes }
+    }
+}
+
+///
+/// The locked store, which tells whoever is watching about the changes made
+/// to it when it is unlocked again, much as the databases announce changes
+/// once they are committed.
+///
+struct StoreGuard<'a> {
+    store: MutexGuard<'a, TodoStore>,
+    /// How many changes there were when the store was locked.
+    seen: usize,
+    watchers: &'a broadcast::Sender<TodoChange>,
+}
+
+impl Deref for StoreGuard<'_> {
+    type Target = TodoStore;
+
+    fn deref(&self) -> &TodoStore {
+        &self.store
+    }
+}
+
+impl DerefMut for StoreGuard<'_> {
+    fn deref_mut(&mut self) -> &mut TodoStore {
+        &mut self.store
+    }
+}
+
+impl Drop for StoreGuard<'_> {
+    fn drop(&mut self) {
+        for change in &self.store.changes[self.seen..] {
+            // Nobody watching is fine too.
+            let _ = self.watchers.send(change.clone());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct TodoStore {
+    todos: BTreeMap<i64, TodoRecord>,
+    /// The todos that have been deleted but not yet purged, which none of the other methods see.
+    trash: BTreeMap<i64, TodoRecord>,
+    last_id: i64,
+    users: BTreeMap<i64, (User, Vec<u8>)>,
+    last_user_id: i64,
+    /// The hashed calendar feed token of each user who has one.
+    feed_tokens: BTreeMap<i64, Vec<u8>>,
+    lists: BTreeMap<i64, TodoList>,
+    last_list_id: i64,
+    /// The tags of each todo that has any.
+    tags: BTreeMap<i64, BTreeSet<String>>,
+    /// The ids of the todos blocking each todo that is blocked by any.
+    blockers: BTreeMap<i64, BTreeSet<i64>>,
+    /// The UIDs calendar apps gave the todos they created.
+    uids: BTreeMap<i64, String>,
+    /// Every todo's history, in the order it happened; the id of each event is its place here.
+    events: Vec<TodoEvent>,
+    /// Every change to todos that clients can see, in order; again, the id of each is its place here.
+    changes: Vec<TodoChange>,
+}
+
+impl TodoStore {
+    /// The todo a record holds, with its tags and the blockers that are not in the trash.
+    fn todo(&self, record: &TodoRecord) -> Todo {
+        let tags = self.tags.get(&record.id).map(|tags| tags.iter().cloned().collect()).unwrap_or_default();
+        let blocked_by = self.blockers.get(&record.id).into_iter().flatten().copied()
+            .filter(|blocker_id| self.todos.contains_key(blocker_id))
+            .collect();
+
+        Todo { tags, blocked_by, ..Todo::from_record(record.clone()) }
+    }
+
+    /// The blockers of the todo `id` that are not done yet.
+    fn open_blockers(&self, id: i64) -> Vec<i64> {
+        self.blockers.get(&id).into_iter().flatten().copied()
+            .filter(|blocker_id| self.todos.get(blocker_id).is_some_and(|blocker| !blocker.done))
+            .collect()
+    }
+
+    /// Whether following what blocks `blocker_id`, and so on, leads to `id`.
+    fn blocks(&self, id: i64, blocker_id: i64) -> bool {
+        let mut seen = BTreeSet::new();
+        let mut pending = vec![blocker_id];
+        while let Some(next) = pending.pop() {
+            if next == id {
+                return true;
+            }
+            if seen.insert(next) {
+                pending.extend(self.blockers.get(&next).into_iter().flatten().copied());
+            }
+        }
+
+        false
+    }
+
+    /// Adds the change from `before` to `after` to the todo's history, unless nothing changed.
+    fn record(&mut self, actor_id: Option<i64>, before: Option<&Todo>, after: Option<&Todo>) {
+        if let Some(event) = TodoEvent::between(actor_id, before, after) {
+            // Purging a todo from the trash changes nothing anyone can see.
+            let kind = match event.kind {
+                TodoEventKind::Created | TodoEventKind::Restored => Some(TodoChangeKind::Created),
+                TodoEventKind::Updated => Some(TodoChangeKind::Updated),
+                TodoEventKind::Deleted => Some(TodoChangeKind::Deleted),
+                TodoEventKind::Purged => None,
+            };
+            if let Some(kind) = kind {
+                self.change(event.todo_id, event.owner_id, kind);
+            }
+
+            let id = self.events.len() as i64 + 1;
+            self.events.push(TodoEvent { id, ..event });
+        }
+    }
+
+    /// Adds to the changes clients can see, as the databases' triggers do.
+    fn change(&mut self, todo_id: i64, owner_id: Option<i64>, kind: TodoChangeKind) {
+        let id = self.changes.len() as i64 + 1;
+        self.changes.push(TodoChange { id, todo_id, kind, owner_id });
+    }
+
+    /// Adds or removes a link, bumping the version only if that changed anything.
+    fn reblock(&mut self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, blocker_id: i64, add: bool) -> Result<Todo, TodoRepoError> {
+        self.get_mut(id, expected_version)?;
+        if add && !self.todos.contains_key(&blocker_id) {
+            return Err(missing_blocker(blocker_id));
+        }
+        if add && self.blocks(id, blocker_id) {
+            return Err(cyclic_dependency(id, blocker_id));
+        }
+
+        let before = self.todo(&self.todos[&id]);
+        let blockers = self.blockers.entry(id).or_default();
+        let changed = if add { blockers.insert(blocker_id) } else { blockers.remove(&blocker_id) };
+        if changed {
+            self.todos.get_mut(&id).unwrap().version += 1;
+        }
+
+        let todo = self.todo(&self.todos[&id]);
+        self.record(actor_id, Some(&before), Some(&todo));
+        Ok(todo)
+    }
+
+    /// Acts as if there were no todo `id` unless it is `owner_id`'s.
+    fn check_owner(&self, owner_id: Option<i64>, id: i64) -> Result<(), TodoRepoError> {
+        match self.todos.get(&id) {
+            Some(record) if record.owner_id == owner_id => Ok(()),
+            _ => Err(TodoRepoError::NotFound(id)),
+        }
+    }
+
+    fn get_mut(&mut self, id: i64, expected_version: Option<i64>) -> Result<&mut TodoRecord, TodoRepoError> {
+        let record = self.todos.get_mut(&id).ok_or(TodoRepoError::NotFound(id))?;
+        if expected_version.is_some_and(|version| version != record.version) {
+            return Err(TodoRepoError::VersionMismatch(id));
+        }
+
+        Ok(record)
+    }
+
+    fn create(&mut self, owner_id: Option<i64>, todo: CreateTodo) -> Result<Todo, TodoRepoError> {
+        if owner_id.is_some_and(|owner_id| !self.users.contains_key(&owner_id)) {
+            return Err(TodoRepoError::Conflict("the todo's owner does not exist".to_string()));
+        }
+        self.check_list_exists(todo.list_id)?;
+        if let Some(parent_id) = todo.parent_id {
+            self.check_parent(None, owner_id, parent_id)?;
+        }
+        let recurrence = recurrence_of(todo.recurrence.as_ref(), todo.due_at)?;
+        self.last_id += 1;
+
+        let record = TodoRecord {
+            id: self.last_id,
+            title: todo.title,
+            description: todo.description,
+            done: false,
+            created_at: now(),
+            version: 1,
+            owner_id,
+            list_id: todo.list_id,
+            due_at: todo.due_at,
+            priority: todo.priority.as_str().to_string(),
+            completed_at: None,
+            parent_id: todo.parent_id,
+            // Where the migrations put todos that have never been moved.
+            position: format!("a{:019}V", self.last_id),
+            deleted_at: None,
+            recurrence: recurrence.as_ref().map(Recurrence::to_string),
+        };
+        self.todos.insert(record.id, record.clone());
+
+        let todo = Todo::from_record(record);
+        self.record(owner_id, None, Some(&todo));
+        Ok(todo)
+    }
+
+    fn check_list_exists(&self, list_id: Option<i64>) -> Result<(), TodoRepoError> {
+        if list_id.is_some_and(|list_id| !self.lists.contains_key(&list_id)) {
+            return Err(TodoRepoError::Conflict("the todo's list does not exist".to_string()));
+        }
+
+        Ok(())
+    }
+
+    /// Checks that `parent_id` is another of `owner_id`'s todos, and not the todo `id` or one of its subtasks.
+    fn check_parent(&self, id: Option<i64>, owner_id: Option<i64>, parent_id: i64) -> Result<(), TodoRepoError> {
+        match self.todos.get(&parent_id) {
+            Some(parent) if parent.owner_id == owner_id => {}
+            _ => return Err(missing_parent(parent_id)),
+        }
+
+        let mut ancestor = Some(parent_id);
+        while let Some(ancestor_id) = ancestor {
+            if Some(ancestor_id) == id {
+                return Err(cyclic_parent(ancestor_id));
+            }
+            ancestor = self.todos.get(&ancestor_id).and_then(|record| record.parent_id);
+        }
+
+        Ok(())
+    }
+
+    /// The ids of the subtasks of `id`, and theirs.
+    fn subtasks(&self, id: i64) -> Vec<i64> {
+        let mut subtasks: Vec<i64> = Vec::new();
+        let mut parents = vec![id];
+        while let Some(parent_id) = parents.pop() {
+            let children = self.todos.values().filter(|record| record.parent_id == Some(parent_id)).map(|record| record.id);
+            for child_id in children {
+                subtasks.push(child_id);
+                parents.push(child_id);
+            }
+        }
+
+        subtasks
+    }
+
+    fn in_archived_list(&self, record: &TodoRecord) -> bool {
+        record.list_id.and_then(|list_id| self.lists.get(&list_id)).is_some_and(|list| list.archived)
+    }
+
+    fn check_email_free(&self, email: &str, except: Option<i64>) -> Result<(), TodoRepoError> {
+        if self.users.values().any(|(user, _)| user.email == email && Some(user.id) != except) {
+            return Err(TodoRepoError::Conflict(format!("a user with email {} already exists", email)));
+        }
+
+        Ok(())
+    }
+
+    fn update(&mut self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, changes: UpdateTodo) -> Result<Todo, TodoRepoError> {
+        let record = self.get_mut(id, expected_version)?;
+        let (owner_id, was_done) = (record.owner_id, record.done);
+        let current = self.todo(&self.todos[&id]);
+        if changes.done == Some(true) && !was_done && !changes.force {
+            let blockers = self.open_blockers(id);
+            if !blockers.is_empty() {
+                return Err(blocked(id, &blockers));
+            }
+        }
+        if changes.done == Some(true) && changes.complete_subtasks && !changes.force {
+            // Nor may anything besides the todo and its subtasks still hold up one of those.
+            let subtasks = self.subtasks(id);
+            for &subtask_id in subtasks.iter().filter(|subtask_id| !self.todos[subtask_id].done && self.todos[subtask_id].deleted_at.is_none()) {
+                let mut blockers = self.open_blockers(subtask_id);
+                blockers.retain(|blocker_id| *blocker_id != id && !subtasks.contains(blocker_id));
+                if !blockers.is_empty() {
+                    return Err(blocked(subtask_id, &blockers));
+                }
+            }
+        }
+        if let Some(Some(parent_id)) = changes.parent_id {
+            self.check_parent(Some(id), owner_id, parent_id)?;
+        }
+        let (recurrence, handed_on) = recurrence_after(&current, &changes)?;
+
+        let record = self.todos.get_mut(&id).unwrap();
+
+        if let Some(title) = changes.title {
+            record.title = title;
+        }
+        if let Some(description) = changes.description {
+            record.description = description;
+        }
+        if let Some(done) = changes.done {
+            record.completed_at = match (record.done, done) {
+                (false, true) => Some(now()),
+                (true, true) => record.completed_at,
+                (_, false) => None,
+            };
+            record.done = done;
+        }
+        if let Some(due_at) = changes.due_at {
+            record.due_at = due_at;
+        }
+        if let Some(priority) = changes.priority {
+            record.priority = priority.as_str().to_string();
+        }
+        if let Some(parent_id) = changes.parent_id {
+            record.parent_id = parent_id;
+        }
+        record.recurrence = recurrence.as_ref().map(Recurrence::to_string);
+        record.version += 1;
+
+        let record = record.clone();
+        let todo = self.todo(&record);
+        self.record(actor_id, Some(&current), Some(&todo));
+        if let Some(recurrence) = handed_on {
+            self.recur(actor_id, &todo, &recurrence)?;
+        }
+
+        if changes.complete_subtasks && changes.done == Some(true) {
+            let completed_at = now();
+            for subtask_id in self.subtasks(id) {
+                if self.todos[&subtask_id].done {
+                    continue;
+                }
+                let before = self.todo(&self.todos[&subtask_id]);
+                let subtask = self.todos.get_mut(&subtask_id).unwrap();
+                subtask.done = true;
+                subtask.completed_at = Some(completed_at);
+                // Repeating subtasks hand their recurrence on, just as they would if marked done one by one.
+                subtask.recurrence = None;
+                subtask.version += 1;
+                let after = self.todo(&self.todos[&subtask_id]);
+                self.record(actor_id, Some(&before), Some(&after));
+                if let Some(recurrence) = &before.recurrence {
+                    self.recur(actor_id, &after, recurrence)?;
+                }
+            }
+        }
+
+        Ok(todo)
+    }
+
+    /// Creates the todo that comes after `done`, which was just marked done and repeated by `recurrence`, with the same tags.
+    fn recur(&mut self, actor_id: Option<i64>, done: &Todo, recurrence: &Recurrence) -> Result<(), TodoRepoError> {
+        let Some(next) = done.next_instance(recurrence) else {
+            return Ok(());
+        };
+        let created = self.create(done.owner_id, next)?;
+        if done.tags.is_empty() {
+            return Ok(());
+        }
+
+        self.tags.insert(created.id, done.tags.iter().cloned().collect());
+        self.todos.get_mut(&created.id).unwrap().version += 1;
+        let tagged = self.todo(&self.todos[&created.id]);
+        self.record(actor_id, Some(&created), Some(&tagged));
+        Ok(())
+    }
+
+    fn delete(&mut self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        self.get_mut(id, expected_version)?;
+
+        // The subtasks all go into the trash at the same time as the todo,
+        // which is how restoring it knows to bring back just those.
+        let mut subtree = self.subtasks(id);
+        subtree.push(id);
+        subtree.sort();
+        let deleted_at = now();
+        let deleted = self.move_between(actor_id, &subtree, Some(deleted_at));
+
+        Ok(deleted.into_iter().find(|todo| todo.id == id).unwrap())
+    }
+
+    fn restore(&mut self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        let record = self.trash.get(&id).ok_or(TodoRepoError::NotFound(id))?;
+        if expected_version.is_some_and(|version| version != record.version) {
+            return Err(TodoRepoError::VersionMismatch(id));
+        }
+        if let Some(parent_id) = record.parent_id.filter(|parent_id| self.trash.contains_key(parent_id)) {
+            return Err(trashed_parent(id, parent_id));
+        }
+
+        let deleted_at = record.deleted_at;
+        let mut subtree = vec![id];
+        let mut parents = vec![id];
+        while let Some(parent_id) = parents.pop() {
+            let children = self.trash.values()
+                .filter(|record| record.parent_id == Some(parent_id) && record.deleted_at == deleted_at)
+                .map(|record| record.id);
+            for child_id in children {
+                subtree.push(child_id);
+                parents.push(child_id);
+            }
+        }
+        subtree.sort();
+        let restored = self.move_between(actor_id, &subtree, None);
+
+        Ok(restored.into_iter().find(|todo| todo.id == id).unwrap())
+    }
+
+    /// Moves the todos `ids` into the trash at `deleted_at`, or out of it if that is `None`, returning them as they end up.
+    fn move_between(&mut self, actor_id: Option<i64>, ids: &[i64], deleted_at: Option<PrimitiveDateTime>) -> Vec<Todo> {
+        // Which blockers count depends on what is in the trash, so every todo
+        // is read before any of them move, and again after they all have.
+        let from = if deleted_at.is_some() { &self.todos } else { &self.trash };
+        let befores: Vec<Todo> = ids.iter().map(|id| self.todo(&from[id])).collect();
+        for id in ids {
+            let (from, to) = if deleted_at.is_some() { (&mut self.todos, &mut self.trash) } else { (&mut self.trash, &mut self.todos) };
+            let record = from.remove(id).unwrap();
+            to.insert(*id, TodoRecord { deleted_at, version: record.version + 1, ..record });
+        }
+
+        let mut moved = Vec::with_capacity(ids.len());
+        for before in befores {
+            let after = self.todo(if deleted_at.is_some() { &self.trash[&before.id] } else { &self.todos[&before.id] });
+            self.record(actor_id, Some(&before), Some(&after));
+            moved.push(after);
+        }
+
+        moved
+    }
+
+    /// Adds or removes a tag, bumping the version only if that changed anything.
+    fn retag(&mut self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, tag: &str, add: bool) -> Result<Todo, TodoRepoError> {
+        let record = self.get_mut(id, expected_version)?;
+        if add && record.owner_id.is_none() {
+            return Err(untaggable());
+        }
+
+        let before = self.todo(&self.todos[&id]);
+        let tags = self.tags.entry(id).or_default();
+        let changed = if add { tags.insert(tag.to_string()) } else { tags.remove(tag) };
+        if changed {
+            self.todos.get_mut(&id).unwrap().version += 1;
+        }
+
+        let todo = self.todo(&self.todos[&id]);
+        self.record(actor_id, Some(&before), Some(&todo));
+        Ok(todo)
+    }
+
+    /// Gives the todo `id` the UID `uid`, taking it from any other of its owner's todos that had it.
+    fn set_uid(&mut self, id: i64, uid: &str) -> Result<(), TodoRepoError> {
+        let owner_id = self.todos.get(&id).ok_or(TodoRepoError::NotFound(id))?.owner_id.ok_or_else(|| no_owner(id))?;
+
+        let TodoStore { todos, trash, uids, .. } = self;
+        uids.retain(|other_id, other_uid| {
+            let other_owner_id = todos.get(other_id).or_else(|| trash.get(other_id)).and_then(|record| record.owner_id);
+            other_uid != uid || other_owner_id != Some(owner_id)
+        });
+        uids.insert(id, uid.to_string());
+
+        Ok(())
+    }
+
+    /// Deletes the todos `doomed` picks out for good, with their subtasks, as deleting their owner does.
+    fn remove_todos(&mut self, doomed: impl Fn(&TodoRecord) -> bool) {
+        let live: Vec<TodoRecord> = self.todos.values().cloned().collect();
+        self.todos.retain(|_, record| !doomed(record));
+        self.trash.retain(|_, record| !doomed(record));
+        self.drop_orphans();
+
+        for record in live {
+            if !self.todos.contains_key(&record.id) {
+                self.change(record.id, record.owner_id, TodoChangeKind::Deleted);
+            }
+        }
+    }
+
+    /// Whether the todo `id` is still there, in the trash or out of it.
+    fn exists(&self, id: i64) -> bool {
+        self.todos.contains_key(&id) || self.trash.contains_key(&id)
+    }
+
+    /// Deletes the subtasks of todos that are no longer there, and forgets the tags and links of all of them.
+    fn drop_orphans(&mut self) {
+        loop {
+            let orphans: Vec<i64> = self.todos.values().chain(self.trash.values())
+                .filter(|record| record.parent_id.is_some_and(|parent_id| !self.exists(parent_id)))
+                .map(|record| record.id)
+                .collect();
+            if orphans.is_empty() {
+                break;
+            }
+            for id in orphans {
+                self.todos.remove(&id);
+                self.trash.remove(&id);
+            }
+        }
+
+        let (todos, trash) = (&self.todos, &self.trash);
+        let exists = |id: &i64| todos.contains_key(id) || trash.contains_key(id);
+        self.tags.retain(|id, _| exists(id));
+        self.blockers.retain(|id, _| exists(id));
+        self.uids.retain(|id, _| exists(id));
+        for blockers in self.blockers.values_mut() {
+            blockers.retain(exists);
+        }
+    }
+}
+
+#[async_trait]
+impl TodoRepo for TodoRepoInMemory {
+    async fn get_all(&self, query: &TodoListQuery) -> Result<TodoPage, TodoRepoError> {
+        let store = self.lock().await;
+
+        let mut records: Vec<TodoRecord> = store.todos.values()
+            .filter(|record| matches_filter(&query.filter, record, store.tags.get(&record.id)))
+            .filter(|record| query.filter.list_id.is_some() || !store.in_archived_list(record))
+            .filter(|record| query.filter.actionable.is_none_or(|actionable| store.open_blockers(record.id).is_empty() == actionable))
+            .filter(|record| match &query.cursor {
+                Some(cursor) => compare(query.sort, record, &cursor.key, cursor.id) == Ordering::Greater,
+                None => true,
+            })
+            .cloned()
+            .collect();
+        records.sort_by(|a, b| compare(query.sort, a, &sort_key(query.sort, b), b.id));
+        records.truncate(query.limit as usize + 1);
+
+        let mut page = TodoPage::from_records(records, query);
+        for todo in &mut page.items {
+            *todo = store.todo(&store.todos[&todo.id]);
+        }
+
+        Ok(page)
+    }
+
+    async fn search(&self, query: &TodoSearchQuery) -> Result<Vec<TodoSearchHit>, TodoRepoError> {
+        let store = self.lock().await;
+        // Unlike Postgres, there is no stemming or query syntax: a todo
+        // matches if it contains every word, ignoring case.
+        let terms = query.terms();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut hits: Vec<TodoSearchHit> = store.todos.values()
+            .filter(|record| query.owner_id.is_none_or(|owner_id| record.owner_id == Some(owner_id)))
+            .filter(|record| !store.in_archived_list(record))
+            .filter_map(|record| {
+                let (title_highlight, title_matches) = highlight(&record.title, &terms);
+                let (description_highlight, description_matches) = highlight(&record.description, &terms);
+                if !terms.iter().all(|term| title_matches.contains(term) || description_matches.contains(term)) {
+                    return None;
+                }
+
+                Some(TodoSearchHit {
+                    todo: store.todo(record),
+                    rank: title_matches.len() as f32 + 0.4 * description_matches.len() as f32,
+                    title_highlight,
+                    description_highlight,
+                })
+            })
+            .collect();
+        hits.sort_by(|a, b| b.rank.total_cmp(&a.rank).then(a.todo.id.cmp(&b.todo.id)));
+        hits.truncate(query.limit as usize);
+
+        Ok(hits)
+    }
+
+    async fn create(&self, owner_id: Option<i64>, todo: CreateTodo) -> Result<Todo, TodoRepoError> {
+        self.lock().await.create(owner_id, todo)
+    }
+
+    async fn get(&self, id: i64) -> Result<Todo, TodoRepoError> {
+        let store = self.lock().await;
+
+        store.todos.get(&id).map(|record| store.todo(record)).ok_or(TodoRepoError::NotFound(id))
+    }
+
+    async fn get_subtree(&self, id: i64) -> Result<Vec<Todo>, TodoRepoError> {
+        let store = self.lock().await;
+        let root = store.todos.get(&id).ok_or(TodoRepoError::NotFound(id))?;
+
+        let mut todos = vec![store.todo(root)];
+        todos.extend(store.subtasks(id).into_iter().map(|subtask_id| store.todo(&store.todos[&subtask_id])));
+        todos.sort_by_key(|todo| todo.id);
+
+        Ok(todos)
+    }
+
+    async fn update(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, changes: &UpdateTodo) -> Result<Todo, TodoRepoError> {
+        self.lock().await.update(actor_id, id, expected_version, changes.clone())
+    }
+
+    async fn patch(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, patch: &TodoPatch, force: bool) -> Result<Todo, TodoRepoError> {
+        let mut store = self.lock().await;
+        let current = store.get_mut(id, expected_version)?.clone();
+
+        // Nothing is written unless the whole patch applies.
+        let current = store.todo(&current);
+        let patched = patch.apply(&current)?;
+        let blockers = store.open_blockers(id);
+        if patched.done && !current.done && !force && !blockers.is_empty() {
+            return Err(blocked(id, &blockers));
+        }
+        let (recurrence, handed_on) = recurrence_after(&current, &patched.as_update())?;
+        let record = store.todos.get_mut(&id).unwrap();
+        record.title = patched.title;
+        record.description = patched.description;
+        record.completed_at = current.completed_at_if_done(patched.done, now());
+        record.done = patched.done;
+        record.due_at = patched.due_at;
+        record.priority = patched.priority.as_str().to_string();
+        record.recurrence = recurrence.as_ref().map(Recurrence::to_string);
+        record.version += 1;
+
+        let record = record.clone();
+        let todo = store.todo(&record);
+        store.record(actor_id, Some(&current), Some(&todo));
+        if let Some(recurrence) = handed_on {
+            store.recur(actor_id, &todo, &recurrence)?;
+        }
+        Ok(todo)
+    }
+
+    async fn delete(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        self.lock().await.delete(actor_id, id, expected_version)
+    }
+
+    async fn move_todo(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, list_id: Option<i64>) -> Result<Todo, TodoRepoError> {
+        let mut store = self.lock().await;
+        store.check_list_exists(list_id)?;
+        let current = store.get_mut(id, expected_version)?.clone();
+        let current = store.todo(&current);
+
+        let record = store.todos.get_mut(&id).unwrap();
+        record.list_id = list_id;
+        record.version += 1;
+
+        let record = record.clone();
+        let todo = store.todo(&record);
+        store.record(actor_id, Some(&current), Some(&todo));
+        Ok(todo)
+    }
+
+    async fn reorder(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, placement: TodoPlacement) -> Result<Todo, TodoRepoError> {
+        let mut store = self.lock().await;
+        let current = store.get_mut(id, expected_version)?.clone();
+        let current = store.todo(&current);
+        let owner_id = current.owner_id;
+        let anchor_id = placement.anchor_id();
+        if anchor_id == id {
+            return Err(unmovable(id));
+        }
+        let anchor = store.todos.get(&anchor_id)
+            .filter(|anchor| anchor.owner_id == owner_id)
+            .ok_or_else(|| missing_anchor(anchor_id))?
+            .position.as_str();
+
+        // Trashed todos keep their positions, and so count, so that restoring
+        // one cannot leave it tied with another.
+        let others = store.todos.values().chain(store.trash.values())
+            .filter(|record| record.owner_id == owner_id && record.id != id)
+            .map(|record| record.position.as_str());
+        let (lower, upper) = match placement {
+            TodoPlacement::Before(_) => (others.filter(|position| *position < anchor).max(), Some(anchor)),
+            TodoPlacement::After(_) => (Some(anchor), others.filter(|position| *position > anchor).min()),
+        };
+        let position = rank_between(lower, upper).ok_or_else(|| unmovable(id))?;
+
+        let record = store.todos.get_mut(&id).unwrap();
+        record.position = position;
+        record.version += 1;
+
+        let record = record.clone();
+        let todo = store.todo(&record);
+        store.record(actor_id, Some(&current), Some(&todo));
+        Ok(todo)
+    }
+
+    async fn tag(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, tag: &str) -> Result<Todo, TodoRepoError> {
+        self.lock().await.retag(actor_id, id, expected_version, tag, true)
+    }
+
+    async fn untag(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, tag: &str) -> Result<Todo, TodoRepoError> {
+        self.lock().await.retag(actor_id, id, expected_version, tag, false)
+    }
+
+    async fn block(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, blocker_id: i64) -> Result<Todo, TodoRepoError> {
+        self.lock().await.reblock(actor_id, id, expected_version, blocker_id, true)
+    }
+
+    async fn unblock(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, blocker_id: i64) -> Result<Todo, TodoRepoError> {
+        self.lock().await.reblock(actor_id, id, expected_version, blocker_id, false)
+    }
+
+    async fn batch(&self, owner_id: Option<i64>, operations: &[TodoOperation]) -> Result<Vec<TodoOperationResult>, TodoBatchError> {
+        let mut store = self.lock().await;
+
+        // Work on a copy, which only replaces the real thing if every operation succeeds.
+        let mut scratch = store.clone();
+        let mut results = Vec::with_capacity(operations.len());
+        for (index, operation) in operations.iter().cloned().enumerate() {
+            let result = match operation {
+                TodoOperation::Create(todo) => scratch.create(owner_id, todo).map(TodoOperationResult::Created),
+                TodoOperation::Update { id, version, changes } => scratch.check_owner(owner_id, id)
+                    .and_then(|()| scratch.update(owner_id, id, version, changes))
+                    .map(TodoOperationResult::Updated),
+                TodoOperation::Delete { id, version } => scratch.check_owner(owner_id, id)
+                    .and_then(|()| scratch.delete(owner_id, id, version))
+                    .map(TodoOperationResult::Deleted),
+            };
+            results.push(result.map_err(TodoBatchError::at(index))?);
+        }
+        *store = scratch;
+
+        Ok(results)
+    }
+
+    async fn import(&self, owner_id: Option<i64>, todo: &CreateTodo, done: bool, tags: &[&str], uid: Option<&str>) -> Result<Todo, TodoRepoError> {
+        let mut store = self.lock().await;
+
+        // Work on a copy, as batches do, so that nothing is kept unless all of it is.
+        let mut scratch = store.clone();
+        let mut imported = scratch.create(owner_id, todo.clone())?;
+        if let Some(uid) = uid {
+            scratch.set_uid(imported.id, uid)?;
+        }
+        if done {
+            let done = UpdateTodo { done: Some(true), force: true, ..Default::default() };
+            imported = scratch.update(owner_id, imported.id, None, done)?;
+        }
+        for tag in tags {
+            imported = scratch.retag(owner_id, imported.id, None, tag, true)?;
+        }
+        *store = scratch;
+
+        Ok(imported)
+    }
+
+    async fn get_due(&self, query: &TodoDueQuery) -> Result<Vec<Todo>, TodoRepoError> {
+        let store = self.lock().await;
+
+        let mut records: Vec<(OffsetDateTime, &TodoRecord)> = store.todos.values()
+            .filter(|record| !record.done)
+            .filter(|record| query.owner_id.is_none_or(|owner_id| record.owner_id == Some(owner_id)))
+            .filter(|record| !store.in_archived_list(record))
+            .filter_map(|record| record.due_at.map(|due_at| (due_at, record)))
+            .filter(|(due_at, _)| *due_at < query.due_before && query.due_from.is_none_or(|due_from| *due_at >= due_from))
+            .collect();
+        records.sort_by_key(|(due_at, record)| (*due_at, record.id));
+        records.truncate(query.limit as usize);
+
+        Ok(records.into_iter().map(|(_, record)| store.todo(record)).collect())
+    }
+
+    async fn get_history(&self, id: i64) -> Result<Vec<TodoEvent>, TodoRepoError> {
+        Ok(self.lock().await.events.iter().filter(|event| event.todo_id == id).cloned().collect())
+    }
+
+    async fn last_modified(&self, owner_id: i64) -> Result<BTreeMap<i64, OffsetDateTime>, TodoRepoError> {
+        let store = self.lock().await;
+        let mut last_modified: BTreeMap<i64, OffsetDateTime> = store.todos.values()
+            .filter(|record| record.owner_id == Some(owner_id))
+            .map(|record| (record.id, record.created_at.assume_utc()))
+            .collect();
+        // Events are kept in the order they happened, so the last of each todo's wins.
+        for event in &store.events {
+            if let Some(at) = last_modified.get_mut(&event.todo_id) {
+                *at = event.occurred_at;
+            }
+        }
+
+        Ok(last_modified)
+    }
+
+    async fn get_audit(&self, query: &TodoAuditQuery) -> Result<Vec<TodoEvent>, TodoRepoError> {
+        let store = self.lock().await;
+
+        Ok(store.events.iter()
+            .filter(|event| query.owner_id.is_none_or(|owner_id| event.owner_id == Some(owner_id)))
+            .filter(|event| query.since.is_none_or(|since| event.occurred_at >= since.assume_utc()))
+            .filter(|event| query.after.is_none_or(|after| event.id > after))
+            .take(query.limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_changes(&self, query: &TodoChangeQuery) -> Result<Vec<TodoChange>, TodoRepoError> {
+        let store = self.lock().await;
+
+        Ok(store.changes.iter()
+            .filter(|change| query.owner_id.is_none_or(|owner_id| change.owner_id == Some(owner_id)))
+            .filter(|change| query.after.is_none_or(|after| change.id > after))
+            .take(query.limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn last_change(&self, owner_id: i64) -> Result<Option<i64>, TodoRepoError> {
+        let store = self.lock().await;
+
+        Ok(store.changes.iter().rev().find(|change| change.owner_id == Some(owner_id)).map(|change| change.id))
+    }
+
+    fn watch_changes(&self) -> broadcast::Receiver<TodoChange> {
+        self.changes.subscribe()
+    }
+
+    fn export(&self, owner_id: i64) -> BoxStream<'_, Result<Todo, TodoRepoError>> {
+        futures_util::stream::once(async move {
+            let store = self.lock().await;
+            let todos: Vec<_> = store.todos.values()
+                .filter(|record| record.owner_id == Some(owner_id))
+                .map(|record| Ok(store.todo(record)))
+                .collect();
+            futures_util::stream::iter(todos)
+        })
+            .flatten()
+            .boxed()
+    }
+
+    async fn get_uids(&self, owner_id: i64) -> Result<BTreeMap<i64, String>, TodoRepoError> {
+        let store = self.lock().await;
+
+        Ok(store.uids.iter()
+            .filter(|(id, _)| store.todos.get(id).is_some_and(|record| record.owner_id == Some(owner_id)))
+            .map(|(id, uid)| (*id, uid.clone()))
+            .collect())
+    }
+
+    async fn set_uid(&self, id: i64, uid: &str) -> Result<(), TodoRepoError> {
+        self.lock().await.set_uid(id, uid)
+    }
+
+    async fn get_trash(&self, query: &TodoTrashQuery) -> Result<Vec<Todo>, TodoRepoError> {
+        let store = self.lock().await;
+
+        let mut records: Vec<&TodoRecord> = store.trash.values()
+            .filter(|record| query.owner_id.is_none_or(|owner_id| record.owner_id == Some(owner_id)))
+            .collect();
+        records.sort_by(|a, b| b.deleted_at.cmp(&a.deleted_at).then(a.id.cmp(&b.id)));
+        records.truncate(query.limit as usize);
+
+        Ok(records.into_iter().map(|record| store.todo(record)).collect())
+    }
+
+    async fn get_trashed(&self, id: i64) -> Result<Todo, TodoRepoError> {
+        let store = self.lock().await;
+
+        store.trash.get(&id).map(|record| store.todo(record)).ok_or(TodoRepoError::NotFound(id))
+    }
+
+    async fn restore(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        self.lock().await.restore(actor_id, id, expected_version)
+    }
+
+    async fn purge_trash(&self, deleted_before: PrimitiveDateTime) -> Result<u64, TodoRepoError> {
+        let mut store = self.lock().await;
+
+        // A subtask never goes into the trash after its parent, so this never
+        // leaves one behind whose parent is purged.
+        let purged: Vec<Todo> = store.trash.values()
+            .filter(|record| record.deleted_at.is_some_and(|deleted_at| deleted_at < deleted_before))
+            .map(|record| store.todo(record))
+            .collect();
+        for todo in &purged {
+            store.trash.remove(&todo.id);
+        }
+        store.drop_orphans();
+        for todo in &purged {
+            store.record(None, Some(todo), None);
+        }
+
+        Ok(purged.len() as u64)
+    }
+}
+
+#[async_trait]
+impl UserRepo for TodoRepoInMemory {
+    async fn get_users(&self) -> Result<Vec<User>, TodoRepoError> {
+        Ok(self.lock().await.users.values().map(|(user, _)| user.clone()).collect())
+    }
+
+    async fn get_user(&self, id: i64) -> Result<User, TodoRepoError> {
+        let store = self.lock().await;
+
+        store.users.get(&id).map(|(user, _)| user.clone()).ok_or(TodoRepoError::UserNotFound(id))
+    }
+
+    async fn create_user(&self, name: String, email: String, token_hash: Vec<u8>) -> Result<User, TodoRepoError> {
+        let mut store = self.lock().await;
+        store.check_email_free(&email, None)?;
+        store.last_user_id += 1;
+
+        let user = User { id: store.last_user_id, name, email };
+        store.users.insert(user.id, (user.clone(), token_hash));
+
+        Ok(user)
+    }
+
+    async fn update_user(&self, id: i64, name: Option<String>, email: Option<String>) -> Result<User, TodoRepoError> {
+        let mut store = self.lock().await;
+        if let Some(email) = &email {
+            store.check_email_free(email, Some(id))?;
+        }
+        let (user, _) = store.users.get_mut(&id).ok_or(TodoRepoError::UserNotFound(id))?;
+
+        if let Some(name) = name {
+            user.name = name;
+        }
+        if let Some(email) = email {
+            user.email = email;
+        }
+
+        Ok(user.clone())
+    }
+
+    async fn delete_user(&self, id: i64) -> Result<User, TodoRepoError> {
+        let mut store = self.lock().await;
+        let (user, _) = store.users.remove(&id).ok_or(TodoRepoError::UserNotFound(id))?;
+        store.feed_tokens.remove(&id);
+        store.remove_todos(|record| record.owner_id == Some(id));
+        store.lists.retain(|_, list| list.owner_id != id);
+
+        Ok(user)
+    }
+
+    async fn authenticate(&self, token_hash: &[u8]) -> Result<Option<User>, TodoRepoError> {
+        let store = self.lock().await;
+
+        Ok(store.users.values().find(|(_, hash)| hash == token_hash).map(|(user, _)| user.clone()))
+    }
+
+    async fn set_feed_token(&self, id: i64, feed_token_hash: Vec<u8>) -> Result<User, TodoRepoError> {
+        let mut store = self.lock().await;
+        let (user, _) = store.users.get(&id).cloned().ok_or(TodoRepoError::UserNotFound(id))?;
+        store.feed_tokens.insert(id, feed_token_hash);
+
+        Ok(user)
+    }
+
+    async fn feed_owner(&self, feed_token_hash: &[u8]) -> Result<Option<User>, TodoRepoError> {
+        let store = self.lock().await;
+
+        Ok(store.feed_tokens.iter().find(|(_, hash)| *hash == feed_token_hash).map(|(id, _)| store.users[id].0.clone()))
+    }
+}
+
+#[async_trait]
+impl TodoListRepo for TodoRepoInMemory {
+    async fn get_lists(&self, owner_id: i64, include_archived: bool) -> Result<Vec<TodoList>, TodoRepoError> {
+        let store = self.lock().await;
+
+        Ok(store.lists.values().filter(|list| list.owner_id == owner_id && (include_archived || !list.archived)).cloned().collect())
+    }
+
+    async fn get_list(&self, id: i64) -> Result<TodoList, TodoRepoError> {
+        self.lock().await.lists.get(&id).cloned().ok_or(TodoRepoError::ListNotFound(id))
+    }
+
+    async fn create_list(&self, owner_id: i64, name: String) -> Result<TodoList, TodoRepoError> {
+        let mut store = self.lock().await;
+        if !store.users.contains_key(&owner_id) {
+            return Err(TodoRepoError::Conflict("the list's owner does not exist".to_string()));
+        }
+        store.last_list_id += 1;
+
+        let list = TodoList { id: store.last_list_id, owner_id, name, archived: false };
+        store.lists.insert(list.id, list.clone());
+
+        Ok(list)
+    }
+
+    async fn update_list(&self, id: i64, name: Option<String>, archived: Option<bool>) -> Result<TodoList, TodoRepoError> {
+        let mut store = self.lock().await;
+        let list = store.lists.get_mut(&id).ok_or(TodoRepoError::ListNotFound(id))?;
+
+        if let Some(name) = name {
+            list.name = name;
+        }
+        if let Some(archived) = archived {
+            list.archived = archived;
+        }
+
+        Ok(list.clone())
+    }
+
+    async fn delete_list(&self, actor_id: Option<i64>, id: i64) -> Result<TodoList, TodoRepoError> {
+        let mut store = self.lock().await;
+        let list = store.lists.remove(&id).ok_or(TodoRepoError::ListNotFound(id))?;
+        let listed: Vec<i64> = store.todos.values().filter(|record| record.list_id == Some(id)).map(|record| record.id).collect();
+        let doomed: BTreeSet<i64> = listed.iter().flat_map(|&todo_id| store.subtasks(todo_id).into_iter().chain([todo_id])).collect();
+        store.move_between(actor_id, &doomed.into_iter().collect::<Vec<_>>(), Some(now()));
+
+        // Todos in the trash outlast the list, just not in it.
+        for record in store.trash.values_mut().filter(|record| record.list_id == Some(id)) {
+            record.list_id = None;
+        }
+
+        Ok(list)
+    }
+}
+
+fn matches_filter(filter: &TodoFilter, record: &TodoRecord, tags: Option<&BTreeSet<String>>) -> bool {
+    let has_tag = |tag: &String| tags.is_some_and(|tags| tags.contains(tag));
+    let tagged = filter.tags.is_empty() || match filter.tag_match {
+        TagMatch::Any => filter.tags.iter().any(has_tag),
+        TagMatch::All => filter.tags.iter().all(has_tag),
+    };
+
+    tagged
+        && filter.owner_id.is_none_or(|owner_id| record.owner_id == Some(owner_id))
+        && filter.list_id.is_none_or(|list_id| record.list_id == Some(list_id))
+        && filter.done.is_none_or(|done| record.done == done)
+        && filter.created_after.is_none_or(|after| record.created_at > after)
+        && filter.created_before.is_none_or(|before| record.created_at < before)
+}
+
+fn sort_key(sort: TodoSort, record: &TodoRecord) -> TodoSortKey {
+    match sort.field {
+        TodoSortField::Position => TodoSortKey::Position(record.position.clone()),
+        TodoSortField::CreatedAt => TodoSortKey::CreatedAt(record.created_at),
+        TodoSortField::Title => TodoSortKey::Title(record.title.clone()),
+    }
+}
+
+/// Where `record` falls relative to the position `(key, id)`, in the order
+/// given by `sort`.
+fn compare(sort: TodoSort, record: &TodoRecord, key: &TodoSortKey, id: i64) -> Ordering {
+    let ordering = match key {
+        TodoSortKey::Position(position) => record.position.as_bytes().cmp(position.as_bytes()),
+        TodoSortKey::CreatedAt(created_at) => record.created_at.cmp(created_at),
+        TodoSortKey::Title(title) => record.title.as_bytes().cmp(title.as_bytes()),
+    }
+        .then(record.id.cmp(&id));
+
+    if sort.descending { ordering.reverse() } else { ordering }
+}
+
+/// Wraps each of `terms` in `text` in `<mark>` tags, and reports which terms were found.
+fn highlight(text: &str, terms: &[String]) -> (String, Vec<String>) {
+    let mut highlighted = String::with_capacity(text.len());
+    let mut found = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find(char::is_alphanumeric) {
+        highlighted.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = rest.find(|c: char| !c.is_alphanumeric()).unwrap_or(rest.len());
+        let word = &rest[..end];
+
+        let lowercase = word.to_lowercase();
+        if terms.contains(&lowercase) {
+            highlighted.push_str("<mark>");
+            highlighted.push_str(word);
+            highlighted.push_str("</mark>");
+            if !found.contains(&lowercase) {
+                found.push(lowercase);
+            }
+        } else {
+            highlighted.push_str(word);
+        }
+        rest = &rest[end..];
+    }
+    highlighted.push_str(rest);
+
+    (highlighted, found)
+}
+
+#[tokio::test]
+async fn todo_repo_in_memory_behaves() {
+    check_todo_repo_behaviour(&TodoRepoInMemory::default()).await;
+}
+
+#[tokio::test]
+async fn todo_repo_in_memory_searches() {
+    let repo = TodoRepoInMemory::default();
+    let in_description = repo.create(None, CreateTodo::new("Visit Rottnest", "Take a selfie with a quokka")).await.unwrap();
+    let in_title = repo.create(None, CreateTodo::new("Feed the Quokka", "Leaves only")).await.unwrap();
+    repo.create(None, CreateTodo::new("Feed the cat", "")).await.unwrap();
+
+    let hits = repo.search(&TodoSearchQuery { q: "quokka".to_string(), limit: 10, owner_id: None }).await.unwrap();
+
+    assert_eq!(hits.iter().map(|h| h.todo.id).collect::<Vec<_>>(), vec![in_title.id, in_description.id]);
+    assert_eq!(hits[0].title_highlight, "Feed the <mark>Quokka</mark>");
+    assert_eq!(hits[1].description_highlight, "Take a selfie with a <mark>quokka</mark>");
+}
+
+#[tokio::test]
+async fn todo_handlers_without_a_database() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoInMemory::default();
+    let (owner, token) = sign_up(&repo).await;
+    let app = todo_router(repo);
+
+    let request = |method: hyper::Method, uri: &str, body: &str| {
+        hyper::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let response = app.clone()
+        .oneshot(request(hyper::Method::POST, "/todos", r#"{"title": "Learn Axum", "description": "Handlers first"}"#))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let created: Todo = serde_json::from_slice(&body).unwrap();
+    assert_eq!((created.id, created.owner_id), (1, Some(owner.id)));
+
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/todos", "")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(page["items"], serde_json::json!([created]));
+
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "/todos/1", r#"{"done": true}"#)).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let updated: Todo = serde_json::from_slice(&body).unwrap();
+    assert!(updated.completed_at.is_some());
+    assert_eq!(updated, Todo { done: true, completed_at: updated.completed_at, version: 2, ..created });
+
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "/todos/1/tags/errands", "")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let tagged: Todo = serde_json::from_slice(&body).unwrap();
+    assert_eq!(tagged, Todo { tags: vec!["errands".to_string()], version: 3, ..updated.clone() });
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "/todos/1/tags/%20", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    for (query, expected) in [("tag=errands&tag=home&tag_match=any", serde_json::json!([tagged])), ("tag=errands&tag=home", serde_json::json!([]))] {
+        let response = app.clone().oneshot(request(hyper::Method::GET, &format!("/todos?{}", query), "")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(page["items"], expected);
+    }
+
+    let response = app.clone().oneshot(request(hyper::Method::DELETE, "/todos/1/tags/errands", "")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let untagged: Todo = serde_json::from_slice(&body).unwrap();
+    assert_eq!(untagged, Todo { version: 4, ..updated });
+
+    let response = app.clone().oneshot(request(hyper::Method::DELETE, "/todos/1", "")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let deleted: Todo = serde_json::from_slice(&body).unwrap();
+    assert!(deleted.deleted_at.is_some());
+    assert_eq!(deleted, Todo { version: 5, deleted_at: deleted.deleted_at, ..untagged.clone() });
+
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/todos/1", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Deleted todos wait in the trash until they are restored, or deleted again.
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/trash", "")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let trash: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(trash, serde_json::json!([deleted]));
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/trash?limit=0", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let restore = |version: &'static str| {
+        let mut restore = request(hyper::Method::POST, "/todos/1/restore", "");
+        restore.headers_mut().insert(header::IF_MATCH, header::HeaderValue::from_static(version));
+        restore
+    };
+    let response = app.clone().oneshot(restore("\"4\"")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::PRECONDITION_FAILED);
+    let response = app.clone().oneshot(restore("\"5\"")).await.unwrap();
+    assert_eq!(response.headers()[header::ETAG], "\"6\"");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let restored: Todo = serde_json::from_slice(&body).unwrap();
+    assert_eq!(restored, Todo { version: 6, ..untagged });
+    let response = app.clone().oneshot(request(hyper::Method::DELETE, "/todos/1", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Due dates can be given with any offset, and come back in UTC.
+    let body = r#"{"title": "Renew passport", "description": "", "due_at": "2020-01-01T09:00:00+10:00", "priority": "high"}"#;
+    let response = app.clone().oneshot(request(hyper::Method::POST, "/todos", body)).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let late: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!((&late["due_at"], &late["priority"]), (&serde_json::json!("2019-12-31T23:00:00Z"), &serde_json::json!("high")));
+
+    for (uri, expected) in [("/todos/overdue", serde_json::json!([late])), ("/todos/upcoming?within=2w", serde_json::json!([]))] {
+        let response = app.clone().oneshot(request(hyper::Method::GET, uri, "")).await.unwrap();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let todos: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(todos, expected);
+    }
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/todos/upcoming?within=soon", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "/todos/2", r#"{"priority": "someday"}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Subtasks come back nested inside their parent when asked for.
+    let body = r#"{"title": "Find old passport", "description": "", "parent_id": 2}"#;
+    let response = app.clone().oneshot(request(hyper::Method::POST, "/todos", body)).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let subtask: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/todos/2?expand=subtree", "")).await.unwrap();
+    assert_eq!(response.headers()[header::ETAG], "\"1\"");
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let tree: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    let (mut expected, mut nested) = (late.clone(), subtask.clone());
+    nested["subtasks"] = serde_json::json!([]);
+    expected["subtasks"] = serde_json::json!([nested]);
+    assert_eq!(tree, expected);
+
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/todos/2?expand=everything", "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // The passport cannot be renewed until the old one is found, short of forcing it.
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "/todos/2/blockers/3", "")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let blocked: Todo = serde_json::from_slice(&body).unwrap();
+    assert_eq!((blocked.blocked_by, blocked.version), (vec![3], 2));
+    for (uri, status) in [("/todos/3/blockers/2", StatusCode::UNPROCESSABLE_ENTITY), ("/todos/2/blockers/4", StatusCode::NOT_FOUND)] {
+        let response = app.clone().oneshot(request(hyper::Method::PUT, uri, "")).await.unwrap();
+        assert_eq!(response.status(), status);
+    }
+
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/todos?actionable=true", "")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(page["items"], serde_json::json!([subtask]));
+
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "/todos/2", r#"{"done": true}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "/todos/2", r#"{"done": true, "force": true}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let mut patch = request(hyper::Method::PATCH, "/todos/3?force=yes", r#"{"done": true}"#);
+    patch.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static(MERGE_PATCH_CONTENT_TYPE));
+    let response = app.clone().oneshot(patch).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    let response = app.clone().oneshot(request(hyper::Method::DELETE, "/todos/2/blockers/3", "")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let unblocked: Todo = serde_json::from_slice(&body).unwrap();
+    assert_eq!((unblocked.blocked_by, unblocked.version), (Vec::new(), 4));
+
+    // Dragging the subtask above its parent moves it there in listings.
+    let response = app.clone().oneshot(request(hyper::Method::POST, "/todos/3/move", r#"{"before": 2}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/todos", "")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let page: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!((&page["items"][0]["id"], &page["items"][1]["id"]), (&serde_json::json!(3), &serde_json::json!(2)));
+    for (body, status) in [(r#"{"before": 2, "after": 2}"#, StatusCode::BAD_REQUEST), (r#"{"after": 9}"#, StatusCode::NOT_FOUND)] {
+        let response = app.clone().oneshot(request(hyper::Method::POST, "/todos/3/move", body)).await.unwrap();
+        assert_eq!(response.status(), status);
+    }
+}
+
+#[tokio::test]
+async fn todo_events_without_a_database() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoInMemory::default();
+    let (owner, token) = sign_up(&repo).await;
+    let (stranger, _) = sign_up(&repo).await;
+    let app = todo_router(repo.clone());
+
+    let request = |last_event_id: &str| {
+        hyper::Request::builder()
+            .uri("/todos/events")
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Last-Event-ID", last_event_id)
+            .body(Body::empty())
+            .unwrap()
+    };
+    // The next event down the stream, skipping keep-alives.
+    async fn next_event(body: &mut Body) -> String {
+        loop {
+            let frame = tokio::time::timeout(Duration::from_secs(5), body.frame()).await.unwrap().unwrap().unwrap();
+            let event = String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap();
+            if !event.starts_with(':') {
+                return event;
+            }
+        }
+    }
+
+    let response = app.clone().oneshot(request("")).await.unwrap();
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+    let mut events = response.into_body();
+    repo.create(Some(stranger.id), CreateTodo::new("Not yours", "")).await.unwrap();
+    let todo = repo.create(Some(owner.id), CreateTodo::new("Watch this", "")).await.unwrap();
+    let created = serde_json::json!({ "todo_id": todo.id, "todo": todo });
+    assert_eq!(next_event(&mut events).await, format!("id: 2\nevent: created\ndata: {}\n\n", created));
+    repo.delete(Some(owner.id), todo.id, None).await.unwrap();
+    let deleted = serde_json::json!({ "todo_id": todo.id, "todo": null });
+    assert_eq!(next_event(&mut events).await, format!("id: 3\nevent: deleted\ndata: {}\n\n", deleted));
+
+    // Reconnecting picks up after the last event the client got.
+    let mut events = app.clone().oneshot(request("2")).await.unwrap().into_body();
+    assert_eq!(next_event(&mut events).await, format!("id: 3\nevent: deleted\ndata: {}\n\n", deleted));
+    let response = app.oneshot(request("latest")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn todo_socket_without_a_database() {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+
+    let repo = TodoRepoInMemory::default();
+    let (owner, token) = sign_up(&repo).await;
+    let (stranger, _) = sign_up(&repo).await;
+    let list = repo.create_list(owner.id, "Errands".to_string()).await.unwrap();
+    let elsewhere = repo.create_list(stranger.id, "Elsewhere".to_string()).await.unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/ws/todos", listener.local_addr().unwrap());
+    let app = todo_router(repo.clone());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+    let request = |token: Option<&str>| {
+        let mut request = url.as_str().into_client_request().unwrap();
+        if let Some(token) = token {
+            request.headers_mut().insert("Authorization", format!("Bearer {}", token).parse().unwrap());
+        }
+        request
+    };
+    // The same person, on two devices.
+    let (mut phone, _) = tokio_tungstenite::connect_async(request(Some(&token))).await.unwrap();
+    let (mut laptop, _) = tokio_tungstenite::connect_async(request(Some(&token))).await.unwrap();
+    assert!(tokio_tungstenite::connect_async(request(None)).await.is_err());
+
+    let send = |command: serde_json::Value| Message::Text(command.to_string());
+    // The next frame from the server, skipping pings.
+    async fn next_frame<S: futures_util::Stream<Item = Result<Message, tokio_tungstenite::tungstenite::Error>> + Unpin>(socket: &mut S) -> serde_json::Value {
+        loop {
+            match tokio::time::timeout(Duration::from_secs(5), socket.next()).await.unwrap().unwrap().unwrap() {
+                Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+                Message::Ping(_) => {}
+                message => panic!("unexpected frame {:?}", message),
+            }
+        }
+    }
+
+    for socket in [&mut phone, &mut laptop] {
+        socket.send(send(serde_json::json!({ "op": "subscribe", "ref": 1, "list_id": list.id }))).await.unwrap();
+        assert_eq!(next_frame(socket).await, serde_json::json!({ "type": "ack", "ref": 1, "list_id": list.id }));
+    }
+    phone.send(send(serde_json::json!({ "op": "subscribe", "ref": 2, "list_id": elsewhere.id }))).await.unwrap();
+    assert_eq!(next_frame(&mut phone).await["status"], 404);
+    phone.send(send(serde_json::json!({ "op": "fly", "ref": "away" }))).await.unwrap();
+    let error = next_frame(&mut phone).await;
+    assert_eq!((&error["type"], &error["ref"], &error["status"]), (&"error".into(), &"away".into(), &422.into()));
+
+    // Whoever sends a command gets an ack, and everyone else a broadcast.
+    phone.send(send(serde_json::json!({ "op": "create", "ref": 3, "title": "Post the parcel", "description": "", "list_id": list.id }))).await.unwrap();
+    let ack = next_frame(&mut phone).await;
+    let todo: Todo = serde_json::from_value(ack["todo"].clone()).unwrap();
+    assert_eq!((&ack["type"], &ack["ref"], &ack["result"]), (&"ack".into(), &3.into(), &"created".into()));
+    let created = serde_json::json!({ "type": "created", "list_id": list.id, "todo_id": todo.id, "todo": todo });
+    assert_eq!(next_frame(&mut laptop).await, created);
+
+    laptop.send(send(serde_json::json!({ "op": "update", "ref": 4, "id": todo.id, "version": todo.version, "done": true }))).await.unwrap();
+    let done: Todo = serde_json::from_value(next_frame(&mut laptop).await["todo"].clone()).unwrap();
+    assert!(done.done);
+    assert_eq!(next_frame(&mut phone).await, serde_json::json!({ "type": "updated", "list_id": list.id, "todo_id": todo.id, "todo": done }));
+    phone.send(send(serde_json::json!({ "op": "update", "ref": 5, "id": todo.id, "version": todo.version, "title": "Too late" }))).await.unwrap();
+    assert_eq!(next_frame(&mut phone).await["status"], 412);
+
+    // Changes made some other way are passed on too, but only for the subscribed lists.
+    repo.create(Some(owner.id), CreateTodo::new("Not in a list", "")).await.unwrap();
+    phone.send(send(serde_json::json!({ "op": "delete", "ref": 6, "id": todo.id }))).await.unwrap();
+    assert_eq!(next_frame(&mut phone).await["result"], "deleted");
+    assert_eq!(next_frame(&mut laptop).await, serde_json::json!({ "type": "deleted", "list_id": list.id, "todo_id": todo.id, "todo": null }));
+    laptop.send(send(serde_json::json!({ "op": "unsubscribe", "ref": 7, "list_id": list.id }))).await.unwrap();
+    assert_eq!(next_frame(&mut laptop).await["type"], "ack");
+    repo.restore(Some(owner.id), todo.id, None).await.unwrap();
+    assert_eq!(next_frame(&mut phone).await["type"], "created");
+    laptop.send(Message::Binary(Vec::new())).await.unwrap();
+    assert_eq!(next_frame(&mut laptop).await["status"], 422);
+}
+
+#[tokio::test]
+async fn todo_import_and_export_without_a_database() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoInMemory::default();
+    let (owner, token) = sign_up(&repo).await;
+    let list = repo.create_list(owner.id, "Errands".to_string()).await.unwrap();
+    let app = todo_router(repo.clone());
+
+    let send = |method: hyper::Method, uri: &str, body: String| {
+        let request = hyper::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(body))
+            .unwrap();
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let (status, content_type) = (response.status(), response.headers()[header::CONTENT_TYPE].clone());
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, content_type, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+    let import = |format: &str, dry_run: bool, body: String| send(hyper::Method::POST, &format!("/todos/import?format={}&dry_run={}", format, dry_run), body);
+    let export = |format: &str| send(hyper::Method::GET, &format!("/todos/export?format={}", format), String::new());
+
+    // Columns can come in any order, and rows can name earlier rows as their parents.
+    let csv = format!(
+        "title,description,done,tags,list_id,id,parent_id,due_at,priority,recurrence\r\n\
+         Pack,\"Socks, shirts\",false,travel; urgent,{},1,,2026-11-01T09:00:00+01:00,high,\"DTSTART;TZID=Europe/Berlin:20261101T090000\nRRULE:FREQ=MONTHLY;INTERVAL=12\"\r\n\
+         Book hotel,,true,,,2,1,,,\r\n\
+         ,No title,,,,,,,,\r\n\
+         Call home,,maybe,,,,,,,\r\n\
+         Too soon,,,,,,3,,,\r\n\
+         Plan,,,,,3,,,,\r\n",
+        list.id,
+    );
+    let (status, _, report) = import("csv", true, csv.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report, serde_json::json!({
+        "dry_run": true,
+        "imported": 3,
+        "errors": [
+            { "line": 5, "message": "missing field `title`" },
+            { "line": 6, "message": "done must be `true` or `false`" },
+            { "line": 7, "message": "todo 3 of this import comes later, or could not be imported" },
+        ],
+    }));
+    assert_eq!(export("ndjson").await.2, "");
+
+    let (_, _, imported) = import("csv", false, csv).await;
+    let mut report = report;
+    report["dry_run"] = false.into();
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&imported).unwrap(), report);
+    let (status, content_type, ndjson) = export("ndjson").await;
+    assert_eq!((status, content_type.to_str().unwrap()), (StatusCode::OK, "application/x-ndjson"));
+    let todos: Vec<PortableTodo> = ndjson.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    let pack = todos[0].id;
+    assert_eq!(todos, vec![
+        PortableTodo {
+            id: pack,
+            title: "Pack".to_string(),
+            description: "Socks, shirts".to_string(),
+            done: false,
+            due_at: Some(time::macros::datetime!(2026-11-01 08:00 UTC)),
+            priority: TodoPriority::High,
+            list_id: Some(list.id),
+            parent_id: None,
+            tags: vec!["travel".to_string(), "urgent".to_string()],
+            recurrence: Some(RecurrenceSpec { rule: "FREQ=MONTHLY;INTERVAL=12".to_string(), time_zone: "Europe/Berlin".to_string(), starts_at: Some("2026-11-01T09:00:00".to_string()) }),
+        },
+        PortableTodo { id: todos[1].id, title: "Book hotel".to_string(), description: String::new(), done: true, due_at: None, priority: TodoPriority::Normal, list_id: None, parent_id: pack, tags: Vec::new(), recurrence: None },
+        PortableTodo { id: todos[2].id, title: "Plan".to_string(), description: String::new(), done: false, due_at: None, priority: TodoPriority::Normal, list_id: None, parent_id: None, tags: Vec::new(), recurrence: None },
+    ]);
+
+    // Exports go back in as they came out.
+    let (status, content_type, csv) = export("csv").await;
+    assert_eq!((status, content_type.to_str().unwrap()), (StatusCode::OK, "text/csv; charset=utf-8"));
+    assert!(csv.starts_with("id,title,description,done,due_at,priority,list_id,parent_id,tags,recurrence\n"));
+    assert!(csv.contains(",Pack,\"Socks, shirts\",false,2026-11-01T08:00:00Z,high,"));
+    assert!(csv.contains(",travel;urgent,\"DTSTART;TZID=Europe/Berlin:20261101T090000\nRRULE:FREQ=MONTHLY;INTERVAL=12\"\n"));
+    let (status, content_type, ics) = export("ics").await;
+    assert_eq!((status, content_type.to_str().unwrap()), (StatusCode::OK, "text/calendar; charset=utf-8"));
+    assert!(ics.starts_with("BEGIN:VCALENDAR\r\n") && ics.ends_with("END:VTODO\r\nEND:VCALENDAR\r\n"));
+    assert!(ics.contains(&format!("\r\nSUMMARY:Pack\r\nDESCRIPTION:Socks\\, shirts\r\nSTATUS:NEEDS-ACTION\r\nDUE:20261101T080000Z\r\nDTSTART;TZID=Europe/Berlin:20261101T090000\r\nRRULE:FREQ=MONTHLY;INTERVAL=12\r\nPRIORITY:3\r\nCATEGORIES:travel,urgent\r\nX-TODO-LIST-ID:{}\r\n", list.id)));
+    for (format, file) in [("csv", csv), ("ndjson", ndjson), ("ics", ics)] {
+        let (_, _, report) = import(format, true, file).await;
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&report).unwrap(), serde_json::json!({ "dry_run": true, "imported": 3, "errors": [] }));
+    }
+
+    let ndjson = "{\"title\": \"Elsewhere\", \"list_id\": 999}\n\n{\"title\": \"Colour\", \"colour\": \"red\"}\n";
+    let (_, _, report) = import("ndjson", false, ndjson.to_string()).await;
+    let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+    assert_eq!((&report["imported"], &report["errors"][0]), (&0.into(), &serde_json::json!({ "line": 1, "message": "list 999 not found" })));
+    assert_eq!(report["errors"][1]["line"], 3);
+    // A dry run turns away recurrences that importing them would.
+    let ndjson = "{\"title\": \"Undated\", \"recurrence\": {\"rule\": \"FREQ=DAILY\", \"time_zone\": \"UTC\"}}\n\
+        {\"title\": \"Elsewhere\", \"due_at\": \"2026-11-01T09:00:00Z\", \"recurrence\": {\"rule\": \"FREQ=DAILY\", \"time_zone\": \"Mars/Olympus\"}}\n\
+        {\"title\": \"Hourly\", \"due_at\": \"2026-11-01T09:00:00Z\", \"recurrence\": {\"rule\": \"FREQ=HOURLY\", \"time_zone\": \"UTC\"}}\n";
+    let (_, _, report) = import("ndjson", true, ndjson.to_string()).await;
+    let report: serde_json::Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(report["imported"], 0);
+    assert_eq!(report["errors"][0], serde_json::json!({ "line": 1, "message": "a todo needs a due date to repeat" }));
+    assert_eq!(report["errors"][1], serde_json::json!({ "line": 2, "message": "Mars/Olympus is not a time zone; use the name of one, such as Europe/Berlin" }));
+    assert_eq!(report["errors"][2]["line"], 3);
+    assert_eq!(import("csv", false, "name\nPack\n".to_string()).await.0, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(export("xml").await.0, StatusCode::BAD_REQUEST);
+    assert_eq!(send(hyper::Method::GET, "/todos/export?format=csv&dry_run=true", String::new()).await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn todo_feed_without_a_database() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoInMemory::default();
+    let (owner, token) = sign_up(&repo).await;
+    let (other, other_token) = sign_up(&repo).await;
+    let app = todo_router(repo.clone());
+
+    let send = |method: hyper::Method, uri: &str, token: Option<&str>, body: String| {
+        let mut request = hyper::Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        let request = request.body(Body::from(body)).unwrap();
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let (status, content_type) = (response.status(), response.headers().get(header::CONTENT_TYPE).cloned());
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, content_type, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+    let create_feed = |token: String| send(hyper::Method::POST, &format!("/users/{}/feed", owner.id), Some(&token), String::new());
+
+    let passport = CreateTodo { due_at: Some(time::macros::datetime!(2026-11-01 09:00 UTC)), ..CreateTodo::new("Renew passport", "Bring photos, and the old one") };
+    let passport = repo.create(Some(owner.id), passport).await.unwrap();
+    let taxes = repo.create(Some(owner.id), CreateTodo { due_at: Some(time::macros::datetime!(2026-10-01 12:00 UTC)), ..CreateTodo::new("File taxes", "") }).await.unwrap();
+    repo.update(Some(owner.id), taxes.id, None, &UpdateTodo { done: Some(true), ..Default::default() }).await.unwrap();
+    repo.create(Some(owner.id), CreateTodo::new("Someday", "")).await.unwrap();
+
+    // Only the user themselves may ask for a feed, and asking again takes the old one back.
+    assert_eq!(create_feed(other_token.clone()).await.0, StatusCode::FORBIDDEN);
+    let (status, _, first) = create_feed(token.clone()).await;
+    assert_eq!(status, StatusCode::OK);
+    let first: TodoFeed = serde_json::from_str(&first).unwrap();
+    let second: TodoFeed = serde_json::from_str(&create_feed(token.clone()).await.2).unwrap();
+    assert_ne!(first.url, second.url);
+    assert_eq!(send(hyper::Method::GET, &first.url, None, String::new()).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(send(hyper::Method::GET, "/feeds/guess/todos.ics", None, String::new()).await.0, StatusCode::NOT_FOUND);
+
+    // The feed needs no other authentication, and has the todos with due dates.
+    let (status, content_type, calendar) = send(hyper::Method::GET, &second.url, None, String::new()).await;
+    assert_eq!((status, content_type.unwrap().to_str().unwrap()), (StatusCode::OK, "text/calendar; charset=utf-8"));
+    assert!(calendar.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n") && calendar.ends_with("END:VCALENDAR\r\n"));
+    assert_eq!(calendar.matches("BEGIN:VTODO\r\n").count(), 2);
+    assert!(!calendar.contains("Someday"));
+    let stamp = time::macros::format_description!("[year][month][day]T[hour][minute][second]Z");
+    let modified = repo.get_history(taxes.id).await.unwrap().last().unwrap().occurred_at.format(&stamp).unwrap();
+    assert!(calendar.contains(&format!(
+        "BEGIN:VTODO\r\nUID:todo-{}@rust-web\r\nDTSTAMP:{}\r\nLAST-MODIFIED:{}\r\nSUMMARY:File taxes\r\nSTATUS:COMPLETED\r\nDUE:20261001T120000Z\r\n",
+        taxes.id, modified, modified,
+    )));
+    assert!(calendar.contains(&format!("UID:todo-{}@rust-web\r\n", passport.id)));
+    assert!(calendar.contains("\r\nDESCRIPTION:Bring photos\\, and the old one\r\nSTATUS:NEEDS-ACTION\r\nDUE:20261101T090000Z\r\n"));
+
+    // What the feed has can be imported into another account.
+    let (status, _, report) = send(hyper::Method::POST, "/todos/import?format=ics", Some(&other_token), calendar).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(serde_json::from_str::<serde_json::Value>(&report).unwrap(), serde_json::json!({ "dry_run": false, "imported": 2, "errors": [] }));
+    let imported: Vec<Todo> = repo.export(other.id).map(Result::unwrap).collect().await;
+    let imported: Vec<_> = imported.into_iter().map(|todo| (todo.title, todo.description, todo.done, todo.due_at)).collect();
+    assert_eq!(imported, vec![
+        ("Renew passport".to_string(), "Bring photos, and the old one".to_string(), false, passport.due_at),
+        ("File taxes".to_string(), String::new(), true, taxes.due_at),
+    ]);
+}
+
+#[tokio::test]
+async fn caldav_without_a_database() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoInMemory::default();
+    let (owner, token) = sign_up(&repo).await;
+    let (_, other_token) = sign_up(&repo).await;
+    let app = todo_router(repo.clone());
+
+    let send = |method: &str, uri: &str, token: Option<&str>, headers: Vec<(&str, &str)>, body: String| {
+        let mut request = hyper::Request::builder().method(hyper::Method::from_bytes(method.as_bytes()).unwrap()).uri(uri);
+        if let Some(token) = token {
+            let credentials = base64::engine::general_purpose::STANDARD.encode(format!("anyone:{}", token));
+            request = request.header("Authorization", format!("Basic {}", credentials));
+        }
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        let request = request.body(Body::from(body)).unwrap();
+        let app = app.clone();
+        async move {
+            let response = app.oneshot(request).await.unwrap();
+            let (status, etag) = (response.status(), response.headers().get(header::ETAG).map(|etag| etag.to_str().unwrap().to_string()));
+            let body = response.into_body().collect().await.unwrap().to_bytes();
+            (status, etag, String::from_utf8(body.to_vec()).unwrap())
+        }
+    };
+    let vtodo = |uid: &str, summary: &str, rest: &str| format!(
+        "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//Tasks//EN\r\nBEGIN:VTODO\r\nUID:{}\r\nDTSTAMP:20261017T080000Z\r\nSUMMARY:{}\r\n{}END:VTODO\r\nEND:VCALENDAR\r\n",
+        uid, summary, rest,
+    );
+
+    let errands = repo.create_list(owner.id, "Errands & chores".to_string()).await.unwrap();
+    let archived = repo.create_list(owner.id, "Old".to_string()).await.unwrap();
+    repo.update_list(archived.id, None, Some(true)).await.unwrap();
+    let milk = repo.create(Some(owner.id), CreateTodo { list_id: Some(errands.id), ..CreateTodo::new("Buy milk", "") }).await.unwrap();
+    repo.create(Some(owner.id), CreateTodo::new("Not in a list", "")).await.unwrap();
+    let calendar = format!("/dav/lists/{}/", errands.id);
+    let milk_href = format!("{}todo-{}@rust-web.ics", calendar, milk.id);
+
+    // Task apps find their way from the server's name to the lists.
+    let (status, _, body) = send("PROPFIND", "/dav/", None, vec![], String::new()).await;
+    assert_eq!((status, body.contains("API token")), (StatusCode::UNAUTHORIZED, true));
+    assert_eq!(send("GET", "/.well-known/caldav", None, vec![], String::new()).await.0, StatusCode::PERMANENT_REDIRECT);
+    let find_home = r#"<?xml version="1.0"?><D:propfind xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav"><D:prop><C:calendar-home-set/><D:sync-token/></D:prop></D:propfind>"#;
+    let (status, _, body) = send("PROPFIND", "/dav/", Some(&token), vec![("Depth", "0")], find_home.to_string()).await;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    assert!(body.contains("<D:propstat><D:prop><C:calendar-home-set><D:href>/dav/lists/</D:href></C:calendar-home-set></D:prop><D:status>HTTP/1.1 200 OK</D:status></D:propstat>"));
+    assert!(body.contains("<D:propstat><D:prop><sync-token xmlns=\"DAV:\"/></D:prop><D:status>HTTP/1.1 404 Not Found</D:status></D:propstat>"));
+    let (status, _, body) = send("PROPFIND", "/dav/lists/", Some(&token), vec![("Depth", "1")], String::new()).await;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    assert!(body.contains(&format!("<D:href>{}</D:href>", calendar)));
+    assert!(body.contains("<D:resourcetype><D:collection/><C:calendar/></D:resourcetype><D:displayname>Errands &amp; chores</D:displayname>"));
+    assert!(!body.contains("Old"));
+    assert_eq!(send("PROPFIND", &format!("/dav/lists/{}/", archived.id), Some(&token), vec![], String::new()).await.0, StatusCode::CONFLICT);
+    assert_eq!(send("PROPFIND", &calendar, Some(&other_token), vec![], String::new()).await.0, StatusCode::NOT_FOUND);
+    let (status, _, _) = send("OPTIONS", &calendar, Some(&token), vec![], String::new()).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(send("MKCALENDAR", &calendar, Some(&token), vec![], String::new()).await.0, StatusCode::METHOD_NOT_ALLOWED);
+
+    // The list's todos are there with their ETags, and can be queried for and fetched.
+    let etags = r#"<D:propfind xmlns:D="DAV:"><D:prop><D:getetag/></D:prop></D:propfind>"#;
+    let (_, _, body) = send("PROPFIND", &calendar, Some(&token), vec![("Depth", "1")], etags.to_string()).await;
+    assert!(body.contains(&format!("<D:href>{}</D:href><D:propstat><D:prop><D:getetag>&quot;1&quot;</D:getetag>", milk_href)));
+    assert!(!body.contains("Not in a list"));
+    let query = |component: &str| format!(
+        r#"<C:calendar-query xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav"><D:prop><D:getetag/><C:calendar-data/></D:prop><C:filter><C:comp-filter name="VCALENDAR"><C:comp-filter name="{}"/></C:comp-filter></C:filter></C:calendar-query>"#,
+        component,
+    );
+    let (status, _, body) = send("REPORT", &calendar, Some(&token), vec![("Depth", "1")], query("VTODO")).await;
+    assert_eq!(status, StatusCode::MULTI_STATUS);
+    assert!(body.contains("<C:calendar-data>BEGIN:VCALENDAR&#13;\nVERSION:2.0&#13;\n") && body.contains("SUMMARY:Buy milk&#13;\n"));
+    let (_, _, body) = send("REPORT", &calendar, Some(&token), vec![], query("VEVENT")).await;
+    assert!(!body.contains("<D:response>"));
+    let (status, etag, body) = send("GET", &milk_href, Some(&token), vec![], String::new()).await;
+    assert_eq!((status, etag.as_deref()), (StatusCode::OK, Some("\"1\"")));
+    assert!(body.contains(&format!("UID:todo-{}@rust-web\r\n", milk.id)));
+
+    // An app's new todo keeps its UID, and updates to it go through the app's ETag.
+    let bread_href = format!("{}Bread%201.ics", calendar);
+    let (status, etag, _) = send("PUT", &bread_href, Some(&token), vec![("If-None-Match", "*")], vtodo("Bread 1", "Buy bread", "CATEGORIES:shop\r\n")).await;
+    assert_eq!((status, etag.as_deref()), (StatusCode::CREATED, Some("\"2\"")));
+    let uids = repo.get_uids(owner.id).await.unwrap();
+    let (&bread_id, _) = uids.iter().find(|(_, uid)| *uid == "Bread 1").unwrap();
+    let bread = repo.get(bread_id).await.unwrap();
+    assert_eq!((bread.title.as_str(), bread.list_id, bread.tags.clone()), ("Buy bread", Some(errands.id), vec!["shop".to_string()]));
+    assert_eq!(send("PUT", &bread_href, Some(&token), vec![("If-None-Match", "*")], vtodo("Bread 1", "Buy bread", "")).await.0, StatusCode::PRECONDITION_FAILED);
+    let done = vtodo("Bread 1", "Buy bread", "STATUS:COMPLETED\r\nCATEGORIES:bakery\r\n");
+    assert_eq!(send("PUT", &bread_href, Some(&token), vec![("If-Match", "\"1\"")], done.clone()).await.0, StatusCode::PRECONDITION_FAILED);
+    let (status, etag, _) = send("PUT", &bread_href, Some(&token), vec![("If-Match", "\"2\"")], done).await;
+    let bread = repo.get(bread_id).await.unwrap();
+    assert_eq!((status, etag), (StatusCode::NO_CONTENT, Some(format!("\"{}\"", bread.version))));
+    assert_eq!((bread.done, bread.tags), (true, vec!["bakery".to_string()]));
+
+    // Changes made through the rest of the API show up the next time the app syncs.
+    repo.update(Some(owner.id), bread_id, None, &UpdateTodo { title: Some("Buy rye bread".to_string()), ..Default::default() }).await.unwrap();
+    let multiget = format!(
+        r#"<C:calendar-multiget xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav"><D:prop><D:getetag/><C:calendar-data/></D:prop><D:href>http://localhost{}</D:href><D:href>{}Gone.ics</D:href></C:calendar-multiget>"#,
+        bread_href, calendar,
+    );
+    let (_, _, body) = send("REPORT", &calendar, Some(&token), vec![], multiget).await;
+    assert!(body.contains(&format!("<D:href>{}Bread%201.ics</D:href>", calendar)) && body.contains("SUMMARY:Buy rye bread&#13;\n"));
+    assert!(body.contains(&format!("<D:href>{}Gone.ics</D:href><D:status>HTTP/1.1 404 Not Found</D:status>", calendar)));
+
+    // Repeating todos keep their rules, and the time zones they repeat in, both ways.
+    let bins_href = format!("{}Bins.ics", calendar);
+    let weekly = "DUE:20261022T050000Z\r\nDTSTART;TZID=Europe/Berlin:20261022T070000\r\nRRULE:FREQ=WEEKLY;BYDAY=TH\r\n";
+    assert_eq!(send("PUT", &bins_href, Some(&token), vec![("If-None-Match", "*")], vtodo("Bins", "Take out the bins", weekly)).await.0, StatusCode::CREATED);
+    let (_, _, body) = send("GET", &bins_href, Some(&token), vec![], String::new()).await;
+    assert!(body.contains(weekly));
+
+    // A todo must be named after its UID, which no other todo may have already.
+    assert_eq!(send("PUT", &format!("{}Other.ics", calendar), Some(&token), vec![], vtodo("Bread 1", "Buy bread", "")).await.0, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(send("PUT", &format!("{}todo-999@rust-web.ics", calendar), Some(&token), vec![], vtodo("todo-999@rust-web", "Mine now", "")).await.0, StatusCode::CONFLICT);
+    assert_eq!(send("PUT", &format!("{}x.ics", calendar), Some(&token), vec![], "BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n".to_string()).await.0, StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Deleting a todo from the app puts it in the trash.
+    assert_eq!(send("DELETE", &milk_href, Some(&token), vec![("If-Match", "\"7\"")], String::new()).await.0, StatusCode::PRECONDITION_FAILED);
+    assert_eq!(send("DELETE", &milk_href, Some(&token), vec![("If-Match", "\"1\"")], String::new()).await.0, StatusCode::NO_CONTENT);
+    assert_eq!(send("GET", &milk_href, Some(&token), vec![], String::new()).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(repo.get_trashed(milk.id).await.unwrap().id, milk.id);
+}
+
+#[tokio::test]
+async fn user_handlers_without_a_database() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoInMemory::default();
+    let app = todo_router(repo.clone());
+
+    let request = |method: hyper::Method, uri: &str, token: Option<&str>, body: &str| {
+        let mut request = hyper::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json");
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    };
+    let sign_up = |email: &str| request(hyper::Method::POST, "/users", None, &serde_json::json!({ "name": "Ann", "email": email }).to_string());
+
+    let response = app.clone().oneshot(sign_up("ann@example.com")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let ann: NewUser = serde_json::from_slice(&body).unwrap();
+    assert_eq!(ann.user, User { id: 1, name: "Ann".to_string(), email: "ann@example.com".to_string() });
+
+    let response = app.clone().oneshot(sign_up("ann@example.com")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = app.clone().oneshot(sign_up("not an email")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    let response = app.clone().oneshot(sign_up("bob@example.com")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let bob: NewUser = serde_json::from_slice(&body).unwrap();
+    repo.create(Some(bob.user.id), CreateTodo::new("Bob's todo", "")).await.unwrap();
+
+    // Everyone can see everyone, but only change themselves.
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/users", Some(&ann.token), "")).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let users: Vec<User> = serde_json::from_slice(&body).unwrap();
+    assert_eq!(users, vec![ann.user.clone(), bob.user.clone()]);
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/users", None, "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "/users/2", Some(&ann.token), r#"{"name": "Robert"}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "/users/2", Some(&bob.token), r#"{"name": "Robert"}"#)).await.unwrap();
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    let robert: User = serde_json::from_slice(&body).unwrap();
+    assert_eq!(robert, User { name: "Robert".to_string(), ..bob.user.clone() });
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "/users/2", Some(&bob.token), r#"{"email": "ann@example.com"}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+
+    // Deleting a user takes their todos, and their token, with them.
+    let response = app.clone().oneshot(request(hyper::Method::DELETE, "/users/2", Some(&bob.token), "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(list_all(&repo, TodoListQuery::default()).await.is_empty());
+    let response = app.oneshot(request(hyper::Method::GET, "/todos", Some(&bob.token), "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn list_handlers_without_a_database() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoInMemory::default();
+    let (_, token) = sign_up(&repo).await;
+    let (_, stranger) = sign_up(&repo).await;
+    let app = todo_router(repo);
+
+    let request = |method: hyper::Method, uri: &str, token: &str, body: &str| {
+        hyper::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let json = |response: Response| async move {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+    };
+
+    let response = app.clone().oneshot(request(hyper::Method::POST, "/lists", &token, r#"{"name": "Home"}"#)).await.unwrap();
+    let home = json(response).await;
+    assert_eq!((home["id"].as_i64(), home["name"].as_str(), home["archived"].as_bool()), (Some(1), Some("Home"), Some(false)));
+    app.clone().oneshot(request(hyper::Method::POST, "/lists", &token, r#"{"name": "Work"}"#)).await.unwrap();
+    let response = app.clone().oneshot(request(hyper::Method::POST, "/lists", &token, r#"{"name": " "}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Other people's lists might as well not exist.
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/lists/1", &stranger, "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = app.clone().oneshot(request(hyper::Method::POST, "/lists/1/todos", &stranger, r#"{"title": "Squat", "description": ""}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = app.clone().oneshot(request(hyper::Method::POST, "/todos", &stranger, r#"{"title": "Squat", "description": "", "list_id": 1}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = app.clone().oneshot(request(hyper::Method::POST, "/lists/1/todos", &token, r#"{"title": "Water the plants", "description": ""}"#)).await.unwrap();
+    let todo: Todo = serde_json::from_value(json(response).await).unwrap();
+    assert_eq!(todo.list_id, Some(1));
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/lists/1/todos", &token, "")).await.unwrap();
+    assert_eq!(json(response).await["items"], serde_json::json!([todo]));
+
+    let uri = format!("/todos/{}/list", todo.id);
+    let response = app.clone().oneshot(request(hyper::Method::PUT, &uri, &stranger, r#"{"list_id": null}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = app.clone().oneshot(request(hyper::Method::PUT, &uri, &token, r#"{"list_id": 2}"#)).await.unwrap();
+    assert_eq!(response.headers()[header::ETAG], "\"2\"");
+    let moved: Todo = serde_json::from_value(json(response).await).unwrap();
+    assert_eq!(moved, Todo { list_id: Some(2), version: 2, ..todo });
+
+    // An archived list keeps its todos, but takes no new ones, and drops out of the listings.
+    let response = app.clone().oneshot(request(hyper::Method::PUT, "/lists/2", &token, r#"{"archived": true}"#)).await.unwrap();
+    assert_eq!(json(response).await["archived"], true);
+    let response = app.clone().oneshot(request(hyper::Method::POST, "/lists/2/todos", &token, r#"{"title": "Too late", "description": ""}"#)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::CONFLICT);
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/lists", &token, "")).await.unwrap();
+    assert_eq!(json(response).await.as_array().unwrap().len(), 1);
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/lists?include_archived=true", &token, "")).await.unwrap();
+    assert_eq!(json(response).await.as_array().unwrap().len(), 2);
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/todos", &token, "")).await.unwrap();
+    assert_eq!(json(response).await["items"], serde_json::json!([]));
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/lists/2/todos", &token, "")).await.unwrap();
+    assert_eq!(json(response).await["items"], serde_json::json!([moved]));
+
+    let response = app.clone().oneshot(request(hyper::Method::DELETE, "/lists/2", &token, "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.oneshot(request(hyper::Method::GET, &format!("/todos/{}", todo.id), &token, "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn todo_history_handlers_without_a_database() {
+    // for Body::collect
+    use http_body_util::BodyExt;
+    /// for ServiceExt::oneshot
+    use tower::util::ServiceExt;
+
+    let repo = TodoRepoInMemory::default();
+    let (owner, token) = sign_up(&repo).await;
+    let (_, stranger) = sign_up(&repo).await;
+    let app = todo_router(repo);
+
+    let request = |method: hyper::Method, uri: &str, token: Option<&str>, body: &str| {
+        let mut request = hyper::Request::builder().method(method).uri(uri).header("Content-Type", "application/json");
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    };
+    let json = |response: Response| async move {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+    };
+
+    app.clone().oneshot(request(hyper::Method::POST, "/todos", Some(&token), r#"{"title": "Renew passport", "description": ""}"#)).await.unwrap();
+    app.clone().oneshot(request(hyper::Method::PUT, "/todos/1", Some(&token), r#"{"done": true}"#)).await.unwrap();
+
+    // Other people's todos might as well not exist, and nobody gets near one without signing in.
+    for (method, body) in [(hyper::Method::GET, ""), (hyper::Method::PUT, r#"{"title": "Hijacked"}"#), (hyper::Method::PATCH, r#"{"title": "Hijacked"}"#), (hyper::Method::DELETE, "")] {
+        for (token, status) in [(Some(stranger.as_str()), StatusCode::NOT_FOUND), (None, StatusCode::UNAUTHORIZED), (Some("not a token"), StatusCode::UNAUTHORIZED)] {
+            let mut request = request(method.clone(), "/todos/1", token, body);
+            if method == hyper::Method::PATCH {
+                request.headers_mut().insert(header::CONTENT_TYPE, header::HeaderValue::from_static(MERGE_PATCH_CONTENT_TYPE));
+            }
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status, "{} by {:?}", method, token);
+        }
+    }
+    let response = app.clone().oneshot(request(hyper::Method::DELETE, "/todos/1", Some(&token), "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/todos/1/history", Some(&token), "")).await.unwrap();
+    let history = json(response).await;
+    let kinds: Vec<_> = history.as_array().unwrap().iter().map(|event| (event["kind"].as_str().unwrap(), event["actor_id"].as_i64())).collect();
+    assert_eq!(kinds, vec![("created", Some(owner.id)), ("updated", Some(owner.id)), ("deleted", Some(owner.id))]);
+    assert_eq!(history[1]["changes"]["done"], serde_json::json!({ "before": false, "after": true }));
+    assert!(OffsetDateTime::parse(history[0]["occurred_at"].as_str().unwrap(), &Rfc3339).is_ok());
+    // Nobody else gets to see it, even once the todo is in the trash.
+    for (uri, token, status) in [("/todos/2/history", Some(token.as_str()), StatusCode::NOT_FOUND), ("/todos/1/history", Some(stranger.as_str()), StatusCode::NOT_FOUND), ("/todos/1/history", None, StatusCode::UNAUTHORIZED)] {
+        let response = app.clone().oneshot(request(hyper::Method::GET, uri, token, "")).await.unwrap();
+        assert_eq!(response.status(), status);
+    }
+
+    // The audit trail is only ever the caller's own.
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/audit?since=2026-01-01T00:00:00Z&after=1", Some(&token), "")).await.unwrap();
+    assert_eq!(json(response).await, serde_json::json!(history.as_array().unwrap()[1..]));
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/audit", Some(&stranger), "")).await.unwrap();
+    assert_eq!(json(response).await, serde_json::json!([]));
+    let response = app.clone().oneshot(request(hyper::Method::GET, "/audit?since=yesterday", Some(&token), "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = app.oneshot(request(hyper::Method::GET, "/audit", None, "")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
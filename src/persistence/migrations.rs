@@ -0,0 +1,177 @@
+//!
+//! Schema migrations. The scripts in `migrations/` (and `migrations/sqlite/`)
+//! are embedded in the binary, so the todo app can bring its database up to
+//! date by itself, and notice when it is pointed at a schema newer than the
+//! one it was built for.
+//!
+
+use sqlx::migrate::{Migrate, MigrateError, Migration, Migrator};
+
+use super::*;
+
+pub(super) static POSTGRES_MIGRATIONS: Migrator = sqlx::migrate!("./migrations");
+pub(super) static SQLITE_MIGRATIONS: Migrator = sqlx::migrate!("./migrations/sqlite");
+
+impl From<MigrateError> for TodoRepoError {
+    fn from(error: MigrateError) -> Self {
+        match error {
+            MigrateError::Execute(error) => error.into(),
+            error => TodoRepoError::Internal(error.to_string()),
+        }
+    }
+}
+
+/// How the schema of a database compares with the migrations built into this binary.
+#[derive(Debug)]
+pub(super) struct MigrationStatus {
+    /// Every migration this binary knows about, and whether it has been applied.
+    pub(super) migrations: Vec<(&'static Migration, bool)>,
+    /// Versions that have been applied to the database, but that this binary knows nothing about.
+    pub(super) unknown: Vec<i64>,
+    /// Versions whose script has changed since it was applied.
+    pub(super) modified: Vec<i64>,
+    /// A version that failed partway through being applied.
+    pub(super) dirty: Option<i64>,
+}
+
+impl MigrationStatus {
+    pub(super) async fn read(conn: &mut (dyn Migrate + Send), migrator: &'static Migrator) -> Result<Self, TodoRepoError> {
+        conn.ensure_migrations_table().await?;
+        let dirty = conn.dirty_version().await?;
+        let applied = conn.list_applied_migrations().await?;
+
+        let migrations: Vec<(&'static Migration, bool)> = migrator.iter()
+            .filter(|migration| !migration.migration_type.is_down_migration())
+            .map(|migration| (migration, applied.iter().any(|a| a.version == migration.version)))
+            .collect();
+        let unknown = applied.iter()
+            .filter(|a| !migrations.iter().any(|(migration, _)| migration.version == a.version))
+            .map(|a| a.version)
+            .collect();
+        let modified = applied.iter()
+            .filter(|a| migrations.iter().any(|(migration, _)| migration.version == a.version && migration.checksum != a.checksum))
+            .map(|a| a.version)
+            .collect();
+
+        Ok(MigrationStatus { migrations, unknown, modified, dirty })
+    }
+
+    /// Why it would be unsafe to carry on with this schema, if it would be.
+    fn problem(&self) -> Option<TodoRepoError> {
+        let message = if let Some(version) = self.dirty {
+            format!("migration {} failed partway through, and needs fixing by hand", version)
+        } else if !self.unknown.is_empty() {
+            format!("the database schema is ahead of this build, which does not know migrations {:?}", self.unknown)
+        } else if !self.modified.is_empty() {
+            format!("migrations {:?} have changed since they were applied", self.modified)
+        } else {
+            return None;
+        };
+
+        Some(TodoRepoError::Unavailable(message))
+    }
+
+    fn pending(&self) -> impl Iterator<Item = &'static Migration> + '_ {
+        self.migrations.iter().filter(|(_, applied)| !applied).map(|(migration, _)| *migration)
+    }
+}
+
+///
+/// Applies every pending migration, in order, and returns them. Nothing is
+/// applied if the schema is in a state this binary does not understand.
+///
+pub(super) async fn migrate_up(conn: &mut (dyn Migrate + Send), migrator: &'static Migrator) -> Result<Vec<&'static Migration>, TodoRepoError> {
+    // Several instances of the app may start at once; only one of them gets to migrate.
+    conn.lock().await?;
+    let result = apply_pending(conn, migrator).await;
+    conn.unlock().await?;
+
+    result
+}
+
+async fn apply_pending(conn: &mut (dyn Migrate + Send), migrator: &'static Migrator) -> Result<Vec<&'static Migration>, TodoRepoError> {
+    let status = MigrationStatus::read(conn, migrator).await?;
+    if let Some(problem) = status.problem() {
+        return Err(problem);
+    }
+
+    let pending: Vec<_> = status.pending().collect();
+    for migration in &pending {
+        conn.apply(migration).await?;
+    }
+
+    Ok(pending)
+}
+
+///
+/// Reverts the most recently applied migration, and returns it, or `None`
+/// if there was nothing to revert.
+///
+pub(super) async fn migrate_down(conn: &mut (dyn Migrate + Send), migrator: &'static Migrator) -> Result<Option<&'static Migration>, TodoRepoError> {
+    conn.lock().await?;
+    let result = revert_latest(conn, migrator).await;
+    conn.unlock().await?;
+
+    result
+}
+
+async fn revert_latest(conn: &mut (dyn Migrate + Send), migrator: &'static Migrator) -> Result<Option<&'static Migration>, TodoRepoError> {
+    let status = MigrationStatus::read(conn, migrator).await?;
+    if let Some(problem) = status.problem() {
+        return Err(problem);
+    }
+
+    let Some(latest) = status.migrations.iter().rev().find(|(_, applied)| *applied).map(|(migration, _)| migration) else {
+        return Ok(None);
+    };
+    let down = migrator.iter()
+        .find(|migration| migration.version == latest.version && migration.migration_type.is_down_migration())
+        .ok_or_else(|| TodoRepoError::Invalid(format!("migration {} cannot be reverted", latest.version)))?;
+    conn.revert(down).await?;
+
+    Ok(Some(down))
+}
+
+#[tokio::test]
+async fn migrations_go_up_and_down() {
+    use sqlx::Connection;
+
+    let mut conn = sqlx::SqliteConnection::connect("sqlite::memory:").await.unwrap();
+    let applied_versions = |status: MigrationStatus| {
+        status.migrations.iter().filter(|(_, applied)| *applied).map(|(m, _)| m.version).collect::<Vec<_>>()
+    };
+
+    let applied = migrate_up(&mut conn, &SQLITE_MIGRATIONS).await.unwrap();
+    assert_eq!(applied.len(), SQLITE_MIGRATIONS.iter().filter(|m| !m.migration_type.is_down_migration()).count());
+    assert!(migrate_up(&mut conn, &SQLITE_MIGRATIONS).await.unwrap().is_empty());
+    sqlx::query("SELECT * FROM todos").fetch_all(&mut conn).await.unwrap();
+
+    // Down, one migration at a time, newest first.
+    for (i, migration) in applied.iter().enumerate().rev() {
+        let reverted = migrate_down(&mut conn, &SQLITE_MIGRATIONS).await.unwrap().unwrap();
+        assert_eq!(reverted.version, migration.version);
+        let status = MigrationStatus::read(&mut conn, &SQLITE_MIGRATIONS).await.unwrap();
+        assert_eq!(applied_versions(status), applied[..i].iter().map(|m| m.version).collect::<Vec<_>>());
+    }
+    assert!(migrate_down(&mut conn, &SQLITE_MIGRATIONS).await.unwrap().is_none());
+    assert!(sqlx::query("SELECT * FROM todos").fetch_all(&mut conn).await.is_err());
+
+    assert_eq!(migrate_up(&mut conn, &SQLITE_MIGRATIONS).await.unwrap().len(), applied.len());
+}
+
+#[tokio::test]
+async fn migrations_refuse_a_newer_schema() {
+    use sqlx::Connection;
+
+    let mut conn = sqlx::SqliteConnection::connect("sqlite::memory:").await.unwrap();
+    migrate_up(&mut conn, &SQLITE_MIGRATIONS).await.unwrap();
+
+    // As if a later build had added a migration of its own.
+    sqlx::query("INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time) VALUES (99990101000000, 'from the future', TRUE, x'00', 0)")
+        .execute(&mut conn).await.unwrap();
+
+    let status = MigrationStatus::read(&mut conn, &SQLITE_MIGRATIONS).await.unwrap();
+    assert_eq!(status.unknown, [99990101000000]);
+    assert!(matches!(migrate_up(&mut conn, &SQLITE_MIGRATIONS).await, Err(TodoRepoError::Unavailable(_))));
+    assert!(matches!(migrate_down(&mut conn, &SQLITE_MIGRATIONS).await, Err(TodoRepoError::Unavailable(_))));
+}
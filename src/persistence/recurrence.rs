@@ -0,0 +1,500 @@
+//!
+//! Recurring todos, after the recurrence rules of iCalendar (RFC 5545), or
+//! as much of them as todos need: a `FREQ` of `DAILY`, `WEEKLY` or
+//! `MONTHLY`, with `INTERVAL`, `BYDAY`, and `COUNT` or `UNTIL`.
+//!
+//! A recurrence keeps to the wall clock of its time zone, so a todo due at
+//! nine every morning stays due at nine when the clocks change. As RFC 5545
+//! has it, a time the clocks skip over is read with the offset from before
+//! they went forward, and one they pass twice as the first of the two.
+//!
+
+use std::{fmt, str::FromStr};
+
+use time::{macros::format_description, Date, Duration, Month, OffsetDateTime, PrimitiveDateTime, UtcOffset, Weekday};
+use time_tz::{timezones, Offset, OffsetDateTimeExt, OffsetResult, PrimitiveDateTimeExt, TimeZone, Tz};
+
+/// How many periods of a rule are looked through for an occurrence before deciding there are no more.
+const MAX_PERIODS: i64 = 10_000;
+
+const WEEKDAYS: [(&str, Weekday); 7] = [
+    ("MO", Weekday::Monday),
+    ("TU", Weekday::Tuesday),
+    ("WE", Weekday::Wednesday),
+    ("TH", Weekday::Thursday),
+    ("FR", Weekday::Friday),
+    ("SA", Weekday::Saturday),
+    ("SU", Weekday::Sunday),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Frequency {
+    fn as_str(self) -> &'static str {
+        match self {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+        }
+    }
+}
+
+/// A day of the week from `BYDAY`, and in monthly rules, which of them in the month, counting back from the end if below zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ByDay {
+    ordinal: Option<i8>,
+    weekday: Weekday,
+}
+
+impl ByDay {
+    fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("BYDAY takes days of the week such as MO or FR, numbered in monthly rules as in 1MO or -1FR, not {}", value);
+        let split = value.len().checked_sub(2).ok_or_else(invalid)?;
+        let (ordinal, code) = (value.get(..split).ok_or_else(invalid)?, value.get(split..).ok_or_else(invalid)?);
+
+        let weekday = WEEKDAYS.iter().find(|(known, _)| *known == code).map(|(_, weekday)| *weekday).ok_or_else(invalid)?;
+        let ordinal = match ordinal {
+            "" => None,
+            ordinal => Some(ordinal.parse::<i8>().ok().filter(|ordinal| (1..=5).contains(&ordinal.abs())).ok_or_else(invalid)?),
+        };
+
+        Ok(ByDay { ordinal, weekday })
+    }
+
+    /// Whether `date`, in a month of `days` days, is this day.
+    fn matches(self, date: Date, days: u8) -> bool {
+        date.weekday() == self.weekday && match self.ordinal {
+            None => true,
+            Some(ordinal) if ordinal > 0 => (date.day() - 1) / 7 + 1 == ordinal.unsigned_abs(),
+            Some(ordinal) => (days - date.day()) / 7 + 1 == ordinal.unsigned_abs(),
+        }
+    }
+}
+
+impl fmt::Display for ByDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code = WEEKDAYS.iter().find(|(_, weekday)| *weekday == self.weekday).map_or("", |(code, _)| code);
+        match self.ordinal {
+            Some(ordinal) => write!(f, "{}{}", ordinal, code),
+            None => f.write_str(code),
+        }
+    }
+}
+
+/// The last occurrence allowed: the last date, in the recurrence's time zone, or the last moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Until {
+    Date(Date),
+    Time(OffsetDateTime),
+}
+
+impl Until {
+    fn parse(value: &str) -> Result<Self, String> {
+        if let Ok(date) = Date::parse(value, format_description!("[year][month][day]")) {
+            return Ok(Until::Date(date));
+        }
+
+        PrimitiveDateTime::parse(value, format_description!("[year][month][day]T[hour][minute][second]Z"))
+            .map(|until| Until::Time(until.assume_utc()))
+            .map_err(|_| format!("UNTIL must be a date, such as 20261231, or a time in UTC, such as 20261231T235959Z, not {}", value))
+    }
+}
+
+impl fmt::Display for Until {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Until::Date(date) => write!(f, "{:04}{:02}{:02}", date.year(), u8::from(date.month()), date.day()),
+            Until::Time(at) => write!(f, "{:04}{:02}{:02}T{:02}{:02}{:02}Z", at.year(), u8::from(at.month()), at.day(), at.hour(), at.minute(), at.second()),
+        }
+    }
+}
+
+///
+/// A recurrence rule, the value of an iCalendar `RRULE`, such as
+/// `FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,TH;COUNT=10`. Rules are read whatever
+/// the case and order of their parts, and written in a set order.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct RecurrenceRule {
+    frequency: Frequency,
+    interval: u32,
+    by_day: Vec<ByDay>,
+    count: Option<u32>,
+    until: Option<Until>,
+}
+
+impl FromStr for RecurrenceRule {
+    type Err = String;
+
+    fn from_str(rule: &str) -> Result<Self, String> {
+        let rule = rule.trim().to_ascii_uppercase();
+        let rule = rule.strip_prefix("RRULE:").unwrap_or(&rule);
+
+        let (mut frequency, mut interval, mut by_day, mut count, mut until) = (None, 1, Vec::new(), None, None);
+        let mut seen = Vec::new();
+        for part in rule.split(';').filter(|part| !part.is_empty()) {
+            let (name, value) = part.split_once('=').ok_or_else(|| format!("{} is not of the form NAME=VALUE", part))?;
+            if seen.contains(&name) {
+                return Err(format!("{} is given more than once", name));
+            }
+            seen.push(name);
+
+            match name {
+                "FREQ" => frequency = Some(match value {
+                    "DAILY" => Frequency::Daily,
+                    "WEEKLY" => Frequency::Weekly,
+                    "MONTHLY" => Frequency::Monthly,
+                    _ => return Err(format!("FREQ must be DAILY, WEEKLY or MONTHLY, not {}", value)),
+                }),
+                "INTERVAL" => interval = value.parse().ok().filter(|interval| *interval > 0)
+                    .ok_or_else(|| format!("INTERVAL must be a whole number above 0, not {}", value))?,
+                "BYDAY" => by_day = value.split(',').map(ByDay::parse).collect::<Result<_, _>>()?,
+                "COUNT" => count = Some(value.parse().ok().filter(|count| *count > 0)
+                    .ok_or_else(|| format!("COUNT must be a whole number above 0, not {}", value))?),
+                "UNTIL" => until = Some(Until::parse(value)?),
+                _ => return Err(format!("{} is not supported; rules may have FREQ, INTERVAL, BYDAY, COUNT and UNTIL", name)),
+            }
+        }
+
+        let frequency = frequency.ok_or("a rule needs a FREQ")?;
+        if count.is_some() && until.is_some() {
+            return Err("a rule may have COUNT or UNTIL, but not both".to_string());
+        }
+        if frequency != Frequency::Monthly && by_day.iter().any(|day| day.ordinal.is_some()) {
+            return Err("only monthly rules can number days in BYDAY".to_string());
+        }
+
+        Ok(RecurrenceRule { frequency, interval, by_day, count, until })
+    }
+}
+
+impl fmt::Display for RecurrenceRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FREQ={}", self.frequency.as_str())?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<String> = self.by_day.iter().map(ByDay::to_string).collect();
+            write!(f, ";BYDAY={}", days.join(","))?;
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        if let Some(until) = self.until {
+            write!(f, ";UNTIL={}", until)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl RecurrenceRule {
+    ///
+    /// The dates the rule gives in the `period`th day, week or month (going
+    /// by `FREQ`) counting from the one `start` is in, in order. Weeks start
+    /// on Monday, and a monthly rule without `BYDAY` skips months that are
+    /// too short to have the day of the month `start` is on.
+    ///
+    fn dates_in(&self, start: Date, period: i64) -> Vec<Date> {
+        let steps = period * i64::from(self.interval);
+        let dates = match self.frequency {
+            Frequency::Daily => start.checked_add(Duration::days(steps)).map(|date| vec![date]),
+            Frequency::Weekly => start.checked_sub(Duration::days(start.weekday().number_days_from_monday().into()))
+                .and_then(|monday| monday.checked_add(Duration::weeks(steps)))
+                .map(|monday| (0..7).filter_map(|day| monday.checked_add(Duration::days(day))).collect()),
+            Frequency::Monthly => {
+                let months = i64::from(start.year()) * 12 + i64::from(u8::from(start.month()) - 1) + steps;
+                let year = i32::try_from(months.div_euclid(12)).ok();
+                let month = Month::try_from(months.rem_euclid(12) as u8 + 1).ok();
+                year.zip(month).and_then(|(year, month)| match self.by_day.is_empty() {
+                    true => Some(Date::from_calendar_date(year, month, start.day()).into_iter().collect()),
+                    false => Date::from_calendar_date(year, month, 1).ok()
+                        .map(|first| (0..month.length(year)).filter_map(|day| first.checked_add(Duration::days(day.into()))).collect()),
+                })
+            }
+        };
+
+        let days_in_month = |date: Date| date.month().length(date.year());
+        dates.unwrap_or_default().into_iter()
+            .filter(|date| match (self.frequency, self.by_day.is_empty()) {
+                (Frequency::Weekly, true) => date.weekday() == start.weekday(),
+                (_, true) => true,
+                (_, false) => self.by_day.iter().any(|day| day.matches(*date, days_in_month(*date))),
+            })
+            .collect()
+    }
+}
+
+///
+/// How a todo repeats: by a rule, keeping to the clock of a time zone,
+/// counting from the local date and time the series starts at. Every
+/// occurrence is at the time of day the series starts at.
+///
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "RecurrenceSpec", into = "RecurrenceSpec")]
+pub(super) struct Recurrence {
+    rule: RecurrenceRule,
+    /// The name of a time zone in the IANA database.
+    time_zone: String,
+    starts_at: PrimitiveDateTime,
+}
+
+impl Recurrence {
+    /// When the todo is next due after `due_at`, or `None` if the rule has run out by then.
+    pub(super) fn next_after(&self, due_at: OffsetDateTime) -> Option<OffsetDateTime> {
+        self.occurrences().find(|at| *at > due_at)
+    }
+
+    /// Every occurrence, in order, starting with `starts_at`, which always counts as the first.
+    fn occurrences(&self) -> impl Iterator<Item = OffsetDateTime> + '_ {
+        let start = self.starts_at.date();
+        let until = self.rule.until;
+        let dates = (0..MAX_PERIODS).flat_map(move |period| self.rule.dates_in(start, period)).filter(move |date| *date > start);
+
+        std::iter::once(start).chain(dates)
+            .take_while(move |date| !matches!(until, Some(Until::Date(until)) if *date > until))
+            .map(move |date| self.instant(PrimitiveDateTime::new(date, self.starts_at.time())))
+            .take_while(move |at| !matches!(until, Some(Until::Time(until)) if *at > until))
+            .take(self.rule.count.map_or(usize::MAX, |count| count as usize))
+    }
+
+    /// The moment it is `local` in the recurrence's time zone.
+    fn instant(&self, local: PrimitiveDateTime) -> OffsetDateTime {
+        instant_in(self.zone(), local)
+    }
+
+    fn zone(&self) -> &'static Tz {
+        // The name was looked up when the recurrence was made.
+        timezones::get_by_name(&self.time_zone).unwrap_or(timezones::db::UTC)
+    }
+}
+
+/// How recurrences are stored: as the `DTSTART` and `RRULE` lines of an iCalendar component.
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start = self.starts_at;
+        write!(
+            f,
+            "DTSTART;TZID={}:{:04}{:02}{:02}T{:02}{:02}{:02}\nRRULE:{}",
+            self.time_zone, start.year(), u8::from(start.month()), start.day(), start.hour(), start.minute(), start.second(), self.rule,
+        )
+    }
+}
+
+impl FromStr for Recurrence {
+    type Err = String;
+
+    fn from_str(stored: &str) -> Result<Self, String> {
+        let invalid = || format!("{} is not a recurrence", stored);
+        let (start, rule) = stored.split_once('\n').ok_or_else(invalid)?;
+        let (time_zone, starts_at) = start.trim().strip_prefix("DTSTART;TZID=").and_then(|start| start.split_once(':')).ok_or_else(invalid)?;
+        let starts_at = PrimitiveDateTime::parse(starts_at, format_description!("[year][month][day]T[hour][minute][second]")).map_err(|_| invalid())?;
+
+        RecurrenceSpec { rule: rule.trim().to_string(), time_zone: time_zone.to_string(), starts_at: None }.starting_at(starts_at)
+    }
+}
+
+///
+/// A recurrence as clients give it, with the rule and the time zone's name,
+/// and the local date and time the series starts at, such as
+/// `2026-10-19T09:00:00`. Left out, that is when the todo is due.
+///
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub(super) struct RecurrenceSpec {
+    pub(super) rule: String,
+    pub(super) time_zone: String,
+    #[serde(default)]
+    pub(super) starts_at: Option<String>,
+}
+
+impl RecurrenceSpec {
+    /// The recurrence, starting when the todo is due, at `due_at`, unless it says otherwise.
+    pub(super) fn anchored(&self, due_at: Option<OffsetDateTime>) -> Result<Recurrence, String> {
+        let starts_at = match (&self.starts_at, due_at) {
+            (Some(starts_at), _) => PrimitiveDateTime::parse(starts_at, format_description!("[year]-[month]-[day]T[hour]:[minute]:[second]"))
+                .map_err(|_| format!("starts_at must be a local date and time, such as 2026-10-19T09:00:00, not {}", starts_at))?,
+            (None, Some(due_at)) => {
+                let zone = timezones::get_by_name(&self.time_zone).ok_or_else(|| unknown_zone(&self.time_zone))?;
+                let local = due_at.to_timezone(zone);
+                PrimitiveDateTime::new(local.date(), local.time()).replace_nanosecond(0).unwrap_or(PrimitiveDateTime::MIN)
+            }
+            (None, None) => return Err("a todo needs a due date to repeat".to_string()),
+        };
+
+        self.starting_at(starts_at)
+    }
+
+    /// The recurrence, starting at the local date and time `starts_at`, whatever the spec says.
+    pub(super) fn starting_at(&self, starts_at: PrimitiveDateTime) -> Result<Recurrence, String> {
+        let rule = self.rule.parse()?;
+        let zone = timezones::get_by_name(&self.time_zone).ok_or_else(|| unknown_zone(&self.time_zone))?;
+
+        Ok(Recurrence { rule, time_zone: zone.name().to_string(), starts_at })
+    }
+}
+
+/// The moment it is `local` on the clock of `zone`, reading times the clocks skip or pass twice as recurrences do.
+pub(super) fn instant_in(zone: &Tz, local: PrimitiveDateTime) -> OffsetDateTime {
+    let at = match local.assume_timezone(zone) {
+        OffsetResult::Some(at) | OffsetResult::Ambiguous(at, _) => at,
+        OffsetResult::None => {
+            // Skipped when the clocks went forward, which they do at most once a day.
+            let before = local.checked_sub(Duration::days(1)).unwrap_or(local).assume_utc();
+            local.assume_offset(zone.get_offset_utc(&before).to_utc())
+        }
+    };
+
+    at.to_offset(UtcOffset::UTC)
+}
+
+fn unknown_zone(name: &str) -> String {
+    format!("{} is not a time zone; use the name of one, such as Europe/Berlin", name)
+}
+
+impl TryFrom<RecurrenceSpec> for Recurrence {
+    type Error = String;
+
+    fn try_from(spec: RecurrenceSpec) -> Result<Self, String> {
+        if spec.starts_at.is_none() {
+            return Err("a recurrence needs a starts_at".to_string());
+        }
+
+        spec.anchored(None)
+    }
+}
+
+impl From<Recurrence> for RecurrenceSpec {
+    fn from(recurrence: Recurrence) -> Self {
+        let start = recurrence.starts_at;
+        RecurrenceSpec {
+            rule: recurrence.rule.to_string(),
+            time_zone: recurrence.time_zone,
+            starts_at: Some(format!(
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+                start.year(), u8::from(start.month()), start.day(), start.hour(), start.minute(), start.second(),
+            )),
+        }
+    }
+}
+
+#[tokio::test]
+async fn rules_read_back_as_written() {
+    let rule: RecurrenceRule = "rrule:byday=mo,-1fr;Freq=Monthly;interval=2;until=20271231".parse().unwrap();
+    assert_eq!(rule.to_string(), "FREQ=MONTHLY;INTERVAL=2;BYDAY=MO,-1FR;UNTIL=20271231");
+    assert_eq!(rule.to_string().parse(), Ok(rule));
+    let rule: RecurrenceRule = "FREQ=DAILY;COUNT=3".parse().unwrap();
+    assert_eq!(rule.to_string(), "FREQ=DAILY;COUNT=3");
+    assert_eq!("FREQ=WEEKLY;UNTIL=20261231T235959Z".parse::<RecurrenceRule>().unwrap().to_string(), "FREQ=WEEKLY;UNTIL=20261231T235959Z");
+
+    let rejected = |rule: &str| rule.parse::<RecurrenceRule>().unwrap_err();
+    assert_eq!(rejected("INTERVAL=2"), "a rule needs a FREQ");
+    assert_eq!(rejected("FREQ=YEARLY"), "FREQ must be DAILY, WEEKLY or MONTHLY, not YEARLY");
+    assert_eq!(rejected("FREQ=DAILY;INTERVAL=0"), "INTERVAL must be a whole number above 0, not 0");
+    assert_eq!(rejected("FREQ=DAILY;COUNT=2;UNTIL=20261231"), "a rule may have COUNT or UNTIL, but not both");
+    assert_eq!(rejected("FREQ=WEEKLY;BYDAY=2MO"), "only monthly rules can number days in BYDAY");
+    assert_eq!(rejected("FREQ=DAILY;FREQ=WEEKLY"), "FREQ is given more than once");
+    assert_eq!(rejected("FREQ=DAILY;BYMONTH=1"), "BYMONTH is not supported; rules may have FREQ, INTERVAL, BYDAY, COUNT and UNTIL");
+    assert!(rejected("FREQ=MONTHLY;BYDAY=6MO").starts_with("BYDAY takes days of the week"));
+    assert!(rejected("FREQ=DAILY;UNTIL=tomorrow").starts_with("UNTIL must be a date"));
+
+    let recurrence = RecurrenceSpec { rule: "FREQ=DAILY".to_string(), time_zone: "Europe/Berlin".to_string(), starts_at: None }
+        .anchored(Some(time::macros::datetime!(2026-10-19 07:00:00.5 UTC)))
+        .unwrap();
+    assert_eq!(recurrence.to_string(), "DTSTART;TZID=Europe/Berlin:20261019T090000\nRRULE:FREQ=DAILY");
+    assert_eq!(recurrence.to_string().parse(), Ok(recurrence.clone()));
+    assert_eq!(serde_json::to_value(&recurrence).unwrap(), serde_json::json!({ "rule": "FREQ=DAILY", "time_zone": "Europe/Berlin", "starts_at": "2026-10-19T09:00:00" }));
+    assert_eq!(serde_json::from_value::<Recurrence>(serde_json::to_value(&recurrence).unwrap()).unwrap(), recurrence);
+    let spec = |time_zone: &str| RecurrenceSpec { rule: "FREQ=DAILY".to_string(), time_zone: time_zone.to_string(), starts_at: None };
+    assert_eq!(spec("Mars/Olympus").anchored(Some(OffsetDateTime::UNIX_EPOCH)).unwrap_err(), "Mars/Olympus is not a time zone; use the name of one, such as Europe/Berlin");
+    assert_eq!(spec("UTC").anchored(None).unwrap_err(), "a todo needs a due date to repeat");
+}
+
+#[tokio::test]
+async fn occurrences_follow_the_rule() {
+    let occurrences = |rule: &str, starts_at: &str, count: usize| {
+        let spec = RecurrenceSpec { rule: rule.to_string(), time_zone: "UTC".to_string(), starts_at: Some(starts_at.to_string()) };
+        spec.anchored(None).unwrap().occurrences().take(count)
+            .map(|at| format!("{} {}", at.date(), at.weekday()))
+            .collect::<Vec<_>>()
+    };
+
+    // 2026-10-19 is a Monday; the start counts as the first occurrence even when it does not fit the rule.
+    assert_eq!(occurrences("FREQ=DAILY;INTERVAL=3", "2026-10-19T09:00:00", 3), ["2026-10-19 Monday", "2026-10-22 Thursday", "2026-10-25 Sunday"]);
+    assert_eq!(occurrences("FREQ=DAILY;BYDAY=SA,SU", "2026-10-19T09:00:00", 3), ["2026-10-19 Monday", "2026-10-24 Saturday", "2026-10-25 Sunday"]);
+    assert_eq!(occurrences("FREQ=WEEKLY", "2026-10-21T09:00:00", 2), ["2026-10-21 Wednesday", "2026-10-28 Wednesday"]);
+    assert_eq!(
+        occurrences("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,TH", "2026-10-22T09:00:00", 4),
+        ["2026-10-22 Thursday", "2026-11-02 Monday", "2026-11-05 Thursday", "2026-11-16 Monday"],
+    );
+    // Months without a 31st are skipped, rather than moved to their last day.
+    assert_eq!(occurrences("FREQ=MONTHLY", "2026-12-31T09:00:00", 3), ["2026-12-31 Thursday", "2027-01-31 Sunday", "2027-03-31 Wednesday"]);
+    assert_eq!(
+        occurrences("FREQ=MONTHLY;BYDAY=1MO,-1FR", "2026-10-05T09:00:00", 4),
+        ["2026-10-05 Monday", "2026-10-30 Friday", "2026-11-02 Monday", "2026-11-27 Friday"],
+    );
+    assert_eq!(occurrences("FREQ=MONTHLY;INTERVAL=3;BYDAY=TU", "2026-12-29T09:00:00", 3), ["2026-12-29 Tuesday", "2027-03-02 Tuesday", "2027-03-09 Tuesday"]);
+
+    // COUNT includes the start, and UNTIL is the last occurrence there can be.
+    assert_eq!(occurrences("FREQ=DAILY;COUNT=2", "2026-10-19T09:00:00", 5), ["2026-10-19 Monday", "2026-10-20 Tuesday"]);
+    assert_eq!(occurrences("FREQ=WEEKLY;UNTIL=20261102", "2026-10-19T09:00:00", 5), ["2026-10-19 Monday", "2026-10-26 Monday", "2026-11-02 Monday"]);
+    assert_eq!(occurrences("FREQ=WEEKLY;UNTIL=20261102T085959Z", "2026-10-19T09:00:00", 5), ["2026-10-19 Monday", "2026-10-26 Monday"]);
+
+    let weekly = RecurrenceSpec { rule: "FREQ=WEEKLY;COUNT=3".to_string(), time_zone: "UTC".to_string(), starts_at: Some("2026-10-19T09:00:00".to_string()) };
+    let weekly = weekly.anchored(None).unwrap();
+    assert_eq!(weekly.next_after(time::macros::datetime!(2026-10-19 09:00 UTC)), Some(time::macros::datetime!(2026-10-26 09:00 UTC)));
+    // A todo that was put off still comes round again at the next occurrence after it.
+    assert_eq!(weekly.next_after(time::macros::datetime!(2026-10-27 12:00 UTC)), Some(time::macros::datetime!(2026-11-02 09:00 UTC)));
+    assert_eq!(weekly.next_after(time::macros::datetime!(2026-11-02 09:00 UTC)), None);
+}
+
+#[tokio::test]
+async fn occurrences_keep_to_the_clock_across_daylight_saving() {
+    let occurrences = |time_zone: &str, rule: &str, starts_at: &str, count: usize| {
+        let spec = RecurrenceSpec { rule: rule.to_string(), time_zone: time_zone.to_string(), starts_at: Some(starts_at.to_string()) };
+        spec.anchored(None).unwrap().occurrences().take(count).collect::<Vec<_>>()
+    };
+
+    // Berlin goes from UTC+1 to UTC+2 at 02:00 on 29 March 2026, and back at 03:00 on 25 October.
+    assert_eq!(occurrences("Europe/Berlin", "FREQ=DAILY", "2026-03-28T09:00:00", 2), [
+        time::macros::datetime!(2026-03-28 08:00 UTC),
+        time::macros::datetime!(2026-03-29 07:00 UTC),
+    ]);
+    assert_eq!(occurrences("Europe/Berlin", "FREQ=WEEKLY", "2026-10-19T09:00:00", 2), [
+        time::macros::datetime!(2026-10-19 07:00 UTC),
+        time::macros::datetime!(2026-10-26 08:00 UTC),
+    ]);
+    // 02:30 never happens on the day the clocks go forward, so it is read with the offset from before, as 03:30;
+    // the day after, the series is back at 02:30.
+    assert_eq!(occurrences("Europe/Berlin", "FREQ=DAILY", "2026-03-28T02:30:00", 3), [
+        time::macros::datetime!(2026-03-28 01:30 UTC),
+        time::macros::datetime!(2026-03-29 01:30 UTC),
+        time::macros::datetime!(2026-03-30 00:30 UTC),
+    ]);
+    // 02:30 happens twice on the day they go back, and the first of them counts.
+    assert_eq!(occurrences("Europe/Berlin", "FREQ=DAILY", "2026-10-24T02:30:00", 3), [
+        time::macros::datetime!(2026-10-24 00:30 UTC),
+        time::macros::datetime!(2026-10-25 00:30 UTC),
+        time::macros::datetime!(2026-10-26 01:30 UTC),
+    ]);
+    // New York changes on other days, and Sydney the other way round.
+    assert_eq!(occurrences("America/New_York", "FREQ=MONTHLY;BYDAY=1SU", "2026-10-04T08:00:00", 2), [
+        time::macros::datetime!(2026-10-04 12:00 UTC),
+        time::macros::datetime!(2026-11-01 13:00 UTC),
+    ]);
+    assert_eq!(occurrences("Australia/Sydney", "FREQ=WEEKLY;BYDAY=SA", "2026-09-26T18:00:00", 3), [
+        time::macros::datetime!(2026-09-26 08:00 UTC),
+        time::macros::datetime!(2026-10-03 08:00 UTC),
+        time::macros::datetime!(2026-10-10 07:00 UTC),
+    ]);
+
+    // Moving on from an occurrence shifted by the gap does not carry the shift along.
+    let daily = RecurrenceSpec { rule: "FREQ=DAILY".to_string(), time_zone: "Europe/Berlin".to_string(), starts_at: Some("2026-03-28T02:30:00".to_string()) };
+    let daily = daily.anchored(None).unwrap();
+    assert_eq!(daily.next_after(time::macros::datetime!(2026-03-29 01:30 UTC)), Some(time::macros::datetime!(2026-03-30 00:30 UTC)));
+}
@@ -0,0 +1,1285 @@
+//!
+//! A SQLite `TodoRepo`, for running the todo app from a single file (or
+//! entirely in memory) rather than against a Postgres server.
+//!
+//! The schema lives in `migrations/sqlite`, and mirrors the Postgres one;
+//! full-text search is done with an FTS5 table kept in sync by triggers.
+//!
+
+use std::str::FromStr;
+
+use sqlx::{sqlite::{SqliteConnectOptions, SqlitePoolOptions}, Sqlite, SqliteConnection, SqlitePool};
+
+use super::*;
+
+/// A todo as exports read it, with its tags and blockers gathered into JSON arrays.
+#[derive(sqlx::FromRow)]
+struct TodoExportRecord {
+    #[sqlx(flatten)]
+    record: TodoRecord,
+    tags: String,
+    blocked_by: String,
+}
+
+impl TodoExportRecord {
+    fn into_todo(self) -> Result<Todo, TodoRepoError> {
+        let tags = serde_json::from_str(&self.tags).map_err(|error| TodoRepoError::Internal(error.to_string()))?;
+        let blocked_by = serde_json::from_str(&self.blocked_by).map_err(|error| TodoRepoError::Internal(error.to_string()))?;
+
+        Ok(Todo { tags, blocked_by, ..Todo::from_record(self.record) })
+    }
+}
+
+/// How often to look for changes to todos, as SQLite cannot announce them.
+const CHANGE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone)]
+pub(super) struct TodoRepoSqlite {
+    pool: SqlitePool,
+    /// Every change to todos, as polling finds them.
+    changes: broadcast::Sender<TodoChange>,
+}
+
+impl TodoRepoSqlite {
+    /// Opens (creating it if need be) the database at `url`, and brings its schema up to date.
+    pub(super) async fn connect(url: &str) -> Result<Self, TodoRepoError> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+
+        // Every connection to an in-memory database gets a database of its
+        // own, so there must only ever be the one, and it must never be closed.
+        let pool = if url.contains(":memory:") || url.contains("mode=memory") {
+            SqlitePoolOptions::new().max_connections(1).idle_timeout(None).max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new().max_connections(16)
+        }
+            .acquire_timeout(Duration::from_secs(5))
+            .connect_with(options)
+            .await?;
+
+        migrations::migrate_up(&mut *pool.acquire().await?, &SQLITE_MIGRATIONS).await?;
+        let changes = Self::poll_changes(&pool).await?;
+
+        Ok(Self { pool, changes })
+    }
+
+    ///
+    /// Starts passing on changes to todos, whoever made them, to whoever is
+    /// watching, looking for new ones every `CHANGE_POLL_INTERVAL`. Only
+    /// changes made after this returns are passed on.
+    ///
+    async fn poll_changes(pool: &SqlitePool) -> Result<broadcast::Sender<TodoChange>, TodoRepoError> {
+        let mut after = sqlx::query_scalar::<_, Option<i64>>("SELECT MAX(id) FROM todo_changes")
+            .fetch_one(pool).await?
+            .unwrap_or(0);
+
+        let (changes, _) = broadcast::channel(CHANGE_BUFFER);
+        let (sender, pool) = (changes.clone(), pool.clone());
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHANGE_POLL_INTERVAL);
+            loop {
+                interval.tick().await;
+                let query = TodoChangeQuery { after: Some(after), limit: MAX_PAGE_LIMIT, owner_id: None };
+                match Self::changes_on(&pool, &query).await {
+                    Ok(changes) => {
+                        for change in changes {
+                            after = change.id;
+                            // Nobody watching is fine too.
+                            let _ = sender.send(change);
+                        }
+                    }
+                    Err(error) => eprintln!("Could not look for todo changes: {}", error),
+                }
+            }
+        });
+
+        Ok(changes)
+    }
+
+    async fn changes_on(pool: &SqlitePool, query: &TodoChangeQuery) -> Result<Vec<TodoChange>, TodoRepoError> {
+        let records = sqlx::query_as::<_, TodoChangeRecord>(
+            r#"
+            SELECT id, todo_id, kind, owner_id
+            FROM todo_changes
+            WHERE (?1 IS NULL OR owner_id = ?1) AND (?2 IS NULL OR id > ?2)
+            ORDER BY id
+            LIMIT ?3
+            "#,
+        )
+            .bind(query.owner_id)
+            .bind(query.after)
+            .bind(query.limit)
+            .fetch_all(pool).await?;
+
+        Ok(records.into_iter().map(TodoChange::from_record).collect())
+    }
+
+    /// The tags of the todos with the given ids, as `(todo_id, tag)` pairs in tag order.
+    async fn tags_of(conn: &mut SqliteConnection, ids: &[i64]) -> Result<Vec<(i64, String)>, TodoRepoError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut sql = QueryBuilder::<Sqlite>::new("SELECT todo_tags.todo_id, tags.name FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id WHERE todo_tags.todo_id IN (");
+        let mut separated = sql.separated(", ");
+        for id in ids {
+            separated.push_bind(*id);
+        }
+        sql.push(") ORDER BY tags.name");
+
+        Ok(sql.build_query_as::<(i64, String)>().fetch_all(conn).await?)
+    }
+
+    /// What blocks the todos with the given ids, as `(todo_id, blocker_id)` pairs in blocker order. Trashed blockers no longer count.
+    async fn blockers_of(conn: &mut SqliteConnection, ids: &[i64]) -> Result<Vec<(i64, i64)>, TodoRepoError> {
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut sql = QueryBuilder::<Sqlite>::new(
+            "SELECT todo_dependencies.todo_id, todo_dependencies.blocker_id FROM todo_dependencies JOIN todos AS blockers ON blockers.id = todo_dependencies.blocker_id WHERE blockers.deleted_at IS NULL AND todo_dependencies.todo_id IN (",
+        );
+        let mut separated = sql.separated(", ");
+        for id in ids {
+            separated.push_bind(*id);
+        }
+        sql.push(") ORDER BY todo_dependencies.blocker_id");
+
+        Ok(sql.build_query_as::<(i64, i64)>().fetch_all(conn).await?)
+    }
+
+    /// Fills in the tags and blockers of `todos`, with a query each however many there are.
+    async fn load_relations(conn: &mut SqliteConnection, mut todos: Vec<&mut Todo>) -> Result<(), TodoRepoError> {
+        let ids: Vec<i64> = todos.iter().map(|todo| todo.id).collect();
+        let tags = Self::tags_of(conn, &ids).await?;
+        attach_tags(&mut todos, tags);
+        let blockers = Self::blockers_of(conn, &ids).await?;
+        attach_blockers(&mut todos, blockers);
+
+        Ok(())
+    }
+
+    /// The blockers of the todo `id` that are not done yet, if the todo itself is not done yet.
+    async fn open_blockers_on(conn: &mut SqliteConnection, id: i64) -> Result<Vec<i64>, TodoRepoError> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            r#"
+            SELECT todo_dependencies.blocker_id
+            FROM todo_dependencies
+            JOIN todos AS blocked ON blocked.id = todo_dependencies.todo_id
+            JOIN todos AS blockers ON blockers.id = todo_dependencies.blocker_id
+            WHERE todo_dependencies.todo_id = ?1 AND NOT blocked.done AND NOT blockers.done AND blockers.deleted_at IS NULL
+            ORDER BY todo_dependencies.blocker_id
+            "#,
+        )
+            .bind(id)
+            .fetch_all(conn).await?)
+    }
+
+    async fn with_relations(conn: &mut SqliteConnection, record: TodoRecord) -> Result<Todo, TodoRepoError> {
+        let mut todo = Todo::from_record(record);
+        Self::load_relations(conn, vec![&mut todo]).await?;
+
+        Ok(todo)
+    }
+
+    /// Reads a todo that is not in the trash inside a transaction, checking it is at `expected_version`.
+    async fn read_on(conn: &mut SqliteConnection, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        let record = sqlx::query_as::<_, TodoRecord>("SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos WHERE id = ?1 AND deleted_at IS NULL")
+            .bind(id)
+            .fetch_optional(&mut *conn).await?
+            .ok_or(TodoRepoError::NotFound(id))?;
+
+        if expected_version.is_some_and(|version| version != record.version) {
+            return Err(TodoRepoError::VersionMismatch(id));
+        }
+
+        Self::with_relations(conn, record).await
+    }
+
+    /// Acts as if there were no todo `id` unless it is `owner_id`'s.
+    async fn check_owner_on(conn: &mut SqliteConnection, owner_id: Option<i64>, id: i64) -> Result<(), TodoRepoError> {
+        match Self::read_on(conn, id, None).await? {
+            todo if todo.owner_id == owner_id => Ok(()),
+            _ => Err(TodoRepoError::NotFound(id)),
+        }
+    }
+
+    ///
+    /// The todo `id` and its subtasks, and theirs, in id order; empty if there
+    /// is no such todo. Only todos that went into the trash at `deleted_at`
+    /// count, or those that are not in the trash if that is `None`.
+    ///
+    async fn subtree_on(conn: &mut SqliteConnection, id: i64, deleted_at: Option<PrimitiveDateTime>) -> Result<Vec<Todo>, TodoRepoError> {
+        let records = sqlx::query_as::<_, TodoRecord>(
+            r#"
+            WITH RECURSIVE subtree (id) AS (
+                SELECT id FROM todos WHERE id = ?1 AND deleted_at IS ?2
+                UNION SELECT todos.id FROM todos JOIN subtree ON todos.parent_id = subtree.id WHERE todos.deleted_at IS ?2
+            )
+            SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence
+            FROM todos
+            WHERE id IN (SELECT id FROM subtree)
+            ORDER BY id
+            "#,
+        )
+            .bind(id)
+            .bind(deleted_at)
+            .fetch_all(&mut *conn).await?;
+
+        let mut todos: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+        Self::load_relations(conn, todos.iter_mut().collect()).await?;
+
+        Ok(todos)
+    }
+
+    ///
+    /// Moves `todos`, the subtree of `id` in id order, into the trash at
+    /// `deleted_at`, or out of it if that is `None`, and records the move in
+    /// each of their histories. Returns them as they end up, in id order.
+    /// SQLite has no row locks, so this only succeeds if none of them have
+    /// changed since they were read.
+    ///
+    async fn trash_on(conn: &mut SqliteConnection, actor_id: Option<i64>, id: i64, todos: &[Todo], deleted_at: Option<PrimitiveDateTime>) -> Result<Vec<Todo>, TodoRepoError> {
+        let mut sql = QueryBuilder::<Sqlite>::new("UPDATE todos SET deleted_at = ");
+        sql.push_bind(deleted_at).push(", version = version + 1 WHERE (id, version) IN (VALUES ");
+        let mut separated = sql.separated(", ");
+        for todo in todos {
+            separated.push("(").push_bind_unseparated(todo.id).push_unseparated(", ").push_bind_unseparated(todo.version).push_unseparated(")");
+        }
+        sql.push(") RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence");
+        let records = sql.build_query_as::<TodoRecord>().fetch_all(&mut *conn).await?;
+        if records.len() != todos.len() {
+            return Err(TodoRepoError::VersionMismatch(id));
+        }
+
+        let mut moved: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+        moved.sort_by_key(|todo| todo.id);
+        Self::load_relations(conn, moved.iter_mut().collect()).await?;
+        for (before, after) in todos.iter().zip(&moved) {
+            Self::record_on(conn, actor_id, Some(before), Some(after)).await?;
+        }
+
+        Ok(moved)
+    }
+
+    /// Adds the change from `before` to `after` to the todo's history, unless nothing changed.
+    async fn record_on(conn: &mut SqliteConnection, actor_id: Option<i64>, before: Option<&Todo>, after: Option<&Todo>) -> Result<(), TodoRepoError> {
+        let Some(event) = TodoEvent::between(actor_id, before, after) else {
+            return Ok(());
+        };
+
+        sqlx::query("INSERT INTO todo_events (todo_id, kind, owner_id, actor_id, changes, occurred_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")
+            .bind(event.todo_id)
+            .bind(event.kind.as_str())
+            .bind(event.owner_id)
+            .bind(event.actor_id)
+            .bind(sqlx::types::Json(&event.changes))
+            .bind(utc_primitive(event.occurred_at))
+            .execute(conn).await?;
+
+        Ok(())
+    }
+
+    ///
+    /// Bumps the version of a todo whose tags or blockers have changed. SQLite has no row
+    /// locks, so this only succeeds if the todo is still at the version it
+    /// was read at.
+    ///
+    async fn touch_on(conn: &mut SqliteConnection, current: &Todo) -> Result<Todo, TodoRepoError> {
+        let record = sqlx::query_as::<_, TodoRecord>(
+            "UPDATE todos SET version = version + 1 WHERE id = ?1 AND version = ?2 RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence",
+        )
+            .bind(current.id)
+            .bind(current.version)
+            .fetch_optional(&mut *conn).await?
+            .ok_or(TodoRepoError::VersionMismatch(current.id))?;
+
+        Self::with_relations(conn, record).await
+    }
+
+    ///
+    /// Checks that `parent_id` is another of `owner_id`'s todos, and that it
+    /// is not the todo `id` (if the todo exists yet) or one of its subtasks.
+    ///
+    async fn check_parent_on(conn: &mut SqliteConnection, id: Option<i64>, owner_id: Option<i64>, parent_id: i64) -> Result<(), TodoRepoError> {
+        let parent_owner_id = sqlx::query_scalar::<_, Option<i64>>("SELECT owner_id FROM todos WHERE id = ?1 AND deleted_at IS NULL")
+            .bind(parent_id)
+            .fetch_optional(&mut *conn).await?;
+        if parent_owner_id != Some(owner_id) {
+            return Err(missing_parent(parent_id));
+        }
+        let Some(id) = id else {
+            return Ok(());
+        };
+
+        // Walking up from the parent only ever follows one row per level.
+        let cyclic = sqlx::query_scalar::<_, bool>(
+            r#"
+            WITH RECURSIVE ancestors (id) AS (
+                SELECT ?1
+                UNION SELECT todos.parent_id FROM todos JOIN ancestors ON todos.id = ancestors.id WHERE todos.parent_id IS NOT NULL
+            )
+            SELECT EXISTS (SELECT 1 FROM ancestors WHERE id = ?2)
+            "#,
+        )
+            .bind(parent_id)
+            .bind(id)
+            .fetch_one(conn).await?;
+
+        if cyclic { Err(cyclic_parent(id)) } else { Ok(()) }
+    }
+
+    // The writes below take a connection rather than the pool, so that a
+    // batch can run them inside its transaction.
+
+    async fn create_on(conn: &mut SqliteConnection, owner_id: Option<i64>, todo: &CreateTodo) -> Result<Todo, TodoRepoError> {
+        if let Some(parent_id) = todo.parent_id {
+            Self::check_parent_on(conn, None, owner_id, parent_id).await?;
+        }
+        let recurrence = recurrence_of(todo.recurrence.as_ref(), todo.due_at)?;
+
+        // The creation time is bound here, rather than left to the column
+        // default, so that it is stored in the same format the filters and
+        // cursors compare it against. Due dates are stored the same way.
+        let record = sqlx::query_as::<_, TodoRecord>(
+            "INSERT INTO todos (title, description, done, created_at, owner_id, list_id, due_at, priority, parent_id, recurrence) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10) RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence",
+        )
+            .bind(&todo.title)
+            .bind(&todo.description)
+            .bind(false)
+            .bind(now())
+            .bind(owner_id)
+            .bind(todo.list_id)
+            .bind(todo.due_at.map(utc_primitive))
+            .bind(todo.priority.as_str())
+            .bind(todo.parent_id)
+            .bind(recurrence.as_ref().map(Recurrence::to_string))
+            .fetch_one(&mut *conn).await?;
+
+        // A new todo has no tags or blockers yet.
+        let todo = Todo::from_record(record);
+        Self::record_on(conn, owner_id, None, Some(&todo)).await?;
+
+        Ok(todo)
+    }
+
+    /// Callers should pass a transaction, as the todo is read before it is written.
+    async fn update_on(conn: &mut SqliteConnection, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, changes: &UpdateTodo) -> Result<Todo, TodoRepoError> {
+        let current = Self::read_on(conn, id, expected_version).await?;
+        if changes.done == Some(true) && !current.done && !changes.force {
+            let blockers = Self::open_blockers_on(conn, id).await?;
+            if !blockers.is_empty() {
+                return Err(blocked(id, &blockers));
+            }
+        }
+        if changes.done == Some(true) && changes.complete_subtasks && !changes.force {
+            // Nor may anything besides the todo and its subtasks still hold up one of those.
+            let subtree = Self::subtree_on(conn, id, None).await?;
+            for subtask in subtree.iter().filter(|subtask| subtask.id != id && !subtask.done) {
+                let mut blockers = Self::open_blockers_on(conn, subtask.id).await?;
+                blockers.retain(|blocker_id| !subtree.iter().any(|todo| todo.id == *blocker_id));
+                if !blockers.is_empty() {
+                    return Err(blocked(subtask.id, &blockers));
+                }
+            }
+        }
+        if let Some(Some(parent_id)) = changes.parent_id {
+            Self::check_parent_on(conn, Some(id), current.owner_id, parent_id).await?;
+        }
+        let (recurrence, handed_on) = recurrence_after(&current, changes)?;
+
+        // The right-hand sides all see the row as it was, so `done` there is
+        // whether it was already done. SQLite has no row locks, so the write
+        // is made conditional on the version read above.
+        let record = sqlx::query_as::<_, TodoRecord>(
+            r#"
+            UPDATE todos SET
+                title = COALESCE(?1, title),
+                description = COALESCE(?2, description),
+                done = COALESCE(?3, done),
+                completed_at = CASE WHEN ?3 IS NULL OR (?3 AND done) THEN completed_at WHEN ?3 THEN ?4 END,
+                due_at = CASE WHEN ?5 THEN ?6 ELSE due_at END,
+                priority = COALESCE(?7, priority),
+                parent_id = CASE WHEN ?8 THEN ?9 ELSE parent_id END,
+                recurrence = ?10,
+                version = version + 1
+            WHERE id = ?11 AND version = ?12
+            RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence
+            "#,
+        )
+            .bind(&changes.title)
+            .bind(&changes.description)
+            .bind(changes.done)
+            .bind(now())
+            .bind(changes.due_at.is_some())
+            .bind(changes.due_at.flatten().map(utc_primitive))
+            .bind(changes.priority.map(TodoPriority::as_str))
+            .bind(changes.parent_id.is_some())
+            .bind(changes.parent_id.flatten())
+            .bind(recurrence.as_ref().map(Recurrence::to_string))
+            .bind(id)
+            .bind(current.version)
+            .fetch_optional(&mut *conn).await?
+            .ok_or(TodoRepoError::VersionMismatch(id))?;
+        let todo = Self::with_relations(conn, record).await?;
+        Self::record_on(conn, actor_id, Some(&current), Some(&todo)).await?;
+        if let Some(recurrence) = handed_on {
+            Self::recur_on(conn, actor_id, &todo, &recurrence).await?;
+        }
+
+        if changes.complete_subtasks && changes.done == Some(true) {
+            // Repeating subtasks hand their recurrence on, just as they would if marked done one by one.
+            let repeating: BTreeMap<i64, Recurrence> = Self::subtree_on(conn, id, None).await?.into_iter()
+                .filter_map(|subtask| Some((subtask.id, subtask.recurrence?)))
+                .collect();
+            let records = sqlx::query_as::<_, TodoRecord>(
+                r#"
+                WITH RECURSIVE subtasks (id) AS (
+                    SELECT id FROM todos WHERE parent_id = ?1 AND deleted_at IS NULL
+                    UNION SELECT todos.id FROM todos JOIN subtasks ON todos.parent_id = subtasks.id WHERE todos.deleted_at IS NULL
+                )
+                UPDATE todos SET done = TRUE, completed_at = ?2, recurrence = NULL, version = version + 1 WHERE id IN (SELECT id FROM subtasks) AND NOT done
+                RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence
+                "#,
+            )
+                .bind(id)
+                .bind(now())
+                .fetch_all(&mut *conn).await?;
+
+            let mut subtasks: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+            Self::load_relations(conn, subtasks.iter_mut().collect()).await?;
+            for subtask in &subtasks {
+                // Only subtasks that were not done yet were written, and nothing else about them changed.
+                let recurrence = repeating.get(&subtask.id).cloned();
+                let before = Todo { done: false, completed_at: None, recurrence: recurrence.clone(), version: subtask.version - 1, ..subtask.clone() };
+                Self::record_on(conn, actor_id, Some(&before), Some(subtask)).await?;
+                if let Some(recurrence) = recurrence {
+                    Self::recur_on(conn, actor_id, subtask, &recurrence).await?;
+                }
+            }
+        }
+
+        Ok(todo)
+    }
+
+    /// Creates the todo that comes after `done`, which was just marked done and repeated by `recurrence`, with the same tags.
+    async fn recur_on(conn: &mut SqliteConnection, actor_id: Option<i64>, done: &Todo, recurrence: &Recurrence) -> Result<(), TodoRepoError> {
+        let Some(next) = done.next_instance(recurrence) else {
+            return Ok(());
+        };
+        let created = Self::create_on(conn, done.owner_id, &next).await?;
+        if done.tags.is_empty() {
+            return Ok(());
+        }
+
+        sqlx::query("INSERT INTO todo_tags (todo_id, tag_id) SELECT ?1, tag_id FROM todo_tags WHERE todo_id = ?2")
+            .bind(created.id)
+            .bind(done.id)
+            .execute(&mut *conn).await?;
+        let tagged = Self::touch_on(conn, &created).await?;
+        Self::record_on(conn, actor_id, Some(&created), Some(&tagged)).await
+    }
+
+    /// Callers should pass a transaction, as the todo is read before it is trashed.
+    async fn delete_on(conn: &mut SqliteConnection, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        Self::read_on(conn, id, expected_version).await?;
+
+        // The subtasks all go into the trash at the same time as the todo,
+        // which is how restoring it knows to bring back just those.
+        let subtree = Self::subtree_on(conn, id, None).await?;
+        let deleted = Self::trash_on(conn, actor_id, id, &subtree, Some(now())).await?;
+
+        deleted.into_iter().find(|todo| todo.id == id).ok_or(TodoRepoError::NotFound(id))
+    }
+
+    /// Callers should pass a transaction, as the todo is read before it is tagged.
+    async fn tag_on(conn: &mut SqliteConnection, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, tag: &str) -> Result<Todo, TodoRepoError> {
+        let current = Self::read_on(conn, id, expected_version).await?;
+        let owner_id = current.owner_id.ok_or_else(untaggable)?;
+
+        // Upserting, rather than doing nothing on conflict, so that the id comes back either way.
+        let tag_id = sqlx::query_scalar::<_, i64>(
+            "INSERT INTO tags (owner_id, name) VALUES (?1, ?2) ON CONFLICT (owner_id, name) DO UPDATE SET name = excluded.name RETURNING id",
+        )
+            .bind(owner_id)
+            .bind(tag)
+            .fetch_one(&mut *conn).await?;
+        let added = sqlx::query("INSERT INTO todo_tags (todo_id, tag_id) VALUES (?1, ?2) ON CONFLICT DO NOTHING")
+            .bind(id)
+            .bind(tag_id)
+            .execute(&mut *conn).await?
+            .rows_affected();
+
+        let todo = if added > 0 { Self::touch_on(conn, &current).await? } else { current.clone() };
+        Self::record_on(conn, actor_id, Some(&current), Some(&todo)).await?;
+
+        Ok(todo)
+    }
+
+    /// Callers should pass a transaction, as the UID is taken from any other todo before it is given to this one.
+    async fn set_uid_on(conn: &mut SqliteConnection, id: i64, uid: &str) -> Result<(), TodoRepoError> {
+        let owner_id = sqlx::query_scalar::<_, Option<i64>>("SELECT owner_id FROM todos WHERE id = ?1 AND deleted_at IS NULL")
+            .bind(id)
+            .fetch_optional(&mut *conn).await?
+            .ok_or(TodoRepoError::NotFound(id))?
+            .ok_or_else(|| no_owner(id))?;
+
+        sqlx::query("DELETE FROM todo_uids WHERE owner_id = ?1 AND uid = ?2")
+            .bind(owner_id)
+            .bind(uid)
+            .execute(&mut *conn).await?;
+        sqlx::query("INSERT INTO todo_uids (todo_id, owner_id, uid) VALUES (?1, ?2, ?3) ON CONFLICT (todo_id) DO UPDATE SET uid = excluded.uid")
+            .bind(id)
+            .bind(owner_id)
+            .bind(uid)
+            .execute(&mut *conn).await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TodoRepo for TodoRepoSqlite {
+    async fn get_all(&self, query: &TodoListQuery) -> Result<TodoPage, TodoRepoError> {
+        let mut sql = QueryBuilder::<Sqlite>::new("SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos WHERE deleted_at IS NULL");
+
+        if let Some(owner_id) = query.filter.owner_id {
+            sql.push(" AND owner_id = ").push_bind(owner_id);
+        }
+        match query.filter.list_id {
+            Some(list_id) => sql.push(" AND list_id = ").push_bind(list_id),
+            None => sql.push(" AND NOT EXISTS (SELECT 1 FROM todo_lists WHERE todo_lists.id = todos.list_id AND todo_lists.archived)"),
+        };
+        if let Some(done) = query.filter.done {
+            sql.push(" AND done = ").push_bind(done);
+        }
+        if let Some(actionable) = query.filter.actionable {
+            sql.push(if actionable { " AND NOT EXISTS" } else { " AND EXISTS" });
+            sql.push(" (SELECT 1 FROM todo_dependencies JOIN todos AS blockers ON blockers.id = todo_dependencies.blocker_id WHERE todo_dependencies.todo_id = todos.id AND NOT blockers.done AND blockers.deleted_at IS NULL)");
+        }
+        if let Some(created_after) = query.filter.created_after {
+            sql.push(" AND created_at > ").push_bind(created_after);
+        }
+        if let Some(created_before) = query.filter.created_before {
+            sql.push(" AND created_at < ").push_bind(created_before);
+        }
+        if !query.filter.tags.is_empty() {
+            sql.push(" AND (SELECT COUNT(*) FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id WHERE todo_tags.todo_id = todos.id AND tags.name IN (");
+            let mut names = sql.separated(", ");
+            for tag in &query.filter.tags {
+                names.push_bind(tag.clone());
+            }
+            sql.push("))");
+            match query.filter.tag_match {
+                TagMatch::Any => sql.push(" > 0"),
+                TagMatch::All => sql.push(" = ").push_bind(query.filter.tags.len() as i64),
+            };
+        }
+
+        // SQLite's default collation already compares titles bytewise.
+        let column = match query.sort.field {
+            TodoSortField::Position => "position",
+            TodoSortField::CreatedAt => "created_at",
+            TodoSortField::Title => "title",
+        };
+        let (direction, comparison) = if query.sort.descending { (" DESC", " < ") } else { (" ASC", " > ") };
+
+        if let Some(cursor) = &query.cursor {
+            sql.push(" AND (").push(column).push(", id)").push(comparison).push("(");
+            match &cursor.key {
+                TodoSortKey::Position(position) => sql.push_bind(position.clone()),
+                TodoSortKey::CreatedAt(created_at) => sql.push_bind(*created_at),
+                TodoSortKey::Title(title) => sql.push_bind(title.clone()),
+            };
+            sql.push(", ").push_bind(cursor.id).push(")");
+        }
+
+        // Fetch one row more than requested, so we know whether there is a next page.
+        sql.push(" ORDER BY ").push(column).push(direction).push(", id").push(direction);
+        sql.push(" LIMIT ").push_bind(query.limit + 1);
+
+        let mut conn = self.pool.acquire().await?;
+        let records = sql.build_query_as::<TodoRecord>()
+            .fetch_all(&mut *conn).await?;
+
+        let mut page = TodoPage::from_records(records, query);
+        Self::load_relations(&mut conn, page.items.iter_mut().collect()).await?;
+
+        Ok(page)
+    }
+
+    async fn search(&self, query: &TodoSearchQuery) -> Result<Vec<TodoSearchHit>, TodoRepoError> {
+        // Quoting every word keeps FTS5 from reading any of it as query syntax;
+        // a todo matches if it contains all of them.
+        let terms = query.terms();
+        if terms.is_empty() {
+            return Ok(Vec::new());
+        }
+        let fts_query = terms.iter().map(|term| format!("\"{}\"", term)).collect::<Vec<_>>().join(" ");
+
+        // bm25 scores better matches lower, so it is negated to rank like ts_rank does.
+        let mut conn = self.pool.acquire().await?;
+        let records = sqlx::query_as::<_, TodoSearchRecord>(
+            r#"
+            SELECT
+                todos.id, todos.title, todos.description, todos.done, todos.created_at, todos.version, todos.owner_id, todos.list_id, todos.due_at, todos.priority, todos.completed_at, todos.parent_id, todos.position, todos.deleted_at, todos.recurrence,
+                -bm25(todos_search, 1.0, 0.4) AS rank,
+                highlight(todos_search, 0, '<mark>', '</mark>') AS title_highlight,
+                snippet(todos_search, 1, '<mark>', '</mark>', ' ... ', 32) AS description_highlight
+            FROM todos_search
+            JOIN todos ON todos.id = todos_search.rowid
+            WHERE todos_search MATCH ?1 AND (?3 IS NULL OR todos.owner_id = ?3) AND todos.deleted_at IS NULL
+                AND NOT EXISTS (SELECT 1 FROM todo_lists WHERE todo_lists.id = todos.list_id AND todo_lists.archived)
+            ORDER BY bm25(todos_search, 1.0, 0.4), todos.id
+            LIMIT ?2
+            "#,
+        )
+            .bind(fts_query)
+            .bind(query.limit)
+            .bind(query.owner_id)
+            .fetch_all(&mut *conn).await?;
+
+        let mut hits: Vec<TodoSearchHit> = records.into_iter().map(TodoSearchHit::from_record).collect();
+        Self::load_relations(&mut conn, hits.iter_mut().map(|hit| &mut hit.todo).collect()).await?;
+
+        Ok(hits)
+    }
+
+    async fn create(&self, owner_id: Option<i64>, todo: CreateTodo) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let todo = Self::create_on(&mut tx, owner_id, &todo).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn get(&self, id: i64) -> Result<Todo, TodoRepoError> {
+        Self::read_on(&mut *self.pool.acquire().await?, id, None).await
+    }
+
+    async fn get_subtree(&self, id: i64) -> Result<Vec<Todo>, TodoRepoError> {
+        let todos = Self::subtree_on(&mut *self.pool.acquire().await?, id, None).await?;
+        if todos.is_empty() {
+            return Err(TodoRepoError::NotFound(id));
+        }
+
+        Ok(todos)
+    }
+
+    async fn update(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, changes: &UpdateTodo) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let todo = Self::update_on(&mut tx, actor_id, id, expected_version, changes).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn patch(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, patch: &TodoPatch, force: bool) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let current = Self::read_on(&mut tx, id, expected_version).await?;
+
+        let patched = patch.apply(&current)?;
+        if patched.done && !current.done && !force {
+            let blockers = Self::open_blockers_on(&mut tx, id).await?;
+            if !blockers.is_empty() {
+                return Err(blocked(id, &blockers));
+            }
+        }
+        let (recurrence, handed_on) = recurrence_after(&current, &patched.as_update())?;
+
+        // SQLite has no row locks, so the write is made conditional on the
+        // version read above instead.
+        let record = sqlx::query_as::<_, TodoRecord>(
+            "UPDATE todos SET title = ?1, description = ?2, done = ?3, completed_at = ?4, due_at = ?5, priority = ?6, recurrence = ?7, version = version + 1 WHERE id = ?8 AND version = ?9 RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence",
+        )
+            .bind(patched.title)
+            .bind(patched.description)
+            .bind(patched.done)
+            .bind(current.completed_at_if_done(patched.done, now()))
+            .bind(patched.due_at.map(utc_primitive))
+            .bind(patched.priority.as_str())
+            .bind(recurrence.as_ref().map(Recurrence::to_string))
+            .bind(id)
+            .bind(current.version)
+            .fetch_optional(&mut *tx).await?
+            .ok_or(TodoRepoError::VersionMismatch(id))?;
+        let todo = Self::with_relations(&mut tx, record).await?;
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        if let Some(recurrence) = handed_on {
+            Self::recur_on(&mut tx, actor_id, &todo, &recurrence).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn delete(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let todo = Self::delete_on(&mut tx, actor_id, id, expected_version).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn move_todo(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, list_id: Option<i64>) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let current = Self::read_on(&mut tx, id, expected_version).await?;
+
+        let record = sqlx::query_as::<_, TodoRecord>(
+            "UPDATE todos SET list_id = ?1, version = version + 1 WHERE id = ?2 AND version = ?3 RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence",
+        )
+            .bind(list_id)
+            .bind(id)
+            .bind(current.version)
+            .fetch_optional(&mut *tx).await?
+            .ok_or(TodoRepoError::VersionMismatch(id))?;
+        let todo = Self::with_relations(&mut tx, record).await?;
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn reorder(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, placement: TodoPlacement) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let current = Self::read_on(&mut tx, id, expected_version).await?;
+        let anchor_id = placement.anchor_id();
+        if anchor_id == id {
+            return Err(unmovable(id));
+        }
+        let anchor = sqlx::query_as::<_, (Option<i64>, String)>("SELECT owner_id, position FROM todos WHERE id = ?1 AND deleted_at IS NULL")
+            .bind(anchor_id)
+            .fetch_optional(&mut *tx).await?
+            .filter(|(owner_id, _)| *owner_id == current.owner_id)
+            .map(|(_, position)| position)
+            .ok_or_else(|| missing_anchor(anchor_id))?;
+
+        // The new position goes between the anchor and whichever todo is on
+        // its other side, not counting the one being moved. Trashed todos
+        // keep their positions, and so count, so that restoring one cannot
+        // leave it tied with another.
+        let (lower, upper) = match placement {
+            TodoPlacement::Before(_) => {
+                let lower = sqlx::query_scalar::<_, String>(
+                    "SELECT position FROM todos WHERE owner_id IS ?1 AND id <> ?2 AND position < ?3 ORDER BY position DESC LIMIT 1",
+                )
+                    .bind(current.owner_id)
+                    .bind(id)
+                    .bind(&anchor)
+                    .fetch_optional(&mut *tx).await?;
+                (lower, Some(anchor))
+            }
+            TodoPlacement::After(_) => {
+                let upper = sqlx::query_scalar::<_, String>(
+                    "SELECT position FROM todos WHERE owner_id IS ?1 AND id <> ?2 AND position > ?3 ORDER BY position LIMIT 1",
+                )
+                    .bind(current.owner_id)
+                    .bind(id)
+                    .bind(&anchor)
+                    .fetch_optional(&mut *tx).await?;
+                (Some(anchor), upper)
+            }
+        };
+        let position = rank_between(lower.as_deref(), upper.as_deref()).ok_or_else(|| unmovable(id))?;
+
+        // SQLite has no row locks, so the write is made conditional on the
+        // version read above instead.
+        let record = sqlx::query_as::<_, TodoRecord>(
+            "UPDATE todos SET moved_position = ?1, version = version + 1 WHERE id = ?2 AND version = ?3 RETURNING id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence",
+        )
+            .bind(position)
+            .bind(id)
+            .bind(current.version)
+            .fetch_optional(&mut *tx).await?
+            .ok_or(TodoRepoError::VersionMismatch(id))?;
+        let todo = Self::with_relations(&mut tx, record).await?;
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn tag(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, tag: &str) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let todo = Self::tag_on(&mut tx, actor_id, id, expected_version, tag).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn untag(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, tag: &str) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let current = Self::read_on(&mut tx, id, expected_version).await?;
+
+        let removed = sqlx::query("DELETE FROM todo_tags WHERE todo_id = ?1 AND tag_id IN (SELECT id FROM tags WHERE name = ?2)")
+            .bind(id)
+            .bind(tag)
+            .execute(&mut *tx).await?
+            .rows_affected();
+
+        let todo = if removed > 0 { Self::touch_on(&mut tx, &current).await? } else { current.clone() };
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn block(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, blocker_id: i64) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let current = Self::read_on(&mut tx, id, expected_version).await?;
+        if blocker_id == id {
+            return Err(cyclic_dependency(id, blocker_id));
+        }
+        let blocker_exists = sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM todos WHERE id = ?1 AND deleted_at IS NULL)")
+            .bind(blocker_id)
+            .fetch_one(&mut *tx).await?;
+        if !blocker_exists {
+            return Err(missing_blocker(blocker_id));
+        }
+
+        // Writing the link before looking for a loop takes the database's
+        // write lock first, so no other link can sneak in between the two.
+        let added = sqlx::query("INSERT INTO todo_dependencies (todo_id, blocker_id) VALUES (?1, ?2) ON CONFLICT DO NOTHING")
+            .bind(id)
+            .bind(blocker_id)
+            .execute(&mut *tx).await?
+            .rows_affected();
+        if added == 0 {
+            return Ok(current);
+        }
+
+        // Following what blocks the blocker, and so on, must not lead back here.
+        let cyclic = sqlx::query_scalar::<_, bool>(
+            r#"
+            WITH RECURSIVE blockers (id) AS (
+                SELECT ?1
+                UNION SELECT todo_dependencies.blocker_id FROM todo_dependencies JOIN blockers ON todo_dependencies.todo_id = blockers.id
+            )
+            SELECT EXISTS (SELECT 1 FROM blockers WHERE id = ?2)
+            "#,
+        )
+            .bind(blocker_id)
+            .bind(id)
+            .fetch_one(&mut *tx).await?;
+        if cyclic {
+            return Err(cyclic_dependency(id, blocker_id));
+        }
+
+        let todo = Self::touch_on(&mut tx, &current).await?;
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn unblock(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>, blocker_id: i64) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let current = Self::read_on(&mut tx, id, expected_version).await?;
+
+        let removed = sqlx::query("DELETE FROM todo_dependencies WHERE todo_id = ?1 AND blocker_id = ?2")
+            .bind(id)
+            .bind(blocker_id)
+            .execute(&mut *tx).await?
+            .rows_affected();
+
+        let todo = if removed > 0 { Self::touch_on(&mut tx, &current).await? } else { current.clone() };
+        Self::record_on(&mut tx, actor_id, Some(&current), Some(&todo)).await?;
+        tx.commit().await?;
+
+        Ok(todo)
+    }
+
+    async fn batch(&self, owner_id: Option<i64>, operations: &[TodoOperation]) -> Result<Vec<TodoOperationResult>, TodoBatchError> {
+        // Dropping the transaction without committing it rolls everything back.
+        let mut tx = self.pool.begin().await?;
+
+        let mut results = Vec::with_capacity(operations.len());
+        for (index, operation) in operations.iter().enumerate() {
+            let result = match operation {
+                TodoOperation::Create(todo) => {
+                    Self::create_on(&mut tx, owner_id, todo).await.map(TodoOperationResult::Created)
+                }
+                TodoOperation::Update { id, version, changes } => {
+                    Self::check_owner_on(&mut tx, owner_id, *id).await.map_err(TodoBatchError::at(index))?;
+                    Self::update_on(&mut tx, owner_id, *id, *version, changes).await.map(TodoOperationResult::Updated)
+                }
+                TodoOperation::Delete { id, version } => {
+                    Self::check_owner_on(&mut tx, owner_id, *id).await.map_err(TodoBatchError::at(index))?;
+                    Self::delete_on(&mut tx, owner_id, *id, *version).await.map(TodoOperationResult::Deleted)
+                }
+            };
+            results.push(result.map_err(TodoBatchError::at(index))?);
+        }
+
+        tx.commit().await?;
+
+        Ok(results)
+    }
+
+    async fn import(&self, owner_id: Option<i64>, todo: &CreateTodo, done: bool, tags: &[&str], uid: Option<&str>) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let mut imported = Self::create_on(&mut tx, owner_id, todo).await?;
+        if let Some(uid) = uid {
+            Self::set_uid_on(&mut tx, imported.id, uid).await?;
+        }
+        if done {
+            let done = UpdateTodo { done: Some(true), force: true, ..Default::default() };
+            imported = Self::update_on(&mut tx, owner_id, imported.id, None, &done).await?;
+        }
+        for tag in tags {
+            imported = Self::tag_on(&mut tx, owner_id, imported.id, None, tag).await?;
+        }
+        tx.commit().await?;
+
+        Ok(imported)
+    }
+
+    async fn get_due(&self, query: &TodoDueQuery) -> Result<Vec<Todo>, TodoRepoError> {
+        let mut conn = self.pool.acquire().await?;
+        let records = sqlx::query_as::<_, TodoRecord>(
+            r#"
+            SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence
+            FROM todos
+            WHERE NOT done AND deleted_at IS NULL AND due_at < ?1 AND (?2 IS NULL OR due_at >= ?2) AND (?3 IS NULL OR owner_id = ?3)
+                AND NOT EXISTS (SELECT 1 FROM todo_lists WHERE todo_lists.id = todos.list_id AND todo_lists.archived)
+            ORDER BY due_at, id
+            LIMIT ?4
+            "#,
+        )
+            .bind(utc_primitive(query.due_before))
+            .bind(query.due_from.map(utc_primitive))
+            .bind(query.owner_id)
+            .bind(query.limit)
+            .fetch_all(&mut *conn).await?;
+
+        let mut todos: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+        Self::load_relations(&mut conn, todos.iter_mut().collect()).await?;
+
+        Ok(todos)
+    }
+
+    async fn get_history(&self, id: i64) -> Result<Vec<TodoEvent>, TodoRepoError> {
+        let records = sqlx::query_as::<_, TodoEventRecord>("SELECT id, todo_id, kind, owner_id, actor_id, changes, occurred_at FROM todo_events WHERE todo_id = ?1 ORDER BY id")
+            .bind(id)
+            .fetch_all(&self.pool).await?;
+
+        Ok(records.into_iter().map(TodoEvent::from_record).collect())
+    }
+
+    async fn last_modified(&self, owner_id: i64) -> Result<BTreeMap<i64, OffsetDateTime>, TodoRepoError> {
+        // Both columns hold UTC in the same format, so the later of them is also the greater.
+        let records = sqlx::query_as::<_, (i64, PrimitiveDateTime)>(
+            r#"
+            SELECT todos.id, COALESCE(MAX(todo_events.occurred_at), todos.created_at)
+            FROM todos
+            LEFT JOIN todo_events ON todo_events.todo_id = todos.id
+            WHERE todos.owner_id = ?1 AND todos.deleted_at IS NULL
+            GROUP BY todos.id
+            "#,
+        )
+            .bind(owner_id)
+            .fetch_all(&self.pool).await?;
+
+        Ok(records.into_iter().map(|(id, last_modified)| (id, last_modified.assume_utc())).collect())
+    }
+
+    async fn get_audit(&self, query: &TodoAuditQuery) -> Result<Vec<TodoEvent>, TodoRepoError> {
+        let records = sqlx::query_as::<_, TodoEventRecord>(
+            r#"
+            SELECT id, todo_id, kind, owner_id, actor_id, changes, occurred_at
+            FROM todo_events
+            WHERE (?1 IS NULL OR owner_id = ?1) AND (?2 IS NULL OR occurred_at >= ?2) AND (?3 IS NULL OR id > ?3)
+            ORDER BY id
+            LIMIT ?4
+            "#,
+        )
+            .bind(query.owner_id)
+            .bind(query.since)
+            .bind(query.after)
+            .bind(query.limit)
+            .fetch_all(&self.pool).await?;
+
+        Ok(records.into_iter().map(TodoEvent::from_record).collect())
+    }
+
+    async fn get_changes(&self, query: &TodoChangeQuery) -> Result<Vec<TodoChange>, TodoRepoError> {
+        Self::changes_on(&self.pool, query).await
+    }
+
+    async fn last_change(&self, owner_id: i64) -> Result<Option<i64>, TodoRepoError> {
+        let id = sqlx::query_scalar::<_, Option<i64>>("SELECT MAX(id) FROM todo_changes WHERE owner_id = ?1")
+            .bind(owner_id)
+            .fetch_one(&self.pool).await?;
+
+        Ok(id)
+    }
+
+    fn watch_changes(&self) -> broadcast::Receiver<TodoChange> {
+        self.changes.subscribe()
+    }
+
+    fn export(&self, owner_id: i64) -> BoxStream<'_, Result<Todo, TodoRepoError>> {
+        sqlx::query_as::<_, TodoExportRecord>(
+            r#"
+            SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence,
+                (
+                    SELECT json_group_array(name) FROM (
+                        SELECT tags.name FROM todo_tags JOIN tags ON tags.id = todo_tags.tag_id
+                        WHERE todo_tags.todo_id = todos.id
+                        ORDER BY tags.name
+                    )
+                ) AS tags,
+                (
+                    SELECT json_group_array(blocker_id) FROM (
+                        SELECT todo_dependencies.blocker_id FROM todo_dependencies JOIN todos AS blockers ON blockers.id = todo_dependencies.blocker_id
+                        WHERE todo_dependencies.todo_id = todos.id AND blockers.deleted_at IS NULL
+                        ORDER BY todo_dependencies.blocker_id
+                    )
+                ) AS blocked_by
+            FROM todos
+            WHERE owner_id = ?1 AND deleted_at IS NULL
+            ORDER BY id
+            "#,
+        )
+            .bind(owner_id)
+            .fetch(&self.pool)
+            .map(|record| record?.into_todo())
+            .boxed()
+    }
+
+    async fn get_uids(&self, owner_id: i64) -> Result<BTreeMap<i64, String>, TodoRepoError> {
+        let uids = sqlx::query_as::<_, (i64, String)>(
+            "SELECT todo_uids.todo_id, todo_uids.uid FROM todo_uids JOIN todos ON todos.id = todo_uids.todo_id WHERE todo_uids.owner_id = ?1 AND todos.deleted_at IS NULL",
+        )
+            .bind(owner_id)
+            .fetch_all(&self.pool).await?;
+
+        Ok(uids.into_iter().collect())
+    }
+
+    async fn set_uid(&self, id: i64, uid: &str) -> Result<(), TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        Self::set_uid_on(&mut tx, id, uid).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    async fn get_trash(&self, query: &TodoTrashQuery) -> Result<Vec<Todo>, TodoRepoError> {
+        let mut conn = self.pool.acquire().await?;
+        let records = sqlx::query_as::<_, TodoRecord>(
+            r#"
+            SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence
+            FROM todos
+            WHERE deleted_at IS NOT NULL AND (?1 IS NULL OR owner_id = ?1)
+            ORDER BY deleted_at DESC, id
+            LIMIT ?2
+            "#,
+        )
+            .bind(query.owner_id)
+            .bind(query.limit)
+            .fetch_all(&mut *conn).await?;
+
+        let mut todos: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+        Self::load_relations(&mut conn, todos.iter_mut().collect()).await?;
+
+        Ok(todos)
+    }
+
+    async fn get_trashed(&self, id: i64) -> Result<Todo, TodoRepoError> {
+        let mut conn = self.pool.acquire().await?;
+        let record = sqlx::query_as::<_, TodoRecord>("SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos WHERE id = ?1 AND deleted_at IS NOT NULL")
+            .bind(id)
+            .fetch_optional(&mut *conn).await?
+            .ok_or(TodoRepoError::NotFound(id))?;
+
+        Self::with_relations(&mut conn, record).await
+    }
+
+    async fn restore(&self, actor_id: Option<i64>, id: i64, expected_version: Option<i64>) -> Result<Todo, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let record = sqlx::query_as::<_, TodoRecord>("SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos WHERE id = ?1 AND deleted_at IS NOT NULL")
+            .bind(id)
+            .fetch_optional(&mut *tx).await?
+            .ok_or(TodoRepoError::NotFound(id))?;
+        if expected_version.is_some_and(|version| version != record.version) {
+            return Err(TodoRepoError::VersionMismatch(id));
+        }
+        if let Some(parent_id) = record.parent_id {
+            let parent_trashed = sqlx::query_scalar::<_, bool>("SELECT EXISTS (SELECT 1 FROM todos WHERE id = ?1 AND deleted_at IS NOT NULL)")
+                .bind(parent_id)
+                .fetch_one(&mut *tx).await?;
+            if parent_trashed {
+                return Err(trashed_parent(id, parent_id));
+            }
+        }
+
+        let subtree = Self::subtree_on(&mut tx, id, record.deleted_at).await?;
+        let restored = Self::trash_on(&mut tx, actor_id, id, &subtree, None).await?;
+        tx.commit().await?;
+
+        restored.into_iter().find(|todo| todo.id == id).ok_or(TodoRepoError::NotFound(id))
+    }
+
+    async fn purge_trash(&self, deleted_before: PrimitiveDateTime) -> Result<u64, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+
+        // A subtask never goes into the trash after its parent, so this never
+        // leaves one behind whose parent is purged.
+        let records = sqlx::query_as::<_, TodoRecord>("SELECT id, title, description, done, created_at, version, owner_id, list_id, due_at, priority, completed_at, parent_id, position, deleted_at, recurrence FROM todos WHERE deleted_at < ?1 ORDER BY id")
+            .bind(deleted_before)
+            .fetch_all(&mut *tx).await?;
+        let mut purged: Vec<Todo> = records.into_iter().map(Todo::from_record).collect();
+        Self::load_relations(&mut tx, purged.iter_mut().collect()).await?;
+
+        // The rows affected leave out subtasks deleted along with their
+        // parents, so the count comes from what was read. Should anything
+        // change in between, SQLite refuses the write rather than let this
+        // work from a stale read.
+        sqlx::query("DELETE FROM todos WHERE deleted_at < ?1")
+            .bind(deleted_before)
+            .execute(&mut *tx).await?;
+        for todo in &purged {
+            Self::record_on(&mut tx, None, Some(todo), None).await?;
+        }
+        tx.commit().await?;
+
+        Ok(purged.len() as u64)
+    }
+}
+
+#[async_trait]
+impl UserRepo for TodoRepoSqlite {
+    async fn get_users(&self) -> Result<Vec<User>, TodoRepoError> {
+        Ok(sqlx::query_as::<_, User>("SELECT id, name, email FROM users ORDER BY id").fetch_all(&self.pool).await?)
+    }
+
+    async fn get_user(&self, id: i64) -> Result<User, TodoRepoError> {
+        sqlx::query_as::<_, User>("SELECT id, name, email FROM users WHERE id = ?1")
+            .bind(id)
+            .fetch_optional(&self.pool).await?
+            .ok_or(TodoRepoError::UserNotFound(id))
+    }
+
+    async fn create_user(&self, name: String, email: String, token_hash: Vec<u8>) -> Result<User, TodoRepoError> {
+        sqlx::query_as::<_, User>("INSERT INTO users (name, email, token_hash) VALUES (?1, ?2, ?3) RETURNING id, name, email")
+            .bind(name)
+            .bind(&email)
+            .bind(token_hash)
+            .fetch_one(&self.pool).await
+            .map_err(email_taken(&email))
+    }
+
+    async fn update_user(&self, id: i64, name: Option<String>, email: Option<String>) -> Result<User, TodoRepoError> {
+        sqlx::query_as::<_, User>("UPDATE users SET name = COALESCE(?1, name), email = COALESCE(?2, email) WHERE id = ?3 RETURNING id, name, email")
+            .bind(name)
+            .bind(&email)
+            .bind(id)
+            .fetch_optional(&self.pool).await
+            .map_err(email_taken(email.as_deref().unwrap_or_default()))?
+            .ok_or(TodoRepoError::UserNotFound(id))
+    }
+
+    async fn delete_user(&self, id: i64) -> Result<User, TodoRepoError> {
+        sqlx::query_as::<_, User>("DELETE FROM users WHERE id = ?1 RETURNING id, name, email")
+            .bind(id)
+            .fetch_optional(&self.pool).await?
+            .ok_or(TodoRepoError::UserNotFound(id))
+    }
+
+    async fn authenticate(&self, token_hash: &[u8]) -> Result<Option<User>, TodoRepoError> {
+        Ok(sqlx::query_as::<_, User>("SELECT id, name, email FROM users WHERE token_hash = ?1").bind(token_hash).fetch_optional(&self.pool).await?)
+    }
+
+    async fn set_feed_token(&self, id: i64, feed_token_hash: Vec<u8>) -> Result<User, TodoRepoError> {
+        sqlx::query_as::<_, User>("UPDATE users SET feed_token_hash = ?1 WHERE id = ?2 RETURNING id, name, email")
+            .bind(feed_token_hash)
+            .bind(id)
+            .fetch_optional(&self.pool).await?
+            .ok_or(TodoRepoError::UserNotFound(id))
+    }
+
+    async fn feed_owner(&self, feed_token_hash: &[u8]) -> Result<Option<User>, TodoRepoError> {
+        Ok(sqlx::query_as::<_, User>("SELECT id, name, email FROM users WHERE feed_token_hash = ?1").bind(feed_token_hash).fetch_optional(&self.pool).await?)
+    }
+}
+
+#[async_trait]
+impl TodoListRepo for TodoRepoSqlite {
+    async fn get_lists(&self, owner_id: i64, include_archived: bool) -> Result<Vec<TodoList>, TodoRepoError> {
+        Ok(sqlx::query_as::<_, TodoList>("SELECT id, owner_id, name, archived FROM todo_lists WHERE owner_id = ?1 AND (?2 OR NOT archived) ORDER BY id")
+            .bind(owner_id)
+            .bind(include_archived)
+            .fetch_all(&self.pool).await?)
+    }
+
+    async fn get_list(&self, id: i64) -> Result<TodoList, TodoRepoError> {
+        sqlx::query_as::<_, TodoList>("SELECT id, owner_id, name, archived FROM todo_lists WHERE id = ?1")
+            .bind(id)
+            .fetch_optional(&self.pool).await?
+            .ok_or(TodoRepoError::ListNotFound(id))
+    }
+
+    async fn create_list(&self, owner_id: i64, name: String) -> Result<TodoList, TodoRepoError> {
+        Ok(sqlx::query_as::<_, TodoList>("INSERT INTO todo_lists (owner_id, name, created_at) VALUES (?1, ?2, ?3) RETURNING id, owner_id, name, archived")
+            .bind(owner_id)
+            .bind(name)
+            .bind(now())
+            .fetch_one(&self.pool).await?)
+    }
+
+    async fn update_list(&self, id: i64, name: Option<String>, archived: Option<bool>) -> Result<TodoList, TodoRepoError> {
+        sqlx::query_as::<_, TodoList>("UPDATE todo_lists SET name = COALESCE(?1, name), archived = COALESCE(?2, archived) WHERE id = ?3 RETURNING id, owner_id, name, archived")
+            .bind(name)
+            .bind(archived)
+            .bind(id)
+            .fetch_optional(&self.pool).await?
+            .ok_or(TodoRepoError::ListNotFound(id))
+    }
+
+    async fn delete_list(&self, actor_id: Option<i64>, id: i64) -> Result<TodoList, TodoRepoError> {
+        let mut tx = self.pool.begin().await?;
+        let listed = sqlx::query_scalar::<_, i64>("SELECT id FROM todos WHERE list_id = ?1 AND deleted_at IS NULL ORDER BY id")
+            .bind(id)
+            .fetch_all(&mut *tx).await?;
+        let mut doomed = BTreeMap::new();
+        for todo_id in listed {
+            doomed.extend(Self::subtree_on(&mut tx, todo_id, None).await?.into_iter().map(|todo| (todo.id, todo)));
+        }
+        let doomed: Vec<Todo> = doomed.into_values().collect();
+        if let Some(first) = doomed.first() {
+            Self::trash_on(&mut tx, actor_id, first.id, &doomed, Some(now())).await?;
+        }
+
+        // Otherwise the list would take the todos in the trash with it.
+        sqlx::query("UPDATE todos SET list_id = NULL WHERE list_id = ?1")
+            .bind(id)
+            .execute(&mut *tx).await?;
+        let list = sqlx::query_as::<_, TodoList>("DELETE FROM todo_lists WHERE id = ?1 RETURNING id, owner_id, name, archived")
+            .bind(id)
+            .fetch_optional(&mut *tx).await?
+            .ok_or(TodoRepoError::ListNotFound(id))?;
+        tx.commit().await?;
+
+        Ok(list)
+    }
+}
+
+#[tokio::test]
+async fn todo_repo_sqlite_behaves() {
+    check_todo_repo_behaviour(&TodoRepoSqlite::connect("sqlite::memory:").await.unwrap()).await;
+}
+
+#[tokio::test]
+async fn todo_repo_sqlite_searches() {
+    let repo = TodoRepoSqlite::connect("sqlite::memory:").await.unwrap();
+    let in_description = repo.create(None, CreateTodo::new("Visit Rottnest", "Take a selfie with a quokka")).await.unwrap();
+    let in_title = repo.create(None, CreateTodo::new("Feed the Quokkas", "Leaves only")).await.unwrap();
+    repo.create(None, CreateTodo::new("Feed the cat", "")).await.unwrap();
+
+    let hits = repo.search(&TodoSearchQuery { q: "quokka".to_string(), limit: 10, owner_id: None }).await.unwrap();
+
+    assert_eq!(hits.iter().map(|h| h.todo.id).collect::<Vec<_>>(), vec![in_title.id, in_description.id]);
+    assert_eq!(hits[0].title_highlight, "Feed the <mark>Quokkas</mark>");
+    assert_eq!(hits[1].description_highlight, "Take a selfie with a <mark>quokka</mark>");
+}